@@ -0,0 +1,68 @@
+// Encodings of the bit manipulation extensions, which rvjit does not provide.
+
+fn r(funct7: u32, rs2: u32, rs1: u8, funct3: u32, rd: u8, opcode: u32) -> u32 {
+    funct7 << 25 | rs2 << 20 | (rs1 as u32) << 15 | funct3 << 12 | (rd as u32) << 7 | opcode
+}
+
+// Zbs
+
+pub fn bseti(rd: u8, rs1: u8, shamt: u8) -> u32 {
+    r(0b0010100, (shamt & 63) as u32, rs1, 1, rd, 0x13)
+}
+
+// Zba
+
+pub fn add_uw(rd: u8, rs1: u8, rs2: u8) -> u32 {
+    r(0b0000100, rs2 as u32, rs1, 0, rd, 0x3b)
+}
+
+pub fn zext_w(rd: u8, rs: u8) -> u32 {
+    add_uw(rd, rs, 0)
+}
+
+// sh1add, sh2add or sh3add: rd = (rs1 << shift) + rs2
+pub fn sh_add(shift: u8, rd: u8, rs1: u8, rs2: u8) -> u32 {
+    r(0b0010000, rs2 as u32, rs1, 2 * shift as u32, rd, 0x33)
+}
+
+// Zbb
+
+pub fn andn(rd: u8, rs1: u8, rs2: u8) -> u32 {
+    r(0b0100000, rs2 as u32, rs1, 7, rd, 0x33)
+}
+
+pub fn orn(rd: u8, rs1: u8, rs2: u8) -> u32 {
+    r(0b0100000, rs2 as u32, rs1, 6, rd, 0x33)
+}
+
+pub fn min(rd: u8, rs1: u8, rs2: u8) -> u32 {
+    r(0b0000101, rs2 as u32, rs1, 4, rd, 0x33)
+}
+
+pub fn minu(rd: u8, rs1: u8, rs2: u8) -> u32 {
+    r(0b0000101, rs2 as u32, rs1, 5, rd, 0x33)
+}
+
+pub fn max(rd: u8, rs1: u8, rs2: u8) -> u32 {
+    r(0b0000101, rs2 as u32, rs1, 6, rd, 0x33)
+}
+
+pub fn maxu(rd: u8, rs1: u8, rs2: u8) -> u32 {
+    r(0b0000101, rs2 as u32, rs1, 7, rd, 0x33)
+}
+
+pub fn sext_b(rd: u8, rs: u8) -> u32 {
+    r(0b0110000, 0b00100, rs, 1, rd, 0x13)
+}
+
+pub fn sext_h(rd: u8, rs: u8) -> u32 {
+    r(0b0110000, 0b00101, rs, 1, rd, 0x13)
+}
+
+pub fn zext_h(rd: u8, rs: u8) -> u32 {
+    r(0b0000100, 0, rs, 4, rd, 0x3b)
+}
+
+pub fn rev8(rd: u8, rs: u8) -> u32 {
+    r(0b0110101, 0b11000, rs, 5, rd, 0x13)
+}
@@ -0,0 +1,344 @@
+extern crate alloc;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+use crate::consts::*;
+use crate::seccomp::SeccompData;
+
+// A classic BPF instruction, `struct sock_filter` in linux.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SockFilter {
+    pub code: u16,
+    pub jt: u8,
+    pub jf: u8,
+    pub k: u32,
+}
+
+impl SockFilter {
+    pub fn new(code: u16, jt: u8, jf: u8, k: u32) -> Self {
+        Self { code, jt, jf, k }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CbpfError {
+    // empty, or more than `BPF_MAXINSNS` instructions
+    InvalidLength { len: usize },
+    InvalidOpcode { pc: usize, code: u16 },
+    DivisionByZero { pc: usize },
+    // shift by 32 or more
+    InvalidShift { pc: usize },
+    // scratch memory beyond `BPF_MEMWORDS`
+    InvalidMemWord { pc: usize },
+    JumpOutOfRange { pc: usize },
+    // the last instruction must return A or a constant
+    MissingReturn,
+    // M[k] read before being written on some path
+    UninitMemWord { pc: usize, k: u32 },
+    // loads of ancillary data, at negative offsets
+    Unsupported { pc: usize },
+    // a seccomp filter loading out of `SeccompData` or not a 32-bit word
+    InvalidDataOffset { pc: usize },
+}
+
+// instructions accepted by `check_classic`, see `chk_code_allowed` in linux
+const ALLOWED_CODES: &[u32] = &[
+    BPF_ALU | BPF_ADD | BPF_K,
+    BPF_ALU | BPF_ADD | BPF_X,
+    BPF_ALU | BPF_SUB | BPF_K,
+    BPF_ALU | BPF_SUB | BPF_X,
+    BPF_ALU | BPF_MUL | BPF_K,
+    BPF_ALU | BPF_MUL | BPF_X,
+    BPF_ALU | BPF_DIV | BPF_K,
+    BPF_ALU | BPF_DIV | BPF_X,
+    BPF_ALU | BPF_MOD | BPF_K,
+    BPF_ALU | BPF_MOD | BPF_X,
+    BPF_ALU | BPF_AND | BPF_K,
+    BPF_ALU | BPF_AND | BPF_X,
+    BPF_ALU | BPF_OR | BPF_K,
+    BPF_ALU | BPF_OR | BPF_X,
+    BPF_ALU | BPF_XOR | BPF_K,
+    BPF_ALU | BPF_XOR | BPF_X,
+    BPF_ALU | BPF_LSH | BPF_K,
+    BPF_ALU | BPF_LSH | BPF_X,
+    BPF_ALU | BPF_RSH | BPF_K,
+    BPF_ALU | BPF_RSH | BPF_X,
+    BPF_ALU | BPF_NEG,
+    BPF_LD | BPF_W | BPF_ABS,
+    BPF_LD | BPF_H | BPF_ABS,
+    BPF_LD | BPF_B | BPF_ABS,
+    BPF_LD | BPF_W | BPF_LEN,
+    BPF_LD | BPF_W | BPF_IND,
+    BPF_LD | BPF_H | BPF_IND,
+    BPF_LD | BPF_B | BPF_IND,
+    BPF_LD | BPF_IMM,
+    BPF_LD | BPF_MEM,
+    BPF_LDX | BPF_W | BPF_LEN,
+    BPF_LDX | BPF_B | BPF_MSH,
+    BPF_LDX | BPF_IMM,
+    BPF_LDX | BPF_MEM,
+    BPF_ST,
+    BPF_STX,
+    BPF_MISC | BPF_TAX,
+    BPF_MISC | BPF_TXA,
+    BPF_RET | BPF_K,
+    BPF_RET | BPF_A,
+    BPF_JMP | BPF_JA,
+    BPF_JMP | BPF_JEQ | BPF_K,
+    BPF_JMP | BPF_JEQ | BPF_X,
+    BPF_JMP | BPF_JGE | BPF_K,
+    BPF_JMP | BPF_JGE | BPF_X,
+    BPF_JMP | BPF_JGT | BPF_K,
+    BPF_JMP | BPF_JGT | BPF_X,
+    BPF_JMP | BPF_JSET | BPF_K,
+    BPF_JMP | BPF_JSET | BPF_X,
+];
+
+// Check a classic BPF program as `bpf_check_classic` in linux does: known opcodes, no division by
+// a zero constant, jumps within the program, and scratch memory written before being read.
+pub fn check_classic(filter: &[SockFilter]) -> Result<(), CbpfError> {
+    let len = filter.len();
+    if len == 0 || len > BPF_MAXINSNS as usize {
+        return Err(CbpfError::InvalidLength { len });
+    }
+    for (pc, f) in filter.iter().enumerate() {
+        let code = f.code as u32;
+        if !ALLOWED_CODES.contains(&code) {
+            return Err(CbpfError::InvalidOpcode { pc, code: f.code });
+        }
+        let is = |codes: &[u32]| codes.contains(&code);
+        if is(&[BPF_ALU | BPF_DIV | BPF_K, BPF_ALU | BPF_MOD | BPF_K]) && f.k == 0 {
+            return Err(CbpfError::DivisionByZero { pc });
+        }
+        if is(&[BPF_ALU | BPF_LSH | BPF_K, BPF_ALU | BPF_RSH | BPF_K]) && f.k >= 32 {
+            return Err(CbpfError::InvalidShift { pc });
+        }
+        if is(&[BPF_LD | BPF_MEM, BPF_LDX | BPF_MEM, BPF_ST, BPF_STX]) && f.k >= BPF_MEMWORDS {
+            return Err(CbpfError::InvalidMemWord { pc });
+        }
+        let in_range = |off: usize| pc + 1 + off < len;
+        let jumps_in = match code & 0x07 {
+            BPF_JMP if code == BPF_JMP | BPF_JA => in_range(f.k as usize),
+            BPF_JMP => in_range(f.jt as usize) && in_range(f.jf as usize),
+            _ => true,
+        };
+        if !jumps_in {
+            return Err(CbpfError::JumpOutOfRange { pc });
+        }
+    }
+    let last = filter[len - 1].code as u32;
+    if last != BPF_RET | BPF_K && last != BPF_RET | BPF_A {
+        return Err(CbpfError::MissingReturn);
+    }
+    check_load_and_stores(filter)
+}
+
+// Every path must write M[k] before reading it. Jumps only go forward, so the words written on
+// every path to an instruction are known once the instructions before it are checked.
+fn check_load_and_stores(filter: &[SockFilter]) -> Result<(), CbpfError> {
+    let mut masks = vec![u16::MAX; filter.len()];
+    let mut valid = 0u16;
+    for (pc, f) in filter.iter().enumerate() {
+        valid &= masks[pc];
+        let code = f.code as u32;
+        match code & 0x07 {
+            BPF_ST | BPF_STX => valid |= 1 << f.k,
+            BPF_LD | BPF_LDX if code & 0xe0 == BPF_MEM && valid & (1 << f.k) == 0 => {
+                return Err(CbpfError::UninitMemWord { pc, k: f.k });
+            }
+            BPF_JMP => {
+                if code == BPF_JMP | BPF_JA {
+                    masks[pc + 1 + f.k as usize] &= valid;
+                } else {
+                    masks[pc + 1 + f.jt as usize] &= valid;
+                    masks[pc + 1 + f.jf as usize] &= valid;
+                }
+                valid = u16::MAX;
+            }
+            BPF_RET => valid = u16::MAX,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// Check a seccomp filter, as `seccomp_check_filter` in linux does. Besides `check_classic`, the
+// filter may only load aligned 32-bit words of `SeccompData` with `LD_ABS`, and has no packet.
+pub fn check_seccomp(filter: &[SockFilter]) -> Result<(), CbpfError> {
+    check_classic(filter)?;
+    for (pc, f) in filter.iter().enumerate() {
+        let code = f.code as u32;
+        if code == BPF_LD | BPF_W | BPF_ABS {
+            if f.k as usize >= size_of::<SeccompData>() || f.k & 3 != 0 {
+                return Err(CbpfError::InvalidDataOffset { pc });
+            }
+        } else if matches!(code & 0x07, BPF_LD | BPF_LDX) && matches!(code & 0xe0, BPF_ABS | BPF_IND | BPF_MSH) {
+            return Err(CbpfError::InvalidOpcode { pc, code: f.code });
+        }
+    }
+    Ok(())
+}
+
+// registers holding the state of a classic program, as in linux
+const REG_A: u8 = BPF_REG_R0;
+const REG_X: u8 = BPF_REG_R7;
+const REG_TMP: u8 = BPF_REG_R8;
+// LD_ABS and LD_IND read the packet of the context in R6, and seccomp filters the context
+const REG_CTX: u8 = BPF_REG_R6;
+
+// stack used by the scratch memory of a translated program
+pub const CBPF_STACK_SIZE: usize = 4 * BPF_MEMWORDS as usize;
+
+// offset of `len` in `struct __sk_buff`
+const SKB_LEN_OFF: i16 = 0;
+
+fn insn(op: u32, dst: u8, src: u8, off: i16, imm: i32) -> u64 {
+    op as u8 as u64 | (dst as u64) << 8 | (src as u64) << 12 | (off as u16 as u64) << 16 | (imm as u32 as u64) << 32
+}
+
+// M[k] is at fp - 4 * (BPF_MEMWORDS - k)
+fn mem_off(k: u32) -> i16 {
+    -4 * (BPF_MEMWORDS - k) as i16
+}
+
+struct Translator {
+    insns: Vec<u64>,
+    // jumps to fix up, with the classic instruction they go to
+    jumps: Vec<(usize, usize)>,
+}
+
+impl Translator {
+    fn emit(&mut self, op: u32, dst: u8, src: u8, off: i16, imm: i32) {
+        self.insns.push(insn(op, dst, src, off, imm));
+    }
+
+    fn emit_jump(&mut self, op: u32, src: u8, imm: i32, target: usize) {
+        let dst = if op == BPF_JMP | BPF_JA { 0 } else { REG_A };
+        self.jumps.push((self.insns.len(), target));
+        self.emit(op, dst, src, 0, imm);
+    }
+}
+
+// Translate a classic BPF socket filter into eBPF, as `bpf_convert_filter` in linux does.
+// A and X live in R0 and R7, M[] on a stack of `CBPF_STACK_SIZE` bytes. The program takes an
+// `__sk_buff` in R1 and is meant to be compiled as a `ProgramType::SocketFilter`.
+pub fn convert_classic(filter: &[SockFilter]) -> Result<Vec<u64>, CbpfError> {
+    check_classic(filter)?;
+    convert(filter, false)
+}
+
+// Translate a seccomp filter into eBPF. The program takes a `SeccompData` in R1, which `LD_ABS`
+// reads in host byte order, and is meant to be compiled with `seccomp_data_descriptor`.
+pub fn convert_seccomp(filter: &[SockFilter]) -> Result<Vec<u64>, CbpfError> {
+    check_seccomp(filter)?;
+    convert(filter, true)
+}
+
+fn convert(filter: &[SockFilter], seccomp: bool) -> Result<Vec<u64>, CbpfError> {
+    let mut t = Translator {
+        insns: Vec::new(),
+        jumps: Vec::new(),
+    };
+    // start of the translation of each instruction
+    let mut starts = Vec::with_capacity(filter.len());
+
+    t.emit(BPF_ALU64 | BPF_X | BPF_MOV, REG_CTX, BPF_REG_R1, 0, 0);
+    t.emit(BPF_ALU | BPF_K | BPF_MOV, REG_A, 0, 0, 0);
+    t.emit(BPF_ALU | BPF_K | BPF_MOV, REG_X, 0, 0, 0);
+
+    for (pc, f) in filter.iter().enumerate() {
+        starts.push(t.insns.len());
+        let code = f.code as u32;
+        let k = f.k as i32;
+        match code & 0x07 {
+            BPF_LD | BPF_LDX => {
+                let dst = if code & 0x07 == BPF_LD { REG_A } else { REG_X };
+                match code & 0xe0 {
+                    BPF_IMM => t.emit(BPF_ALU | BPF_K | BPF_MOV, dst, 0, 0, k),
+                    BPF_ABS if seccomp => t.emit(BPF_LDX | BPF_MEM | BPF_W, dst, REG_CTX, k as i16, 0),
+                    BPF_LEN if seccomp => {
+                        t.emit(BPF_ALU | BPF_K | BPF_MOV, dst, 0, 0, size_of::<SeccompData>() as i32);
+                    }
+                    BPF_ABS | BPF_IND | BPF_MSH if k < 0 => return Err(CbpfError::Unsupported { pc }),
+                    BPF_ABS => t.emit(code, 0, 0, 0, k),
+                    BPF_IND => t.emit(code, 0, REG_X, 0, k),
+                    BPF_LEN => t.emit(BPF_LDX | BPF_MEM | BPF_W, dst, REG_CTX, SKB_LEN_OFF, 0),
+                    BPF_MEM => t.emit(BPF_LDX | BPF_MEM | BPF_W, dst, BPF_REG_FP, mem_off(f.k), 0),
+                    _ => {
+                        // X = 4 * (P[k] & 0xf), the length of an IP header
+                        t.emit(BPF_ALU64 | BPF_X | BPF_MOV, REG_TMP, REG_A, 0, 0);
+                        t.emit(BPF_LD | BPF_ABS | BPF_B, 0, 0, 0, k);
+                        t.emit(BPF_ALU | BPF_K | BPF_AND, REG_A, 0, 0, 0xf);
+                        t.emit(BPF_ALU | BPF_K | BPF_MUL, REG_A, 0, 0, 4);
+                        t.emit(BPF_ALU64 | BPF_X | BPF_MOV, REG_X, REG_A, 0, 0);
+                        t.emit(BPF_ALU64 | BPF_X | BPF_MOV, REG_A, REG_TMP, 0, 0);
+                    }
+                }
+            }
+            BPF_ST | BPF_STX => {
+                let src = if code == BPF_ST { REG_A } else { REG_X };
+                t.emit(BPF_STX | BPF_MEM | BPF_W, BPF_REG_FP, src, mem_off(f.k), 0);
+            }
+            BPF_ALU => {
+                let op = code & 0xf0;
+                if op == BPF_NEG {
+                    t.emit(BPF_ALU | BPF_K | BPF_NEG, REG_A, 0, 0, 0);
+                } else if code & BPF_X == 0 {
+                    t.emit(code, REG_A, 0, 0, k);
+                } else {
+                    // a division by zero returns 0
+                    if op == BPF_DIV || op == BPF_MOD {
+                        t.emit(BPF_JMP | BPF_K | BPF_JNE, REG_X, 0, 2, 0);
+                        t.emit(BPF_ALU | BPF_K | BPF_MOV, REG_A, 0, 0, 0);
+                        t.emit(BPF_JMP | BPF_EXIT, 0, 0, 0, 0);
+                    }
+                    t.emit(code, REG_A, REG_X, 0, 0);
+                }
+            }
+            BPF_JMP if code == BPF_JMP | BPF_JA => t.emit_jump(BPF_JMP | BPF_JA, 0, 0, pc + 1 + f.k as usize),
+            BPF_JMP => {
+                // A is a u32, compared as one
+                let (src, imm) = if code & BPF_X != 0 { (REG_X, 0) } else { (0, k) };
+                let (jt, jf) = (pc + 1 + f.jt as usize, pc + 1 + f.jf as usize);
+                let op = code & 0xf0;
+                let inverse = match op {
+                    BPF_JEQ => Some(BPF_JNE),
+                    BPF_JGT => Some(BPF_JLE),
+                    BPF_JGE => Some(BPF_JLT),
+                    _ => None,
+                };
+                match inverse {
+                    _ if f.jf == 0 => t.emit_jump(BPF_JMP32 | (code & 0xf8), src, imm, jt),
+                    Some(inverse) if f.jt == 0 => t.emit_jump(BPF_JMP32 | (code & BPF_X) | inverse, src, imm, jf),
+                    _ => {
+                        t.emit_jump(BPF_JMP32 | (code & 0xf8), src, imm, jt);
+                        t.emit_jump(BPF_JMP | BPF_JA, 0, 0, jf);
+                    }
+                }
+            }
+            BPF_RET => {
+                if code == BPF_RET | BPF_K {
+                    t.emit(BPF_ALU | BPF_K | BPF_MOV, REG_A, 0, 0, k);
+                }
+                t.emit(BPF_JMP | BPF_EXIT, 0, 0, 0, 0);
+            }
+            _ => {
+                let (dst, src) = if code == BPF_MISC | BPF_TAX {
+                    (REG_X, REG_A)
+                } else {
+                    (REG_A, REG_X)
+                };
+                t.emit(BPF_ALU | BPF_X | BPF_MOV, dst, src, 0, 0);
+            }
+        }
+    }
+
+    for &(idx, target) in &t.jumps {
+        let off = (starts[target] - (idx + 1)) as i16;
+        t.insns[idx] |= (off as u16 as u64) << 16;
+    }
+    Ok(t.insns)
+}
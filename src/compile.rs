@@ -1,15 +1,35 @@
 #[allow(unused)]
 extern crate alloc;
 
+use alloc::borrow::Cow;
 use alloc::collections::BTreeMap;
+use alloc::string::String;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
 
+use crate::bitmanip::{andn, max, maxu, min, minu, orn, rev8, sext_b, sext_h, sh_add, zext_h, zext_w};
 use crate::consts::*;
+use crate::context::{convert_ctx_accesses, ContextDescriptor};
+use crate::fuse::{fuse_pairs, Fused, Operand};
+use crate::imm::synthesize;
+use crate::helper::{
+    ArgKind, Helper, HelperRegistry, InlineFn, BPF_FUNC_MAP_LOOKUP_ELEM, BPF_FUNC_PROBE_READ,
+    BPF_FUNC_PROBE_READ_KERNEL, PROBE_READ_ARGS,
+};
+use crate::map::{bpf_map_lookup_elem, BpfMap, MapAttr, MapTable, MapType};
+use crate::program::ProgramType;
+use crate::opt::optimize;
+use crate::rvc::compress;
+pub use crate::opt::OptLevel;
+use crate::zext::zext_needed;
+use crate::verifier::{CtxLayout, InsnAux, MapPtrState, MemKind, Verifier, VerifierError, BPF_COMPLEXITY_LIMIT_INSNS};
 use rvjit::rv32i::*;
 use rvjit::rv32m::*;
 use rvjit::rv64i::*;
 use rvjit::rv64m::*;
 
+pub use crate::consts::BPF_F_TEST_RND_HI32;
+
 // this mapping is made consistent with linux BPF JIT for RV64
 fn bpf_to_rv_reg(reg: u8) -> u8 {
     static REG_MAP: [u8; BPF_MAX_REGS] = [
@@ -28,56 +48,558 @@ fn bpf_to_rv_reg(reg: u8) -> u8 {
     REG_MAP[reg as usize]
 }
 
-fn is_in_i32_range(v: i64) -> bool {
-    -(1 << 31) <= v && v < (1 << 31)
+// reach of auipc + jalr, ±2 GiB
+fn is_in_auipc_range(off: i64) -> bool {
+    (-(1 << 31)..(1 << 31) - (1 << 11)).contains(&off)
 }
 
 fn is_in_i12_range(v: i32) -> bool {
     -(1 << 11) <= v && v < (1 << 11)
 }
 
+// reach of conditional branches, ±4 KiB
+fn is_in_branch_range(off: isize) -> bool {
+    (-(1 << 12)..(1 << 12)).contains(&off)
+}
+
+// reach of jal, ±1 MiB
+fn is_in_jal_range(off: isize) -> bool {
+    (-(1 << 20)..(1 << 20)).contains(&off)
+}
+
 fn round_up(x: usize, d: usize) -> usize {
     ((x + d - 1) / d) * d
 }
 
+// bytes accessed by a load or store
+fn access_size(op: u8) -> i32 {
+    match (op & 0b11000) as u32 {
+        BPF_B => 1,
+        BPF_H => 2,
+        BPF_W => 4,
+        _ => 8,
+    }
+}
+
 // type Helper = unsafe fn(u64, u64, u64, u64, u64) -> u64;
 
+// condition of a RISC-V branch, taken if `rs1 op rs2`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Cond {
+    Eq,
+    Ne,
+    Lt,
+    Ge,
+    Ltu,
+    Geu,
+}
+
+impl Cond {
+    fn invert(self) -> Self {
+        match self {
+            Cond::Eq => Cond::Ne,
+            Cond::Ne => Cond::Eq,
+            Cond::Lt => Cond::Ge,
+            Cond::Ge => Cond::Lt,
+            Cond::Ltu => Cond::Geu,
+            Cond::Geu => Cond::Ltu,
+        }
+    }
+
+    fn branch(self, off: i32, rs1: u8, rs2: u8) -> u32 {
+        let off = off as u32;
+        match self {
+            Cond::Eq => beq(off, rs1, rs2),
+            Cond::Ne => bne(off, rs1, rs2),
+            Cond::Lt => blt(off, rs1, rs2),
+            Cond::Ge => bge(off, rs1, rs2),
+            Cond::Ltu => bltu(off, rs1, rs2),
+            Cond::Geu => bgeu(off, rs1, rs2),
+        }
+    }
+}
+
+// where a jump of the program body goes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum JumpTarget {
+    Insn(usize),
+    Exit,
+}
+
+// What the program uses, found before jitting so that the prologue saves and sets up no more than needed
+#[derive(Clone, Copy, Debug, Default)]
+struct Usage {
+    // eBPF registers written, and BPF_REG_FP if the stack is used
+    written: u16,
+    calls: bool,
+}
+
+impl Usage {
+    fn scan(insns: &[u64]) -> Self {
+        let mut usage = Self::default();
+        for &insn in insns {
+            let op = (insn & 0xff) as u8;
+            let dst = ((insn & 0x0f00) >> 8) as u8;
+            let src = ((insn & 0xf000) >> 12) as u8;
+            if dst == BPF_REG_FP || src == BPF_REG_FP {
+                usage.written |= 1 << BPF_REG_FP;
+            }
+            match (op & 0b111) as u32 {
+                BPF_ALU | BPF_ALU64 | BPF_LDX => usage.written |= 1 << dst,
+                _ if op == LD_IMM_DW => usage.written |= 1 << dst,
+                _ if op == JMP_K_CALL => usage.calls = true,
+                _ => {}
+            }
+        }
+        usage
+    }
+
+    fn writes(&self, reg: u8) -> bool {
+        self.written & (1 << reg) != 0
+    }
+}
+
+// offsets of the program body in a pass, for the next one to lay out its jumps
+#[derive(Clone, Debug, Default)]
+struct Layout {
+    pc_map: BTreeMap<usize, usize>,
+    exit: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompileError {
+    // LD_IMM_DW refers a map fd which is not in the map table
+    UnknownMapFd { bpf_pc: usize, fd: u32 },
+    // LD_IMM_DW with a src_reg other than 0 or BPF_PSEUDO_MAP_FD, or a call with a src_reg other than 0
+    UnsupportedPseudoSrc { bpf_pc: usize, src: u8 },
+    // call to a helper ID which is not in the helper registry
+    UnknownHelper { bpf_pc: usize, id: u32 },
+    // more than `BPF_MAXINSNS` instructions
+    ProgramTooLarge { len: usize },
+    // a jump outside of the program, which only the verifier rejects otherwise
+    JumpOutOfRange { bpf_pc: usize, target: isize },
+    // rejected by the verifier, see `get_verifier_log` for details
+    Verifier(VerifierError),
+}
+
+// A load which may fault, see `search_exception_table`.
+// Offsets are relative to the start of the jitted code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExceptionEntry {
+    pub insn_off: usize,
+    pub fixup_off: usize,
+    // RISC-V register to be zeroed
+    pub reg: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Fixup {
+    pub resume_pc: usize,
+    pub reg: u8,
+}
+
+// To be called by the trap handler on a load fault at `fault_pc` in the jitted code at `code_base`.
+// If the faulting load is in the exception table, the handler should zero `reg` and resume at `resume_pc`,
+// as linux does for BPF_PROBE_MEM on RV64.
+pub fn search_exception_table(table: &[ExceptionEntry], code_base: usize, fault_pc: usize) -> Option<Fixup> {
+    let insn_off = fault_pc.checked_sub(code_base)?;
+    let i = table.binary_search_by_key(&insn_off, |e| e.insn_off).ok()?;
+    Some(Fixup {
+        resume_pc: code_base + table[i].fixup_off,
+        reg: table[i].reg,
+    })
+}
+
+// seed of the upper halves filled in under `BPF_F_TEST_RND_HI32`
+const RND_HI32_SEED: u64 = 0x9e37_79b9_7f4a_7c15;
+
+// Where a sandboxed program reports the access which aborted it, see `set_sandbox`.
+// The prologue clears it, so it always tells about the last run.
+#[repr(C)]
+#[derive(Default)]
+pub struct SandboxStatus {
+    aborted: AtomicU64,
+    addr: AtomicU64,
+}
+
+impl SandboxStatus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // address of the access out of the allowed regions, if the last run was aborted by one
+    pub fn violation(&self) -> Option<u64> {
+        match self.aborted.load(Ordering::Relaxed) {
+            0 => None,
+            _ => Some(self.addr.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Run a program jitted with `set_sandbox(.., self)`, telling an abort apart from the value it returns.
+    ///
+    /// # Safety
+    ///
+    /// `prog` must be the jitted code of a program sandboxed with this status, `ctx` its context of
+    /// the size given to `set_sandbox`.
+    pub unsafe fn run(&self, prog: unsafe extern "C" fn(u64) -> u64, ctx: u64) -> Result<u64, SandboxViolation> {
+        self.run_with(|| prog(ctx))
+    }
+
+    // Same as `run`, the program being run by `run`, e.g. in an emulator.
+    pub fn run_with<F>(&self, run: F) -> Result<u64, SandboxViolation>
+    where
+        F: FnOnce() -> u64,
+    {
+        let ret = run();
+        match self.violation() {
+            Some(addr) => Err(SandboxViolation { addr }),
+            None => Ok(ret),
+        }
+    }
+
+    fn as_ptr(&self) -> *const u64 {
+        self.aborted.as_ptr()
+    }
+}
+
+// The abort of a sandboxed program, at an access or a helper argument out of the allowed regions
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SandboxViolation {
+    pub addr: u64,
+}
+
+// Budget of a metered program, loaded by the prologue and stored back on exit.
+// Each backward jump or helper call taken consumes one unit. A program running out of it is
+// aborted, which is told by `exhausted` rather than by the return value, see `set_meter`.
+#[repr(C)]
+#[derive(Default)]
+pub struct Meter {
+    budget: AtomicU64,
+    exhausted: AtomicU64,
+}
+
+impl Meter {
+    pub fn new(budget: u64) -> Self {
+        Self {
+            budget: AtomicU64::new(budget),
+            exhausted: AtomicU64::new(0),
+        }
+    }
+
+    // budget of the next run
+    pub fn set(&self, budget: u64) {
+        self.budget.store(budget, Ordering::Relaxed);
+    }
+
+    // budget left by the last run, 0 if it was exhausted
+    pub fn remaining(&self) -> u64 {
+        self.budget.load(Ordering::Relaxed)
+    }
+
+    // whether the last run was aborted for lack of budget, as opposed to using it all up
+    pub fn exhausted(&self) -> bool {
+        self.exhausted.load(Ordering::Relaxed) != 0
+    }
+
+    fn as_ptr(&self) -> *const u64 {
+        self.budget.as_ptr()
+    }
+}
+
+// RISC-V extensions of the target beyond RV64IM, which the JIT may use
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TargetFeatures {
+    // compressed instructions, emitted in place of the 32-bit ones whenever their operands allow it
+    pub rvc: bool,
+    // single-bit instructions, `bseti` for constants
+    pub zbs: bool,
+    // address generation, `zext.w` for zero extension and `sh1add` - `sh3add` for scaled indexes
+    pub zba: bool,
+    // basic bit manipulation, `rev8` for byte swaps, along with `zext.h`, `sext.b/h`, `andn`, `orn`
+    // and `min`/`max` for the pairs of eBPF instructions `fuse::fuse_pairs` finds
+    pub zbb: bool,
+}
+
+// how jitted code calls helpers
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CallMode {
+    // through the PLT after the code, which may then be loaded anywhere
+    #[default]
+    Plt,
+    // straight to the helpers, from code loaded at this address
+    Direct(u64),
+}
+
 pub struct JitContext<'a> {
     bpf_insns: &'a [u64],
+    maps: Option<&'a MapTable>,
+    probe_mem: bool,
+    verify: bool,
+    verifier_log: String,
+    ctx_layout: CtxLayout,
+    ctx_desc: Option<Cow<'a, ContextDescriptor>>,
+    prog_type: Option<ProgramType>,
+    bounded_loops: bool,
+    complexity_limit: usize,
+    insn_aux: Vec<InsnAux>, // facts proven by the verifier
+    sandbox_ctx_size: Option<usize>,
+    sandbox_status: Option<&'a SandboxStatus>,
+    sandbox_regions: Vec<(u64, u64, bool)>, // (start, end, writable)
+    meter: Option<&'a Meter>,
+    prog_flags: u32,
+    opt_level: OptLevel,
+    call_mode: CallMode,
+    features: TargetFeatures,
+    origin: Vec<usize>, // pc in the original program of each optimized instruction
+    usage: Usage,
+    zext: Vec<bool>, // whether 32-bit results must be zero-extended, see `zext_needed`
+    fused: Vec<Option<Fused>>, // pairs of instructions jitted as one, see `fuse_pairs`
+    rnd_hi32: u64,   // state of the random upper halves
     bpf_pc: usize,
-    pub code: Vec<u32>,
-    pub code_size: usize,
+    insn: u64,       // eBPF instruction being emitted
+    body_len: usize, // of the program being emitted
+    pub code: Vec<u32>, // halfwords in little-endian order, the last word padded with zero
+    pub code_size: usize, // in bytes
     pc_map: BTreeMap<usize, usize>,
-    plt_loads: Vec<usize>, // for BPF call
-    exits: Vec<usize>,     // for BPF exit
-    jumps: Vec<(usize, usize)>, // for BPF jump, (bpf_pc, rv_off)
+    prev_layout: Option<Layout>, // of the previous pass
+    exit_off: usize,
+    plt_loads: Vec<(usize, usize)>, // for BPF call, (rv_off, plt_slot)
+    plt: Vec<u64>,                  // helper addresses, in order of first use
+    plt_slots: BTreeMap<u32, usize>, // helper ID -> plt slot
+    pool_loads: Vec<(usize, usize, u8)>, // for constants loaded from the pool, (rv_off, pool slot, rd)
+    pool: Vec<u64>,                      // 64-bit constants, in order of first use
+    pool_slots: BTreeMap<u64, usize>,    // constant -> pool slot
+    probe_read_calls: Vec<usize>, // for builtin bpf_probe_read_kernel
+    extable: Vec<ExceptionEntry>,
+    sandbox_checks: Vec<usize>, // for sandbox check before memory access
+    region_table_loads: Vec<usize>,
+    abort: usize,
+    sandbox_aborts: Vec<usize>, // for helper argument checks, jump to the abort path
+    meter_checks: Vec<usize>, // for budget check, jump to the exhausted path
 }
 
 impl<'a> JitContext<'a> {
     pub fn new(bpf_insns: &'a [u64]) -> Self {
         Self {
             bpf_insns,
+            maps: None,
+            probe_mem: false,
+            verify: false,
+            verifier_log: String::new(),
+            ctx_layout: CtxLayout::default(),
+            ctx_desc: None,
+            prog_type: None,
+            bounded_loops: false,
+            complexity_limit: BPF_COMPLEXITY_LIMIT_INSNS,
+            insn_aux: Vec::new(),
+            sandbox_ctx_size: None,
+            sandbox_status: None,
+            sandbox_regions: Vec::new(),
+            meter: None,
+            prog_flags: 0,
+            opt_level: OptLevel::default(),
+            call_mode: CallMode::default(),
+            features: TargetFeatures::default(),
+            origin: Vec::new(),
+            usage: Usage::default(),
+            zext: Vec::new(),
+            fused: Vec::new(),
+            rnd_hi32: RND_HI32_SEED,
             bpf_pc: 0,
+            insn: 0,
+            body_len: 0,
             code: Vec::new(),
             code_size: 0,
             pc_map: BTreeMap::new(),
+            prev_layout: None,
+            exit_off: 0,
             plt_loads: Vec::new(),
-            exits: Vec::new(),
-            jumps: Vec::new(),
+            plt: Vec::new(),
+            plt_slots: BTreeMap::new(),
+            pool_loads: Vec::new(),
+            pool: Vec::new(),
+            pool_slots: BTreeMap::new(),
+            probe_read_calls: Vec::new(),
+            extable: Vec::new(),
+            sandbox_checks: Vec::new(),
+            region_table_loads: Vec::new(),
+            abort: 0,
+            sandbox_aborts: Vec::new(),
+            meter_checks: Vec::new(),
         }
     }
 
+    // maps referred by `BPF_PSEUDO_MAP_FD` are resolved against this table
+    pub fn set_map_table(&mut self, maps: &'a MapTable) {
+        self.maps = Some(maps);
+    }
+
+    // treat every LDX_MEM as BPF_PROBE_MEM, a fault on such a load reads zero instead
+    pub fn set_probe_mem(&mut self, enable: bool) {
+        self.probe_mem = enable;
+    }
+
+    // Run the verifier before jitting, which is required for untrusted programs.
+    // Memory accesses proven safe by it are neither sandboxed nor recorded in the exception table.
+    pub fn set_verify(&mut self, enable: bool) {
+        self.verify = enable;
+    }
+
+    // layout of the context passed in R1 as seen by the verifier, which rejects any access to it otherwise
+    pub fn set_ctx_layout(&mut self, layout: CtxLayout) {
+        self.ctx_layout = layout;
+    }
+
+    // Let programs see the context through the fields of `desc`, rewritten into accesses to the real struct.
+    // Accesses to the context are found by the verifier, which is enabled.
+    pub fn set_ctx_descriptor(&mut self, desc: &'a ContextDescriptor) {
+        self.ctx_desc = Some(Cow::Borrowed(desc));
+        self.verify = true;
+    }
+
+    // Restrict the program to what its type allows, which the verifier enforces.
+    // The context is described by the type unless `set_ctx_descriptor` is called.
+    pub fn set_program_type(&mut self, prog_type: ProgramType) {
+        self.prog_type = Some(prog_type);
+        self.verify = true;
+    }
+
+    // Let the verifier accept loops it can prove to terminate within the complexity limit.
+    // Programs with loops are rejected otherwise.
+    pub fn set_bounded_loops(&mut self, enable: bool) {
+        self.bounded_loops = enable;
+    }
+
+    // instructions the verifier may simulate on all paths, `BPF_COMPLEXITY_LIMIT_INSNS` by default
+    pub fn set_complexity_limit(&mut self, limit: usize) {
+        self.complexity_limit = limit;
+    }
+
+    pub fn get_verifier_log(&self) -> &str {
+        &self.verifier_log
+    }
+
+    // Check every LDX/ST/STX at runtime against the stack, the context of `ctx_size` bytes passed in R1,
+    // values of maps in the map table and regions added by `add_sandbox_region`, and so the pointers
+    // passed to helpers. A program accessing memory outside of them is aborted, which is reported to
+    // `status` as the return value may be anything, see `SandboxStatus::run`.
+    pub fn set_sandbox(&mut self, ctx_size: usize, status: &'a SandboxStatus) {
+        self.sandbox_status = Some(status);
+        self.sandbox_ctx_size = Some(ctx_size);
+    }
+
+    pub fn add_sandbox_region(&mut self, start: u64, len: usize, writable: bool) {
+        self.sandbox_regions.push((start, start + len as u64, writable));
+    }
+
+    // Meter the program with the budget in `meter`, kept in s7 while it runs.
+    // A program running out of budget exits with 0, and `meter.exhausted()` tells it apart.
+    pub fn set_meter(&mut self, meter: &'a Meter) {
+        self.meter = Some(meter);
+    }
+
+    // `BPF_F_*` flags of the program, of which `BPF_F_TEST_RND_HI32` fills the upper half of 32-bit
+    // results which are not zero-extended with random bits, to test that they are never read
+    pub fn set_prog_flags(&mut self, flags: u32) {
+        self.prog_flags = flags;
+    }
+
+    // Optimize the program at `level` before jitting it, see `OptLevel`.
+    // Verifier facts and errors still refer to pcs of the original program.
+    pub fn set_opt_level(&mut self, level: OptLevel) {
+        self.opt_level = level;
+    }
+
+    // Call helpers straight from the code with `CallMode::Direct`, once the address it is loaded at is known.
+    // Helpers are called through the PLT by default, which keeps the code position independent.
+    pub fn set_call_mode(&mut self, mode: CallMode) {
+        self.call_mode = mode;
+    }
+
+    // extensions the target implements, none by default
+    pub fn set_target_features(&mut self, features: TargetFeatures) {
+        self.features = features;
+    }
+
+    // sorted by `insn_off`, to be passed to `search_exception_table`
+    pub fn get_exception_table(&self) -> &[ExceptionEntry] {
+        &self.extable
+    }
+
+    // The machine code, whose compressed instructions are only aligned to 2 bytes. It has
+    // `code_size` bytes, the last word being padded with zero.
     pub fn get_rv_code(&self) -> &Vec<u32> {
         &self.code
     }
 
+    // pc in the original program of the instruction at `bpf_pc`
+    fn origin_pc(&self, bpf_pc: usize) -> usize {
+        self.origin.get(bpf_pc).copied().unwrap_or(bpf_pc)
+    }
+
+    // whether the memory access at `bpf_pc` was proven within bounds by the verifier
+    fn is_proven_safe(&self, bpf_pc: usize) -> bool {
+        self.insn_aux
+            .get(self.origin_pc(bpf_pc))
+            .and_then(|aux| aux.mem)
+            .is_some_and(|mem| mem != MemKind::Probe)
+    }
+
+    // emit `i`, compressed if possible under `TargetFeatures::rvc`
     fn emit(&mut self, i: u32) {
-        self.code.push(i);
-        self.code_size += 4;
+        match compress(i) {
+            Some(c) if self.features.rvc => self.emit_half(c),
+            _ => self.emit_word(i),
+        }
+    }
+
+    // emit `i` as it is, for instructions whose size must not depend on their operands
+    fn emit_word(&mut self, i: u32) {
+        if self.code_size.is_multiple_of(4) {
+            self.code.push(i);
+            self.code_size += 4;
+        } else {
+            self.emit_half(i as u16);
+            self.emit_half((i >> 16) as u16);
+        }
+    }
+
+    fn emit_half(&mut self, h: u16) {
+        if self.code_size.is_multiple_of(4) {
+            self.code.push(h as u32);
+        } else {
+            *self.code.last_mut().unwrap() |= (h as u32) << 16;
+        }
+        self.code_size += 2;
+    }
+
+    // bytes taken by `i` once emitted
+    fn insn_size(&self, i: u32) -> usize {
+        if self.features.rvc && compress(i).is_some() {
+            2
+        } else {
+            4
+        }
     }
 
     fn emit_placeholder(&mut self, _s: &str) {
-        self.emit(0); // invalid instruction
+        self.emit_word(0); // invalid instruction
+    }
+
+    // replace the placeholder at `rvoff` with `i`
+    fn patch(&mut self, rvoff: usize, i: u32) {
+        for (off, h) in [(rvoff, i as u16), (rvoff + 2, (i >> 16) as u16)].iter() {
+            let shift = 8 * (off % 4);
+            let word = &mut self.code[off / 4];
+            *word = *word & !(0xffff << shift) | (*h as u32) << shift;
+        }
+    }
+
+    // pad the code with zero up to a multiple of `align` bytes
+    fn emit_padding(&mut self, align: usize) {
+        while !self.code_size.is_multiple_of(align) {
+            self.emit_half(0);
+        }
     }
 
     pub fn emit_lui(&mut self, rd: u8, imm: u32) {
@@ -140,6 +662,18 @@ impl<'a> JitContext<'a> {
         self.emit(addiw(rd, rs1, imm as u32));
     }
 
+    pub fn emit_andi(&mut self, rd: u8, rs1: u8, imm: i32) {
+        self.emit(andi(rd, rs1, imm as u32));
+    }
+
+    pub fn emit_ori(&mut self, rd: u8, rs1: u8, imm: i32) {
+        self.emit(ori(rd, rs1, imm as u32));
+    }
+
+    pub fn emit_xori(&mut self, rd: u8, rs1: u8, imm: i32) {
+        self.emit(xori(rd, rs1, imm as u32));
+    }
+
     pub fn emit_slli(&mut self, rd: u8, rs: u8, shamt: u8) {
         self.emit(slli64(rd, rs, shamt));
     }
@@ -203,33 +737,123 @@ impl<'a> JitContext<'a> {
 
     // zero-extend a 32-bit value
     pub fn emit_zext_32(&mut self, rd: u8, rs: u8) {
-        self.emit_slli(rd, rs, 32);
-        self.emit_srli(rd, rd, 32);
+        if self.features.zba {
+            self.emit(zext_w(rd, rs));
+        } else {
+            self.emit_slli(rd, rs, 32);
+            self.emit_srli(rd, rd, 32);
+        }
     }
 
-    // code generation for immediate is not straightforward.
-    // this snippet is adapted from linux, see https://elixir.bootlin.com/linux/latest/source/arch/riscv/net/bpf_jit_comp64.c#L139
-    pub fn emit_imm(&mut self, rd: u8, imm: i64) {
-        let hi = (imm + (1 << 11)) >> 12;
-        let lo = (((imm & 0xfff) << 52) >> 52) as i32; // sign extended
+    // zero-extend a 16-bit value
+    pub fn emit_zext_16(&mut self, rd: u8, rs: u8) {
+        if self.features.zbb {
+            self.emit(zext_h(rd, rs));
+        } else {
+            self.emit_slli(rd, rs, 48);
+            self.emit_srli(rd, rd, 48);
+        }
+    }
 
-        if is_in_i32_range(imm) {
-            if hi != 0 {
-                self.emit_lui(rd, hi as u32);
-                self.emit_addiw(rd, rd, lo);
-            } else {
-                self.emit_addi(rd, RV_REG_ZERO, lo);
+    // Reverse the lower `bits` of `rd` byte by byte, zero-extended. Without Zbb, bytes are moved to t2
+    // one at a time from the lowest, as linux does.
+    pub fn emit_bswap(&mut self, rd: u8, bits: i32) {
+        if self.features.zbb {
+            self.emit(rev8(rd, rd));
+            if bits < 64 {
+                self.emit_srli(rd, rd, (64 - bits) as u8);
             }
             return;
         }
+        let bytes = bits / 8;
+        self.emit_andi(RV_REG_T2, rd, 0xff);
+        for i in 1..bytes {
+            self.emit_slli(RV_REG_T2, RV_REG_T2, 8);
+            self.emit_srli(rd, rd, 8);
+            self.emit_andi(RV_REG_T1, rd, 0xff);
+            self.emit_or(if i == bytes - 1 { rd } else { RV_REG_T2 }, RV_REG_T2, RV_REG_T1);
+        }
+    }
+
+    // a pair of eBPF instructions found by `fuse_pairs`
+    fn emit_fused(&mut self, fused: Fused) {
+        let reg = bpf_to_rv_reg;
+        match fused {
+            Fused::ShiftAdd { dst, src, add, shift } => self.emit(sh_add(shift, reg(dst), reg(src), reg(add))),
+            Fused::AndNot { dst, src, not } => self.emit(andn(reg(dst), reg(src), reg(not))),
+            Fused::OrNot { dst, src, not } => self.emit(orn(reg(dst), reg(src), reg(not))),
+            Fused::ZeroExtend { dst, bits: 32 } => self.emit_zext_32(reg(dst), reg(dst)),
+            Fused::ZeroExtend { dst, .. } => self.emit_zext_16(reg(dst), reg(dst)),
+            Fused::SignExtend { dst, bits: 8 } => self.emit(sext_b(reg(dst), reg(dst))),
+            Fused::SignExtend { dst, .. } => self.emit(sext_h(reg(dst), reg(dst))),
+            Fused::MinMax { max: is_max, signed, dst, src } => {
+                let rs = match src {
+                    Operand::Reg(src) => reg(src),
+                    Operand::Imm(0) => RV_REG_ZERO,
+                    Operand::Imm(imm) => {
+                        self.emit_imm(RV_REG_T1, imm as i64);
+                        RV_REG_T1
+                    }
+                };
+                let op = match (is_max, signed) {
+                    (false, false) => minu,
+                    (true, false) => maxu,
+                    (false, true) => min,
+                    (true, true) => max,
+                };
+                self.emit(op(reg(dst), reg(dst), rs));
+            }
+        }
+    }
 
-        let shift = hi.trailing_zeros() as u8; // find first bit
-        self.emit_imm(rd, hi >> shift);
+    // Zero-extend the result of the 32-bit op being emitted, unless its upper half is never read.
+    // Under `BPF_F_TEST_RND_HI32`, such an upper half is filled with random bits instead.
+    fn emit_zext_32_result(&mut self, rd: u8) {
+        if self.zext.get(self.bpf_pc).copied().unwrap_or(true) {
+            self.emit_zext_32(rd, rd);
+        } else if self.prog_flags & BPF_F_TEST_RND_HI32 != 0 {
+            // xorshift64
+            self.rnd_hi32 ^= self.rnd_hi32 << 13;
+            self.rnd_hi32 ^= self.rnd_hi32 >> 7;
+            self.rnd_hi32 ^= self.rnd_hi32 << 17;
+            self.emit_imm(RV_REG_T1, self.rnd_hi32 as i32 as i64);
+            self.emit_slli(RV_REG_T1, RV_REG_T1, 32);
+            self.emit_zext_32(rd, rd);
+            self.emit_or(rd, rd, RV_REG_T1);
+        }
+    }
+
+    // load `imm` with the shortest sequence found by `imm::synthesize`
+    pub fn emit_imm(&mut self, rd: u8, imm: i64) {
+        for i in synthesize(rd, imm, self.features.zbs) {
+            self.emit(i);
+        }
+    }
+
+    fn seq_size(&self, seq: &[u32]) -> usize {
+        seq.iter().map(|&i| self.insn_size(i)).sum()
+    }
 
-        self.emit_slli(rd, rd, shift + 12);
-        if lo != 0 {
-            self.emit_addi(rd, rd, lo);
+    // Load `imm` from the constant pool with `auipc` + `ld` when it takes less space than a sequence,
+    // counting the 8 bytes of a new pool slot.
+    fn emit_const(&mut self, rd: u8, imm: i64) {
+        let seq = synthesize(rd, imm, self.features.zbs);
+        let pool_size = if self.pool_slots.contains_key(&(imm as u64)) { 8 } else { 16 };
+        if self.seq_size(&seq) <= pool_size {
+            for i in seq {
+                self.emit(i);
+            }
+            return;
         }
+        let pool = &mut self.pool;
+        let slot = *self.pool_slots.entry(imm as u64).or_insert_with(|| {
+            pool.push(imm as u64);
+            pool.len() - 1
+        });
+        let rvoff = self.code_size;
+        self.pool_loads.push((rvoff, slot, rd));
+        self.emit_placeholder("auipc rd, %hi(pool + slot * 8)");
+        self.emit_placeholder("ld rd, %lo(pool + slot * 8)(rd)");
     }
 
     // dst stands for a eBPF register
@@ -237,94 +861,692 @@ impl<'a> JitContext<'a> {
         self.pc_map.insert(self.bpf_pc - 1, self.code_size);
 
         let rd = bpf_to_rv_reg(dst);
-        self.emit_imm(rd, imm);
+        self.emit_const(rd, imm);
+    }
+
+    // LD_IMM_DW with BPF_PSEUDO_MAP_FD, load the address of the map
+    pub fn emit_load_map_fd(&mut self, dst: u8, fd: u32) -> Result<(), CompileError> {
+        let map_ptr = self
+            .maps
+            .and_then(|maps| maps.map_ptr(fd))
+            .ok_or(CompileError::UnknownMapFd {
+                bpf_pc: self.origin_pc(self.bpf_pc - 1),
+                fd,
+            })?;
+        self.emit_load_imm64(dst, map_ptr as i64);
+        Ok(())
+    }
+
+    pub fn emit_call(&mut self, helper: &Helper) {
+        self.emit_meter_check();
+        match self.call_mode {
+            CallMode::Plt => self.emit_plt_call(helper),
+            CallMode::Direct(load_addr) => self.emit_direct_call(load_addr, helper.func),
+        }
+        self.emit_addi(bpf_to_rv_reg(BPF_REG_R0), RV_REG_A0, 0); // move a0 -> R0
     }
 
-    pub fn emit_call(&mut self, imm: i32) {
+    fn emit_plt_call(&mut self, helper: &Helper) {
+        let plt = &mut self.plt;
+        let slot = *self.plt_slots.entry(helper.id).or_insert_with(|| {
+            plt.push(helper.func);
+            plt.len() - 1
+        });
+
         let rvoff = self.code_size;
-        self.plt_loads.push(rvoff);
-        self.emit_placeholder("auipc t1, %hi(plt)");
-        self.emit_placeholder("addi t1, t1, %lo(plt)");
-        // assume there are no more than 2048 / 8 = 256 helper functions
-        self.emit_addi(RV_REG_T1, RV_REG_T1, imm * 8);
+        self.plt_loads.push((rvoff, slot));
+        self.emit_placeholder("auipc t1, %hi(plt + slot * 8)");
+        self.emit_placeholder("addi t1, t1, %lo(plt + slot * 8)");
         self.emit_ld(RV_REG_T2, RV_REG_T1, 0);
         self.emit_jalr(RV_REG_RA, RV_REG_T2, 0);
+    }
+
+    // Call `func` from code loaded at `load_addr` with `jal`, `auipc` + `jalr`, or `jalr` to its address.
+    // Code only shrinks from one pass to the next, so the form is chosen to reach `func` from anywhere
+    // between the start of the code and here, and a call never grows. The address is loaded instead of
+    // `auipc` when that is no longer, which may happen with compressed instructions.
+    fn emit_direct_call(&mut self, load_addr: u64, func: u64) {
+        let first = func.wrapping_sub(load_addr) as i64;
+        let here = func.wrapping_sub(load_addr + self.code_size as u64) as i64;
+        let far = synthesize(RV_REG_T1, func as i64, self.features.zbs);
+        let far_size = self.seq_size(&far) + self.insn_size(jalr(RV_REG_RA, RV_REG_T1, 0));
+        if is_in_jal_range(first as isize) && is_in_jal_range(here as isize) {
+            self.emit_jal(RV_REG_RA, here as i32);
+        } else if is_in_auipc_range(first) && is_in_auipc_range(here) && far_size >= 8 {
+            let hi = (here as i32 + (1 << 11)) >> 12;
+            let lo = ((here as i32) << 20) >> 20;
+            self.emit(auipc(RV_REG_T1, (hi as u32) << 12)); // see notes
+            self.emit_word(jalr(RV_REG_RA, RV_REG_T1, lo as u32));
+        } else {
+            self.emit_imm(RV_REG_T1, func as i64);
+            self.emit_jalr(RV_REG_RA, RV_REG_T1, 0);
+        }
+    }
+
+    // emit a helper with its inline emitter instead of calling it, see `HelperRegistry::set_inline`
+    pub fn emit_inline_call(&mut self, emit: InlineFn) {
+        emit(self, bpf_to_rv_reg(BPF_REG_R0));
+    }
+
+    // The array map looked up by `bpf_map_lookup_elem` at `bpf_pc`, which the verifier found to be
+    // the same on every path.
+    fn array_lookup_map(&self, bpf_pc: usize, helper: &Helper) -> Option<&'a BpfMap> {
+        if helper.id != BPF_FUNC_MAP_LOOKUP_ELEM || helper.func != bpf_map_lookup_elem as *const () as u64 {
+            return None;
+        }
+        let fd = match self.insn_aux.get(self.origin_pc(bpf_pc))?.map? {
+            MapPtrState::Fd(fd) => fd,
+            MapPtrState::Poison => return None,
+        };
+        self.maps?.get(fd).filter(|map| map.attr().map_type == MapType::Array)
+    }
+
+    // R0 = &values[*(u32 *)R2], or NULL if the index is out of the array, as `array_map_gen_lookup`
+    // of linux does in place of `bpf_map_lookup_elem`
+    pub fn emit_array_lookup(&mut self, map: &BpfMap) {
+        let r0 = bpf_to_rv_reg(BPF_REG_R0);
+        let (values, _) = map.value_region();
+        let attr = map.attr();
+        self.emit_lwu(RV_REG_T1, bpf_to_rv_reg(BPF_REG_R2), 0);
+        self.emit_imm(RV_REG_T2, attr.max_entries as i64);
+        self.emit_addi(r0, RV_REG_ZERO, 0);
+        let branch = self.code_size;
+        self.emit_placeholder("bgeu t1, t2, out");
+        // values of 2, 4 or 8 bytes are indexed by sh1add - sh3add
+        let shift = attr.value_size.trailing_zeros() as u8;
+        let shift_add = self.features.zba && attr.value_size.is_power_of_two() && (1..=3).contains(&shift);
+        if !attr.value_size.is_power_of_two() {
+            self.emit_imm(RV_REG_T2, attr.value_size as i64);
+            self.emit_mul(RV_REG_T1, RV_REG_T1, RV_REG_T2);
+        } else if !shift_add {
+            self.emit_slli(RV_REG_T1, RV_REG_T1, shift);
+        }
+        self.emit_const(r0, values as i64);
+        if shift_add {
+            self.emit(sh_add(shift, r0, RV_REG_T1, r0));
+        } else {
+            self.emit_add(r0, r0, RV_REG_T1);
+        }
+        let off = self.code_size - branch;
+        self.patch(branch, bgeu(off as u32, RV_REG_T1, RV_REG_T2));
+    }
+
+    // call the builtin fault-safe bpf_probe_read_kernel, see `build_probe_read`
+    pub fn emit_probe_read_call(&mut self) {
+        self.emit_meter_check();
+        let rvoff = self.code_size;
+        self.probe_read_calls.push(rvoff);
+        self.emit_placeholder("jal ra, probe_read");
         self.emit_addi(bpf_to_rv_reg(BPF_REG_R0), RV_REG_A0, 0); // move a0 -> R0
     }
 
+    // R0 = `size` bytes of the packet at `imm` (plus `index` for LD_IND), in network byte order.
+    // The packet is given by `data` and `data_end` of the `SkBuff` in R6, the program returns 0
+    // if the load is out of it.
+    pub fn emit_ld_abs(&mut self, index: Option<u8>, imm: i32, size: i32) {
+        let r0 = bpf_to_rv_reg(BPF_REG_R0);
+        let skb = bpf_to_rv_reg(BPF_REG_R6);
+        self.emit_imm(RV_REG_T0, imm as i64);
+        if let Some(index) = index {
+            self.emit_add(RV_REG_T0, RV_REG_T0, index);
+        }
+        self.emit_ld(RV_REG_T1, skb, 0);
+        self.emit_ld(RV_REG_T2, skb, 8);
+        self.emit_add(RV_REG_T0, RV_REG_T0, RV_REG_T1);
+        let below = self.code_size;
+        self.emit_placeholder("bltu t0, t1, out");
+        self.emit_addi(RV_REG_T1, RV_REG_T0, size);
+        let beyond = self.code_size;
+        self.emit_placeholder("bltu t2, t1, out");
+        self.emit_lbu(r0, RV_REG_T0, 0);
+        for i in 1..size {
+            self.emit_lbu(RV_REG_T1, RV_REG_T0, i);
+            self.emit_slli(r0, r0, 8);
+            self.emit_or(r0, r0, RV_REG_T1);
+        }
+        let done = self.code_size;
+        self.emit_placeholder("j done");
+        // out:
+        let out = self.code_size;
+        self.patch(below, bltu((out - below) as u32, RV_REG_T0, RV_REG_T1));
+        self.patch(beyond, bltu((out - beyond) as u32, RV_REG_T2, RV_REG_T1));
+        self.emit_addi(r0, RV_REG_ZERO, 0);
+        self.emit_exit();
+        // done:
+        self.patch(done, jal(RV_REG_ZERO, (self.code_size - done) as u32));
+    }
+
+    // bounds check `size` bytes at `base + off`, before the access is emitted
+    pub fn emit_sandbox_check(&mut self, base: u8, off: i16, size: i32, is_write: bool) {
+        if is_in_i12_range(off as i32) {
+            self.emit_addi(RV_REG_T0, base, off as i32);
+        } else {
+            self.emit_imm(RV_REG_T0, off as i64);
+            self.emit_add(RV_REG_T0, RV_REG_T0, base);
+        }
+        self.emit_imm(RV_REG_T1, size as i64);
+        self.emit_sandbox_check_call(is_write);
+    }
+
+    fn emit_sandbox_check_call(&mut self, is_write: bool) {
+        self.emit_addi(RV_REG_T2, RV_REG_ZERO, is_write as i32);
+        let rvoff = self.code_size;
+        self.sandbox_checks.push(rvoff);
+        self.emit_placeholder("jal ra, sandbox_check");
+    }
+
+    // abort the program, reporting the value of `reg`
+    fn emit_sandbox_abort(&mut self, reg: u8) {
+        self.emit_addi(RV_REG_T0, reg, 0);
+        let rvoff = self.code_size;
+        self.sandbox_aborts.push(rvoff);
+        self.emit_placeholder("j abort");
+    }
+
+    // Check the pointers passed to a helper before the call, as the verifier does, unless it did.
+    // Memory goes through the sandbox check, maps are compared against those of the map table and
+    // the context against s6. The program is aborted on a bad pointer, which is reported.
+    pub fn emit_helper_arg_checks(&mut self, args: &[ArgKind]) {
+        if self.sandbox_status.is_none() || self.verify {
+            return;
+        }
+        let arg_reg = |i: usize| bpf_to_rv_reg(BPF_REG_R1 + i as u8);
+        for (i, &arg) in args.iter().enumerate() {
+            let reg = arg_reg(i);
+            match arg {
+                ArgKind::ConstMapPtr => self.emit_map_arg_check(reg, &args[i + 1..], i + 1),
+                ArgKind::PtrToCtx => {
+                    let ok = self.code_size;
+                    self.emit_placeholder("beq reg, s6, ok");
+                    self.emit_sandbox_abort(reg);
+                    self.patch(ok, beq((self.code_size - ok) as u32, reg, RV_REG_S6));
+                }
+                ArgKind::PtrToMem | ArgKind::PtrToUninitMem => {
+                    let is_write = arg == ArgKind::PtrToUninitMem;
+                    // the size is given by the next argument, if any
+                    match args.get(i + 1) {
+                        Some(&size_arg) if matches!(size_arg, ArgKind::ConstSize | ArgKind::ConstSizeOrZero) => {
+                            let size = arg_reg(i + 1);
+                            // nothing is accessed with a size of zero, if it is allowed
+                            let skip = self.code_size;
+                            if size_arg == ArgKind::ConstSizeOrZero {
+                                self.emit_placeholder("beqz size, skip");
+                            }
+                            self.emit_addi(RV_REG_T0, reg, 0);
+                            self.emit_addi(RV_REG_T1, size, 0);
+                            self.emit_sandbox_check_call(is_write);
+                            if size_arg == ArgKind::ConstSizeOrZero {
+                                self.patch(skip, beq((self.code_size - skip) as u32, size, RV_REG_ZERO));
+                            }
+                        }
+                        _ => self.emit_sandbox_check(reg, 0, 1, is_write),
+                    }
+                }
+                ArgKind::PtrToLong => self.emit_sandbox_check(reg, 0, 8, true),
+                ArgKind::PtrToConstStr => self.emit_const_str_check(reg),
+                // keys and values are checked along with their map
+                ArgKind::PtrToMapKey | ArgKind::PtrToMapValue => {}
+                ArgKind::Anything | ArgKind::ConstSize | ArgKind::ConstSizeOrZero => {}
+            }
+        }
+    }
+
+    // The map in `reg` must be one of the map table, whose sizes the keys and values among `args`,
+    // starting from argument `first`, are checked with.
+    fn emit_map_arg_check(&mut self, reg: u8, args: &[ArgKind], first: usize) {
+        let maps: Vec<(u64, MapAttr)> = match self.maps {
+            Some(maps) => maps.maps().map(|(ptr, map)| (ptr, *map.attr())).collect(),
+            None => Vec::new(),
+        };
+        let mut done = Vec::new();
+        for (ptr, attr) in maps {
+            self.emit_const(RV_REG_T1, ptr as i64);
+            let next = self.code_size;
+            self.emit_placeholder("bne reg, t1, next");
+            for (i, &arg) in args.iter().enumerate() {
+                let size = match arg {
+                    ArgKind::PtrToMapKey => attr.key_size,
+                    ArgKind::PtrToMapValue => attr.value_size,
+                    _ => continue,
+                };
+                self.emit_sandbox_check(bpf_to_rv_reg(BPF_REG_R1 + (first + i) as u8), 0, size as i32, false);
+            }
+            done.push(self.code_size);
+            self.emit_placeholder("j done");
+            self.patch(next, bne((self.code_size - next) as u32, reg, RV_REG_T1));
+        }
+        self.emit_sandbox_abort(reg);
+        for off in done {
+            self.patch(off, jal(RV_REG_ZERO, (self.code_size - off) as u32));
+        }
+    }
+
+    // The string in `reg` must start in the values of a frozen `BPF_F_RDONLY_PROG` map, before their
+    // last NUL, so it ends within them. See `BpfMap::const_str_region`.
+    fn emit_const_str_check(&mut self, reg: u8) {
+        let regions: Vec<(u64, u64)> = match self.maps {
+            Some(maps) => maps.maps().filter_map(|(_, map)| map.const_str_region()).collect(),
+            None => Vec::new(),
+        };
+        let mut ok = Vec::new();
+        for (start, end) in regions {
+            self.emit_const(RV_REG_T1, start as i64);
+            let next = self.code_size;
+            self.emit_placeholder("bltu reg, t1, next");
+            self.emit_const(RV_REG_T1, end as i64);
+            ok.push(self.code_size);
+            self.emit_placeholder("bltu reg, t1, ok");
+            self.patch(next, bltu((self.code_size - next) as u32, reg, RV_REG_T1));
+        }
+        self.emit_sandbox_abort(reg);
+        for off in ok {
+            self.patch(off, bltu((self.code_size - off) as u32, reg, RV_REG_T1));
+        }
+    }
+
+    // register the load at `insn_off`, on fault `reg` is zeroed and execution resumes at `fixup_off`
+    fn add_exception_entry(&mut self, insn_off: usize, reg: u8, fixup_off: usize) {
+        self.extable.push(ExceptionEntry {
+            insn_off,
+            fixup_off,
+            reg,
+        });
+    }
+
     pub fn emit_exit(&mut self) {
+        self.emit_relaxed_jump(None, JumpTarget::Exit, false);
+    }
+
+    fn meter_check_size(&self) -> usize {
+        8 + self.insn_size(addi(RV_REG_S7, RV_REG_S7, -1i32 as u32))
+    }
+
+    // consume one unit of budget, or exit if there is none left
+    pub fn emit_meter_check(&mut self) {
+        if self.meter.is_none() {
+            return;
+        }
+        self.emit(bne(8, RV_REG_S7, RV_REG_ZERO));
         let rvoff = self.code_size;
-        self.exits.push(rvoff);
-        self.emit_placeholder("j exit");
+        self.meter_checks.push(rvoff);
+        self.emit_placeholder("j exhausted");
+        self.emit_addi(RV_REG_S7, RV_REG_S7, -1);
+    }
+
+    // Distance from the current position to `target` in the previous pass, None in the first one.
+    // Code emitted so far for the current instruction has the same size in every pass.
+    fn prev_distance(&self, target: JumpTarget) -> Option<isize> {
+        let prev = self.prev_layout.as_ref()?;
+        let to = match target {
+            JumpTarget::Insn(pc) => prev.pc_map[&pc],
+            JumpTarget::Exit => prev.exit,
+        };
+        let here = prev.pc_map[&self.bpf_pc] + self.code_size - self.pc_map[&self.bpf_pc];
+        Some(to as isize - here as isize)
+    }
+
+    // Jump to `target` if `cond` holds, or always without it, in the shortest form reaching it:
+    // nothing, `bxx`, `bxx; jal` or `bxx; auipc; jalr`, the branch being inverted to skip the jump in the last
+    // two. Distances are taken from the previous pass, the first one emitting the longest form.
+    // A `metered` jump consumes budget only when taken.
+    // With compressed instructions, `c.beqz`/`c.bnez` and `c.j` are used within their reach. Whether an
+    // instruction is compressed then depends on the distance only, so that no jump grows either.
+    fn emit_relaxed_jump(&mut self, cond: Option<(Cond, u8, u8)>, target: JumpTarget, metered: bool) {
+        let delta = self.prev_distance(target);
+        let meter_size = if metered && self.meter.is_some() { self.meter_check_size() } else { 0 };
+        // falls through: `ja +0`, or `exit` at the end of the program into the epilogue
+        let is_next = match target {
+            JumpTarget::Insn(pc) => pc == self.bpf_pc + 1,
+            JumpTarget::Exit => self.bpf_pc + 1 == self.body_len,
+        };
+        if cond.is_none() && meter_size == 0 && is_next {
+            return;
+        }
+        if let (Some((cond, rs1, rs2)), Some(delta)) = (cond, delta) {
+            if meter_size == 0 && is_in_branch_range(delta) {
+                self.emit(cond.branch(delta as i32, rs1, rs2));
+                return;
+            }
+        }
+
+        // distance from the jump itself, after the inverted branch, whose short offset is compressible
+        // whenever its registers are
+        let branch_size = cond.map_or(0, |(cond, rs1, rs2)| self.insn_size(cond.invert().branch(8, rs1, rs2)));
+        let skipped = branch_size + meter_size;
+        let delta = delta.map(|delta| delta - skipped as isize);
+        let is_long = !delta.is_some_and(is_in_jal_range);
+        let jump_size = match delta {
+            Some(delta) if !is_long => self.insn_size(jal(RV_REG_ZERO, delta as u32)),
+            _ => 8,
+        };
+        if let Some((cond, rs1, rs2)) = cond {
+            self.emit(cond.invert().branch((skipped + jump_size) as i32, rs1, rs2));
+        }
+        if metered {
+            self.emit_meter_check();
+        }
+        let delta = delta.unwrap_or(0) as i32;
+        if is_long {
+            let hi = (delta + (1 << 11)) >> 12;
+            let lo = (delta << 20) >> 20;
+            self.emit(auipc(RV_REG_T1, (hi as u32) << 12)); // see notes
+            self.emit_word(jalr(RV_REG_ZERO, RV_REG_T1, lo as u32));
+        } else {
+            self.emit_jal(RV_REG_ZERO, delta);
+        }
+    }
+
+    // target of the eBPF jump being emitted, backward jumps being metered
+    fn jump_target(&self) -> (JumpTarget, bool) {
+        let off = (self.insn >> 16) as i16;
+        let dst_pc = (self.bpf_pc as isize + 1 + off as isize) as usize;
+        (JumpTarget::Insn(dst_pc), off < 0)
     }
 
     pub fn emit_jump(&mut self) {
-        // eBPF jumps have 16-bit offset, which can span at most 2^16 * 8 = 2^19 bytes
-        // this offset can be fit into the immediate field of RISC-V's jal instruction
-        let rvoff = self.code_size;
-        self.jumps.push((self.bpf_pc, rvoff));
-        self.emit_placeholder("jal L?");
+        let (target, metered) = self.jump_target();
+        self.emit_relaxed_jump(None, target, metered);
     }
 
-    fn fixup_plt_load(&mut self, rvoff: usize, plt_offset: usize) {
-        let rel_off = (plt_offset - rvoff) as i32;
+    // conditional jump of the eBPF instruction being emitted, taken if `rs1 cond rs2`
+    fn emit_cond_jump(&mut self, cond: Cond, rs1: u8, rs2: u8) {
+        let (target, metered) = self.jump_target();
+        self.emit_relaxed_jump(Some((cond, rs1, rs2)), target, metered);
+    }
+
+    // fill `auipc rd; addi rd, rd` placeholders at `rvoff` with the address of `target`
+    fn fixup_pcrel_addr(&mut self, rvoff: usize, target: usize, rd: u8) {
+        let rel_off = (target as isize - rvoff as isize) as i32;
         let hi = (rel_off + (1 << 11)) >> 12;
         let lo = rel_off & 0xfff;
-        let i = rvoff / 4;
-        self.code[i] = auipc(RV_REG_T1, (hi as u32) << 12); // see notes
-        self.code[i + 1] = addi(RV_REG_T1, RV_REG_T1, lo as u32);
+        self.patch(rvoff, auipc(rd, (hi as u32) << 12)); // see notes
+        self.patch(rvoff + 4, addi(rd, rd, lo as u32));
     }
 
-    pub fn build_helper_fn_table(&mut self, helpers: &[u64]) {
-        // pad zero to satisfy 16 bytes alignment
-        while self.code_size % 16 != 0 {
-            self.emit(0);
+    fn fixup_plt_load(&mut self, rvoff: usize, entry_offset: usize) {
+        self.fixup_pcrel_addr(rvoff, entry_offset, RV_REG_T1);
+    }
+
+    // Check whether t0 .. t0 + t1 is in an allowed region, t2 is 1 for writes.
+    // Returns if it is, otherwise aborts the program. Clobbers t3 - t6.
+    pub fn build_sandbox_check(&mut self) {
+        if self.sandbox_checks.is_empty() {
+            return;
+        }
+        // laid out with fixed offsets, thus not compressed
+        let rvc = core::mem::replace(&mut self.features.rvc, false);
+        let check = self.code_size;
+        let mut to_fail = Vec::new();
+
+        // end of the access, which must not overflow
+        self.emit_add(RV_REG_T3, RV_REG_T0, RV_REG_T1);
+        to_fail.push(self.code_size);
+        self.emit_placeholder("bltu t3, t0, fail");
+
+        // stack: sp .. BPF_REG_FP
+        let fp = bpf_to_rv_reg(BPF_REG_FP);
+        self.emit(bltu(12, RV_REG_T0, RV_REG_SP));
+        self.emit(bltu(8, fp, RV_REG_T3));
+        self.emit_jalr(RV_REG_ZERO, RV_REG_RA, 0); // ret
+
+        // context: saved in s6 by the prologue
+        let ctx_size = self.sandbox_ctx_size.unwrap_or(0);
+        if ctx_size > 0 {
+            let not_ctx = self.code_size;
+            self.emit_placeholder("bltu t0, s6, not_ctx");
+            self.emit_imm(RV_REG_T4, ctx_size as i64);
+            self.emit_add(RV_REG_T4, RV_REG_T4, RV_REG_S6);
+            self.emit(bltu(8, RV_REG_T4, RV_REG_T3));
+            self.emit_jalr(RV_REG_ZERO, RV_REG_RA, 0); // ret
+            self.patch(not_ctx, bltu((self.code_size - not_ctx) as u32, RV_REG_T0, RV_REG_S6));
+        }
+
+        // other regions: table of (start, end, writable), see `build_region_table`
+        if !self.sandbox_regions.is_empty() {
+            self.region_table_loads.push(self.code_size);
+            self.emit_placeholder("auipc t4, %hi(regions)");
+            self.emit_placeholder("addi t4, t4, %lo(regions)");
+            self.emit_imm(RV_REG_T5, self.sandbox_regions.len() as i64);
+            // loop:
+            to_fail.push(self.code_size);
+            self.emit_placeholder("beqz t5, fail");
+            self.emit_ld(RV_REG_T6, RV_REG_T4, 0);
+            self.emit(bltu(20, RV_REG_T0, RV_REG_T6)); // goto next
+            self.emit_ld(RV_REG_T6, RV_REG_T4, 8);
+            self.emit(bltu(12, RV_REG_T6, RV_REG_T3)); // goto next
+            self.emit_ld(RV_REG_T6, RV_REG_T4, 16);
+            self.emit(bgeu(16, RV_REG_T6, RV_REG_T2)); // goto ok
+            // next:
+            self.emit_addi(RV_REG_T4, RV_REG_T4, 24);
+            self.emit_addi(RV_REG_T5, RV_REG_T5, -1);
+            self.emit_jal(RV_REG_ZERO, -36); // goto loop
+            // ok:
+            self.emit_jalr(RV_REG_ZERO, RV_REG_RA, 0); // ret
+        }
+
+        // fail:
+        let fail = self.code_size;
+        self.emit_jal(RV_REG_ZERO, self.abort as i32 - fail as i32);
+        for off in to_fail {
+            let delta = (fail - off) as u32;
+            let i = if off == check + 4 {
+                bltu(delta, RV_REG_T3, RV_REG_T0)
+            } else {
+                beq(delta, RV_REG_T5, RV_REG_ZERO)
+            };
+            self.patch(off, i);
+        }
+        self.features.rvc = rvc;
+
+        let calls = self.sandbox_checks.clone();
+        for off in calls {
+            self.patch(off, jal(RV_REG_RA, (check - off) as u32));
+        }
+    }
+
+    pub fn build_region_table(&mut self) {
+        if self.region_table_loads.is_empty() {
+            return;
         }
+        // the PLT before, if any, is 16 bytes aligned
+        self.emit_padding(8);
+        let table = self.code_size;
+        for (start, end, writable) in self.sandbox_regions.clone() {
+            for v in [start, end, writable as u64].iter() {
+                self.emit_word(*v as u32);
+                self.emit_word((*v >> 32) as u32);
+            }
+        }
+        for off in self.region_table_loads.clone() {
+            self.fixup_pcrel_addr(off, table, RV_REG_T4);
+        }
+    }
+
+    // long bpf_probe_read_kernel(void *dst, u32 size, const void *unsafe_ptr)
+    // copies byte by byte, a faulting load jumps to the fault path which zeroes dst and returns -EFAULT
+    pub fn build_probe_read(&mut self) {
+        if self.probe_read_calls.is_empty() {
+            return;
+        }
+        // laid out with fixed offsets, thus not compressed
+        let rvc = core::mem::replace(&mut self.features.rvc, false);
+        let probe_read = self.code_size;
+
+        // keep dst and size for the fault path
+        self.emit_addi(RV_REG_T0, RV_REG_A0, 0);
+        self.emit_addi(RV_REG_T2, RV_REG_A1, 0);
+        self.emit(beq(28, RV_REG_A1, RV_REG_ZERO)); // size == 0
+        // loop:
+        let fault_load = self.code_size;
+        self.emit_lbu(RV_REG_T1, RV_REG_A2, 0);
+        self.emit_sb(RV_REG_T1, RV_REG_A0, 0);
+        self.emit_addi(RV_REG_A0, RV_REG_A0, 1);
+        self.emit_addi(RV_REG_A2, RV_REG_A2, 1);
+        self.emit_addi(RV_REG_A1, RV_REG_A1, -1);
+        self.emit(bne(-20i32 as u32, RV_REG_A1, RV_REG_ZERO)); // goto loop
+        // done:
+        self.emit_addi(RV_REG_A0, RV_REG_ZERO, 0);
+        self.emit_jalr(RV_REG_ZERO, RV_REG_RA, 0); // ret
+        // fault:
+        let fault = self.code_size;
+        self.emit_sb(RV_REG_ZERO, RV_REG_T0, 0);
+        self.emit_addi(RV_REG_T0, RV_REG_T0, 1);
+        self.emit_addi(RV_REG_T2, RV_REG_T2, -1);
+        self.emit(bne(-12i32 as u32, RV_REG_T2, RV_REG_ZERO)); // goto fault
+        self.emit_addi(RV_REG_A0, RV_REG_ZERO, -EFAULT as i32);
+        self.emit_jalr(RV_REG_ZERO, RV_REG_RA, 0); // ret
+        self.features.rvc = rvc;
+
+        // loads of the program body come first, the table stays sorted
+        self.extable.push(ExceptionEntry {
+            insn_off: fault_load,
+            fixup_off: fault,
+            reg: RV_REG_T1,
+        });
+
+        let calls = self.probe_read_calls.clone();
+        for off in calls {
+            self.patch(off, jal(RV_REG_RA, (probe_read - off) as u32));
+        }
+    }
+
+    // only the helpers called by the program are put into the table
+    pub fn build_helper_fn_table(&mut self) {
+        if self.plt.is_empty() {
+            return;
+        }
+        // pad zero to satisfy 16 bytes alignment
+        self.emit_padding(16);
         let plt_offset = self.code_size;
 
-        for &helper in helpers {
+        // TODO: omit clone of Vec
+        for helper in self.plt.clone() {
             let lo = helper as u32;
             let hi = (helper >> 32) as u32;
-            self.emit(lo);
-            self.emit(hi);
+            self.emit_word(lo);
+            self.emit_word(hi);
         }
 
-        // TODO: omit clone of Vec
         let plt_loads = self.plt_loads.clone();
-        for off in plt_loads {
-            self.fixup_plt_load(off, plt_offset);
+        for (off, slot) in plt_loads {
+            self.fixup_plt_load(off, plt_offset + slot * 8);
         }
     }
 
-    fn fixup_exit(&mut self, rvoff: usize, real_exit: usize) {
-        let i = rvoff / 4;
-        self.code[i] = jal(RV_REG_ZERO, (real_exit - rvoff) as u32);
+    // 64-bit constants loaded by `emit_const`, after the code
+    pub fn build_const_pool(&mut self) {
+        if self.pool.is_empty() {
+            return;
+        }
+        self.emit_padding(8);
+        let pool_offset = self.code_size;
+        for i in 0..self.pool.len() {
+            let imm = self.pool[i];
+            self.emit_word(imm as u32);
+            self.emit_word((imm >> 32) as u32);
+        }
+        for i in 0..self.pool_loads.len() {
+            let (rvoff, slot, rd) = self.pool_loads[i];
+            let rel_off = (pool_offset + slot * 8 - rvoff) as i32;
+            let hi = (rel_off + (1 << 11)) >> 12;
+            let lo = (rel_off << 20) >> 20;
+            self.patch(rvoff, auipc(rd, (hi as u32) << 12)); // see notes
+            self.patch(rvoff + 4, ld(rd, rd, lo as u32));
+        }
     }
 
-    fn fixup_jump(&mut self, bpf_pc: usize, rvoff: usize) {
-        let bpf_insn = self.bpf_insns[bpf_pc];
-        let insn_off = (bpf_insn >> 16) as i16;
-        // NOTE: offset of eBPF jump is relative to the next instruction
-        let dst_pc = bpf_pc as isize + 1 + (insn_off as isize);
-        let dst_rvoff = *self.pc_map.get(&(dst_pc as usize)).unwrap();
-        let delta = dst_rvoff as isize - rvoff as isize;
-        let i = rvoff / 4;
-        self.code[i] = jal(RV_REG_ZERO, delta as u32);
+    // start a new pass over the program, keeping the layout of the last one
+    fn start_pass(&mut self) {
+        if !self.code.is_empty() {
+            self.prev_layout = Some(Layout {
+                pc_map: core::mem::take(&mut self.pc_map),
+                exit: self.exit_off,
+            });
+        }
+        self.code.clear();
+        self.code_size = 0;
+        self.pc_map.clear();
+        self.plt_loads.clear();
+        self.plt.clear();
+        self.plt_slots.clear();
+        self.pool_loads.clear();
+        self.pool.clear();
+        self.pool_slots.clear();
+        self.probe_read_calls.clear();
+        self.extable.clear();
+        self.sandbox_checks.clear();
+        self.region_table_loads.clear();
+        self.sandbox_aborts.clear();
+        self.meter_checks.clear();
+        // the same upper halves in every pass
+        self.rnd_hi32 = RND_HI32_SEED;
+    }
+
+    // whether the eBPF stack is set up, which sandbox checks compare addresses against
+    fn uses_stack(&self) -> bool {
+        self.usage.writes(BPF_REG_FP) || self.sandbox_ctx_size.is_some()
+    }
+
+    // Registers saved by the prologue, from the top of the frame.
+    // Only those the program writes are saved, none at all for a leaf program without stack.
+    fn saved_regs(&self) -> Vec<u8> {
+        let mut regs = Vec::new();
+        // sandbox checks are called as well
+        if self.usage.calls || self.sandbox_ctx_size.is_some() {
+            regs.push(RV_REG_RA);
+        }
+        for reg in BPF_REG_R6..BPF_REG_FP {
+            if self.usage.writes(reg) {
+                regs.push(bpf_to_rv_reg(reg));
+            }
+        }
+        if self.uses_stack() {
+            regs.push(bpf_to_rv_reg(BPF_REG_FP));
+        }
+        // s6 holds the context for sandbox checks
+        if self.sandbox_ctx_size.is_some() {
+            regs.push(RV_REG_S6);
+        }
+        // s7 holds the budget
+        if self.meter.is_some() {
+            regs.push(RV_REG_S7);
+        }
+        // s0 holds the frame
+        if !regs.is_empty() {
+            let at = (regs[0] == RV_REG_RA) as usize;
+            regs.insert(at, RV_REG_FP);
+        }
+        regs
     }
 
     pub fn emit_prologue(&mut self, stack_size: usize) {
-        self.emit_addi(RV_REG_SP, RV_REG_SP, -56);
-        self.emit_sd(RV_REG_RA, RV_REG_SP, 48);
-        self.emit_sd(RV_REG_FP, RV_REG_SP, 40);
-        self.emit_sd(RV_REG_S1, RV_REG_SP, 32);
-        self.emit_sd(RV_REG_S2, RV_REG_SP, 24);
-        self.emit_sd(RV_REG_S3, RV_REG_SP, 16);
-        self.emit_sd(RV_REG_S4, RV_REG_SP, 8);
-        self.emit_sd(RV_REG_S5, RV_REG_SP, 0);
-
-        // set frame pointer (s0)
-        self.emit_addi(RV_REG_FP, RV_REG_SP, 56);
+        let regs = self.saved_regs();
+        if !regs.is_empty() {
+            let frame_size = 8 * regs.len() as i32;
+            self.emit_addi(RV_REG_SP, RV_REG_SP, -frame_size);
+            for (i, &reg) in regs.iter().enumerate() {
+                self.emit_sd(reg, RV_REG_SP, frame_size - 8 * (i as i32 + 1));
+            }
+
+            // set frame pointer (s0)
+            self.emit_addi(RV_REG_FP, RV_REG_SP, frame_size);
+        }
+
+        if let Some(status) = self.sandbox_status {
+            self.emit_addi(RV_REG_S6, bpf_to_rv_reg(BPF_REG_R1), 0);
+            self.emit_const(RV_REG_T0, status.as_ptr() as i64);
+            self.emit_sd(RV_REG_ZERO, RV_REG_T0, 0);
+        }
+
+        if let Some(meter) = self.meter {
+            self.emit_const(RV_REG_T0, meter.as_ptr() as i64);
+            self.emit_ld(RV_REG_S7, RV_REG_T0, 0);
+            self.emit_sd(RV_REG_ZERO, RV_REG_T0, 8);
+        }
 
+        if !self.uses_stack() {
+            return;
+        }
         // set BPF_REG_FP and allocate stack space for eBPF code
         self.emit_addi(bpf_to_rv_reg(BPF_REG_FP), RV_REG_SP, 0);
 
@@ -335,56 +1557,98 @@ impl<'a> JitContext<'a> {
 
     pub fn emit_epilogue(&mut self) {
         let real_exit = self.code_size;
-        let exits = self.exits.clone();
-        for off in exits {
-            self.fixup_exit(off, real_exit);
-        }
-
-        let jumps = self.jumps.clone();
-        for (pc, off) in jumps {
-            self.fixup_jump(pc, off);
-        }
+        self.exit_off = real_exit;
 
         // return value: move R0 to a0
         self.emit_addi(RV_REG_A0, bpf_to_rv_reg(BPF_REG_R0), 0);
 
-        // restore stack pointer from frame pointer
-        self.emit_addi(RV_REG_SP, RV_REG_FP, 0);
-        self.emit_ld(RV_REG_S5, RV_REG_SP, -56);
-        self.emit_ld(RV_REG_S4, RV_REG_SP, -48);
-        self.emit_ld(RV_REG_S3, RV_REG_SP, -40);
-        self.emit_ld(RV_REG_S2, RV_REG_SP, -32);
-        self.emit_ld(RV_REG_S1, RV_REG_SP, -24);
-        self.emit_ld(RV_REG_FP, RV_REG_SP, -16);
-        self.emit_ld(RV_REG_RA, RV_REG_SP, -8);
+        // store the budget left
+        if let Some(meter) = self.meter {
+            self.emit_const(RV_REG_T0, meter.as_ptr() as i64);
+            self.emit_sd(RV_REG_S7, RV_REG_T0, 0);
+        }
+
+        let regs = self.saved_regs();
+        if !regs.is_empty() {
+            // restore stack pointer from frame pointer
+            self.emit_addi(RV_REG_SP, RV_REG_FP, 0);
+            for (i, &reg) in regs.iter().enumerate().rev() {
+                self.emit_ld(reg, RV_REG_SP, -8 * (i as i32 + 1));
+            }
+        }
         self.emit_jalr(RV_REG_ZERO, RV_REG_RA, 0); // ret
+
+        // abort: report the address of the access, still in t0 after the check, and exit with 0
+        if let Some(status) = self.sandbox_status {
+            self.abort = self.code_size;
+            let aborts = self.sandbox_aborts.clone();
+            for off in aborts {
+                self.patch(off, jal(RV_REG_ZERO, (self.abort - off) as u32));
+            }
+            self.emit_const(RV_REG_T1, status.as_ptr() as i64);
+            self.emit_sd(RV_REG_T0, RV_REG_T1, 8);
+            self.emit_addi(RV_REG_T2, RV_REG_ZERO, 1);
+            self.emit_sd(RV_REG_T2, RV_REG_T1, 0);
+            self.emit_addi(bpf_to_rv_reg(BPF_REG_R0), RV_REG_ZERO, 0);
+            self.emit_jal(RV_REG_ZERO, real_exit as i32 - self.code_size as i32);
+        }
+
+        // exhausted: flag it and exit with 0, the budget being 0 when stored back
+        if let (Some(meter), false) = (self.meter, self.meter_checks.is_empty()) {
+            let exhausted = self.code_size;
+            self.emit_const(RV_REG_T0, meter.as_ptr() as i64);
+            self.emit_addi(RV_REG_T1, RV_REG_ZERO, 1);
+            self.emit_sd(RV_REG_T1, RV_REG_T0, 8);
+            self.emit_addi(bpf_to_rv_reg(BPF_REG_R0), RV_REG_ZERO, 0);
+            self.emit_jal(RV_REG_ZERO, real_exit as i32 - self.code_size as i32);
+            let checks = self.meter_checks.clone();
+            for off in checks {
+                self.patch(off, jal(RV_REG_ZERO, (exhausted - off) as u32));
+            }
+        }
     }
 }
 
-fn emit_instructions(ctx: &mut JitContext) {
+// `insns` are the instructions of the program after the context accesses are converted
+fn emit_instructions(ctx: &mut JitContext, insns: &[u64], helpers: &HelperRegistry) -> Result<(), CompileError> {
     let mut prev_imm: i32 = 0;
     let mut prev_dst: u8 = 0;
+    let mut prev_src: u8 = 0;
     let mut is_load_imm64 = false;
+    let mut is_fused = false;
 
-    for (i, &insn) in ctx.bpf_insns.iter().enumerate() {
+    for (i, &insn) in insns.iter().enumerate() {
         let op = (insn & 0xff) as u8;
         let dst = ((insn & 0x0f00) >> 8) as u8;
         let src = ((insn & 0xf000) >> 12) as u8;
         let off = (insn >> 16) as i16;
         let imm = (insn >> 32) as i32;
         ctx.bpf_pc = i;
+        ctx.insn = insn;
 
         // process the only 16-bytes instruction: LD_IMM_DW
         if is_load_imm64 {
             is_load_imm64 = false;
-            let imm64 = (prev_imm as u32 as u64) | ((imm as u32 as u64) << 32);
-            ctx.emit_load_imm64(prev_dst, imm64 as i64);
+            match prev_src as u32 {
+                0 => {
+                    let imm64 = (prev_imm as u32 as u64) | ((imm as u32 as u64) << 32);
+                    ctx.emit_load_imm64(prev_dst, imm64 as i64);
+                }
+                BPF_PSEUDO_MAP_FD => ctx.emit_load_map_fd(prev_dst, prev_imm as u32)?,
+                _ => {
+                    return Err(CompileError::UnsupportedPseudoSrc {
+                        bpf_pc: ctx.origin_pc(i - 1),
+                        src: prev_src,
+                    })
+                }
+            }
             continue;
         }
 
         if op == LD_IMM_DW {
             prev_imm = imm;
             prev_dst = dst;
+            prev_src = src;
             is_load_imm64 = true;
             continue;
         }
@@ -394,11 +1658,31 @@ fn emit_instructions(ctx: &mut JitContext) {
             _ => false,
         };
         let use_imm = (op & 8) == 0;
+        // K forms with an immediate fitting I-type instructions
+        let use_i12 = use_imm && is_in_i12_range(imm);
         let mut rd = bpf_to_rv_reg(dst);
         let mut rs = bpf_to_rv_reg(src);
 
         ctx.pc_map.insert(ctx.bpf_pc, ctx.code_size);
 
+        if matches!((op & 0b111) as u32, BPF_JMP | BPF_JMP32) && !matches!((op & 0xf0) as u32, BPF_CALL | BPF_EXIT) {
+            let target = i as isize + 1 + off as isize;
+            if target < 0 || target as usize >= insns.len() {
+                return Err(CompileError::JumpOutOfRange { bpf_pc: ctx.origin_pc(i), target });
+            }
+        }
+
+        // the second instruction of a pair has been jitted along with the first one
+        if is_fused {
+            is_fused = false;
+            continue;
+        }
+        if let Some(fused) = ctx.fused[i] {
+            ctx.emit_fused(fused);
+            is_fused = true;
+            continue;
+        }
+
         // helpers
         let c_emit_t1_imm = |ctx: &mut JitContext, rs: &mut u8| {
             if use_imm {
@@ -406,26 +1690,53 @@ fn emit_instructions(ctx: &mut JitContext) {
                 *rs = RV_REG_T1;
             }
         };
+        // compares against 0 use the zero register
+        let c_emit_cmp_imm = |ctx: &mut JitContext, rs: &mut u8| {
+            if use_imm && imm == 0 {
+                *rs = RV_REG_ZERO;
+            } else {
+                c_emit_t1_imm(ctx, rs);
+            }
+        };
         let c_emit_zext32 = |ctx: &mut JitContext, rd: u8| {
             if !is64 {
-                ctx.emit_zext_32(rd, rd);
+                ctx.emit_zext_32_result(rd);
             }
         };
         let c_emit_br_reg32 = |ctx: &mut JitContext, rs: &mut u8, rd: &mut u8| {
             if !is64 {
-                ctx.emit_zext_32(RV_REG_T1, *rs);
+                if *rs != RV_REG_ZERO {
+                    ctx.emit_zext_32(RV_REG_T1, *rs);
+                    *rs = RV_REG_T1;
+                }
                 ctx.emit_zext_32(RV_REG_T2, *rd);
-                *rs = RV_REG_T1;
                 *rd = RV_REG_T2;
             }
         };
 
         match op {
+            ALU_K_ADD | ALU64_K_ADD if use_i12 => {
+                if is64 {
+                    ctx.emit_addi(rd, rd, imm);
+                } else {
+                    ctx.emit_addiw(rd, rd, imm);
+                }
+                c_emit_zext32(ctx, rd);
+            }
             ALU_X_ADD | ALU_K_ADD | ALU64_X_ADD | ALU64_K_ADD => {
                 c_emit_t1_imm(ctx, &mut rs);
                 ctx.emit_add(rd, rd, rs);
                 c_emit_zext32(ctx, rd);
             }
+            // add -imm instead, which fits unless imm is -2048
+            ALU_K_SUB | ALU64_K_SUB if use_i12 && imm != -(1 << 11) => {
+                if is64 {
+                    ctx.emit_addi(rd, rd, -imm);
+                } else {
+                    ctx.emit_addiw(rd, rd, -imm);
+                }
+                c_emit_zext32(ctx, rd);
+            }
             ALU_X_SUB | ALU_K_SUB | ALU64_X_SUB | ALU64_K_SUB => {
                 if use_imm {
                     ctx.emit_imm(RV_REG_T1, imm as i64);
@@ -439,16 +1750,28 @@ fn emit_instructions(ctx: &mut JitContext) {
                 }
                 c_emit_zext32(ctx, rd);
             }
+            ALU_K_AND | ALU64_K_AND if use_i12 => {
+                ctx.emit_andi(rd, rd, imm);
+                c_emit_zext32(ctx, rd);
+            }
             ALU_X_AND | ALU64_X_AND | ALU_K_AND | ALU64_K_AND => {
                 c_emit_t1_imm(ctx, &mut rs);
                 ctx.emit_and(rd, rd, rs);
                 c_emit_zext32(ctx, rd);
             }
+            ALU_K_OR | ALU64_K_OR if use_i12 => {
+                ctx.emit_ori(rd, rd, imm);
+                c_emit_zext32(ctx, rd);
+            }
             ALU_X_OR | ALU64_X_OR | ALU_K_OR | ALU64_K_OR => {
                 c_emit_t1_imm(ctx, &mut rs);
                 ctx.emit_or(rd, rd, rs);
                 c_emit_zext32(ctx, rd);
             }
+            ALU_K_XOR | ALU64_K_XOR if use_i12 => {
+                ctx.emit_xori(rd, rd, imm);
+                c_emit_zext32(ctx, rd);
+            }
             ALU_X_XOR | ALU64_X_XOR | ALU_K_XOR | ALU64_K_XOR => {
                 c_emit_t1_imm(ctx, &mut rs);
                 ctx.emit_xor(rd, rd, rs);
@@ -463,7 +1786,11 @@ fn emit_instructions(ctx: &mut JitContext) {
                 }
                 c_emit_zext32(ctx, rd);
             }
-            ALU_X_DIV | ALU64_X_DIV | ALU_K_DIV | ALU64_K_DIV => {
+            // a division by zero gives 0 as in the kernel, where divu gives all ones
+            ALU_K_DIV | ALU64_K_DIV if imm == 0 => {
+                ctx.emit_addi(rd, RV_REG_ZERO, 0);
+            }
+            ALU_K_DIV | ALU64_K_DIV => {
                 c_emit_t1_imm(ctx, &mut rs);
                 if is64 {
                     ctx.emit_divu(rd, rd, rs);
@@ -472,6 +1799,22 @@ fn emit_instructions(ctx: &mut JitContext) {
                 }
                 c_emit_zext32(ctx, rd);
             }
+            // the quotient is masked with t2 = -(rs != 0), taken before rd is written as rs may be rd
+            ALU_X_DIV | ALU64_X_DIV => {
+                if is64 {
+                    ctx.emit(sltu(RV_REG_T2, RV_REG_ZERO, rs));
+                    ctx.emit_sub(RV_REG_T2, RV_REG_ZERO, RV_REG_T2);
+                    ctx.emit_divu(rd, rd, rs);
+                } else {
+                    ctx.emit_slli(RV_REG_T2, rs, 32);
+                    ctx.emit(sltu(RV_REG_T2, RV_REG_ZERO, RV_REG_T2));
+                    ctx.emit_sub(RV_REG_T2, RV_REG_ZERO, RV_REG_T2);
+                    ctx.emit_divuw(rd, rd, rs);
+                }
+                ctx.emit_and(rd, rd, RV_REG_T2);
+                c_emit_zext32(ctx, rd);
+            }
+            // remu by zero keeps the dividend, as in the kernel
             ALU_X_MOD | ALU64_X_MOD | ALU_K_MOD | ALU64_K_MOD => {
                 c_emit_t1_imm(ctx, &mut rs);
                 if is64 {
@@ -489,7 +1832,36 @@ fn emit_instructions(ctx: &mut JitContext) {
                 }
                 c_emit_zext32(ctx, rd);
             }
-            // TODO: 32 bit shifts
+            // the W forms only use the low 5 bits of rs, as eBPF masks 32 bit shift amounts
+            ALU_X_LSH | ALU_K_LSH => {
+                if use_imm {
+                    ctx.emit(slliw(rd, rd, imm as u8));
+                } else {
+                    ctx.emit(sllw(rd, rd, rs));
+                }
+                c_emit_zext32(ctx, rd);
+            }
+            ALU_X_RSH | ALU_K_RSH => {
+                if use_imm {
+                    ctx.emit(srliw(rd, rd, imm as u8));
+                } else {
+                    ctx.emit(srlw(rd, rd, rs));
+                }
+                c_emit_zext32(ctx, rd);
+            }
+            ALU_X_ARSH | ALU_K_ARSH => {
+                if use_imm {
+                    ctx.emit(sraiw(rd, rd, imm as u8));
+                } else {
+                    ctx.emit(sraw(rd, rd, rs));
+                }
+                c_emit_zext32(ctx, rd);
+            }
+            ALU_K_NEG => {
+                ctx.emit_subw(rd, RV_REG_ZERO, rd);
+                c_emit_zext32(ctx, rd);
+            }
+            ALU64_K_NEG => ctx.emit_sub(rd, RV_REG_ZERO, rd),
             ALU64_X_LSH | ALU64_K_LSH => {
                 if use_imm {
                     ctx.emit_slli(rd, rd, imm as u8);
@@ -511,7 +1883,19 @@ fn emit_instructions(ctx: &mut JitContext) {
                     ctx.emit(sra(rd, rd, rs));
                 }
             }
-            LDX_MEM_B | LDX_MEM_H | LDX_MEM_W | LDX_MEM_DW => {
+            // to little endian only truncates on RV64, to big endian and the unconditional ALU64 form swap bytes
+            ALU_K_END => match imm {
+                16 => ctx.emit_zext_16(rd, rd),
+                32 => ctx.emit_zext_32(rd, rd),
+                _ => {}
+            },
+            ALU_X_END | ALU64_K_END => ctx.emit_bswap(rd, imm),
+            LDX_MEM_B | LDX_MEM_H | LDX_MEM_W | LDX_MEM_DW |
+            LDX_PROBE_MEM_B | LDX_PROBE_MEM_H | LDX_PROBE_MEM_W | LDX_PROBE_MEM_DW => {
+                let proven = ctx.is_proven_safe(i);
+                if ctx.sandbox_ctx_size.is_some() && !proven {
+                    ctx.emit_sandbox_check(rs, off, access_size(op), false);
+                }
                 let mut load_insn_imm = off as i32;
                 if !is_in_i12_range(load_insn_imm) {
                     ctx.emit_imm(RV_REG_T2, off as i64);
@@ -521,6 +1905,7 @@ fn emit_instructions(ctx: &mut JitContext) {
                 }
 
                 let size_mod = (op & 0b11000) as u32;
+                let load_off = ctx.code_size;
                 // NOTE: should we sign extend the result?
                 match size_mod {
                     BPF_B => ctx.emit_lbu(rd, rs, load_insn_imm),
@@ -529,9 +1914,15 @@ fn emit_instructions(ctx: &mut JitContext) {
                     BPF_DW => ctx.emit_ld(rd, rs, load_insn_imm),
                     _ => unreachable!()
                 }
+                if (ctx.probe_mem && !proven) || (op & 0xe0) as u32 == BPF_PROBE_MEM {
+                    ctx.add_exception_entry(load_off, rd, ctx.code_size);
+                }
             }
             ST_MEM_B | ST_MEM_H | ST_MEM_W | ST_MEM_DW |
             STX_MEM_B | STX_MEM_H | STX_MEM_W | STX_MEM_DW => {
+                if ctx.sandbox_ctx_size.is_some() && !ctx.is_proven_safe(i) {
+                    ctx.emit_sandbox_check(rd, off, access_size(op), true);
+                }
                 let mut store_insn_imm = off as i32;
                 let rs1= if !is_in_i12_range(store_insn_imm) {
                     ctx.emit_imm(RV_REG_T1, off as i64);
@@ -563,89 +1954,174 @@ fn emit_instructions(ctx: &mut JitContext) {
                 ctx.emit_jump();
             }
             JMP_X_JEQ | JMP_K_JEQ | JMP32_X_JEQ | JMP32_K_JEQ => {
-                c_emit_t1_imm(ctx, &mut rs);
+                c_emit_cmp_imm(ctx, &mut rs);
                 c_emit_br_reg32(ctx, &mut rs, &mut rd);
-                ctx.emit(bne(8, rs, rd)); // dst != src
-                ctx.emit_jump();
+                ctx.emit_cond_jump(Cond::Eq, rd, rs); // dst == src
             }
             JMP_X_JGT | JMP_K_JGT | JMP32_X_JGT | JMP32_K_JGT => {
-                c_emit_t1_imm(ctx, &mut rs);
+                c_emit_cmp_imm(ctx, &mut rs);
                 c_emit_br_reg32(ctx, &mut rs, &mut rd);
-                ctx.emit(bgeu(8, rs, rd)); // dst <= src (unsigned)
-                ctx.emit_jump();
+                ctx.emit_cond_jump(Cond::Ltu, rs, rd); // dst > src (unsigned)
             }
             JMP_X_JGE | JMP_K_JGE | JMP32_X_JGE | JMP32_K_JGE => {
-                c_emit_t1_imm(ctx, &mut rs);
+                c_emit_cmp_imm(ctx, &mut rs);
                 c_emit_br_reg32(ctx, &mut rs, &mut rd);
-                ctx.emit(bltu(8, rd, rs)); // dst < src (unsigned)
-                ctx.emit_jump();
+                ctx.emit_cond_jump(Cond::Geu, rd, rs); // dst >= src (unsigned)
             }
             JMP_X_JSET | JMP_K_JSET | JMP32_X_JSET | JMP32_K_JSET => {
-                c_emit_t1_imm(ctx, &mut rs);
-                ctx.emit_and(RV_REG_T1, rs, rd);
-                c_emit_zext32(ctx, RV_REG_T1);
-                ctx.emit(beq(8, RV_REG_T1, RV_REG_ZERO)); // dst & src == 0
-                ctx.emit_jump();
+                if use_i12 {
+                    ctx.emit_andi(RV_REG_T1, rd, imm);
+                } else {
+                    c_emit_t1_imm(ctx, &mut rs);
+                    ctx.emit_and(RV_REG_T1, rs, rd);
+                }
+                if !is64 {
+                    ctx.emit_zext_32(RV_REG_T1, RV_REG_T1);
+                }
+                ctx.emit_cond_jump(Cond::Ne, RV_REG_T1, RV_REG_ZERO); // dst & src != 0
             }
             JMP_X_JNE | JMP_K_JNE | JMP32_X_JNE | JMP32_K_JNE => {
-                c_emit_t1_imm(ctx, &mut rs);
+                c_emit_cmp_imm(ctx, &mut rs);
                 c_emit_br_reg32(ctx, &mut rs, &mut rd);
-                ctx.emit(beq(8, rs, rd)); // dst == src
-                ctx.emit_jump();
+                ctx.emit_cond_jump(Cond::Ne, rd, rs); // dst != src
             }
             JMP_X_JSGT | JMP_K_JSGT | JMP32_X_JSGT | JMP32_K_JSGT => {
-                c_emit_t1_imm(ctx, &mut rs);
+                c_emit_cmp_imm(ctx, &mut rs);
                 c_emit_br_reg32(ctx, &mut rs, &mut rd);
-                // NOTE: 8 stands for two RISC-V instructions (self + jal)
-                ctx.emit(bge(8, rs, rd)); // dst <= src (signed)
-                ctx.emit_jump();
+                ctx.emit_cond_jump(Cond::Lt, rs, rd); // dst > src (signed)
             }
             JMP_X_JSGE | JMP_K_JSGE | JMP32_X_JSGE | JMP32_K_JSGE => {
-                c_emit_t1_imm(ctx, &mut rs);
+                c_emit_cmp_imm(ctx, &mut rs);
                 c_emit_br_reg32(ctx, &mut rs, &mut rd);
-                ctx.emit(blt(8, rd, rs)); // dst < src (signed)
-                ctx.emit_jump();
+                ctx.emit_cond_jump(Cond::Ge, rd, rs); // dst >= src (signed)
             }
             JMP_X_JLT | JMP_K_JLT | JMP32_X_JLT | JMP32_K_JLT => {
-                c_emit_t1_imm(ctx, &mut rs);
+                c_emit_cmp_imm(ctx, &mut rs);
                 c_emit_br_reg32(ctx, &mut rs, &mut rd);
-                ctx.emit(bgeu(8, rd, rs)); // dst >= src (unsigned)
-                ctx.emit_jump();
+                ctx.emit_cond_jump(Cond::Ltu, rd, rs); // dst < src (unsigned)
             }
             JMP_X_JLE | JMP_K_JLE | JMP32_X_JLE | JMP32_K_JLE => {
-                c_emit_t1_imm(ctx, &mut rs);
+                c_emit_cmp_imm(ctx, &mut rs);
                 c_emit_br_reg32(ctx, &mut rs, &mut rd);
-                ctx.emit(bltu(8, rs, rd)); // dst > src (unsigned)
-                ctx.emit_jump();
+                ctx.emit_cond_jump(Cond::Geu, rs, rd); // dst <= src (unsigned)
             }
             JMP_X_JSLT | JMP_K_JSLT | JMP32_X_JSLT | JMP32_K_JSLT => {
-                c_emit_t1_imm(ctx, &mut rs);
+                c_emit_cmp_imm(ctx, &mut rs);
                 c_emit_br_reg32(ctx, &mut rs, &mut rd);
-                ctx.emit(bge(8, rd, rs)); // dst >= src (signed)
-                ctx.emit_jump();
+                ctx.emit_cond_jump(Cond::Lt, rd, rs); // dst < src (signed)
             }
             JMP_X_JSLE | JMP_K_JSLE | JMP32_X_JSLE | JMP32_K_JSLE => {
-                c_emit_t1_imm(ctx, &mut rs);
+                c_emit_cmp_imm(ctx, &mut rs);
                 c_emit_br_reg32(ctx, &mut rs, &mut rd);
-                ctx.emit(blt(8, rs, rd)); // dst > src (signed)
-                ctx.emit_jump();
+                ctx.emit_cond_jump(Cond::Ge, rs, rd); // dst <= src (signed)
             }
             JMP_K_CALL => {
-                ctx.emit_call(imm);
+                // bpf-to-bpf calls and kfuncs are not supported
+                if src != 0 {
+                    return Err(CompileError::UnsupportedPseudoSrc { bpf_pc: ctx.origin_pc(i), src });
+                }
+                let id = imm as u32;
+                match helpers.get(id) {
+                    Some(helper) => {
+                        ctx.emit_helper_arg_checks(helper.args);
+                        match (helpers.get_inline(id), ctx.array_lookup_map(i, helper)) {
+                            (Some(emit), _) => ctx.emit_inline_call(emit),
+                            (None, Some(map)) => ctx.emit_array_lookup(map),
+                            (None, None) => ctx.emit_call(helper),
+                        }
+                    }
+                    // builtin, unless overridden by the registry
+                    None if id == BPF_FUNC_PROBE_READ || id == BPF_FUNC_PROBE_READ_KERNEL => {
+                        ctx.emit_helper_arg_checks(&PROBE_READ_ARGS);
+                        ctx.emit_probe_read_call()
+                    }
+                    None => return Err(CompileError::UnknownHelper { bpf_pc: ctx.origin_pc(i), id }),
+                }
             }
             JMP_K_EXIT => {
                 ctx.emit_exit();
             }
+            LD_ABS_B | LD_ABS_H | LD_ABS_W | LD_IND_B | LD_IND_H | LD_IND_W
+                if ctx.prog_type.is_some_and(|t| t.allows_ld_abs()) =>
+            {
+                let index = if (op & 0xe0) as u32 == BPF_IND { Some(rs) } else { None };
+                ctx.emit_ld_abs(index, imm, access_size(op));
+            }
             _ => {
                 todo!("unimplemented eBPF instruction op = {:#x}", op)
             }
         }
     }
+    Ok(())
 }
 
-pub fn compile(ctx: &mut JitContext, helpers: &[u64], stack_size: usize) {
-    ctx.emit_prologue(stack_size);
-    emit_instructions(ctx);
-    ctx.emit_epilogue();
-    ctx.build_helper_fn_table(helpers);
+pub fn compile(ctx: &mut JitContext, helpers: &HelperRegistry, stack_size: usize) -> Result<(), CompileError> {
+    if ctx.bpf_insns.len() > BPF_MAXINSNS as usize {
+        return Err(CompileError::ProgramTooLarge { len: ctx.bpf_insns.len() });
+    }
+    if ctx.verify {
+        let mut verifier = Verifier::new(ctx.bpf_insns, helpers);
+        if let Some(maps) = ctx.maps {
+            verifier.set_map_table(maps);
+        }
+        verifier.set_ctx_layout(ctx.ctx_layout);
+        if let Some(prog_type) = ctx.prog_type {
+            verifier.set_program_type(prog_type);
+            if ctx.ctx_desc.is_none() {
+                ctx.ctx_desc = prog_type.ctx_descriptor().map(Cow::Owned);
+            }
+        }
+        if let Some(desc) = &ctx.ctx_desc {
+            verifier.set_ctx_descriptor(desc);
+        }
+        verifier.set_stack_size(stack_size);
+        verifier.set_bounded_loops(ctx.bounded_loops);
+        verifier.set_complexity_limit(ctx.complexity_limit);
+        let res = verifier.verify();
+        ctx.verifier_log = String::from(verifier.log());
+        res.map_err(CompileError::Verifier)?;
+        ctx.insn_aux = verifier.insn_aux().to_vec();
+    }
+
+    if ctx.sandbox_ctx_size.is_some() {
+        if let Some(maps) = ctx.maps {
+            for (start, len, writable) in maps.value_regions() {
+                ctx.add_sandbox_region(start, len, writable);
+            }
+        }
+    }
+
+    let insns = if ctx.ctx_desc.is_some() {
+        Cow::Owned(convert_ctx_accesses(ctx.bpf_insns, &ctx.insn_aux))
+    } else {
+        Cow::Borrowed(ctx.bpf_insns)
+    };
+    let insns = match optimize(&insns, ctx.opt_level) {
+        Some((optimized, origin)) => {
+            ctx.origin = origin;
+            Cow::Owned(optimized)
+        }
+        None => insns,
+    };
+    ctx.body_len = insns.len();
+    ctx.zext = zext_needed(&insns);
+    ctx.fused = fuse_pairs(&insns, ctx.features.zba, ctx.features.zbb);
+    ctx.usage = Usage::scan(&insns);
+
+    // Lay out jumps as the linux JIT does, each pass using the offsets of the previous one.
+    // The first pass emits the longest forms, so no jump grows and passes stop once the size converges.
+    let mut prev_size = None;
+    while prev_size != Some(ctx.code_size) {
+        prev_size = Some(ctx.code_size);
+        ctx.start_pass();
+        ctx.emit_prologue(stack_size);
+        emit_instructions(ctx, &insns, helpers)?;
+        ctx.emit_epilogue();
+    }
+    ctx.build_probe_read();
+    ctx.build_sandbox_check();
+    ctx.build_helper_fn_table();
+    ctx.build_region_table();
+    ctx.build_const_pool();
+    Ok(())
 }
@@ -59,10 +59,22 @@ pub const BPF_ABS: u32 = 0x20;
 pub const BPF_IND: u32 = 0x40;
 pub const BPF_MEM: u32 = 0x60;
 pub const BPF_ATOMIC: u32 = 0xc0;
+// fault-safe load, its faults are fixed up by the exception table
+pub const BPF_PROBE_MEM: u32 = 0x20;
+
+// classic BPF, whose other opcodes are the same in eBPF
+pub const BPF_RET: u32 = 0x06;
+pub const BPF_MISC: u32 = 0x07;
+pub const BPF_LEN: u32 = 0x80;
+pub const BPF_MSH: u32 = 0xa0;
+// RET of the accumulator
+pub const BPF_A: u32 = 0x10;
+pub const BPF_TAX: u32 = 0x00;
+pub const BPF_TXA: u32 = 0x80;
+// 32-bit words of scratch memory
+pub const BPF_MEMWORDS: u32 = 16;
 
 // TODO
-pub const BPF_LEN: u32 = 128;
-pub const BPF_MSH: u32 = 160;
 pub const BPF_MAXINSNS: u32 = 4096;
 pub const BPF_XADD: u32 = 192;
 pub const BPF_TO_LE: u32 = 0;
@@ -94,6 +106,30 @@ pub const BPF_BUILD_ID_SIZE: u32 = 20;
 pub const BPF_OBJ_NAME_LEN: u32 = 16;
 pub const BPF_TAG_SIZE: u32 = 8;
 
+// map types
+pub const BPF_MAP_TYPE_HASH: u32 = 1;
+pub const BPF_MAP_TYPE_ARRAY: u32 = 2;
+pub const BPF_MAP_TYPE_ARRAY_OF_MAPS: u32 = 12;
+pub const BPF_MAP_TYPE_HASH_OF_MAPS: u32 = 13;
+
+// map flags
+pub const BPF_F_RDONLY_PROG: u32 = 1 << 7;
+
+// map update flags
+pub const BPF_ANY: u64 = 0;
+pub const BPF_NOEXIST: u64 = 1;
+pub const BPF_EXIST: u64 = 2;
+
+// errno values returned by helpers
+pub const EPERM: i64 = 1;
+pub const ENOENT: i64 = 2;
+pub const E2BIG: i64 = 7;
+pub const EACCES: i64 = 13;
+pub const EFAULT: i64 = 14;
+pub const EEXIST: i64 = 17;
+pub const EINVAL: i64 = 22;
+pub const ERANGE: i64 = 34;
+
 pub const ALU_K_ADD: u8 = (BPF_ALU | BPF_K | BPF_ADD) as u8;
 pub const ALU_X_ADD: u8 = (BPF_ALU | BPF_X | BPF_ADD) as u8;
 pub const ALU_K_SUB: u8 = (BPF_ALU | BPF_K | BPF_SUB) as u8;
@@ -247,6 +283,10 @@ pub const LDX_MEM_B: u8 = (BPF_LDX | BPF_MEM | BPF_B) as u8;
 pub const LDX_MEM_H: u8 = (BPF_LDX | BPF_MEM | BPF_H) as u8;
 pub const LDX_MEM_W: u8 = (BPF_LDX | BPF_MEM | BPF_W) as u8;
 pub const LDX_MEM_DW: u8 = (BPF_LDX | BPF_MEM | BPF_DW) as u8;
+pub const LDX_PROBE_MEM_B: u8 = (BPF_LDX | BPF_PROBE_MEM | BPF_B) as u8;
+pub const LDX_PROBE_MEM_H: u8 = (BPF_LDX | BPF_PROBE_MEM | BPF_H) as u8;
+pub const LDX_PROBE_MEM_W: u8 = (BPF_LDX | BPF_PROBE_MEM | BPF_W) as u8;
+pub const LDX_PROBE_MEM_DW: u8 = (BPF_LDX | BPF_PROBE_MEM | BPF_DW) as u8;
 pub const LDX_XADD_B: u8 = (BPF_LDX | BPF_XADD | BPF_B) as u8;
 pub const LDX_XADD_H: u8 = (BPF_LDX | BPF_XADD | BPF_H) as u8;
 pub const LDX_XADD_W: u8 = (BPF_LDX | BPF_XADD | BPF_W) as u8;
@@ -326,3 +366,9 @@ pub const RV_REG_S2: u8 = 18;
 pub const RV_REG_S3: u8 = 19;
 pub const RV_REG_S4: u8 = 20;
 pub const RV_REG_S5: u8 = 21;
+pub const RV_REG_S6: u8 = 22;
+pub const RV_REG_S7: u8 = 23;
+pub const RV_REG_T3: u8 = 28;
+pub const RV_REG_T4: u8 = 29;
+pub const RV_REG_T5: u8 = 30;
+pub const RV_REG_T6: u8 = 31;
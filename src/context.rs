@@ -0,0 +1,158 @@
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+use crate::consts::*;
+use crate::verifier::InsnAux;
+
+// what a field of the context holds, and how programs may access it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FieldAccess {
+    ReadOnly,
+    // written as a whole
+    ReadWrite,
+    // pointers to the start and the end of packet data, read as a whole
+    PktData,
+    PktDataEnd,
+    // pointer to the metadata before packet data, up to the start of it
+    PktMeta,
+}
+
+// A field of the context as programs see it, at `off` in the UAPI struct, and where it really is.
+// A field smaller in the real struct reads zero-extended, a larger one truncated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CtxField {
+    pub off: u32,
+    pub size: u32,
+    pub real_off: u32,
+    pub real_size: u32,
+    pub access: FieldAccess,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DescriptorError {
+    // size not a power of 2 up to 8, misaligned or out of the context
+    InvalidField,
+    Overlap,
+    // written fields must have the same size in the real struct, packet pointers must be 8 bytes
+    SizeMismatch,
+}
+
+// the access to the real context an access to the context of a program is converted to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CtxAccess {
+    // `size` bytes, at `delta` from the offset accessed by the program
+    Mem { delta: i32, size: u32 },
+    // bytes beyond the field in the real struct, which read as zero
+    Zero,
+}
+
+// Layout of the context passed in R1 as seen by programs (e.g. `__sk_buff` or `xdp_md`), mapped to the
+// internal struct of the host. Programs may only access the declared fields, see `convert_ctx_accesses`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ContextDescriptor {
+    size: usize,
+    fields: Vec<CtxField>,
+}
+
+impl ContextDescriptor {
+    // `size` of the UAPI struct
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            fields: Vec::new(),
+        }
+    }
+
+    pub fn add_field(&mut self, field: CtxField) -> Result<(), DescriptorError> {
+        let valid_size = |size: u32| matches!(size, 1 | 2 | 4 | 8);
+        if !valid_size(field.size)
+            || !valid_size(field.real_size)
+            || field.off & (field.size - 1) != 0
+            || (field.off + field.size) as usize > self.size
+        {
+            return Err(DescriptorError::InvalidField);
+        }
+        let size_ok = match field.access {
+            FieldAccess::ReadOnly => true,
+            FieldAccess::ReadWrite => field.real_size == field.size,
+            FieldAccess::PktData | FieldAccess::PktDataEnd | FieldAccess::PktMeta => field.real_size == 8,
+        };
+        if !size_ok {
+            return Err(DescriptorError::SizeMismatch);
+        }
+        if self.fields.iter().any(|f| f.off < field.off + field.size && field.off < f.off + f.size) {
+            return Err(DescriptorError::Overlap);
+        }
+        self.fields.push(field);
+        Ok(())
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn fields(&self) -> &[CtxField] {
+        &self.fields
+    }
+
+    // Convert an access of `size` bytes at `off` in the UAPI struct, along with the field accessed.
+    // Returns `None` if the access is not allowed.
+    pub fn convert(&self, off: u32, size: u32, is_write: bool) -> Option<(&CtxField, CtxAccess)> {
+        let field = self.fields.iter().find(|f| f.off <= off && off + size <= f.off + f.size)?;
+        let whole = off == field.off && size == field.size;
+        let delta = field.real_off as i32 - field.off as i32;
+        let access = match field.access {
+            FieldAccess::ReadOnly if is_write => return None,
+            FieldAccess::ReadOnly if whole => CtxAccess::Mem {
+                delta,
+                size: size.min(field.real_size),
+            },
+            FieldAccess::ReadOnly => {
+                // a part of the field, which must be entirely in or out of the real one
+                let start = off - field.off;
+                if start >= field.real_size {
+                    CtxAccess::Zero
+                } else if start + size <= field.real_size {
+                    CtxAccess::Mem { delta, size }
+                } else {
+                    return None;
+                }
+            }
+            FieldAccess::ReadWrite if whole || !is_write => CtxAccess::Mem { delta, size },
+            FieldAccess::PktData | FieldAccess::PktDataEnd | FieldAccess::PktMeta if whole && !is_write => {
+                CtxAccess::Mem { delta, size: 8 }
+            }
+            _ => return None,
+        };
+        Some((field, access))
+    }
+}
+
+// Rewrite the accesses to the context found by the verifier into accesses to the real struct.
+// Instructions are rewritten in place, so jumps are kept as they are.
+pub fn convert_ctx_accesses(insns: &[u64], aux: &[InsnAux]) -> Vec<u64> {
+    let mut insns = insns.to_vec();
+    for (insn, aux) in insns.iter_mut().zip(aux) {
+        let op = (*insn & 0xff) as u8;
+        let dst = ((*insn >> 8) & 0xf) as u8;
+        *insn = match aux.ctx {
+            None => continue,
+            // r = 0
+            Some(CtxAccess::Zero) => ALU64_K_MOV as u64 | (dst as u64) << 8,
+            Some(CtxAccess::Mem { delta, size }) => {
+                let size_mod = match size {
+                    1 => BPF_B,
+                    2 => BPF_H,
+                    4 => BPF_W,
+                    _ => BPF_DW,
+                };
+                let op = (op & !0b11000) as u64 | size_mod as u64;
+                // the verifier ensures the offset fits
+                let off = ((*insn >> 16) as i16 as i32 + delta) as u16 as u64;
+                (*insn & !0xffff_00ff) | op | off << 16
+            }
+        };
+    }
+    insns
+}
@@ -0,0 +1,359 @@
+// An RV64IMC interpreter, with the bit manipulation instructions the JIT may use, running jitted code
+// in place, on host memory, for tests.
+// A jump out of the code regions is a call to a host `extern "C"` function, such as a helper.
+
+extern crate std;
+
+use std::vec;
+use std::vec::Vec;
+
+use crate::compile::{search_exception_table, ExceptionEntry};
+
+type HostFn = unsafe extern "C" fn(u64, u64, u64, u64, u64) -> u64;
+
+// return address of `call`, which stops the interpreter
+const RETURN_ADDR: u64 = 0xdead_0000;
+
+fn sext(v: u64, bits: u32) -> u64 {
+    let shift = 64 - bits;
+    (((v << shift) as i64) >> shift) as u64
+}
+
+pub struct Emu {
+    pub x: [u64; 32],
+    pub pc: u64,
+    // (start, end) of the code regions
+    code: Vec<(u64, u64)>,
+    stack: Vec<u64>,
+    pub steps: u64,
+    // (start, end) of the addresses whose loads fault
+    pub faults: Vec<(u64, u64)>,
+    // (code address, exception table) to fix up faulting loads with
+    extables: Vec<(u64, Vec<ExceptionEntry>)>,
+}
+
+impl Emu {
+    pub fn new() -> Self {
+        Self {
+            x: [0; 32],
+            pc: 0,
+            code: Vec::new(),
+            stack: vec![0; 8192],
+            steps: 0,
+            faults: Vec::new(),
+            extables: Vec::new(),
+        }
+    }
+
+    // returns the address of `code`
+    pub fn add_code(&mut self, code: &[u32]) -> u64 {
+        let start = code.as_ptr() as u64;
+        self.code.push((start, start + 4 * code.len() as u64));
+        start
+    }
+
+    // faulting loads of the code at `code` are fixed up by `table`, as a trap handler would
+    pub fn set_exception_table(&mut self, code: u64, table: &[ExceptionEntry]) {
+        self.extables.push((code, table.to_vec()));
+    }
+
+    fn in_code(&self, addr: u64) -> bool {
+        self.code.iter().any(|&(start, end)| start <= addr && addr < end)
+    }
+
+    // call `entry` with `args` in a0.., and return a0
+    pub fn call(&mut self, entry: u64, args: &[u64]) -> u64 {
+        self.x[1] = RETURN_ADDR;
+        self.x[2] = (self.stack.as_ptr() as u64 + 8 * self.stack.len() as u64) & !15;
+        for (i, &arg) in args.iter().enumerate() {
+            self.x[10 + i] = arg;
+        }
+        self.pc = entry;
+        while self.pc != RETURN_ADDR {
+            if !self.in_code(self.pc) {
+                let f: HostFn = unsafe { core::mem::transmute(self.pc as usize) };
+                self.x[10] = unsafe { f(self.x[10], self.x[11], self.x[12], self.x[13], self.x[14]) };
+                self.pc = self.x[1];
+                continue;
+            }
+            self.steps += 1;
+            assert!(self.steps < 10_000_000, "too many steps");
+            let half = unsafe { *(self.pc as *const u16) };
+            if half & 3 != 3 {
+                self.step(expand(half), 2);
+            } else {
+                let insn = unsafe { (self.pc as *const u32).read_unaligned() };
+                self.step(insn, 4);
+            }
+            self.x[0] = 0;
+        }
+        self.x[10]
+    }
+
+    fn load(addr: u64, size: u32) -> u64 {
+        unsafe {
+            match size {
+                1 => *(addr as *const u8) as u64,
+                2 => (addr as *const u16).read_unaligned() as u64,
+                4 => (addr as *const u32).read_unaligned() as u64,
+                _ => (addr as *const u64).read_unaligned(),
+            }
+        }
+    }
+
+    fn store(addr: u64, size: u32, v: u64) {
+        unsafe {
+            match size {
+                1 => *(addr as *mut u8) = v as u8,
+                2 => (addr as *mut u16).write_unaligned(v as u16),
+                4 => (addr as *mut u32).write_unaligned(v as u32),
+                _ => (addr as *mut u64).write_unaligned(v),
+            }
+        }
+    }
+
+    // run `insn`, which takes `len` bytes
+    fn step(&mut self, insn: u32, len: u64) {
+        let opcode = insn & 0x7f;
+        let rd = ((insn >> 7) & 31) as usize;
+        let funct3 = (insn >> 12) & 7;
+        let funct7 = insn >> 25;
+        let a = self.x[((insn >> 15) & 31) as usize];
+        let b = self.x[((insn >> 20) & 31) as usize];
+        let imm_i = sext((insn >> 20) as u64, 12);
+        let imm_s = sext(((insn >> 25) << 5 | (insn >> 7) & 31) as u64, 12);
+        let mut next = self.pc + len;
+        match opcode {
+            // lui, auipc
+            0x37 => self.x[rd] = sext((insn & 0xffff_f000) as u64, 32),
+            0x17 => self.x[rd] = self.pc.wrapping_add(sext((insn & 0xffff_f000) as u64, 32)),
+            // jal
+            0x6f => {
+                let imm = (insn >> 31) << 20 | ((insn >> 12) & 0xff) << 12 | ((insn >> 20) & 1) << 11 | ((insn >> 21) & 0x3ff) << 1;
+                self.x[rd] = next;
+                next = self.pc.wrapping_add(sext(imm as u64, 21));
+            }
+            // jalr
+            0x67 => {
+                let target = a.wrapping_add(imm_i) & !1;
+                self.x[rd] = next;
+                next = target;
+            }
+            // branches
+            0x63 => {
+                let imm = (insn >> 31) << 12 | ((insn >> 7) & 1) << 11 | ((insn >> 25) & 0x3f) << 5 | ((insn >> 8) & 0xf) << 1;
+                let taken = match funct3 {
+                    0 => a == b,
+                    1 => a != b,
+                    4 => (a as i64) < (b as i64),
+                    5 => (a as i64) >= (b as i64),
+                    6 => a < b,
+                    7 => a >= b,
+                    _ => panic!("illegal branch {:#010x}", insn),
+                };
+                if taken {
+                    next = self.pc.wrapping_add(sext(imm as u64, 13));
+                }
+            }
+            // loads
+            0x03 => {
+                let size = 1 << (funct3 & 3);
+                let addr = a.wrapping_add(imm_i);
+                if self.faults.iter().any(|&(start, end)| start <= addr && addr < end) {
+                    let fixup = self
+                        .extables
+                        .iter()
+                        .find_map(|(code, table)| search_exception_table(table, *code as usize, self.pc as usize))
+                        .unwrap_or_else(|| panic!("unhandled fault on {:#x} at {:#x}", addr, self.pc));
+                    self.x[fixup.reg as usize] = 0;
+                    next = fixup.resume_pc as u64;
+                } else {
+                    let v = Self::load(addr, size);
+                    self.x[rd] = if funct3 < 3 { sext(v, size * 8) } else { v };
+                }
+            }
+            // stores
+            0x23 => Self::store(a.wrapping_add(imm_s), 1 << funct3, b),
+            // op-imm
+            0x13 => {
+                let shamt = (imm_i & 63) as u32;
+                self.x[rd] = match funct3 {
+                    0 => a.wrapping_add(imm_i),
+                    1 if insn >> 26 == 0b001010 => a | 1 << shamt, // bseti
+                    1 if insn >> 20 == 0x604 => a as i8 as u64,   // sext.b
+                    1 if insn >> 20 == 0x605 => a as i16 as u64,  // sext.h
+                    1 => a << shamt,
+                    2 => ((a as i64) < (imm_i as i64)) as u64,
+                    3 => (a < imm_i) as u64,
+                    4 => a ^ imm_i,
+                    5 if insn >> 20 == 0x6b8 => a.swap_bytes(), // rev8
+                    5 if insn >> 30 & 1 == 1 => ((a as i64) >> shamt) as u64,
+                    5 => a >> shamt,
+                    6 => a | imm_i,
+                    _ => a & imm_i,
+                };
+            }
+            // op-imm-32
+            0x1b => {
+                let shamt = (imm_i & 31) as u32;
+                let v = match funct3 {
+                    0 => a.wrapping_add(imm_i),
+                    1 => a << shamt,
+                    5 if insn >> 30 & 1 == 1 => ((a as i32) >> shamt) as u64,
+                    5 => ((a as u32) >> shamt) as u64,
+                    _ => panic!("illegal op-imm-32 {:#010x}", insn),
+                };
+                self.x[rd] = sext(v & 0xffff_ffff, 32);
+            }
+            // op
+            0x33 => {
+                let shamt = (b & 63) as u32;
+                self.x[rd] = match (funct7, funct3) {
+                    (0, 0) => a.wrapping_add(b),
+                    (0x20, 0) => a.wrapping_sub(b),
+                    (0, 1) => a << shamt,
+                    (0, 2) => ((a as i64) < (b as i64)) as u64,
+                    (0, 3) => (a < b) as u64,
+                    (0, 4) => a ^ b,
+                    (0, 5) => a >> shamt,
+                    (0x20, 5) => ((a as i64) >> shamt) as u64,
+                    (0, 6) => a | b,
+                    (0, 7) => a & b,
+                    (1, 0) => a.wrapping_mul(b),
+                    (1, 1) => ((a as i64 as i128 * b as i64 as i128) >> 64) as u64,
+                    (1, 3) => ((a as u128 * b as u128) >> 64) as u64,
+                    (1, 4) if b == 0 => u64::MAX,
+                    (1, 4) => (a as i64).wrapping_div(b as i64) as u64,
+                    (1, 5) => a.checked_div(b).unwrap_or(u64::MAX),
+                    (1, 6) if b == 0 => a,
+                    (1, 6) => (a as i64).wrapping_rem(b as i64) as u64,
+                    (1, 7) => a.checked_rem(b).unwrap_or(a),
+                    // sh1add, sh2add, sh3add
+                    (0x10, 2) | (0x10, 4) | (0x10, 6) => (a << (funct3 / 2)).wrapping_add(b),
+                    (0x20, 7) => a & !b, // andn
+                    (0x20, 6) => a | !b, // orn
+                    (5, 4) => (a as i64).min(b as i64) as u64,
+                    (5, 5) => a.min(b),
+                    (5, 6) => (a as i64).max(b as i64) as u64,
+                    (5, 7) => a.max(b),
+                    _ => panic!("illegal op {:#010x}", insn),
+                };
+            }
+            // add.uw, zext.h
+            0x3b if funct7 == 4 => {
+                self.x[rd] = match funct3 {
+                    0 => (a as u32 as u64).wrapping_add(b),
+                    4 => a as u16 as u64,
+                    _ => panic!("illegal op-32 {:#010x}", insn),
+                };
+            }
+            // op-32
+            0x3b => {
+                let (a, b) = (a as u32, b as u32);
+                let shamt = b & 31;
+                let v = match (funct7, funct3) {
+                    (0, 0) => a.wrapping_add(b),
+                    (0x20, 0) => a.wrapping_sub(b),
+                    (0, 1) => a << shamt,
+                    (0, 5) => a >> shamt,
+                    (0x20, 5) => ((a as i32) >> shamt) as u32,
+                    (1, 0) => a.wrapping_mul(b),
+                    (1, 4) if b == 0 => u32::MAX,
+                    (1, 4) => (a as i32).wrapping_div(b as i32) as u32,
+                    (1, 5) => a.checked_div(b).unwrap_or(u32::MAX),
+                    (1, 6) if b == 0 => a,
+                    (1, 6) => (a as i32).wrapping_rem(b as i32) as u32,
+                    (1, 7) => a.checked_rem(b).unwrap_or(a),
+                    _ => panic!("illegal op-32 {:#010x}", insn),
+                };
+                self.x[rd] = sext(v as u64, 32);
+            }
+            _ => panic!("illegal instruction {:#010x} at {:#x}", insn, self.pc),
+        }
+        self.pc = next;
+    }
+}
+
+// The 32-bit instruction a compressed one stands for.
+fn expand(c: u16) -> u32 {
+    let c = c as u32;
+    let bit = |from: u32, to: u32| ((c >> from) & 1) << to;
+    let bits = |hi: u32, lo: u32, to: u32| ((c >> lo) & ((1 << (hi - lo + 1)) - 1)) << to;
+    // registers x8-x15 of the 3-bit fields
+    let r_hi = bits(9, 7, 0) + 8;
+    let r_lo = bits(4, 2, 0) + 8;
+    let rd = bits(11, 7, 0);
+    let rs2 = bits(6, 2, 0);
+    let i_type = |imm: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32| {
+        (imm & 0xfff) << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+    };
+    let s_type = |imm: u32, rs2: u32, rs1: u32, funct3: u32| {
+        (imm >> 5 & 0x7f) << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | (imm & 31) << 7 | 0x23
+    };
+    let r_type = |funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32| {
+        funct7 << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+    };
+    // sign-extended 6-bit immediate of c.addi, c.li, ...
+    let imm6 = sext((bit(12, 5) | bits(6, 2, 0)) as u64, 6) as u32;
+    let funct3 = c >> 13;
+    match (c & 3, funct3) {
+        (0, 0) => {
+            let imm = bits(12, 11, 4) | bits(10, 7, 6) | bit(6, 2) | bit(5, 3);
+            assert!(imm != 0, "illegal compressed instruction {:#06x}", c);
+            i_type(imm, 2, 0, r_lo, 0x13) // c.addi4spn
+        }
+        (0, 2) => i_type(bits(12, 10, 3) | bit(6, 2) | bit(5, 6), r_hi, 2, r_lo, 0x03), // c.lw
+        (0, 3) => i_type(bits(12, 10, 3) | bits(6, 5, 6), r_hi, 3, r_lo, 0x03),          // c.ld
+        (0, 6) => s_type(bits(12, 10, 3) | bit(6, 2) | bit(5, 6), r_lo, r_hi, 2),       // c.sw
+        (0, 7) => s_type(bits(12, 10, 3) | bits(6, 5, 6), r_lo, r_hi, 3),                // c.sd
+        (1, 0) => i_type(imm6, rd, 0, rd, 0x13), // c.addi
+        (1, 1) => i_type(imm6, rd, 0, rd, 0x1b), // c.addiw
+        (1, 2) => i_type(imm6, 0, 0, rd, 0x13),  // c.li
+        (1, 3) if rd == 2 => {
+            let imm = bit(12, 9) | bit(6, 4) | bit(5, 6) | bits(4, 3, 7) | bit(2, 5);
+            i_type(sext(imm as u64, 10) as u32, 2, 0, 2, 0x13) // c.addi16sp
+        }
+        (1, 3) => imm6 << 12 | rd << 7 | 0x37, // c.lui
+        (1, 4) => match bits(11, 10, 0) {
+            0 => i_type(imm6 & 63, r_hi, 5, r_hi, 0x13),           // c.srli
+            1 => i_type(0x400 | imm6 & 63, r_hi, 5, r_hi, 0x13),   // c.srai
+            2 => i_type(imm6, r_hi, 7, r_hi, 0x13),                // c.andi
+            _ => {
+                let (funct7, funct3, opcode) = match bit(12, 2) | bits(6, 5, 0) {
+                    0 => (0x20, 0, 0x33), // c.sub
+                    1 => (0, 4, 0x33),    // c.xor
+                    2 => (0, 6, 0x33),    // c.or
+                    3 => (0, 7, 0x33),    // c.and
+                    4 => (0x20, 0, 0x3b), // c.subw
+                    5 => (0, 0, 0x3b),    // c.addw
+                    _ => panic!("illegal compressed instruction {:#06x}", c),
+                };
+                r_type(funct7, r_lo, r_hi, funct3, r_hi, opcode)
+            }
+        },
+        (1, 5) => {
+            let imm = bit(12, 11) | bit(11, 4) | bits(10, 9, 8) | bit(8, 10) | bit(7, 6) | bit(6, 7) | bits(5, 3, 1) | bit(2, 5);
+            let imm = sext(imm as u64, 12) as u32;
+            // jal zero
+            (imm >> 20 & 1) << 31 | (imm >> 1 & 0x3ff) << 21 | (imm >> 11 & 1) << 20 | (imm >> 12 & 0xff) << 12 | 0x6f
+        }
+        (1, 6) | (1, 7) => {
+            let imm = bit(12, 8) | bits(11, 10, 3) | bits(6, 5, 6) | bits(4, 3, 1) | bit(2, 5);
+            let imm = sext(imm as u64, 9) as u32;
+            // beq, bne against zero
+            (imm >> 12 & 1) << 31 | (imm >> 5 & 0x3f) << 25 | r_hi << 15 | (funct3 & 1) << 12 | (imm >> 1 & 0xf) << 8 | (imm >> 11 & 1) << 7 | 0x63
+        }
+        (2, 0) => i_type(imm6 & 63, rd, 1, rd, 0x13),                                    // c.slli
+        (2, 2) => i_type(bit(12, 5) | bits(6, 4, 2) | bits(3, 2, 6), 2, 2, rd, 0x03),  // c.lwsp
+        (2, 3) => i_type(bit(12, 5) | bits(6, 5, 3) | bits(4, 2, 6), 2, 3, rd, 0x03),  // c.ldsp
+        (2, 4) => match (bit(12, 0), rs2) {
+            (0, 0) => i_type(0, rd, 0, 0, 0x67),         // c.jr
+            (0, _) => r_type(0, rs2, 0, 0, rd, 0x33),    // c.mv
+            (_, 0) => i_type(0, rd, 0, 1, 0x67),         // c.jalr
+            (_, _) => r_type(0, rs2, rd, 0, rd, 0x33),   // c.add
+        },
+        (2, 6) => s_type(bits(12, 9, 2) | bits(8, 7, 6), rs2, 2, 2), // c.swsp
+        (2, 7) => s_type(bits(12, 10, 3) | bits(9, 7, 6), rs2, 2, 3), // c.sdsp
+        _ => panic!("illegal compressed instruction {:#06x}", c),
+    }
+}
@@ -0,0 +1,221 @@
+extern crate alloc;
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::consts::*;
+use crate::zext::{insn_starts, live_out, ARG_REGS, CALLER_SAVED};
+
+fn reg_bit(reg: u8) -> u16 {
+    1 << reg
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operand {
+    Reg(u8),
+    Imm(i32),
+}
+
+// A pair of eBPF instructions done by a single instruction of Zba or Zbb, on eBPF registers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fused {
+    // dst = (src << shift) + add, by sh1add - sh3add
+    ShiftAdd {
+        dst: u8,
+        src: u8,
+        add: u8,
+        shift: u8,
+    },
+    // dst = src & !not, by andn
+    AndNot {
+        dst: u8,
+        src: u8,
+        not: u8,
+    },
+    // dst = src | !not, by orn
+    OrNot {
+        dst: u8,
+        src: u8,
+        not: u8,
+    },
+    // the lower `bits` of dst zero-extended, by zext.w or zext.h
+    ZeroExtend {
+        dst: u8,
+        bits: u8,
+    },
+    // the lower `bits` of dst sign-extended, by sext.b or sext.h
+    SignExtend {
+        dst: u8,
+        bits: u8,
+    },
+    // dst = min(dst, src) or max(dst, src), by min(u) or max(u)
+    MinMax {
+        max: bool,
+        signed: bool,
+        dst: u8,
+        src: Operand,
+    },
+}
+
+// Registers `insn` reads, and registers it writes, as a whole.
+fn uses_defs(insn: u64) -> (u16, u16) {
+    let op = insn as u8;
+    let dst = ((insn >> 8) & 0xf) as u8;
+    let src = ((insn >> 12) & 0xf) as u8;
+    let code = (op & 0xf0) as u32;
+    let src_bit = if (op & 8) != 0 { reg_bit(src) } else { 0 };
+    match (op & 0b111) as u32 {
+        BPF_ALU | BPF_ALU64 if code == BPF_END => (reg_bit(dst), reg_bit(dst)),
+        BPF_ALU | BPF_ALU64 if code == BPF_MOV => (src_bit, reg_bit(dst)),
+        BPF_ALU | BPF_ALU64 => (reg_bit(dst) | src_bit, reg_bit(dst)),
+        BPF_JMP | BPF_JMP32 => match code {
+            BPF_JA => (0, 0),
+            BPF_CALL => (ARG_REGS, CALLER_SAVED),
+            BPF_EXIT => (reg_bit(BPF_REG_R0), 0),
+            _ => (reg_bit(dst) | src_bit, 0),
+        },
+        BPF_LDX => (reg_bit(src), reg_bit(dst)),
+        BPF_ST => (reg_bit(dst), 0),
+        BPF_STX => (reg_bit(dst) | reg_bit(src), 0),
+        _ if op == LD_IMM_DW => (0, reg_bit(dst)),
+        // LD_ABS / LD_IND
+        _ => {
+            let index = if (op & 0xe0) as u32 == BPF_IND { reg_bit(src) } else { 0 };
+            (reg_bit(BPF_REG_R6) | index, CALLER_SAVED)
+        }
+    }
+}
+
+// (op, dst, src, off, imm)
+fn fields(insn: u64) -> (u8, u8, u8, i16, i32) {
+    (
+        insn as u8,
+        ((insn >> 8) & 0xf) as u8,
+        ((insn >> 12) & 0xf) as u8,
+        (insn >> 16) as i16,
+        (insn >> 32) as i32,
+    )
+}
+
+// `a` and `b` as a single instruction, `live` being the registers read after `b`
+fn fuse(a: u64, b: u64, live: u16, zba: bool, zbb: bool) -> Option<Fused> {
+    let (a_op, a_dst, a_src, a_off, a_imm) = fields(a);
+    let (b_op, b_dst, b_src, _, b_imm) = fields(b);
+    let dead = |reg: u8| live & reg_bit(reg) == 0;
+    match (a_op, b_op) {
+        // dst <<= 32; dst >>= 32, or 48
+        (ALU64_K_LSH, ALU64_K_RSH) if b_dst == a_dst && b_imm == a_imm => match a_imm {
+            32 if zba => Some(Fused::ZeroExtend { dst: a_dst, bits: 32 }),
+            48 if zbb => Some(Fused::ZeroExtend { dst: a_dst, bits: 16 }),
+            _ => None,
+        },
+        // dst <<= 56; dst s>>= 56, or 48
+        (ALU64_K_LSH, ALU64_K_ARSH) if zbb && b_dst == a_dst && b_imm == a_imm => match a_imm {
+            56 => Some(Fused::SignExtend { dst: a_dst, bits: 8 }),
+            48 => Some(Fused::SignExtend { dst: a_dst, bits: 16 }),
+            _ => None,
+        },
+        // src <<= shift; dst += src, src being either the shifted register or dead afterwards
+        (ALU64_K_LSH, ALU64_X_ADD) if zba && (1..=3).contains(&a_imm) => {
+            let shift = a_imm as u8;
+            if b_dst == a_dst && b_src != a_dst {
+                Some(Fused::ShiftAdd {
+                    dst: a_dst,
+                    src: a_dst,
+                    add: b_src,
+                    shift,
+                })
+            } else if b_src == a_dst && b_dst != a_dst && dead(a_dst) {
+                Some(Fused::ShiftAdd {
+                    dst: b_dst,
+                    src: a_dst,
+                    add: b_dst,
+                    shift,
+                })
+            } else {
+                None
+            }
+        }
+        // not ^= -1; dst &= not, or |=
+        (ALU64_K_XOR, ALU64_X_AND) | (ALU64_K_XOR, ALU64_X_OR) if zbb && a_imm == -1 => {
+            let (dst, src, not) = if b_dst == a_dst && b_src != a_dst {
+                (a_dst, b_src, a_dst)
+            } else if b_src == a_dst && b_dst != a_dst && dead(a_dst) {
+                (b_dst, b_dst, a_dst)
+            } else {
+                return None;
+            };
+            if b_op == ALU64_X_AND {
+                Some(Fused::AndNot { dst, src, not })
+            } else {
+                Some(Fused::OrNot { dst, src, not })
+            }
+        }
+        // if dst <op> src goto +1; dst = src
+        (_, ALU64_X_MOV) | (_, ALU64_K_MOV) if zbb && a_op as u32 & 0b111 == BPF_JMP && a_off == 1 => {
+            let src = if a_op & 8 != 0 {
+                Operand::Reg(a_src)
+            } else {
+                Operand::Imm(a_imm)
+            };
+            let same_src = match src {
+                Operand::Reg(reg) => b_op == ALU64_X_MOV && b_src == reg,
+                Operand::Imm(imm) => b_op == ALU64_K_MOV && b_imm == imm,
+            };
+            if b_dst != a_dst || !same_src {
+                return None;
+            }
+            // dst is kept if the condition holds
+            let (max, signed) = match (a_op & 0xf0) as u32 {
+                BPF_JLT | BPF_JLE => (false, false),
+                BPF_JGT | BPF_JGE => (true, false),
+                BPF_JSLT | BPF_JSLE => (false, true),
+                BPF_JSGT | BPF_JSGE => (true, true),
+                _ => return None,
+            };
+            Some(Fused::MinMax {
+                max,
+                signed,
+                dst: a_dst,
+                src,
+            })
+        }
+        _ => None,
+    }
+}
+
+// The pairs of instructions which Zba (`zba`) or Zbb (`zbb`) does in one, by the pc of their first
+// instruction. The second one is never a jump target, and registers they leave with a different
+// value are never read afterwards.
+pub fn fuse_pairs(insns: &[u64], zba: bool, zbb: bool) -> Vec<Option<Fused>> {
+    let len = insns.len();
+    let mut fused = vec![None; len];
+    if !zba && !zbb {
+        return fused;
+    }
+    let starts = insn_starts(insns);
+    let mut targets = vec![false; len + 1];
+    for pc in (0..len).filter(|&pc| starts[pc]) {
+        let (op, _, _, off, _) = fields(insns[pc]);
+        let class = (op & 0b111) as u32;
+        let code = (op & 0xf0) as u32;
+        if (class == BPF_JMP || class == BPF_JMP32) && code != BPF_CALL && code != BPF_EXIT {
+            if let Some(target) = targets.get_mut((pc as isize + 1 + off as isize) as usize) {
+                *target = true;
+            }
+        }
+    }
+    let live = live_out(insns, uses_defs);
+    let mut pc = 0;
+    while pc + 1 < len {
+        if starts[pc] && starts[pc + 1] && !targets[pc + 1] {
+            fused[pc] = fuse(insns[pc], insns[pc + 1], live[pc + 1], zba, zbb);
+            if fused[pc].is_some() {
+                pc += 2;
+                continue;
+            }
+        }
+        pc += 1;
+    }
+    fused
+}
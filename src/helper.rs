@@ -0,0 +1,267 @@
+extern crate alloc;
+
+use alloc::collections::BTreeMap;
+
+use crate::compile::JitContext;
+use crate::helper_lib::*;
+use crate::map::{bpf_map_delete_elem, bpf_map_lookup_elem, bpf_map_update_elem};
+use crate::xdp::{bpf_xdp_adjust_head, bpf_xdp_adjust_meta, bpf_xdp_adjust_tail};
+
+// helper IDs, consistent with `enum bpf_func_id` in linux
+pub const BPF_FUNC_MAP_LOOKUP_ELEM: u32 = 1;
+pub const BPF_FUNC_MAP_UPDATE_ELEM: u32 = 2;
+pub const BPF_FUNC_MAP_DELETE_ELEM: u32 = 3;
+pub const BPF_FUNC_PROBE_READ: u32 = 4;
+pub const BPF_FUNC_KTIME_GET_NS: u32 = 5;
+pub const BPF_FUNC_TRACE_PRINTK: u32 = 6;
+pub const BPF_FUNC_GET_PRANDOM_U32: u32 = 7;
+pub const BPF_FUNC_GET_SMP_PROCESSOR_ID: u32 = 8;
+pub const BPF_FUNC_GET_CURRENT_PID_TGID: u32 = 14;
+pub const BPF_FUNC_GET_CURRENT_COMM: u32 = 16;
+pub const BPF_FUNC_XDP_ADJUST_HEAD: u32 = 44;
+pub const BPF_FUNC_XDP_ADJUST_META: u32 = 54;
+pub const BPF_FUNC_XDP_ADJUST_TAIL: u32 = 65;
+pub const BPF_FUNC_STRTOL: u32 = 105;
+pub const BPF_FUNC_STRTOUL: u32 = 106;
+pub const BPF_FUNC_PROBE_READ_KERNEL: u32 = 113;
+pub const BPF_FUNC_KTIME_GET_BOOT_NS: u32 = 125;
+pub const BPF_FUNC_SNPRINTF: u32 = 165;
+
+// kind of a helper argument, mirrors `enum bpf_arg_type` in linux
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArgKind {
+    Anything,
+    ConstMapPtr,
+    PtrToMapKey,
+    PtrToMapValue,
+    PtrToCtx,
+    // readable memory, its size is given by the following `ConstSize` argument
+    PtrToMem,
+    // writable memory, its size is given by the following `ConstSize` argument
+    PtrToUninitMem,
+    ConstSize,
+    ConstSizeOrZero,
+    // NUL terminated string, constant as it is in a frozen `BPF_F_RDONLY_PROG` map
+    PtrToConstStr,
+    // writable 8 bytes
+    PtrToLong,
+}
+
+// arguments of the builtin bpf_probe_read_kernel, see `JitContext::build_probe_read`
+pub const PROBE_READ_ARGS: [ArgKind; 3] = [ArgKind::PtrToUninitMem, ArgKind::ConstSize, ArgKind::Anything];
+
+// kind of a helper return value, mirrors `enum bpf_return_type` in linux
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RetKind {
+    Integer,
+    Void,
+    PtrToMapValueOrNull,
+}
+
+// Helpers which may move packet data, after which packet pointers are invalid.
+// See `bpf_helper_changes_pkt_data` in linux.
+pub fn changes_pkt_data(id: u32) -> bool {
+    matches!(
+        id,
+        BPF_FUNC_XDP_ADJUST_HEAD | BPF_FUNC_XDP_ADJUST_META | BPF_FUNC_XDP_ADJUST_TAIL
+    )
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Helper {
+    pub id: u32,
+    pub name: &'static str,
+    // address of the function, called with R1-R5 in a0-a4 and returning R0 in a0
+    pub func: u64,
+    pub args: &'static [ArgKind],
+    pub ret: RetKind,
+}
+
+// Emits the body of a helper in place of its call, leaving R0 in `rd`.
+// R1-R5 (a0-a4) hold the arguments and may be clobbered, as a call does.
+pub type InlineFn = fn(ctx: &mut JitContext, rd: u8);
+
+// Helpers callable by eBPF programs, keyed by their helper ID.
+// Only the helpers a program actually calls are put into its PLT.
+pub struct HelperRegistry {
+    helpers: BTreeMap<u32, Helper>,
+    inline: BTreeMap<u32, InlineFn>,
+}
+
+impl HelperRegistry {
+    pub fn new() -> Self {
+        Self {
+            helpers: BTreeMap::new(),
+            inline: BTreeMap::new(),
+        }
+    }
+
+    // Inline the registered helper `id` with `emit` instead of calling it, for trivial helpers
+    // such as `bpf_get_smp_processor_id` which the host knows how to compute in place.
+    pub fn set_inline(&mut self, id: u32, emit: InlineFn) {
+        self.inline.insert(id, emit);
+    }
+
+    pub fn get_inline(&self, id: u32) -> Option<InlineFn> {
+        self.inline.get(&id).copied()
+    }
+
+    // returns the helper previously registered under the same ID, if any
+    pub fn register(&mut self, helper: Helper) -> Option<Helper> {
+        self.helpers.insert(helper.id, helper)
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Helper> {
+        self.helpers.get(&id)
+    }
+
+    pub fn get_by_name(&self, name: &str) -> Option<&Helper> {
+        self.helpers.values().find(|helper| helper.name == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Helper> {
+        self.helpers.values()
+    }
+
+    // register helpers operating on maps, see `map` module
+    pub fn register_map_helpers(&mut self) {
+        self.register(Helper {
+            id: BPF_FUNC_MAP_LOOKUP_ELEM,
+            name: "bpf_map_lookup_elem",
+            func: bpf_map_lookup_elem as *const () as u64,
+            args: &[ArgKind::ConstMapPtr, ArgKind::PtrToMapKey],
+            ret: RetKind::PtrToMapValueOrNull,
+        });
+        self.register(Helper {
+            id: BPF_FUNC_MAP_UPDATE_ELEM,
+            name: "bpf_map_update_elem",
+            func: bpf_map_update_elem as *const () as u64,
+            args: &[
+                ArgKind::ConstMapPtr,
+                ArgKind::PtrToMapKey,
+                ArgKind::PtrToMapValue,
+                ArgKind::Anything,
+            ],
+            ret: RetKind::Integer,
+        });
+        self.register(Helper {
+            id: BPF_FUNC_MAP_DELETE_ELEM,
+            name: "bpf_map_delete_elem",
+            func: bpf_map_delete_elem as *const () as u64,
+            args: &[ArgKind::ConstMapPtr, ArgKind::PtrToMapKey],
+            ret: RetKind::Integer,
+        });
+    }
+
+    // register helpers resizing the packet of XDP programs, see `xdp` module
+    pub fn register_xdp_helpers(&mut self) {
+        for &(id, name, func) in &[
+            (BPF_FUNC_XDP_ADJUST_HEAD, "bpf_xdp_adjust_head", bpf_xdp_adjust_head as *const ()),
+            (BPF_FUNC_XDP_ADJUST_META, "bpf_xdp_adjust_meta", bpf_xdp_adjust_meta as *const ()),
+            (BPF_FUNC_XDP_ADJUST_TAIL, "bpf_xdp_adjust_tail", bpf_xdp_adjust_tail as *const ()),
+        ] {
+            self.register(Helper {
+                id,
+                name,
+                func: func as u64,
+                args: &[ArgKind::PtrToCtx, ArgKind::Anything],
+                ret: RetKind::Integer,
+            });
+        }
+    }
+
+    // register the common helpers of `helper_lib` module
+    pub fn register_std_helpers(&mut self) {
+        self.register(Helper {
+            id: BPF_FUNC_TRACE_PRINTK,
+            name: "bpf_trace_printk",
+            func: bpf_trace_printk as *const () as u64,
+            args: &[
+                ArgKind::PtrToMem,
+                ArgKind::ConstSize,
+                ArgKind::Anything,
+                ArgKind::Anything,
+                ArgKind::Anything,
+            ],
+            ret: RetKind::Integer,
+        });
+        self.register(Helper {
+            id: BPF_FUNC_KTIME_GET_NS,
+            name: "bpf_ktime_get_ns",
+            func: bpf_ktime_get_ns as *const () as u64,
+            args: &[],
+            ret: RetKind::Integer,
+        });
+        self.register(Helper {
+            id: BPF_FUNC_KTIME_GET_BOOT_NS,
+            name: "bpf_ktime_get_boot_ns",
+            func: bpf_ktime_get_boot_ns as *const () as u64,
+            args: &[],
+            ret: RetKind::Integer,
+        });
+        self.register(Helper {
+            id: BPF_FUNC_GET_PRANDOM_U32,
+            name: "bpf_get_prandom_u32",
+            func: bpf_get_prandom_u32 as *const () as u64,
+            args: &[],
+            ret: RetKind::Integer,
+        });
+        self.register(Helper {
+            id: BPF_FUNC_GET_CURRENT_PID_TGID,
+            name: "bpf_get_current_pid_tgid",
+            func: bpf_get_current_pid_tgid as *const () as u64,
+            args: &[],
+            ret: RetKind::Integer,
+        });
+        self.register(Helper {
+            id: BPF_FUNC_GET_CURRENT_COMM,
+            name: "bpf_get_current_comm",
+            func: bpf_get_current_comm as *const () as u64,
+            args: &[ArgKind::PtrToUninitMem, ArgKind::ConstSize],
+            ret: RetKind::Integer,
+        });
+        self.register(Helper {
+            id: BPF_FUNC_SNPRINTF,
+            name: "bpf_snprintf",
+            func: bpf_snprintf as *const () as u64,
+            args: &[
+                ArgKind::PtrToUninitMem,
+                ArgKind::ConstSizeOrZero,
+                ArgKind::PtrToConstStr,
+                ArgKind::PtrToMem,
+                ArgKind::ConstSizeOrZero,
+            ],
+            ret: RetKind::Integer,
+        });
+        self.register(Helper {
+            id: BPF_FUNC_STRTOL,
+            name: "bpf_strtol",
+            func: bpf_strtol as *const () as u64,
+            args: &[
+                ArgKind::PtrToMem,
+                ArgKind::ConstSize,
+                ArgKind::Anything,
+                ArgKind::PtrToLong,
+            ],
+            ret: RetKind::Integer,
+        });
+        self.register(Helper {
+            id: BPF_FUNC_STRTOUL,
+            name: "bpf_strtoul",
+            func: bpf_strtoul as *const () as u64,
+            args: &[
+                ArgKind::PtrToMem,
+                ArgKind::ConstSize,
+                ArgKind::Anything,
+                ArgKind::PtrToLong,
+            ],
+            ret: RetKind::Integer,
+        });
+    }
+}
+
+impl Default for HelperRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,428 @@
+extern crate alloc;
+
+use alloc::string::String;
+use core::cell::UnsafeCell;
+use core::fmt::{self, Write};
+use core::slice;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::consts::*;
+
+// Implementations of common helpers, see `HelperRegistry::register_std_helpers`.
+// The host provides the environment through a few small traits, installed by the `set_*` functions
+// before any program runs.
+
+pub const TASK_COMM_LEN: usize = 16;
+// strings printed by `%s` are truncated to this length
+const MAX_STR_LEN: usize = 256;
+// linux limits bpf_trace_printk to 3 and bpf_snprintf to 12 arguments
+const MAX_TRACE_PRINTK_ARGS: usize = 3;
+const MAX_SNPRINTF_ARGS: usize = 12;
+
+pub trait OutputSink: Sync {
+    fn write_str(&self, s: &str);
+}
+
+pub trait Clock: Sync {
+    // monotonic time, not counting suspend
+    fn ktime_get_ns(&self) -> u64;
+
+    // monotonic time, counting suspend
+    fn ktime_get_boot_ns(&self) -> u64 {
+        self.ktime_get_ns()
+    }
+}
+
+pub trait Task: Sync {
+    fn pid(&self) -> u32;
+    fn tgid(&self) -> u32;
+    // name of the current task, NUL padded
+    fn comm(&self) -> [u8; TASK_COMM_LEN];
+}
+
+struct Host {
+    output: Option<&'static dyn OutputSink>,
+    clock: Option<&'static dyn Clock>,
+    task: Option<&'static dyn Task>,
+}
+
+struct HostCell(UnsafeCell<Host>);
+
+// written only by the `set_*` functions, which must not race with running programs
+unsafe impl Sync for HostCell {}
+
+static HOST: HostCell = HostCell(UnsafeCell::new(Host {
+    output: None,
+    clock: None,
+    task: None,
+}));
+
+static PRANDOM_STATE: AtomicU64 = AtomicU64::new(0x2545_f491_4f6c_dd1d);
+
+fn host() -> &'static Host {
+    unsafe { &*HOST.0.get() }
+}
+
+/// # Safety
+///
+/// Must not be called while programs using the helpers are running.
+pub unsafe fn set_output_sink(output: &'static dyn OutputSink) {
+    (*HOST.0.get()).output = Some(output);
+}
+
+/// # Safety
+///
+/// Must not be called while programs using the helpers are running.
+pub unsafe fn set_clock(clock: &'static dyn Clock) {
+    (*HOST.0.get()).clock = Some(clock);
+}
+
+/// # Safety
+///
+/// Must not be called while programs using the helpers are running.
+pub unsafe fn set_task(task: &'static dyn Task) {
+    (*HOST.0.get()).task = Some(task);
+}
+
+pub fn set_prandom_seed(seed: u64) {
+    // xorshift gets stuck at zero
+    PRANDOM_STATE.store(seed | 1, Ordering::Relaxed);
+}
+
+fn errno(e: i64) -> u64 {
+    (-e) as u64
+}
+
+// Read a NUL terminated string from a pointer given by the program.
+unsafe fn c_str<'a>(ptr: u64, max_len: usize) -> &'a [u8] {
+    let ptr = ptr as *const u8;
+    let mut len = 0;
+    while len < max_len && *ptr.add(len) != 0 {
+        len += 1;
+    }
+    slice::from_raw_parts(ptr, len)
+}
+
+// Format `fmt` as printf does with the subset of conversions linux allows in bpf_trace_printk
+// and bpf_snprintf: `%d %i %u %x %X %o %c %s %p %%` with optional `-`/`0` flag, width and
+// `l`/`ll` modifiers. Returns -EINVAL on unsupported conversions or missing arguments.
+unsafe fn format(fmt: &[u8], args: &[u64], out: &mut dyn Write) -> Result<(), i64> {
+    let mut args = args.iter();
+    let mut i = 0;
+    while i < fmt.len() && fmt[i] != 0 {
+        let c = fmt[i];
+        i += 1;
+        if c != b'%' {
+            out.write_char(c as char).map_err(|_| -EINVAL)?;
+            continue;
+        }
+
+        let mut left_align = false;
+        let mut zero_pad = false;
+        while i < fmt.len() && (fmt[i] == b'-' || fmt[i] == b'0') {
+            left_align |= fmt[i] == b'-';
+            zero_pad |= fmt[i] == b'0';
+            i += 1;
+        }
+        let mut width = 0;
+        while i < fmt.len() && fmt[i].is_ascii_digit() {
+            width = width * 10 + (fmt[i] - b'0') as usize;
+            i += 1;
+        }
+        let mut long = 0;
+        while i < fmt.len() && fmt[i] == b'l' && long < 2 {
+            long += 1;
+            i += 1;
+        }
+        if i >= fmt.len() {
+            return Err(-EINVAL);
+        }
+        let conv = fmt[i];
+        i += 1;
+        if conv == b'%' {
+            out.write_char('%').map_err(|_| -EINVAL)?;
+            continue;
+        }
+
+        let arg = *args.next().ok_or(-EINVAL)?;
+        // without `l`, the argument is an int
+        let unsigned = if long == 0 { arg as u32 as u64 } else { arg };
+        let signed = if long == 0 { arg as i32 as i64 } else { arg as i64 };
+
+        let mut buf = String::new();
+        match conv {
+            b'd' | b'i' => write!(buf, "{}", signed),
+            b'u' => write!(buf, "{}", unsigned),
+            b'x' => write!(buf, "{:x}", unsigned),
+            b'X' => write!(buf, "{:X}", unsigned),
+            b'o' => write!(buf, "{:o}", unsigned),
+            b'p' => write!(buf, "0x{:x}", arg),
+            b'c' => write!(buf, "{}", arg as u8 as char),
+            b's' if arg == 0 => write!(buf, "(null)"),
+            b's' => write!(buf, "{}", String::from_utf8_lossy(c_str(arg, MAX_STR_LEN))),
+            _ => return Err(-EINVAL),
+        }
+        .map_err(|_| -EINVAL)?;
+
+        let res = if left_align {
+            write!(out, "{:<width$}", buf, width = width)
+        } else if zero_pad && !matches!(conv, b'c' | b's') {
+            // keep the sign in front of the padding
+            match buf.strip_prefix('-') {
+                Some(abs) => write!(out, "-{:0>width$}", abs, width = width.saturating_sub(1)),
+                None => write!(out, "{:0>width$}", buf, width = width),
+            }
+        } else {
+            write!(out, "{:>width$}", buf, width = width)
+        };
+        res.map_err(|_| -EINVAL)?;
+    }
+    Ok(())
+}
+
+/// long bpf_trace_printk(const char *fmt, u32 fmt_size, ...), helper #6
+///
+/// # Safety
+///
+/// `fmt` must point to `fmt_size` readable bytes, and strings printed by `%s` must be readable up to
+/// their NUL or 256 bytes. Must not race with `set_output_sink`, `set_clock` or `set_task`.
+pub unsafe extern "C" fn bpf_trace_printk(fmt: u64, fmt_size: u64, a1: u64, a2: u64, a3: u64) -> u64 {
+    let fmt = slice::from_raw_parts(fmt as *const u8, fmt_size as usize);
+    // format string must be NUL terminated
+    if fmt.last() != Some(&0) {
+        return errno(EINVAL);
+    }
+
+    let args: [u64; MAX_TRACE_PRINTK_ARGS] = [a1, a2, a3];
+    let mut out = String::new();
+    if let Err(e) = format(fmt, &args, &mut out) {
+        return e as u64;
+    }
+
+    if let Some(output) = host().output {
+        output.write_str(&out);
+    }
+    out.len() as u64
+}
+
+/// u64 bpf_ktime_get_ns(void), helper #5
+///
+/// # Safety
+///
+/// Must not race with `set_output_sink`, `set_clock` or `set_task`.
+pub unsafe extern "C" fn bpf_ktime_get_ns() -> u64 {
+    host().clock.map_or(0, |clock| clock.ktime_get_ns())
+}
+
+/// u64 bpf_ktime_get_boot_ns(void), helper #125
+///
+/// # Safety
+///
+/// Must not race with `set_output_sink`, `set_clock` or `set_task`.
+pub unsafe extern "C" fn bpf_ktime_get_boot_ns() -> u64 {
+    host().clock.map_or(0, |clock| clock.ktime_get_boot_ns())
+}
+
+/// u32 bpf_get_prandom_u32(void), helper #7
+///
+/// # Safety
+///
+/// None, it is `unsafe` only to match the other helpers.
+pub unsafe extern "C" fn bpf_get_prandom_u32() -> u64 {
+    // xorshift64*, good enough for sampling and load balancing
+    let mut x = PRANDOM_STATE.load(Ordering::Relaxed);
+    x ^= x >> 12;
+    x ^= x << 25;
+    x ^= x >> 27;
+    PRANDOM_STATE.store(x, Ordering::Relaxed);
+    (x.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 32) as u32 as u64
+}
+
+/// u64 bpf_get_current_pid_tgid(void), helper #14
+///
+/// # Safety
+///
+/// Must not race with `set_output_sink`, `set_clock` or `set_task`.
+pub unsafe extern "C" fn bpf_get_current_pid_tgid() -> u64 {
+    match host().task {
+        Some(task) => ((task.tgid() as u64) << 32) | task.pid() as u64,
+        None => errno(EINVAL),
+    }
+}
+
+/// long bpf_get_current_comm(void *buf, u32 size_of_buf), helper #16
+///
+/// # Safety
+///
+/// `buf` must point to `size_of_buf` writable bytes. Must not race with `set_output_sink`, `set_clock` or `set_task`.
+pub unsafe extern "C" fn bpf_get_current_comm(buf: u64, size: u64) -> u64 {
+    let buf = slice::from_raw_parts_mut(buf as *mut u8, size as usize);
+    let task = match host().task {
+        Some(task) => task,
+        None => {
+            buf.fill(0);
+            return errno(EINVAL);
+        }
+    };
+    if buf.is_empty() {
+        return errno(EINVAL);
+    }
+
+    // truncate and always terminate with NUL, as strscpy does
+    let comm = task.comm();
+    let len = c_str(comm.as_ptr() as u64, TASK_COMM_LEN.min(buf.len() - 1)).len();
+    buf[..len].copy_from_slice(&comm[..len]);
+    buf[len..].fill(0);
+    0
+}
+
+// writes into a fixed buffer, counting what does not fit
+struct TruncatingWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for TruncatingWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &b in s.as_bytes() {
+            // keep the last byte for NUL
+            if self.len + 1 < self.buf.len() {
+                self.buf[self.len] = b;
+            }
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
+
+/// long bpf_snprintf(char *str, u32 str_size, const char *fmt, u64 *data, u32 data_len), helper #165
+/// Returns the length of the whole formatted string including NUL, even if it was truncated.
+///
+/// # Safety
+///
+/// `str` must point to `str_size` writable bytes, `data` to `data_len` readable bytes and `fmt` to a
+/// NUL terminated string. Strings printed by `%s` must be readable up to their NUL or 256 bytes.
+pub unsafe extern "C" fn bpf_snprintf(str: u64, str_size: u64, fmt: u64, data: u64, data_len: u64) -> u64 {
+    if !data_len.is_multiple_of(8) || data_len as usize / 8 > MAX_SNPRINTF_ARGS {
+        return errno(EINVAL);
+    }
+    let args = if data_len == 0 {
+        &[][..]
+    } else {
+        slice::from_raw_parts(data as *const u64, data_len as usize / 8)
+    };
+    let buf = if str_size == 0 {
+        &mut [][..]
+    } else {
+        slice::from_raw_parts_mut(str as *mut u8, str_size as usize)
+    };
+    // terminated within a constant map value, which the verifier checks
+    let fmt = c_str(fmt, usize::MAX);
+
+    let mut out = TruncatingWriter { buf, len: 0 };
+    if let Err(e) = format(fmt, args, &mut out) {
+        return e as u64;
+    }
+    if !out.buf.is_empty() {
+        let end = out.len.min(out.buf.len() - 1);
+        out.buf[end] = 0;
+    }
+    out.len as u64 + 1
+}
+
+// Parse an integer as the kernel's strtol/strtoul, returning the number of characters consumed.
+fn parse_integer(buf: &[u8], flags: u64, signed: bool) -> Result<(usize, u64, bool), i64> {
+    // the lower 5 bits are the base, other bits are reserved
+    if flags & !0x1f != 0 {
+        return Err(-EINVAL);
+    }
+    let mut base = (flags & 0x1f) as u32;
+    if base != 0 && base != 8 && base != 10 && base != 16 {
+        return Err(-EINVAL);
+    }
+
+    let mut i = 0;
+    while i < buf.len() && buf[i].is_ascii_whitespace() {
+        i += 1;
+    }
+    let mut negative = false;
+    if signed && i < buf.len() && buf[i] == b'-' {
+        negative = true;
+        i += 1;
+    }
+
+    let has_hex_prefix = i + 1 < buf.len() && buf[i] == b'0' && (buf[i + 1] | 0x20) == b'x';
+    if base == 0 {
+        base = if has_hex_prefix {
+            16
+        } else if i < buf.len() && buf[i] == b'0' {
+            8
+        } else {
+            10
+        };
+    }
+    if base == 16 && has_hex_prefix {
+        i += 2;
+    }
+
+    let start = i;
+    let mut value: u64 = 0;
+    while i < buf.len() {
+        let digit = match (buf[i] as char).to_digit(base) {
+            Some(digit) => digit as u64,
+            None => break,
+        };
+        value = value
+            .checked_mul(base as u64)
+            .and_then(|v| v.checked_add(digit))
+            .ok_or(-ERANGE)?;
+        i += 1;
+    }
+    if i == start {
+        return Err(-EINVAL);
+    }
+    Ok((i, value, negative))
+}
+
+/// long bpf_strtol(const char *buf, size_t buf_len, u64 flags, long *res), helper #105
+///
+/// # Safety
+///
+/// `buf` must point to `buf_len` readable bytes and `res` to 8 writable bytes.
+pub unsafe extern "C" fn bpf_strtol(buf: u64, buf_len: u64, flags: u64, res: u64) -> u64 {
+    let buf = slice::from_raw_parts(buf as *const u8, buf_len as usize);
+    match parse_integer(buf, flags, true) {
+        Ok((consumed, value, negative)) => {
+            let value = if negative {
+                if value > i64::MIN.unsigned_abs() {
+                    return errno(ERANGE);
+                }
+                (value as i64).wrapping_neg()
+            } else {
+                if value > i64::MAX as u64 {
+                    return errno(ERANGE);
+                }
+                value as i64
+            };
+            *(res as *mut i64) = value;
+            consumed as u64
+        }
+        Err(e) => e as u64,
+    }
+}
+
+/// long bpf_strtoul(const char *buf, size_t buf_len, u64 flags, unsigned long *res), helper #106
+///
+/// # Safety
+///
+/// `buf` must point to `buf_len` readable bytes and `res` to 8 writable bytes.
+pub unsafe extern "C" fn bpf_strtoul(buf: u64, buf_len: u64, flags: u64, res: u64) -> u64 {
+    let buf = slice::from_raw_parts(buf as *const u8, buf_len as usize);
+    match parse_integer(buf, flags, false) {
+        Ok((consumed, value, _)) => {
+            *(res as *mut u64) = value;
+            consumed as u64
+        }
+        Err(e) => e as u64,
+    }
+}
@@ -0,0 +1,164 @@
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+use crate::bitmanip::bseti;
+use crate::consts::RV_REG_ZERO;
+use rvjit::rv32i::*;
+use rvjit::rv64i::*;
+
+// One instruction of a sequence loading a constant into `rd`, which it reads unless it is the first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Op {
+    Lui(i32),
+    Addi(i32),
+    Addiw(i32),
+    Slli(u8),
+    Srli(u8),
+    Xori(i32),
+    Bseti(u8),
+}
+
+fn sext12(v: i64) -> i64 {
+    (v << 52) >> 52
+}
+
+fn is_i32(v: i64) -> bool {
+    v == v as i32 as i64
+}
+
+// `lui` + `addiw`, or `addi` alone
+fn load_i32(imm: i64, seq: &mut Vec<Op>) {
+    let lo = sext12(imm);
+    let hi = ((imm - lo) >> 12) as i32;
+    if hi == 0 {
+        seq.push(Op::Addi(lo as i32));
+        return;
+    }
+    seq.push(Op::Lui(hi));
+    if lo != 0 {
+        seq.push(Op::Addiw(lo as i32));
+    }
+}
+
+// The recursive algorithm of linux: the upper bits shifted down to their first set bit, shifted back
+// by `slli`, then the lower 12 bits added by `addi`.
+fn split(imm: i64) -> Vec<Op> {
+    let mut seq = Vec::new();
+    if is_i32(imm) {
+        load_i32(imm, &mut seq);
+        return seq;
+    }
+    let lo = sext12(imm);
+    let hi = imm.wrapping_sub(lo);
+    let shift = hi.trailing_zeros() as u8;
+    seq = split(hi >> shift);
+    seq.push(Op::Slli(shift));
+    if lo != 0 {
+        seq.push(Op::Addi(lo as i32));
+    }
+    seq
+}
+
+// positive values with leading zeros, loaded shifted up to bit 63 and shifted back by `srli`,
+// the bits shifted in being zeros or ones, whichever is shorter
+fn shift_right(imm: i64) -> Option<Vec<Op>> {
+    if imm <= 0 {
+        return None;
+    }
+    let lz = imm.leading_zeros();
+    let shifted = imm << lz;
+    let filled = shifted | ((1i64 << lz) - 1);
+    let mut seq = shortest(split(shifted), split(filled));
+    seq.push(Op::Srli(lz as u8));
+    Some(seq)
+}
+
+// the nearest 32-bit constant, plus `addi` for values just beyond the 32-bit range
+fn neighbour(imm: i64) -> Option<Vec<Op>> {
+    let base = imm.clamp(i32::MIN as i64, i32::MAX as i64);
+    let delta = imm - base;
+    if delta == 0 || !(-2048..2048).contains(&delta) {
+        return None;
+    }
+    let mut seq = Vec::new();
+    load_i32(base, &mut seq);
+    seq.push(Op::Addi(delta as i32));
+    Some(seq)
+}
+
+// a 32-bit constant with bits above 31 set by `bseti`
+fn set_bits(imm: i64) -> Option<Vec<Op>> {
+    let mut best: Option<Vec<Op>> = None;
+    for &base in [imm as i32 as i64, imm & 0x7fff_ffff, 0].iter() {
+        let bits = imm ^ base;
+        // bits can only be set
+        if bits & !imm != 0 {
+            continue;
+        }
+        let mut seq = Vec::new();
+        if base != 0 {
+            load_i32(base, &mut seq);
+        }
+        seq.extend((0..64).filter(|bit| bits >> bit & 1 == 1).map(|bit| Op::Bseti(bit as u8)));
+        best = Some(match best {
+            Some(best) => shortest(best, seq),
+            None => seq,
+        });
+    }
+    best
+}
+
+fn shortest(a: Vec<Op>, b: Vec<Op>) -> Vec<Op> {
+    if b.len() < a.len() {
+        b
+    } else {
+        a
+    }
+}
+
+fn search(imm: i64, zbs: bool) -> Vec<Op> {
+    let mut best = split(imm);
+    if best.len() <= 1 {
+        return best;
+    }
+    let zbs = if zbs { set_bits(imm) } else { None };
+    for seq in [shift_right(imm), neighbour(imm), zbs].iter().flatten() {
+        if seq.len() < best.len() {
+            best = seq.clone();
+        }
+    }
+    best
+}
+
+// Shortest sequence loading `imm` into `rd` among the forms above, along with the complement of a
+// shorter constant inverted by `xori rd, rd, -1`. `zbs` allows `bseti`.
+pub fn synthesize(rd: u8, imm: i64, zbs: bool) -> Vec<u32> {
+    let mut seq = search(imm, zbs);
+    if seq.len() > 2 {
+        let mut not = search(!imm, zbs);
+        not.push(Op::Xori(-1));
+        seq = shortest(seq, not);
+    }
+    seq.iter()
+        .enumerate()
+        .map(|(i, &op)| {
+            let rs = if i == 0 { RV_REG_ZERO } else { rd };
+            match op {
+                Op::Lui(hi) => lui(rd, (hi as u32) << 12), // see notes
+                Op::Addi(lo) => addi(rd, rs, lo as u32),
+                Op::Addiw(lo) => addiw(rd, rs, lo as u32),
+                Op::Slli(shift) => slli64(rd, rs, shift),
+                Op::Srli(shift) => srli64(rd, rs, shift),
+                Op::Xori(imm) => xori(rd, rs, imm as u32),
+                Op::Bseti(bit) => bseti(rd, rs, bit),
+            }
+        })
+        .collect()
+}
+
+// instructions of the recursive algorithm of linux, which `synthesize` never exceeds
+#[cfg(test)]
+pub fn linux_len(imm: i64) -> usize {
+    split(imm).len()
+}
@@ -0,0 +1,94 @@
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+use crate::compile::JitContext;
+use crate::consts::*;
+
+// registers of a kprobe context, saved as `struct pt_regs`
+pub const PT_REGS_COUNT: usize = 32;
+
+// The context of kprobe programs, `struct pt_regs` of linux for RISC-V (see `struct user_regs_struct`).
+// Slot 0 is the probed pc and slot i holds register x_i.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PtRegs {
+    pub pc: u64,
+    pub ra: u64,
+    pub sp: u64,
+    pub gp: u64,
+    pub tp: u64,
+    pub t0: u64,
+    pub t1: u64,
+    pub t2: u64,
+    pub s0: u64,
+    pub s1: u64,
+    pub a0: u64,
+    pub a1: u64,
+    pub a2: u64,
+    pub a3: u64,
+    pub a4: u64,
+    pub a5: u64,
+    pub a6: u64,
+    pub a7: u64,
+    pub s2: u64,
+    pub s3: u64,
+    pub s4: u64,
+    pub s5: u64,
+    pub s6: u64,
+    pub s7: u64,
+    pub s8: u64,
+    pub s9: u64,
+    pub s10: u64,
+    pub s11: u64,
+    pub t3: u64,
+    pub t4: u64,
+    pub t5: u64,
+    pub t6: u64,
+}
+
+// offsets in `PtRegs` read by the `PT_REGS_*` accessors of libbpf (bpf_tracing.h)
+pub const PT_REGS_IP: i16 = 0;
+pub const PT_REGS_RET: i16 = 8; // ra
+pub const PT_REGS_SP: i16 = 16;
+pub const PT_REGS_FP: i16 = 64; // s0
+pub const PT_REGS_RC: i16 = 80; // a0
+pub const PT_REGS_PARM1: i16 = 80;
+pub const PT_REGS_PARM2: i16 = 88;
+pub const PT_REGS_PARM3: i16 = 96;
+pub const PT_REGS_PARM4: i16 = 104;
+pub const PT_REGS_PARM5: i16 = 112;
+pub const PT_REGS_PARM6: i16 = 120;
+pub const PT_REGS_PARM7: i16 = 128;
+pub const PT_REGS_PARM8: i16 = 136;
+
+const FRAME_SIZE: i32 = 8 * PT_REGS_COUNT as i32;
+
+// Build the trampoline a probe at `probe_addr` jumps to, calling the jitted program at `prog`.
+// The probed site is patched to jump here with `jalr t0, ..`, which is free at function entry as it
+// is for ftrace. The trampoline spills the registers into a `PtRegs` on the stack, passes it to the
+// program and restores them before returning to t0. The t0 slot thus holds the return address.
+// The value returned by the program is ignored.
+pub fn build_kprobe_trampoline(probe_addr: u64, prog: u64) -> Vec<u32> {
+    let mut ctx = JitContext::new(&[]);
+    ctx.emit_addi(RV_REG_SP, RV_REG_SP, -FRAME_SIZE);
+    for reg in (1..PT_REGS_COUNT as u8).filter(|&reg| reg != RV_REG_SP) {
+        ctx.emit_sd(reg, RV_REG_SP, 8 * reg as i32);
+    }
+    // sp and pc at the probe
+    ctx.emit_addi(RV_REG_T1, RV_REG_SP, FRAME_SIZE);
+    ctx.emit_sd(RV_REG_T1, RV_REG_SP, PT_REGS_SP as i32);
+    ctx.emit_imm(RV_REG_T1, probe_addr as i64);
+    ctx.emit_sd(RV_REG_T1, RV_REG_SP, PT_REGS_IP as i32);
+
+    ctx.emit_addi(RV_REG_A0, RV_REG_SP, 0);
+    ctx.emit_imm(RV_REG_T1, prog as i64);
+    ctx.emit_jalr(RV_REG_RA, RV_REG_T1, 0);
+
+    for reg in (1..PT_REGS_COUNT as u8).filter(|&reg| reg != RV_REG_SP) {
+        ctx.emit_ld(reg, RV_REG_SP, 8 * reg as i32);
+    }
+    ctx.emit_addi(RV_REG_SP, RV_REG_SP, FRAME_SIZE);
+    ctx.emit_jalr(RV_REG_ZERO, RV_REG_T0, 0);
+    ctx.code
+}
@@ -1,16 +1,43 @@
 #![no_std]
 
+mod bitmanip;
+pub mod cbpf;
 pub mod compile;
 mod consts;
+pub mod context;
+mod fuse;
+pub mod helper;
+pub mod helper_lib;
+mod imm;
+pub mod kprobe;
+pub mod map;
+pub mod opt;
+pub mod program;
+mod rvc;
+pub mod seccomp;
+pub mod verifier;
+pub mod xdp;
+mod zext;
+
+#[cfg(all(test, feature = "std"))]
+mod emu;
 
 #[cfg(all(test, feature = "std"))]
 mod test {
     extern crate std;
 
     use crate::compile::{JitContext, *};
+    use crate::helper::*;
+    use crate::helper_lib::*;
+    use crate::map::*;
     use std::io::Write;
     use std::vec::Vec;
 
+    // a helper returning 42, registered as 0x1000 by the tests calling it
+    extern "C" fn get_answer(_: u64, _: u64, _: u64, _: u64, _: u64) -> u64 {
+        42
+    }
+
     #[test]
     fn compile_sum_test() {
         // load eBPF program
@@ -30,10 +57,17 @@ mod test {
 
         // create JIT context
         let mut ctx = JitContext::new(&insns);
-        let helpers = [0xdeadu64, 0xbeef, 0xbad, 0xc0de];
+        let mut helpers = HelperRegistry::new();
+        helpers.register(Helper {
+            id: BPF_FUNC_TRACE_PRINTK,
+            name: "bpf_trace_printk",
+            func: 0xdead,
+            args: &[ArgKind::PtrToMem, ArgKind::ConstSize],
+            ret: RetKind::Integer,
+        });
 
         // compile and write to c stub code
-        compile(&mut ctx, &helpers, 512);
+        compile(&mut ctx, &helpers, 512).unwrap();
 
         // create file to output generated machine code
         let mut stub_source = std::fs::File::create("tests/test_jit.c").unwrap();
@@ -72,4 +106,1940 @@ mod test {
         // };
         // f.write(slice).unwrap();
     }
+
+    #[test]
+    fn map_in_map_test() {
+        let mut maps = MapTable::new();
+        let inner_attr = MapAttr {
+            map_type: MapType::Array,
+            key_size: 4,
+            value_size: 8,
+            max_entries: 4,
+            map_flags: 0,
+        };
+        let template = maps.create(inner_attr).unwrap();
+        let policy = maps.create(inner_attr).unwrap();
+        let mismatch = maps
+            .create(MapAttr {
+                max_entries: 8,
+                ..inner_attr
+            })
+            .unwrap();
+        let outer = maps
+            .create_map_in_map(
+                MapAttr {
+                    map_type: MapType::ArrayOfMaps,
+                    key_size: 4,
+                    value_size: 4,
+                    max_entries: 2,
+                    map_flags: 0,
+                },
+                template,
+            )
+            .unwrap();
+
+        let key = 1u32.to_ne_bytes();
+        maps.update_elem(policy, &0u32.to_ne_bytes(), &42u64.to_ne_bytes(), 0)
+            .unwrap();
+        assert_eq!(
+            maps.update_elem(outer, &key, &mismatch.to_ne_bytes(), 0),
+            Err(MapError::InnerMapMismatch)
+        );
+        maps.update_elem(outer, &key, &policy.to_ne_bytes(), 0)
+            .unwrap();
+
+        // lookup in the outer map yields the inner map, usable by further map helpers
+        let outer_ptr = maps.map_ptr(outer).unwrap();
+        unsafe {
+            let inner = bpf_map_lookup_elem(outer_ptr, key.as_ptr() as u64);
+            assert_eq!(inner, maps.map_ptr(policy).unwrap());
+            let value = bpf_map_lookup_elem(inner, 0u32.to_ne_bytes().as_ptr() as u64);
+            assert_eq!(*(value as *const u64), 42);
+            assert_eq!(bpf_map_lookup_elem(outer_ptr, 0u32.to_ne_bytes().as_ptr() as u64), 0);
+        }
+
+        // LD_IMM_DW with BPF_PSEUDO_MAP_FD resolves against the map table
+        let insns = [0x1018u64 | ((outer as u64) << 32), 0, 0x95];
+        let mut ctx = JitContext::new(&insns);
+        ctx.set_map_table(&maps);
+        compile(&mut ctx, &HelperRegistry::new(), 0).unwrap();
+
+        let insns = [0x1018u64 | (7 << 32), 0, 0x95];
+        let mut ctx = JitContext::new(&insns);
+        ctx.set_map_table(&maps);
+        assert_eq!(
+            compile(&mut ctx, &HelperRegistry::new(), 0),
+            Err(CompileError::UnknownMapFd { bpf_pc: 0, fd: 7 })
+        );
+    }
+
+    #[test]
+    fn helper_registry_test() {
+        let mut helpers = HelperRegistry::new();
+        helpers.register_map_helpers();
+        helpers.register(Helper {
+            id: 300,
+            name: "bpf_custom",
+            func: 0x1234_5678_9abc,
+            args: &[ArgKind::Anything],
+            ret: RetKind::Integer,
+        });
+        assert_eq!(helpers.get_by_name("bpf_custom").unwrap().id, 300);
+
+        // call 300; call 300; exit
+        let insns = [0x85 | (300 << 32), 0x85 | (300 << 32), 0x95];
+        let mut ctx = JitContext::new(&insns);
+        compile(&mut ctx, &helpers, 0).unwrap();
+        // the PLT only holds the helper which is called
+        let code = ctx.get_rv_code();
+        assert_eq!(&code[code.len() - 2..], &[0x5678_9abc, 0x1234]);
+
+        let insns = [0x85 | (7 << 32), 0x95];
+        let mut ctx = JitContext::new(&insns);
+        assert_eq!(
+            compile(&mut ctx, &helpers, 0),
+            Err(CompileError::UnknownHelper { bpf_pc: 0, id: 7 })
+        );
+    }
+
+    #[test]
+    fn helper_lib_test() {
+        unsafe {
+            let mut buf = [0u8; 16];
+            let args = [42u64, (-7i64) as u64, 0xbeef];
+            let fmt = b"%d:%05ld %x|%s\0";
+            let s = b"ebpf\0";
+            let args = [args[0], args[1], args[2], s.as_ptr() as u64];
+            let len = bpf_snprintf(
+                buf.as_mut_ptr() as u64,
+                buf.len() as u64,
+                fmt.as_ptr() as u64,
+                args.as_ptr() as u64,
+                32,
+            );
+            // "42:-0007 beef|ebpf" is truncated
+            assert_eq!(len, 19);
+            assert_eq!(&buf, b"42:-0007 beef|e\0");
+
+            let mut res = 0i64;
+            let num = b" -0x1f!";
+            let consumed = bpf_strtol(num.as_ptr() as u64, num.len() as u64, 0, &mut res as *mut i64 as u64);
+            assert_eq!((consumed, res), (6, -31));
+            let mut res = 0u64;
+            assert_eq!(
+                bpf_strtoul(num.as_ptr() as u64, num.len() as u64, 0, &mut res as *mut u64 as u64) as i64,
+                -22
+            );
+        }
+
+        // the format of bpf_snprintf must be a string in a frozen read-only map
+        use crate::emu::Emu;
+        use crate::verifier::VerifierErrorKind::*;
+        use crate::verifier::*;
+
+        let mut maps = MapTable::new();
+        let attr = MapAttr {
+            map_type: MapType::Array,
+            key_size: 4,
+            value_size: 8,
+            max_entries: 1,
+            map_flags: BPF_F_RDONLY_PROG,
+        };
+        let fmt = maps.create(attr).unwrap();
+        let writable = maps.create(MapAttr { map_flags: 0, ..attr }).unwrap();
+        for &fd in [fmt, writable].iter() {
+            maps.update_elem(fd, &0u32.to_ne_bytes(), b"bpf!\0xyz", 0).unwrap();
+        }
+        maps.freeze(writable).unwrap();
+        let mut helpers = HelperRegistry::new();
+        helpers.register_map_helpers();
+        helpers.register_std_helpers();
+        let prog = |fd: u32| {
+            std::vec![
+                0x0000_0000_0000_1118 | (fd as u64) << 32, // r1 = map[fd]
+                0,
+                0x0000_0000_fffc_0a62, // *(u32 *)(r10 - 4) = 0
+                0xa2bf,                // r2 = r10
+                0xffff_fffc_0000_0207, // r2 += -4
+                0x0000_0001_0000_0085, // call bpf_map_lookup_elem
+                0x0007_0015,           // if r0 == 0 goto exit
+                0x03bf,                // r3 = r0
+                0xa1bf,                // r1 = r10
+                0xffff_fff0_0000_0107, // r1 += -16
+                0x0000_0008_0000_02b7, // r2 = 8
+                0x04b7,                // r4 = 0
+                0x05b7,                // r5 = 0
+                0x0000_00a5_0000_0085, // call bpf_snprintf
+                0x95,
+            ]
+        };
+        let verify = |insns: &[u64], maps: &MapTable| {
+            let mut ctx = JitContext::new(insns);
+            ctx.set_map_table(maps);
+            ctx.set_verify(true);
+            compile(&mut ctx, &helpers, 16).map(|_| ctx.get_rv_code().clone())
+        };
+        let rejected = |pc, kind| Err(CompileError::Verifier(VerifierError { bpf_pc: pc, kind }));
+
+        let mut insns = prog(fmt);
+        assert_eq!(verify(&insns, &maps), rejected(13, UnterminatedString { reg: 3 }));
+        maps.freeze(fmt).unwrap();
+        assert_eq!(maps.update_elem(fmt, &0u32.to_ne_bytes(), &[0; 8], 0), Err(MapError::ReadOnly));
+        let code = verify(&insns, &maps).unwrap();
+        let mut emu = Emu::new();
+        let entry = emu.add_code(&code);
+        assert_eq!(emu.call(entry, &[]), 5);
+
+        // "xyz" is not terminated within the value, and the value may not be written
+        insns[7] = 0x0000_0005_0000_0007; // r0 += 5
+        insns.insert(8, 0x03bf); // r3 = r0
+        insns[6] = 0x0008_0015; // if r0 == 0 goto exit
+        assert_eq!(verify(&insns, &maps), rejected(14, UnterminatedString { reg: 3 }));
+        insns[8] = 0x0072; // *(u8 *)(r0 + 0) = 0
+        assert_eq!(verify(&insns, &maps), rejected(8, WriteToReadOnlyMap { reg: 0 }));
+        let (kind, expected) = ("map_value", ArgKind::PtrToConstStr);
+        assert_eq!(
+            verify(&prog(writable), &maps),
+            rejected(13, InvalidHelperArg { reg: 3, kind, expected })
+        );
+    }
+
+    #[test]
+    fn exception_table_test() {
+        // r0 = *(u64 *)(r1 + 8) as BPF_PROBE_MEM; call bpf_probe_read_kernel; exit
+        let insns = [0x0000_0000_0008_1039u64, 0x85 | (113 << 32), 0x95];
+        let mut ctx = JitContext::new(&insns);
+        compile(&mut ctx, &HelperRegistry::new(), 0).unwrap();
+
+        let table = ctx.get_exception_table().to_vec();
+        assert_eq!(table.len(), 2);
+        let load = table[0];
+        assert_eq!(load.fixup_off, load.insn_off + 4);
+        let fixup = search_exception_table(&table, 0x1000, 0x1000 + load.insn_off).unwrap();
+        assert_eq!(fixup.resume_pc, 0x1000 + load.insn_off + 4);
+        assert_eq!(search_exception_table(&table, 0x1000, 0x1000 + load.insn_off + 4), None);
+
+        // loads of faulting addresses are zeroed and skipped, and bpf_probe_read_kernel zeroes its buffer
+        use crate::consts::EFAULT;
+        use crate::emu::Emu;
+
+        let prog = [
+            0xffff_ffff_fff8_0a7a, // *(u64 *)(r10 - 8) = -1
+            0x1739,                // r7 = *(u64 *)(r1 + 0) as BPF_PROBE_MEM
+            0x23bf,                // r3 = r2
+            0xa1bf,                // r1 = r10
+            0xffff_fff8_0000_0107, // r1 += -8
+            0x0000_0008_0000_02b7, // r2 = 8
+            0x0000_0071_0000_0085, // call bpf_probe_read_kernel
+            0x700f,                // r0 += r7
+            0x0000_0000_fff8_a179, // r1 = *(u64 *)(r10 - 8)
+            0x100f,                // r0 += r1
+            0x95,
+        ];
+        let mut ctx = JitContext::new(&prog);
+        compile(&mut ctx, &HelperRegistry::new(), 64).unwrap();
+        let mut emu = Emu::new();
+        emu.faults.push((0, 0x1000));
+        let entry = emu.add_code(ctx.get_rv_code());
+        emu.set_exception_table(entry, ctx.get_exception_table());
+        let (p, q) = (&100u64 as *const u64 as u64, &1000u64 as *const u64 as u64);
+        assert_eq!(emu.call(entry, &[p, q]), 1100);
+        assert_eq!(emu.call(entry, &[0x10, q]), 1000);
+        assert_eq!(emu.call(entry, &[p, 0x10]), (100 - EFAULT) as u64);
+        assert_eq!(emu.call(entry, &[0x10, 0xff8]), -EFAULT as u64);
+    }
+
+    #[test]
+    fn sandbox_test() {
+        use crate::emu::Emu;
+
+        let mut maps = MapTable::new();
+        let attr = MapAttr {
+            map_type: MapType::Array,
+            key_size: 4,
+            value_size: 8,
+            max_entries: 1,
+            map_flags: 0,
+        };
+        let fd = maps.create(attr).unwrap();
+        let value = maps.get(fd).unwrap().value_region().0;
+        let ctx_buf = [1u64, 2];
+        let read_only = std::vec![3u64, 4]; // away from the context
+        let (ctx_ptr, ro_ptr) = (ctx_buf.as_ptr() as u64, read_only.as_ptr() as u64);
+
+        let read = |off: i16| std::vec![0x2079 | (off as u16 as u64) << 16, 0x95]; // r0 = *(u64 *)(r2 + off)
+        let write = |off: i16| {
+            std::vec![
+                0x327b | (off as u16 as u64) << 16, // *(u64 *)(r2 + off) = r3
+                0x0000_0001_0000_00b7,              // r0 = 1
+                0x95,
+            ]
+        };
+        let stack = std::vec![
+            0x0000_0000_fff8_1a7b, // *(u64 *)(r10 - 8) = r1
+            0x0000_0000_fff8_a079, // r0 = *(u64 *)(r10 - 8)
+            0x95,
+        ];
+        let ctx = std::vec![
+            0x0000_0000_0008_1079, // r0 = *(u64 *)(r1 + 8)
+            0x017b,                // *(u64 *)(r1 + 0) = r0
+            0x95,
+        ];
+        // programs, (r2, r3), and their result or the address of the access aborting them
+        let cases = [
+            (stack, [0, 0], Ok(ctx_ptr)),
+            (ctx, [0, 0], Ok(2)),
+            (read(0), [value, 0], Ok(0)),
+            (write(0), [value, 5], Ok(1)),
+            (read(8), [ro_ptr, 0], Ok(4)),
+            // beyond the context and a map value
+            (read(16), [ctx_ptr, 0], Err(ctx_ptr + 16)),
+            (write(4), [value, 5], Err(value + 4)),
+            // a read-only region
+            (write(0), [ro_ptr, 5], Err(ro_ptr)),
+            // an access wrapping around the address space, and a base wrapping below 0 with its offset
+            (read(0), [u64::MAX - 3, 0], Err(u64::MAX - 3)),
+            (read(-16), [8, 0], Err(-8i64 as u64)),
+        ];
+
+        // pointers passed to helpers are checked too: r1 = r2; r2 = r3; call bpf_map_lookup_elem; exit
+        let lookup = std::vec![0x21bf, 0x32bf, 0x0000_0001_0000_0085, 0x95];
+        let map_ptr = maps.map_ptr(fd).unwrap();
+        let helper_cases = [
+            // the key is the upper half of the value, 0
+            (lookup.clone(), [map_ptr, value + 4], Ok(value)),
+            // a map value passed as a map, and a key beyond the context
+            (lookup.clone(), [value, value], Err(value)),
+            (lookup, [map_ptr, ctx_ptr + 14], Err(ctx_ptr + 14)),
+        ];
+
+        let status = SandboxStatus::new();
+        let mut helpers = HelperRegistry::new();
+        helpers.register_map_helpers();
+        let mut emu = Emu::new();
+        for (prog, [r2, r3], expected) in cases.iter().chain(helper_cases.iter()) {
+            let mut ctx = JitContext::new(prog);
+            ctx.set_sandbox(16, &status);
+            ctx.set_map_table(&maps);
+            ctx.add_sandbox_region(ro_ptr, 16, false);
+            compile(&mut ctx, &helpers, 64).unwrap();
+            let entry = emu.add_code(ctx.get_rv_code());
+            let ret = status.run_with(|| emu.call(entry, &[ctx_ptr, *r2, *r3]));
+            assert_eq!(ret, expected.map_err(|addr| SandboxViolation { addr }));
+            if ret.is_err() {
+                assert_eq!(emu.x[10], 0);
+            }
+        }
+        assert_eq!(ctx_buf[0], 2);
+        assert_eq!(maps.lookup_elem(fd, &0u32.to_ne_bytes()).unwrap(), &5u64.to_ne_bytes());
+        assert_eq!(read_only, [3, 4]);
+
+        // the checks cost 16 to 19 instructions per run, depending on the address of the status, and 13
+        // per access to the context
+        let none = std::vec![0xb7, 0x95]; // r0 = 0; exit
+        let mut steps = Vec::new();
+        for prog in [&none, &cases[1].0].iter() {
+            for &sandbox in [false, true].iter() {
+                let mut ctx = JitContext::new(prog);
+                if sandbox {
+                    ctx.set_sandbox(16, &status);
+                }
+                compile(&mut ctx, &helpers, 64).unwrap();
+                let entry = emu.add_code(ctx.get_rv_code());
+                emu.steps = 0;
+                emu.call(entry, &[ctx_ptr]);
+                steps.push(emu.steps);
+            }
+        }
+        assert!((16..=19).contains(&(steps[1] - steps[0])));
+        assert_eq!(steps[3] - steps[2], steps[1] - steps[0] + 2 * 13);
+    }
+
+    #[test]
+    fn meter_test() {
+        use crate::emu::Emu;
+
+        let mut helpers = HelperRegistry::new();
+        helpers.register(Helper {
+            id: 0x1000,
+            name: "get_answer",
+            func: get_answer as *const () as u64,
+            args: &[],
+            ret: RetKind::Integer,
+        });
+        // r1 - 1 back-edges taken
+        let jumps = [
+            0xb7,                  // r0 = 0
+            0x0000_0001_0000_0007, // loop: r0 += 1
+            0xffff_ffff_0000_0107, // r1 += -1
+            0xfffd_0155,           // if r1 != 0 goto loop
+            0x95,
+        ];
+        // r1 calls and r1 - 1 back-edges taken
+        let calls = [
+            0x16bf,                // r6 = r1
+            0x07b7,                // r7 = 0
+            0x0000_1000_0000_0085, // loop: call get_answer
+            0x070f,                // r7 += r0
+            0xffff_ffff_0000_0607, // r6 += -1
+            0xfffc_0655,           // if r6 != 0 goto loop
+            0x70bf,                // r0 = r7
+            0x95,
+        ];
+        // programs, r1, and their result or None if they run out of a budget of 10
+        let cases: [(&[u64], u64, Option<u64>); 6] = [
+            (&jumps, 5, Some(5)),
+            (&jumps, 11, Some(11)), // the whole budget used, but not exhausted
+            (&jumps, 12, None),
+            (&calls, 5, Some(5 * 42)),
+            (&calls, 6, None),
+            (&calls, 1000, None),
+        ];
+
+        let meter = Meter::new(0);
+        let mut emu = Emu::new();
+        for &(prog, r1, expected) in cases.iter() {
+            let mut ctx = JitContext::new(prog);
+            ctx.set_meter(&meter);
+            compile(&mut ctx, &helpers, 0).unwrap();
+            let entry = emu.add_code(ctx.get_rv_code());
+            meter.set(10);
+            let ret = emu.call(entry, &[r1]);
+            match expected {
+                Some(expected) => {
+                    assert_eq!(ret, expected);
+                    assert!(!meter.exhausted());
+                    // one unit per back-edge and per call
+                    let used = if prog == &jumps[..] { r1 - 1 } else { 2 * r1 - 1 };
+                    assert_eq!(meter.remaining(), 10 - used);
+                }
+                None => {
+                    assert_eq!(ret, 0);
+                    assert!(meter.exhausted());
+                    assert_eq!(meter.remaining(), 0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn verifier_test() {
+        use crate::emu::Emu;
+        use crate::verifier::Region::*;
+        use crate::verifier::VerifierErrorKind::*;
+        use crate::verifier::*;
+
+        let verify = |insns: &[u64]| {
+            let mut ctx = JitContext::new(insns);
+            ctx.set_verify(true);
+            let res = compile(&mut ctx, &HelperRegistry::new(), 0);
+            (res, std::string::String::from(ctx.get_verifier_log()))
+        };
+        let rejected = |pc, kind| Err(CompileError::Verifier(VerifierError { bpf_pc: pc, kind }));
+
+        // r0 = 0; exit
+        let (res, log) = verify(&[0xb7, 0x95]);
+        assert_eq!(res, Ok(()));
+        assert!(log.starts_with("0: (b7) r0 = 0\n1: (95) exit\n"));
+
+        // r0 = r2; exit
+        let (res, log) = verify(&[0x20bf, 0x95]);
+        assert_eq!(res, rejected(0, VerifierErrorKind::UninitRegister(2)));
+        assert!(log.ends_with("R2 !read_ok\n"));
+
+        // r10 = 0; exit
+        let (res, _) = verify(&[0x0ab7, 0x95]);
+        assert_eq!(res, rejected(0, VerifierErrorKind::WriteToFrameReg));
+
+        // r0 = 0; goto pc-2
+        let (res, _) = verify(&[0xb7, 0xfffe_0005]);
+        assert_eq!(res, rejected(1, VerifierErrorKind::BackEdge { target: 0 }));
+
+        // r0 = 0; r1 = 1; r0 += r1; r1 += 1; if r1 <= 100 goto pc-3; exit
+        let sum = [0xb7, 0x0000_0001_0000_01b7, 0x100f, 0x0000_0001_0000_0107, 0x0000_0064_fffd_01b5, 0x95];
+        let (res, _) = verify(&sum);
+        assert_eq!(res, rejected(4, VerifierErrorKind::BackEdge { target: 2 }));
+        let verify_loops = |insns: &[u64], limit| {
+            let mut ctx = JitContext::new(insns);
+            ctx.set_verify(true);
+            ctx.set_bounded_loops(true);
+            ctx.set_complexity_limit(limit);
+            compile(&mut ctx, &HelperRegistry::new(), 0)
+        };
+        assert_eq!(verify_loops(&sum, BPF_COMPLEXITY_LIMIT_INSNS), Ok(()));
+        let header = Some(2);
+        assert_eq!(verify_loops(&sum, 100), rejected(4, VerifierErrorKind::ComplexityLimit { header, limit: 100 }));
+
+        // r0 = 0; goto pc-1
+        assert_eq!(verify_loops(&[0xb7, 0xffff_0005], 100), rejected(1, VerifierErrorKind::InfiniteLoop));
+
+        // goto pc+1; r0 = 1 ll; exit
+        let (res, _) = verify(&[0x0001_0005, 0x0000_0001_0000_0018, 0, 0x95]);
+        assert_eq!(res, rejected(0, VerifierErrorKind::JumpIntoLdImm64 { target: 1 }));
+
+        // goto pc+100; exit, which the JIT rejects as well without the verifier
+        let (res, _) = verify(&[0x0064_0005, 0x95]);
+        assert_eq!(res, rejected(0, VerifierErrorKind::JumpOutOfRange { target: 101 }));
+        let mut ctx = JitContext::new(&[0x0064_0005, 0x95]);
+        let res = compile(&mut ctx, &HelperRegistry::new(), 0);
+        assert_eq!(res, Err(CompileError::JumpOutOfRange { bpf_pc: 0, target: 101 }));
+
+        // r0 = 0; exit; exit
+        let (res, _) = verify(&[0xb7, 0x95, 0x95]);
+        assert_eq!(res, rejected(2, VerifierErrorKind::Unreachable));
+
+        // look up key 0 of an array map and read the value, which may be NULL
+        let mut maps = MapTable::new();
+        let attr = MapAttr {
+            map_type: MapType::Array,
+            key_size: 4,
+            value_size: 8,
+            max_entries: 1,
+            map_flags: 0,
+        };
+        let fd = maps.create(attr).unwrap() as u64;
+        let mut helpers = HelperRegistry::new();
+        helpers.register_map_helpers();
+        let mut prog = std::vec![
+            0xfffc_0a62, // *(u32 *)(r10 - 4) = 0
+            0x0000_0000_0000_1118 | fd << 32, // r1 = map[fd]
+            0,
+            0xa2bf, // r2 = r10
+            0xffff_fffc_0000_0207, // r2 += -4
+            0x0000_0001_0000_0085, // call bpf_map_lookup_elem
+            0x0079, // r0 = *(u64 *)(r0 + 0)
+            0x95,
+        ];
+        let verify_with = |insns: &[u64], layout: CtxLayout, stack_size| {
+            let mut ctx = JitContext::new(insns);
+            ctx.set_map_table(&maps);
+            ctx.set_verify(true);
+            ctx.set_ctx_layout(layout);
+            compile(&mut ctx, &helpers, stack_size)
+        };
+        let no_ctx = CtxLayout::default();
+        let res = verify_with(&prog, no_ctx, 8);
+        assert_eq!(res, rejected(6, VerifierErrorKind::NullPointerAccess { reg: 0 }));
+
+        // if r0 == 0 goto exit
+        prog.insert(6, 0x0001_0015);
+        assert_eq!(verify_with(&prog, no_ctx, 8), Ok(()));
+
+        // the value is 8 bytes long: r0 = *(u32 *)(r0 + 4), or + 6
+        let map_value = MapValue { value_size: 8 };
+        prog[7] = 0x0004_0061;
+        assert_eq!(verify_with(&prog, no_ctx, 8), Ok(()));
+        prog[7] = 0x0006_0061;
+        let (region, off, size) = (map_value, 6, 4);
+        assert_eq!(verify_with(&prog, no_ctx, 8), rejected(7, OutOfBounds { reg: 0, region, off, size }));
+
+        // r0 += the first byte of the value & 7, or & 15, and read a byte there
+        prog.splice(6..8, std::vec![
+            0x0004_0015,           // if r0 == 0 goto exit
+            0x0171,                // r1 = *(u8 *)(r0 + 0)
+            0x0000_0007_0000_0157, // r1 &= 7
+            0x100f,                // r0 += r1
+            0x0071,                // r0 = *(u8 *)(r0 + 0)
+        ]);
+        assert_eq!(verify_with(&prog, no_ctx, 8), Ok(()));
+        prog[8] = 0x0000_000f_0000_0157;
+        let (region, off, size) = (map_value, 15, 1);
+        assert_eq!(verify_with(&prog, no_ctx, 8), rejected(10, OutOfBounds { reg: 0, region, off, size }));
+
+        // 8 bytes of the packet past a compare with data_end, then one more
+        let pkt_ctx = CtxLayout {
+            size: 24,
+            pkt_data: Some(0),
+            pkt_data_end: Some(8),
+        };
+        let mut pkt = [
+            0x1279,                // r2 = *(u64 *)(r1 + 0)
+            0x0008_1379,           // r3 = *(u64 *)(r1 + 8)
+            0x24bf,                // r4 = r2
+            0x0000_0008_0000_0407, // r4 += 8
+            0x0002_342d,           // if r4 > r3 goto drop
+            0x2079,                // r0 = *(u64 *)(r2 + 0)
+            0x95,
+            0x0000_0001_0000_00b7, // drop: r0 = 1
+            0x95,
+        ];
+        assert_eq!(verify_with(&pkt, pkt_ctx, 0), Ok(()));
+        pkt[5] = 0x0001_2079; // r0 = *(u64 *)(r2 + 1)
+        let (region, off, size) = (Packet { range: 8 }, 1, 8);
+        assert_eq!(verify_with(&pkt, pkt_ctx, 0), rejected(5, OutOfBounds { reg: 2, region, off, size }));
+
+        // *(u64 *)(r10 - 16) = 0; r0 = *(u64 *)(r10 - 16), with a stack of 16 bytes, or 8
+        let stack = [0x0000_0000_fff0_0a7a, 0xfff0_a079, 0x95];
+        assert_eq!(verify_with(&stack, no_ctx, 16), Ok(()));
+        let (region, off, size) = (Stack { size: 8 }, -16, 8);
+        assert_eq!(verify_with(&stack, no_ctx, 8), rejected(0, OutOfBounds { reg: 10, region, off, size }));
+
+        // the context spilled and filled back, one byte of it being overwritten in between
+        let mut spill = std::vec![
+            0xfff8_1a7b, // *(u64 *)(r10 - 8) = r1
+            0xfff8_a179, // r1 = *(u64 *)(r10 - 8)
+            0x0010_1079, // r0 = *(u64 *)(r1 + 16)
+            0x95,
+        ];
+        assert_eq!(verify_with(&spill, pkt_ctx, 8), Ok(()));
+        spill.insert(1, 0xfff8_0a72); // *(u8 *)(r10 - 8) = 0
+        let kind = InvalidMemAccess { reg: 1, kind: "scalar" };
+        assert_eq!(verify_with(&spill, pkt_ctx, 8), rejected(3, kind));
+
+        // the stack access is proven in bounds with r6 = 1 / 0 = 0, which the code must agree with
+        let div = [
+            0x0000_0001_0000_06b7, // r6 = 1
+            0x01b7,                // r1 = 0
+            0x163f,                // r6 /= r1
+            0x0000_000c_0000_0667, // r6 <<= 12
+            0xa2bf,                // r2 = r10
+            0x620f,                // r2 += r6
+            0x0000_0007_fff8_027a, // *(u64 *)(r2 - 8) = 7
+            0x60bf,                // r0 = r6
+            0x95,
+        ];
+        let mut ctx = JitContext::new(&div);
+        ctx.set_verify(true);
+        assert_eq!(compile(&mut ctx, &HelperRegistry::new(), 8), Ok(()));
+        let mut emu = Emu::new();
+        let entry = emu.add_code(ctx.get_rv_code());
+        assert_eq!(emu.call(entry, &[]), 0);
+        // likewise with 32-bit shifts, (0x80000000 s>> 31) + 1 and (1 << (34 & 31)) - 4 being 0
+        let shift = [
+            0x8000_0000_0000_06b4, // w6 = 0x80000000
+            0x0000_001f_0000_06c4, // w6 s>>= 31
+            0x0000_0001_0000_0604, // w6 += 1
+            0x0000_0022_0000_01b7, // r1 = 34
+            0x0000_0001_0000_07b7, // r7 = 1
+            0x176c,                // w7 <<= w1
+            0xffff_fffc_0000_0704, // w7 += -4
+            0x760f,                // r6 += r7
+            0x0000_000c_0000_0667, // r6 <<= 12
+            0xa2bf,                // r2 = r10
+            0x620f,                // r2 += r6
+            0x0000_0007_fff8_027a, // *(u64 *)(r2 - 8) = 7
+            0x60bf,                // r0 = r6
+            0x95,
+        ];
+        let mut ctx = JitContext::new(&shift);
+        ctx.set_verify(true);
+        assert_eq!(compile(&mut ctx, &HelperRegistry::new(), 8), Ok(()));
+        let entry = emu.add_code(ctx.get_rv_code());
+        assert_eq!(emu.call(entry, &[]), 0);
+        // r0 = r1, then r0 <op>= r2 or 0, a division by zero giving 0 and a modulo the dividend
+        let cases = [
+            (0x203f, 7, 0, 0),                                      // r0 /= r2
+            (0x203f, 7, 2, 3),                                      // r0 /= r2
+            (0x003f, 0, 0, 0),                                      // r0 /= r0
+            (0x0037, 7, 2, 0),                                      // r0 /= 0
+            (0x209f, 7, 0, 7),                                      // r0 %= r2
+            (0x203c, 7, 1 << 32, 0),                                // w0 /= w2
+            (0x203c, 0xffff_ffff_0000_0007, 2, 3),                  // w0 /= w2
+            (0x209c, 0x1_0000_0007, 0, 7),                          // w0 %= w2
+            // 32-bit shifts use the low 5 bits of the amount
+            (0x206c, 0xffff_ffff_8000_0003, 33, 6),                 // w0 <<= w2
+            (0x207c, 0x1_8000_0000, 35, 0x1000_0000),               // w0 >>= w2
+            (0x20cc, 0x8000_0000, 4, 0xf800_0000),                  // w0 s>>= w2
+            (0x0000_001f_0000_00c4, 0x1_8000_0000, 0, 0xffff_ffff), // w0 s>>= 31
+            (0x0084, 0x1_0000_0005, 0, 0xffff_fffb),                // w0 = -w0
+            (0x0087, 5, 0, -5i64 as u64),                           // r0 = -r0
+        ];
+        for &(insn, r1, r2, expected) in cases.iter() {
+            let prog = [0x10bf, insn, 0x95];
+            let mut ctx = JitContext::new(&prog);
+            compile(&mut ctx, &HelperRegistry::new(), 0).unwrap();
+            let entry = emu.add_code(ctx.get_rv_code());
+            assert_eq!(emu.call(entry, &[r1, r2]), expected, "{:#x}", insn);
+        }
+    }
+
+    #[test]
+    fn bounded_loop_test() {
+        use crate::consts::BPF_MAXINSNS;
+        use crate::emu::Emu;
+        use crate::verifier::*;
+
+        let verify = |insns: &[u64], limit| {
+            let mut ctx = JitContext::new(insns);
+            ctx.set_verify(true);
+            ctx.set_bounded_loops(true);
+            ctx.set_complexity_limit(limit);
+            let res = compile(&mut ctx, &HelperRegistry::new(), 0);
+            (res, std::string::String::from(ctx.get_verifier_log()), ctx.get_rv_code().clone())
+        };
+        // for (i = 1; i <= 100; i++) sum += i, as in tests/test_ebpf.c
+        let sum = [
+            0xb7,                  // r0 = 0
+            0x0000_0001_0000_01b7, // r1 = 1
+            0x100f,                // loop: r0 += r1
+            0x0000_0001_0000_0107, // r1 += 1
+            0x0000_0064_fffd_01b5, // if r1 <= 100 goto loop
+            0x95,
+        ];
+        let (res, _, code) = verify(&sum, BPF_COMPLEXITY_LIMIT_INSNS);
+        assert_eq!(res, Ok(()));
+        let mut emu = Emu::new();
+        let entry = emu.add_code(&code);
+        assert_eq!(emu.call(entry, &[]), 5050);
+
+        // counting up to r1, which is not known
+        let unbounded = [
+            0xb7,                  // r0 = 0
+            0x0000_0001_0000_0007, // loop: r0 += 1
+            0xfffe_105d,           // if r0 != r1 goto loop
+            0x95,
+        ];
+        let (res, log, _) = verify(&unbounded, 1000);
+        let kind = VerifierErrorKind::ComplexityLimit { header: Some(1), limit: 1000 };
+        assert_eq!(res, Err(CompileError::Verifier(VerifierError { bpf_pc: 1, kind })));
+        assert!(log.ends_with("loop at insn 1 is not bounded within 1000 insns, stopped at insn 1\n"));
+
+        // a program of BPF_MAXINSNS instructions, then one more
+        let mut long = std::vec![0xb7; BPF_MAXINSNS as usize - 1];
+        long.push(0x95);
+        assert_eq!(verify(&long, BPF_COMPLEXITY_LIMIT_INSNS).0, Ok(()));
+        long.insert(0, 0xb7);
+        let len = BPF_MAXINSNS as usize + 1;
+        assert_eq!(verify(&long, BPF_COMPLEXITY_LIMIT_INSNS).0, Err(CompileError::ProgramTooLarge { len }));
+    }
+
+    #[test]
+    fn ctx_descriptor_test() {
+        use crate::context::*;
+        use crate::verifier::*;
+
+        // UAPI: u32 mark at 0, u32 ifindex at 4; real: u64 ifindex at 0, u32 mark at 8
+        let mut desc = ContextDescriptor::new(8);
+        let mark = CtxField {
+            off: 0,
+            size: 4,
+            real_off: 8,
+            real_size: 4,
+            access: FieldAccess::ReadWrite,
+        };
+        let ifindex = CtxField {
+            off: 4,
+            size: 4,
+            real_off: 0,
+            real_size: 8,
+            access: FieldAccess::ReadOnly,
+        };
+        desc.add_field(mark).unwrap();
+        desc.add_field(ifindex).unwrap();
+        assert_eq!(desc.add_field(mark), Err(DescriptorError::Overlap));
+
+        // r0 = *(u32 *)(r1 + 4); *(u32 *)(r1 + 0) = r0; exit
+        let prog = [0x0004_1061, 0x0000_0163, 0x95];
+        let helpers = HelperRegistry::new();
+        let mut verifier = Verifier::new(&prog, &helpers);
+        verifier.set_ctx_descriptor(&desc);
+        assert_eq!(verifier.verify(), Ok(()));
+        let insns = convert_ctx_accesses(&prog, verifier.insn_aux());
+        // r0 = *(u32 *)(r1 + 0); *(u32 *)(r1 + 8) = r0; exit
+        assert_eq!(insns, [0x0000_1061, 0x0008_0163, 0x95]);
+
+        // ifindex is read-only
+        let prog = [0x0004_0162, 0xb7, 0x95];
+        let mut verifier = Verifier::new(&prog, &helpers);
+        verifier.set_ctx_descriptor(&desc);
+        assert!(matches!(verifier.verify(), Err(VerifierError { bpf_pc: 0, kind: VerifierErrorKind::OutOfBounds { .. } })));
+    }
+
+    #[test]
+    fn program_type_test() {
+        use crate::program::*;
+        use crate::verifier::*;
+
+        assert_eq!(ProgramType::from_section_name("xdp"), Some(ProgramType::Xdp));
+        assert_eq!(ProgramType::from_section_name("kprobe/do_sys_open"), Some(ProgramType::Kprobe));
+        assert_eq!(ProgramType::from_section_name("tp/sched/sched_switch"), Some(ProgramType::Tracepoint));
+        assert_eq!(ProgramType::from_section_name("xdpfoo"), None);
+
+        let mut helpers = HelperRegistry::new();
+        helpers.register_std_helpers();
+        let compile_as = |prog_type, insns: &[u64]| {
+            let mut ctx = JitContext::new(insns);
+            ctx.set_program_type(prog_type);
+            compile(&mut ctx, &helpers, 0)
+        };
+        let rejected = |pc, kind| Err(CompileError::Verifier(VerifierError { bpf_pc: pc, kind }));
+
+        // r0 = 4; exit
+        assert_eq!(compile_as(ProgramType::Xdp, &[0x0000_0004_0000_00b7, 0x95]), Ok(()));
+        let range = (0, 1);
+        let kind = VerifierErrorKind::InvalidReturnValue { umin: 4, umax: 4, range };
+        assert_eq!(compile_as(ProgramType::Kprobe, &[0x0000_0004_0000_00b7, 0x95]), rejected(1, kind));
+
+        // call bpf_get_current_pid_tgid; r0 = 0; exit
+        let prog = [0x0000_000e_0000_0085, 0xb7, 0x95];
+        assert_eq!(compile_as(ProgramType::Tracepoint, &prog), Ok(()));
+        assert_eq!(compile_as(ProgramType::Xdp, &prog), rejected(0, VerifierErrorKind::HelperNotAllowed(14)));
+
+        // r0 = *(u16 *)skb[12]; exit
+        let prog = [0x0000_000c_0000_0028, 0x95];
+        assert_eq!(compile_as(ProgramType::Xdp, &prog), rejected(0, VerifierErrorKind::LdAbsNotAllowed));
+    }
+
+    #[test]
+    fn branch_relaxation_test() {
+        use crate::emu::Emu;
+
+        // a short loop, then a branch over more than 4 KiB of code
+        const FILL: u64 = 1100;
+        let mut prog = std::vec![
+            0xb7, // r0 = 0
+            0x0000_000a_0000_02b7, // r2 = 10
+            0x0000_0003_0000_0007, // loop: r0 += 3
+            0x0000_0001_0000_0217, // r2 -= 1
+            0xfffd_0255, // if r2 != 0 goto loop
+            0x0000_001e_0000_0015 | (FILL + 2) << 16, // if r0 == 30 goto far
+        ];
+        prog.extend((0..FILL).map(|_| 0x0000_0001_0000_03b7)); // r3 = 1
+        prog.extend(&[
+            0x0000_0001_0000_00b7, // r0 = 1
+            0x95,
+            0x0000_0064_0000_0007, // far: r0 += 100
+            0x95,
+        ]);
+        let mut ctx = JitContext::new(&prog);
+        compile(&mut ctx, &HelperRegistry::new(), 0).unwrap();
+        let code = ctx.get_rv_code();
+
+        // the loop is a single bne, the far branch and the first exit use jal, none needs auipc + jalr
+        let jal_zero = |&&i: &&u32| i & 0xfff == 0x6f;
+        assert_eq!(code.iter().filter(jal_zero).count(), 2);
+        assert!(!code.iter().any(|&i| i & 0x7f == 0x17));
+
+        let mut emu = Emu::new();
+        let entry = emu.add_code(code);
+        assert_eq!(emu.call(entry, &[]), 130);
+    }
+    #[test]
+    fn code_size_test() {
+        use crate::cbpf::*;
+        use crate::emu::Emu;
+        use crate::program::ProgramType;
+        use std::string::String;
+
+        let alu_imm = [
+            0x0000_0001_0000_0107, // r1 += 1
+            0x0000_00ff_0000_0157, // r1 &= 0xff
+            0x0000_0002_0000_0117, // r1 -= 2
+            0x0000_0004_0000_0147, // r1 |= 4
+            0x0000_0008_0000_01a7, // r1 ^= 8
+            0x0000_0003_0000_0167, // r1 <<= 3
+            0x0000_0001_0000_0177, // r1 >>= 1
+            0xffff_ffff_0000_0104, // w1 += -1
+            0x0000_0004_0000_0164, // w1 <<= 4
+            0x0000_0002_0000_0174, // w1 >>= 2
+            0x10bf, // r0 = r1
+            0x95,
+        ];
+        let cmp_zero = [
+            0xb7, // r0 = 0
+            0x0003_0115, // if r1 == 0 goto exit
+            0x0000_0001_0000_00b7, // r0 = 1
+            0x0001_0156, // if w1 != 0 goto exit
+            0x0000_0002_0000_00b7, // r0 = 2
+            0x95,
+        ];
+        let count_loop = [
+            0xb7, // r0 = 0
+            0x0000_000a_0000_02b7, // r2 = 10
+            0x0000_0003_0000_0007, // loop: r0 += 3
+            0x0000_0001_0000_0217, // r2 -= 1
+            0xfffd_0255, // if r2 != 0 goto loop
+            0x95,
+        ];
+        let call = [
+            0x0000_0005_0000_0085, // call bpf_ktime_get_ns
+            0x0000_000a_0000_0077, // r0 >>= 10
+            0x95,
+        ];
+        let filter = [
+            SockFilter::new(0x28, 0, 0, 12), // ldh [12]
+            SockFilter::new(0x15, 0, 1, 0x800), // jeq #0x800, 0, 1
+            SockFilter::new(0x06, 0, 0, u32::MAX), // ret #-1
+            SockFilter::new(0x06, 0, 0, 0), // ret #0
+        ];
+        let filter = convert_classic(&filter).unwrap();
+
+        let mut helpers = HelperRegistry::new();
+        helpers.register_std_helpers();
+        let jit = |insns: &[u64], prog_type: Option<ProgramType>, stack_size: usize| {
+            let mut ctx = JitContext::new(insns);
+            if let Some(prog_type) = prog_type {
+                ctx.set_program_type(prog_type);
+            }
+            compile(&mut ctx, &helpers, stack_size).unwrap();
+            ctx.get_rv_code().clone()
+        };
+        let programs = [
+            ("alu_imm", jit(&alu_imm, None, 0)),
+            ("cmp_zero", jit(&cmp_zero, None, 0)),
+            ("count_loop", jit(&count_loop, None, 0)),
+            ("call", jit(&call, None, 0)),
+            ("sock_filter", jit(&filter, Some(ProgramType::SocketFilter), CBPF_STACK_SIZE)),
+        ];
+
+        let mut emu = Emu::new();
+        let alu_imm = emu.add_code(&programs[0].1);
+        for &x in [0u64, 7, 0xfe, u64::MAX].iter() {
+            let r = ((x.wrapping_add(1) & 0xff).wrapping_sub(2) | 4) ^ 8;
+            let w = ((((r << 3) >> 1) as u32).wrapping_sub(1) << 4) >> 2;
+            assert_eq!(emu.call(alu_imm, &[x]), w as u64);
+        }
+        let cmp_zero = emu.add_code(&programs[1].1);
+        assert_eq!(emu.call(cmp_zero, &[0]), 0);
+        assert_eq!(emu.call(cmp_zero, &[5]), 1);
+        assert_eq!(emu.call(cmp_zero, &[1 << 32]), 2);
+
+        // bytes of code emitted per program, `UPDATE_CODE_SIZE=1 cargo test` rewrites the golden file
+        let sizes: String = programs
+            .iter()
+            .map(|(name, code)| std::format!("{} {}\n", name, 4 * code.len()))
+            .collect();
+        let golden = "tests/code_size.txt";
+        if std::env::var_os("UPDATE_CODE_SIZE").is_some() {
+            std::fs::write(golden, &sizes).unwrap();
+        }
+        assert_eq!(std::fs::read_to_string(golden).unwrap(), sizes);
+    }
+    #[test]
+    fn zext_test() {
+        use crate::emu::Emu;
+
+        let prog = [
+            0x0000_0005_0000_0104, // w1 += 5
+            0x0000_0003_0000_0124, // w1 *= 3
+            0x12bc, // w2 = w1
+            0x0000_0004_0000_0264, // w2 <<= 4
+            0x0000_0064_0001_0226, // if w2 > 100 goto +1
+            0x0000_0007_0000_02b4, // w2 = 7
+            0x20bf, // r0 = r2
+            0x100f, // r0 += r1
+            0x95,
+        ];
+        let jit = |flags: u32| {
+            let mut ctx = JitContext::new(&prog);
+            ctx.set_prog_flags(flags);
+            compile(&mut ctx, &HelperRegistry::new(), 0).unwrap();
+            ctx.get_rv_code().clone()
+        };
+        // `w1 += 5` and `w2 = w1` are only read as 32-bit values, so only 3 of r1 and r2 are zero-extended
+        let is_zext = |i: u32| i & 0xfff0_707f == 0x0200_5013 && [10, 11].contains(&((i >> 7) & 31)); // srli a0/a1, 32
+        let zext = |code: &Vec<u32>| code.iter().filter(|&&i| is_zext(i)).count();
+        let code = jit(0);
+        assert_eq!(zext(&code), 3);
+        let rnd = jit(BPF_F_TEST_RND_HI32);
+        assert!(rnd.len() > code.len());
+
+        let mut emu = Emu::new();
+        let entries = [emu.add_code(&code), emu.add_code(&rnd)];
+        for &x in [0u64, 3, 0xffff_ffff_0000_0010, 0x5555_5555].iter() {
+            let a = (x as u32).wrapping_add(5).wrapping_mul(3);
+            let b = if a << 4 > 100 { a << 4 } else { 7 };
+            for &entry in entries.iter() {
+                assert_eq!(emu.call(entry, &[x]), b as u64 + a as u64);
+            }
+        }
+    }
+    #[test]
+    fn prologue_test() {
+        use crate::emu::Emu;
+
+        let mut helpers = HelperRegistry::new();
+        helpers.register(Helper {
+            id: 0x1000,
+            name: "get_answer",
+            func: get_answer as *const () as u64,
+            args: &[],
+            ret: RetKind::Integer,
+        });
+        let jit = |prog: &[u64]| {
+            let mut ctx = JitContext::new(prog);
+            compile(&mut ctx, &helpers, 64).unwrap();
+            ctx.get_rv_code().clone()
+        };
+        let is_sd = |&&i: &&u32| i & 0x707f == 0x3023;
+
+        // a leaf without stack has no frame: li a5, 1; mv a0, a5; ret
+        let leaf = jit(&[0x0000_0001_0000_00b7, 0x95]);
+        assert_eq!(leaf.len(), 3);
+
+        // ra, s0 and s1 for R6 are saved, other callee-saved registers are left alone
+        let call = jit(&[
+            0x16bf, // r6 = r1
+            0x0000_1000_0000_0085, // call get_answer
+            0x600f, // r0 += r6
+            0x95,
+        ]);
+        assert_eq!(call.iter().filter(is_sd).count(), 3);
+
+        let mut emu = Emu::new();
+        let entries = [emu.add_code(&leaf), emu.add_code(&call)];
+        let saved: Vec<u64> = (8..10).chain(18..28).map(|i| emu.x[i]).collect();
+        assert_eq!(emu.call(entries[0], &[]), 1);
+        assert_eq!(emu.call(entries[1], &[100]), 142);
+        let after: Vec<u64> = (8..10).chain(18..28).map(|i| emu.x[i]).collect();
+        assert_eq!(saved, after);
+    }
+    #[test]
+    fn opt_test() {
+        use crate::emu::Emu;
+
+        let consts = [
+            0x0000_000a_0000_02b7, // r2 = 10
+            0x23bf, // r3 = r2
+            0x0000_0004_0000_0327, // r3 *= 4
+            0x0000_001e_0001_0325, // if r3 > 30 goto +1
+            0x01b7, // r1 = 0
+            0x14bf, // r4 = r1
+            0x0000_0063_0000_05b7, // r5 = 99
+            0xffff_ffff_0000_05b4, // w5 = -1
+            0x0000_0002_0000_0504, // w5 += 2
+            0x40bf, // r0 = r4
+            0x300f, // r0 += r3
+            0x500f, // r0 += r5
+            0x95,
+        ];
+        let count_loop = [
+            0x00b7, // r0 = 0
+            0x0000_0005_0000_02b7, // r2 = 5
+            0x100f, // r0 += r1
+            0x0000_0001_0000_0217, // r2 -= 1
+            0xfffd_0255, // if r2 != 0 goto -3
+            0x95,
+        ];
+        // shifts by known amounts out of range, of which only the low bits count
+        let shifts = [
+            0x0000_0046_0000_02b7, // r2 = 70
+            0x216f, // r1 <<= r2
+            0x0000_0041_0000_02b7, // r2 = 65
+            0x21cf, // r1 s>>= r2
+            0x0000_0042_0000_02b7, // r2 = 66
+            0x217f, // r1 >>= r2
+            0x10bf, // r0 = r1
+            0x95,
+        ];
+        let jit = |prog: &[u64], level: OptLevel| {
+            let mut ctx = JitContext::new(prog);
+            ctx.set_opt_level(level);
+            compile(&mut ctx, &HelperRegistry::new(), 0).unwrap();
+            ctx.get_rv_code().clone()
+        };
+        let levels = [OptLevel::O0, OptLevel::O1, OptLevel::O2];
+        let codes = |prog: &[u64]| -> Vec<Vec<u32>> { levels.iter().map(|&level| jit(prog, level)).collect() };
+        let consts_codes = codes(&consts);
+        // the branch, the dead path and the copies are gone: mv a5, a0; addi a5, a5, 40; addi a5, a5, 1; mv a0, a5; ret
+        assert!(consts_codes[1].len() < consts_codes[0].len());
+        assert_eq!(consts_codes[2].len(), 5);
+
+        // shifted by 6, 1 and 2 as immediates
+        let (folded, _) = crate::opt::optimize(&shifts, OptLevel::O1).unwrap();
+        let shifted: Vec<u64> = folded.iter().copied().filter(|&insn| [0x67, 0xc7, 0x77].contains(&(insn as u8))).collect();
+        assert_eq!(shifted, [0x0000_0006_0000_0167, 0x0000_0001_0000_01c7, 0x0000_0002_0000_0177]);
+
+        let mut emu = Emu::new();
+        let cases: [(Vec<Vec<u32>>, fn(u64) -> u64); 3] = [
+            (consts_codes, |x| x.wrapping_add(41)),
+            (codes(&count_loop), |x| x.wrapping_mul(5)),
+            (codes(&shifts), |x| ((x << 6) as i64 >> 1) as u64 >> 2),
+        ];
+        for (codes, expected) in cases.iter() {
+            let entries: Vec<u64> = codes.iter().map(|code| emu.add_code(code)).collect();
+            for &x in [0u64, 7, 0xffff_ffff_ffff_fff0].iter() {
+                for &entry in entries.iter() {
+                    assert_eq!(emu.call(entry, &[x]), expected(x));
+                }
+            }
+        }
+    }
+    #[test]
+    fn inline_helper_test() {
+        use crate::emu::Emu;
+
+        static CLOCK: u64 = 1000;
+        extern "C" fn get_cpu(_: u64, _: u64, _: u64, _: u64, _: u64) -> u64 {
+            unreachable!()
+        }
+        fn inline_cpu(ctx: &mut JitContext, rd: u8) {
+            ctx.emit_imm(rd, 3);
+        }
+        fn inline_ktime(ctx: &mut JitContext, rd: u8) {
+            ctx.emit_imm(rd, &CLOCK as *const u64 as i64);
+            ctx.emit_ld(rd, rd, 0);
+        }
+        let mut helpers = HelperRegistry::new();
+        helpers.register_map_helpers();
+        helpers.register_std_helpers();
+        helpers.register(Helper {
+            id: BPF_FUNC_GET_SMP_PROCESSOR_ID,
+            name: "bpf_get_smp_processor_id",
+            func: get_cpu as *const () as u64,
+            args: &[],
+            ret: RetKind::Integer,
+        });
+        helpers.set_inline(BPF_FUNC_GET_SMP_PROCESSOR_ID, inline_cpu);
+        helpers.set_inline(BPF_FUNC_KTIME_GET_NS, inline_ktime);
+
+        let mut maps = MapTable::new();
+        let attr = MapAttr {
+            map_type: MapType::Array,
+            key_size: 4,
+            value_size: 24,
+            max_entries: 4,
+            map_flags: 0,
+        };
+        let fd = maps.create(attr).unwrap();
+        let mut value = [0u8; 24];
+        value[..8].copy_from_slice(&42u64.to_ne_bytes());
+        maps.update_elem(fd, &2u32.to_ne_bytes(), &value, 0).unwrap();
+
+        let prog = |key: i32| {
+            [
+                0x0000_0000_fffc_0a62 | (key as u32 as u64) << 32, // *(u32 *)(r10 - 4) = key
+                0xa2bf, // r2 = r10
+                0xffff_fffc_0000_0207, // r2 += -4
+                0x1118 | (fd as u64) << 32, // r1 = map
+                0,
+                0x0000_0001_0000_0085, // call bpf_map_lookup_elem
+                0x0000_0000_0002_0055, // if r0 != 0 goto +2
+                0x0000_0064_0000_00b7, // r0 = 100
+                0x95,
+                0x0679, // r6 = *(u64 *)(r0 + 0)
+                0x0000_0008_0000_0085, // call bpf_get_smp_processor_id
+                0x600f, // r0 += r6
+                0x07bf, // r7 = r0
+                0x0000_0005_0000_0085, // call bpf_ktime_get_ns
+                0x700f, // r0 += r7
+                0x95,
+            ]
+        };
+        let jit = |prog: &[u64]| {
+            let mut ctx = JitContext::new(prog);
+            ctx.set_map_table(&maps);
+            ctx.set_verify(true);
+            compile(&mut ctx, &helpers, 64).unwrap();
+            ctx.get_rv_code().clone()
+        };
+        let codes = [jit(&prog(2)), jit(&prog(4))];
+        // no helper is called
+        let is_call = |&&i: &&u32| i & 0xfff == 0x0e7; // jalr ra
+        assert_eq!(codes[0].iter().filter(is_call).count(), 0);
+
+        let mut emu = Emu::new();
+        let entries = [emu.add_code(&codes[0]), emu.add_code(&codes[1])];
+        assert_eq!(emu.call(entries[0], &[]), 42 + 3 + 1000);
+        assert_eq!(emu.call(entries[1], &[]), 100);
+    }
+    #[test]
+    fn direct_call_test() {
+        use crate::emu::Emu;
+
+        // one buffer holding the program at its start and helpers jitted near and far from it
+        let mut buf = std::vec![0u32; (2 << 20) / 4 + 64];
+        let near = buf.len() - (2 << 20) / 4 - 2;
+        let far = buf.len() - 2;
+        for &at in [near, far].iter() {
+            buf[at] = 0x02a0_0513; // li a0, 42
+            buf[at + 1] = 0x0000_8067; // ret
+        }
+        let load_addr = buf.as_ptr() as u64;
+        let mut emu = Emu::new();
+        emu.add_code(&buf);
+
+        let prog = [
+            0x0000_1000_0000_0085, // call get_answer
+            0x0000_0064_0000_0007, // r0 += 100
+            0x95,
+        ];
+        let is_jal_ra = |i: &u32| i & 0xfff == 0x0ef;
+        let is_auipc_t1 = |i: &u32| i & 0xfff == 0x317;
+        for &(func, jals, auipcs) in [
+            (&buf[near] as *const u32 as u64, 1, Some(0)),
+            (&buf[far] as *const u32 as u64, 0, Some(1)),
+            // likely out of reach of auipc
+            (get_answer as *const () as u64, 0, None),
+        ]
+        .iter()
+        {
+            let mut helpers = HelperRegistry::new();
+            helpers.register(Helper {
+                id: 0x1000,
+                name: "get_answer",
+                func,
+                args: &[],
+                ret: RetKind::Integer,
+            });
+            let mut ctx = JitContext::new(&prog);
+            ctx.set_call_mode(CallMode::Direct(load_addr));
+            compile(&mut ctx, &helpers, 0).unwrap();
+            let code = ctx.get_rv_code();
+            // the PLT is not loaded
+            assert!(!code.contains(&0x0003_3383)); // ld t2, 0(t1)
+            assert_eq!(code.iter().filter(|i| is_jal_ra(i)).count(), jals);
+            if let Some(auipcs) = auipcs {
+                assert_eq!(code.iter().filter(|i| is_auipc_t1(i)).count(), auipcs);
+            }
+            buf[..code.len()].copy_from_slice(code);
+            assert_eq!(emu.call(load_addr, &[]), 142);
+        }
+    }
+    #[test]
+    fn imm_test() {
+        use crate::emu::Emu;
+        use crate::imm::{linux_len, synthesize};
+
+        let mut buf = [0u32; 16];
+        let mut emu = Emu::new();
+        let entry = emu.add_code(&buf);
+        let mut check = |imm: i64| {
+            for &zbs in [false, true].iter() {
+                let seq = synthesize(10, imm, zbs); // a0
+                assert!(seq.len() <= linux_len(imm), "{:#x}", imm);
+                buf[..seq.len()].copy_from_slice(&seq);
+                buf[seq.len()] = 0x0000_8067; // ret
+                emu.steps = 0;
+                assert_eq!(emu.call(entry, &[]) as i64, imm, "{:#x} zbs {}", imm, zbs);
+            }
+            synthesize(10, imm, true).len()
+        };
+
+        // 9-bit values at every position, their complements and neighbours
+        for v in -256i64..256 {
+            for shift in 0..64 {
+                let imm = v << shift;
+                for &imm in [imm, !imm, imm.wrapping_add(1), imm.wrapping_sub(1)].iter() {
+                    check(imm);
+                }
+            }
+        }
+        // every pair of bits, and every value around the 32-bit boundaries
+        for i in 0..64 {
+            for j in 0..64 {
+                let imm = 1i64 << i | 1i64 << j;
+                check(imm);
+                check(!imm);
+            }
+        }
+        for delta in -2049i64..2049 {
+            for &base in [i32::MIN as i64, i32::MAX as i64, u32::MAX as i64, 0].iter() {
+                check(base + delta);
+            }
+        }
+        let mut x = 0x2545_f491_4f6c_dd1du64;
+        for _ in 0..20_000 {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            check(x as i64);
+        }
+
+        // a shift of all ones, a negation, a neighbour of a 32-bit constant and a single bit
+        assert_eq!(check(0x0000_ffff_ffff_ffff), 2);
+        assert_eq!(check(0xffff_f7ee_dfff_efffu64 as i64), 5);
+        assert_eq!(check(-0x8000_0005), 2);
+        assert_eq!(check(1 << 40), 1);
+
+        // LD_IMM_DW of a constant longer than 4 instructions is loaded from the pool, once
+        let imm = 0x1234_5678_9abc_def1u64;
+        let prog = [
+            0x0018 | (imm as u32 as u64) << 32, // r0 = imm
+            (imm >> 32) << 32,
+            0x0118 | (imm as u32 as u64) << 32, // r1 = imm
+            (imm >> 32) << 32,
+            0x100f, // r0 += r1
+            0x95,
+        ];
+        let mut ctx = JitContext::new(&prog);
+        compile(&mut ctx, &HelperRegistry::new(), 0).unwrap();
+        let code = ctx.get_rv_code();
+        assert_eq!(code.iter().filter(|&&i| i & 0x707f == 0x3003).count(), 2); // ld
+        assert_eq!(&code[code.len() - 2..], &[imm as u32, (imm >> 32) as u32]);
+        let entry = emu.add_code(code);
+        assert_eq!(emu.call(entry, &[]), imm.wrapping_mul(2));
+    }
+    #[test]
+    fn rvc_test() {
+        use crate::cbpf::*;
+        use crate::emu::Emu;
+        use crate::program::{ProgramType, SkBuff};
+        use crate::rvc::compress;
+        use rvjit::rv32i::*;
+        use rvjit::rv64i::*;
+
+        // as encoded by GNU as
+        let encodings = [
+            (addi(10, 10, 1), 0x0505),          // c.addi a0, 1
+            (addi(15, 0, 1), 0x4785),           // c.li a5, 1
+            (addi(10, 15, 0), 0x853e),          // c.mv a0, a5
+            (add(10, 10, 11), 0x952e),          // c.add a0, a1
+            (sub(10, 10, 11), 0x8d0d),          // c.sub a0, a1
+            (andi(10, 10, 15), 0x893d),         // c.andi a0, 15
+            (addiw(10, 10, -1i32 as u32), 0x357d), // c.addiw a0, -1
+            (lui(10, 1 << 12), 0x6505),         // c.lui a0, 1
+            (addi(2, 2, -16i32 as u32), 0x1141), // c.addi sp, -16
+            (addi(2, 2, -64i32 as u32), 0x7139), // c.addi16sp sp, -64
+            (addi(10, 2, 8), 0x0028),           // c.addi4spn a0, sp, 8
+            (slli64(10, 10, 32), 0x1502),       // c.slli a0, 32
+            (srli64(10, 10, 32), 0x9101),       // c.srli a0, 32
+            (sd(2, 1, 8), 0xe406),              // c.sdsp ra, 8(sp)
+            (ld(1, 2, 8), 0x60a2),              // c.ldsp ra, 8(sp)
+            (ld(15, 10, 8), 0x651c),            // c.ld a5, 8(a0)
+            (sd(10, 15, 8), 0xe51c),            // c.sd a5, 8(a0)
+            (lw(10, 11, 4), 0x41c8),            // c.lw a0, 4(a1)
+            (jal(0, 8), 0xa021),                // c.j 8
+            (beq(8, 15, 0), 0xc781),            // c.beqz a5, 8
+            (jalr(0, 1, 0), 0x8082),            // c.jr ra
+            (jalr(1, 6, 0), 0x9302),            // c.jalr t1
+        ];
+        for &(insn, c) in encodings.iter() {
+            assert_eq!(compress(insn), Some(c), "{:#010x}", insn);
+        }
+        // registers out of x8-x15, misaligned offsets, and no c.jal on RV64
+        assert_eq!(compress(addi(10, 11, 1)), None);
+        assert_eq!(compress(ld(15, 10, 4)), None);
+        assert_eq!(compress(beq(8, 5, 0)), None);
+        assert_eq!(compress(jal(1, 8)), None);
+
+        let mut helpers = HelperRegistry::new();
+        helpers.register(Helper {
+            id: 0x1000,
+            name: "get_answer",
+            func: get_answer as *const () as u64,
+            args: &[],
+            ret: RetKind::Integer,
+        });
+        let rvc = TargetFeatures {
+            rvc: true,
+            ..Default::default()
+        };
+
+        // a short loop, then a branch over 1100 instructions
+        let mut relax = std::vec![
+            0xb7, // r0 = 0
+            0x0000_000a_0000_02b7, // r2 = 10
+            0x0000_0003_0000_0007, // loop: r0 += 3
+            0x0000_0001_0000_0217, // r2 -= 1
+            0xfffd_0255, // if r2 != 0 goto loop
+            0x0000_001e_0000_0015 | 1102 << 16, // if r0 == 30 goto far
+        ];
+        relax.extend((0..1100).map(|_| 0x0000_0001_0000_03b7)); // r3 = 1
+        relax.extend(&[
+            0x0000_0001_0000_00b7, // r0 = 1
+            0x95,
+            0x0000_0064_0000_0007, // far: r0 += 100
+            0x95,
+        ]);
+        // r1 calls of a helper in a metered loop, around a sandboxed spill of r1
+        let metered = [
+            0x16bf, // r6 = r1
+            0xfff8_6a7b, // *(u64 *)(r10 - 8) = r6
+            0x07b7, // r7 = 0
+            0x0000_1000_0000_0085, // loop: call get_answer
+            0x070f, // r7 += r0
+            0xffff_ffff_0000_0607, // r6 += -1
+            0xfffc_0655, // if r6 != 0 goto loop
+            0xfff8_a079, // r0 = *(u64 *)(r10 - 8)
+            0x700f, // r0 += r7
+            0x95,
+        ];
+        // tcpdump -dd 'ip and tcp', as in `cbpf_test`
+        let filter = [
+            SockFilter::new(0x28, 0, 0, 12),
+            SockFilter::new(0x15, 0, 8, 0x800),
+            SockFilter::new(0x30, 0, 0, 23),
+            SockFilter::new(0x02, 0, 0, 0),
+            SockFilter::new(0xb1, 0, 0, 14),
+            SockFilter::new(0x60, 0, 0, 0),
+            SockFilter::new(0x15, 0, 3, 6),
+            SockFilter::new(0x80, 0, 0, 0),
+            SockFilter::new(0x0c, 0, 0, 0),
+            SockFilter::new(0x16, 0, 0, 0),
+            SockFilter::new(0x06, 0, 0, 0),
+        ];
+        let filter = convert_classic(&filter).unwrap();
+        let mut pkt = [0u8; 34];
+        pkt[12] = 0x08;
+        pkt[14] = 0x45;
+        pkt[23] = 6;
+        let skb = SkBuff {
+            data: pkt.as_ptr() as u64,
+            data_end: pkt.as_ptr() as u64 + pkt.len() as u64,
+            len: pkt.len() as u32,
+            ..Default::default()
+        };
+
+        // instructions of a compressed stream
+        let insns = |code: &[u32], size: usize| {
+            let halves: Vec<u16> = code.iter().flat_map(|&w| std::vec![w as u16, (w >> 16) as u16]).collect();
+            let mut insns = Vec::new();
+            let mut i = 0;
+            while i < size / 2 {
+                if halves[i] & 3 != 3 {
+                    insns.push(halves[i] as u32);
+                    i += 1;
+                } else {
+                    insns.push(halves[i] as u32 | (halves[i + 1] as u32) << 16);
+                    i += 2;
+                }
+            }
+            insns
+        };
+
+        fn jit(mut ctx: JitContext, features: TargetFeatures, helpers: &HelperRegistry) -> (Vec<u32>, usize) {
+            ctx.set_target_features(features);
+            compile(&mut ctx, helpers, 64).unwrap();
+            (ctx.get_rv_code().clone(), ctx.code_size)
+        }
+        let meter = Meter::new(0);
+        let status = SandboxStatus::new();
+        let mut emu = Emu::new();
+        let mut sizes = Vec::new();
+        for &features in [TargetFeatures::default(), rvc].iter() {
+            let relax = jit(JitContext::new(&relax), features, &helpers);
+            let mut ctx = JitContext::new(&metered);
+            ctx.set_meter(&meter);
+            ctx.set_sandbox(0, &status);
+            let metered = jit(ctx, features, &helpers);
+            let mut ctx = JitContext::new(&filter);
+            ctx.set_program_type(ProgramType::SocketFilter);
+            let filter = jit(ctx, features, &helpers);
+
+            sizes.push(relax.1 + metered.1 + filter.1);
+            // the loop of `relax` is closed by c.bnez a1
+            assert_eq!(insns(&relax.0, relax.1).contains(&0xfdf5), features.rvc);
+
+            let entry = emu.add_code(&relax.0);
+            assert_eq!(emu.call(entry, &[]), 130);
+            let entry = emu.add_code(&metered.0);
+            meter.set(100);
+            assert_eq!(emu.call(entry, &[10]), 10 + 42 * 10);
+            assert_eq!(meter.remaining(), 100 - 19);
+            assert_eq!(emu.call(entry, &[60]), 0);
+            assert!(meter.exhausted());
+            let entry = emu.add_code(&filter.0);
+            assert_eq!(emu.call(entry, &[&skb as *const SkBuff as u64]), 34 + 20);
+        }
+        assert!(4 * sizes[1] < 3 * sizes[0]);
+    }
+    #[test]
+    fn bitmanip_test() {
+        use crate::bitmanip::*;
+        use crate::emu::Emu;
+        use crate::verifier::{VerifierError, VerifierErrorKind};
+
+        // as encoded by GNU as
+        let encodings = [
+            (zext_w(10, 10), 0x0805_053b),        // zext.w a0, a0
+            (sh_add(3, 10, 11, 10), 0x20a5_e533), // sh3add a0, a1, a0
+            (andn(10, 10, 11), 0x40b5_7533),      // andn a0, a0, a1
+            (orn(10, 10, 11), 0x40b5_6533),       // orn a0, a0, a1
+            (minu(10, 10, 11), 0x0ab5_5533),      // minu a0, a0, a1
+            (max(10, 10, 11), 0x0ab5_6533),       // max a0, a0, a1
+            (sext_b(10, 10), 0x6045_1513),        // sext.b a0, a0
+            (sext_h(10, 10), 0x6055_1513),        // sext.h a0, a0
+            (zext_h(10, 10), 0x0805_453b),        // zext.h a0, a0
+            (rev8(10, 10), 0x6b85_5513),          // rev8 a0, a0
+        ];
+        for &(insn, expected) in encodings.iter() {
+            assert_eq!(insn, expected, "{:#010x}", expected);
+        }
+
+        // programs of r1 and r2, their result, and the instruction they use with Zba and Zbb (a5 is r0,
+        // a0 r1, a1 r2 and t1 a temporary), or one they must not use
+        let r0_r1 = 0x10bf; // r0 = r1
+        let cases: [(Vec<u64>, fn(u64, u64) -> u64, u32, bool); 14] = [
+            (std::vec![r0_r1, 0x0000_0010_0000_00dc, 0x95], |x, _| (x as u16).swap_bytes() as u64, rev8(15, 15), true), // r0 = be16 r0
+            (std::vec![r0_r1, 0x0000_0020_0000_00dc, 0x95], |x, _| (x as u32).swap_bytes() as u64, rev8(15, 15), true), // r0 = be32 r0
+            (std::vec![r0_r1, 0x0000_0040_0000_00dc, 0x95], |x, _| x.swap_bytes(), rev8(15, 15), true), // r0 = be64 r0
+            (std::vec![r0_r1, 0x0000_0010_0000_00d4, 0x95], |x, _| x as u16 as u64, zext_h(15, 15), true), // r0 = le16 r0
+            (std::vec![r0_r1, 0x0000_0020_0000_00d7, 0x95], |x, _| (x as u32).swap_bytes() as u64, rev8(15, 15), true), // r0 = bswap32 r0
+            (
+                std::vec![r0_r1, 0x0000_0038_0000_0067, 0x0000_0038_0000_00c7, 0x95], // r0 <<= 56; r0 s>>= 56
+                |x, _| x as i8 as u64,
+                sext_b(15, 15),
+                true,
+            ),
+            (
+                std::vec![r0_r1, 0x0000_0020_0000_0067, 0x0000_0020_0000_0077, 0x95], // r0 <<= 32; r0 >>= 32
+                |x, _| x as u32 as u64,
+                zext_w(15, 15),
+                true,
+            ),
+            (
+                std::vec![0x20bf, 0x0000_0003_0000_0167, 0x100f, 0x95], // r0 = r2; r1 <<= 3; r0 += r1
+                |x, y| y.wrapping_add(x << 3),
+                sh_add(3, 15, 10, 15),
+                true,
+            ),
+            (
+                std::vec![r0_r1, 0x0000_0002_0000_0067, 0x200f, 0x95], // r0 <<= 2; r0 += r2
+                |x, y| (x << 2).wrapping_add(y),
+                sh_add(2, 15, 15, 11),
+                true,
+            ),
+            (
+                std::vec![0xffff_ffff_0000_01a7, 0x125f, 0x20bf, 0x95], // r1 ^= -1; r2 &= r1; r0 = r2
+                |x, y| y & !x,
+                andn(11, 11, 10),
+                true,
+            ),
+            (
+                std::vec![r0_r1, 0xffff_ffff_0000_00a7, 0x204f, 0x95], // r0 ^= -1; r0 |= r2
+                |x, y| y | !x,
+                orn(15, 11, 15),
+                true,
+            ),
+            (
+                std::vec![r0_r1, 0x0001_20ad, 0x20bf, 0x95], // if r0 < r2 goto +1; r0 = r2
+                |x, y| x.min(y),
+                minu(15, 15, 11),
+                true,
+            ),
+            (
+                std::vec![r0_r1, 0x0000_0064_0001_0065, 0x0000_0064_0000_00b7, 0x95], // if r0 s> 100 goto +1; r0 = 100
+                |x, _| (x as i64).max(100) as u64,
+                max(15, 15, 6),
+                true,
+            ),
+            // r1 is read after the pair
+            (
+                std::vec![0xffff_ffff_0000_01a7, 0x125f, 0x20bf, 0x100f, 0x95], // r1 ^= -1; r2 &= r1; r0 = r2; r0 += r1
+                |x, y| (y & !x).wrapping_add(!x),
+                andn(11, 11, 10),
+                false,
+            ),
+        ];
+        // `r0 >>= 32` is a jump target
+        let target = [r0_r1, 0x0001_0215, 0x0000_0020_0000_0067, 0x0000_0020_0000_0077, 0x95]; // if r2 == 0 goto +1
+        let jit = |prog: &[u64], features: TargetFeatures| {
+            let mut ctx = JitContext::new(prog);
+            ctx.set_target_features(features);
+            compile(&mut ctx, &HelperRegistry::new(), 0).unwrap();
+            ctx.get_rv_code().clone()
+        };
+
+        let values = [0u64, 1, 2, 0x7f, 0x80, 100, 0xffff_8001, 0x1234_5678_9abc_def0, 1 << 63, u64::MAX];
+        let mut emu = Emu::new();
+        for &(zba, zbb) in [(false, false), (true, false), (false, true), (true, true)].iter() {
+            let features = TargetFeatures { zba, zbb, ..Default::default() };
+            for (prog, f, insn, used) in cases.iter() {
+                let code = jit(prog, features);
+                if zba && zbb {
+                    assert_eq!(code.contains(insn), *used, "{:#010x}", insn);
+                }
+                let entry = emu.add_code(&code);
+                for &x in values.iter() {
+                    for &y in values.iter() {
+                        emu.steps = 0;
+                        assert_eq!(emu.call(entry, &[x, y]), f(x, y), "{:#x} {:#x} {:?}", x, y, features);
+                    }
+                }
+            }
+            let code = jit(&target, features);
+            assert!(!code.contains(&zext_w(15, 15)));
+            let entry = emu.add_code(&code);
+            assert_eq!(emu.call(entry, &[0x1234_5678_9abc_def0, 0]), 0x1234_5678);
+            assert_eq!(emu.call(entry, &[0x1234_5678_9abc_def0, 1]), 0x9abc_def0);
+        }
+
+        // the verifier proves the stack access in bounds with swapped constants, which the code must agree with
+        let swap = [
+            0x0000_0800_0000_06b7, // r6 = 0x800
+            0x0000_0010_0000_06d7, // r6 = bswap16 r6
+            0x0001_0008_0000_07b7, // r7 = 0x10008
+            0x0000_0010_0000_07d4, // r7 = le16 r7
+            0x761f,                // r6 -= r7
+            0x0000_000c_0000_0667, // r6 <<= 12
+            0xa2bf,                // r2 = r10
+            0x620f,                // r2 += r6
+            0x0000_0007_fff8_027a, // *(u64 *)(r2 - 8) = 7
+            0x60bf,                // r0 = r6
+            0x95,
+        ];
+        for &zbb in [false, true].iter() {
+            let mut ctx = JitContext::new(&swap);
+            ctx.set_verify(true);
+            ctx.set_target_features(TargetFeatures { zbb, ..Default::default() });
+            assert_eq!(compile(&mut ctx, &HelperRegistry::new(), 8), Ok(()));
+            let entry = emu.add_code(ctx.get_rv_code());
+            assert_eq!(emu.call(entry, &[]), 0);
+        }
+        // r0 = 0; r0 = be8 r0; exit
+        let mut ctx = JitContext::new(&[0xb7, 0x0000_0008_0000_00dc, 0x95]);
+        ctx.set_verify(true);
+        let kind = VerifierErrorKind::InvalidEndSize(8);
+        let res = compile(&mut ctx, &HelperRegistry::new(), 0);
+        assert_eq!(res, Err(CompileError::Verifier(VerifierError { bpf_pc: 1, kind })));
+
+        // values of inlined array lookups are indexed by sh3add
+        let mut helpers = HelperRegistry::new();
+        helpers.register_map_helpers();
+        let mut maps = MapTable::new();
+        let attr = MapAttr {
+            map_type: MapType::Array,
+            key_size: 4,
+            value_size: 8,
+            max_entries: 4,
+            map_flags: 0,
+        };
+        let fd = maps.create(attr).unwrap();
+        maps.update_elem(fd, &2u32.to_ne_bytes(), &42u64.to_ne_bytes(), 0).unwrap();
+        let prog = |key: i32| {
+            [
+                0x0000_0000_fffc_0a62 | (key as u32 as u64) << 32, // *(u32 *)(r10 - 4) = key
+                0xa2bf, // r2 = r10
+                0xffff_fffc_0000_0207, // r2 += -4
+                0x1118 | (fd as u64) << 32, // r1 = map
+                0,
+                0x0000_0001_0000_0085, // call bpf_map_lookup_elem
+                0x0000_0000_0002_0055, // if r0 != 0 goto +2
+                0x0000_0064_0000_00b7, // r0 = 100
+                0x95,
+                0x0079, // r0 = *(u64 *)(r0 + 0)
+                0x95,
+            ]
+        };
+        for &(key, expected) in [(2, 42), (4, 100)].iter() {
+            let prog = prog(key);
+            let mut ctx = JitContext::new(&prog);
+            ctx.set_map_table(&maps);
+            ctx.set_verify(true);
+            ctx.set_target_features(TargetFeatures { zba: true, ..Default::default() });
+            compile(&mut ctx, &helpers, 64).unwrap();
+            let code = ctx.get_rv_code().clone();
+            assert!(code.contains(&sh_add(3, 15, 6, 15)));
+            let entry = emu.add_code(&code);
+            assert_eq!(emu.call(entry, &[]), expected);
+        }
+    }
+    #[test]
+    fn kprobe_test() {
+        use crate::consts::*;
+        use crate::emu::Emu;
+        use crate::kprobe::*;
+        use crate::program::ProgramType;
+        use rvjit::rv32i::*;
+        use rvjit::rv32m::*;
+        use rvjit::rv64i::*;
+
+        let mut maps = MapTable::new();
+        let attr = MapAttr {
+            map_type: MapType::Array,
+            key_size: 4,
+            value_size: 16,
+            max_entries: 1,
+            map_flags: 0,
+        };
+        let fd = maps.create(attr).unwrap();
+        let mut helpers = HelperRegistry::new();
+        helpers.register_map_helpers();
+
+        // save PT_REGS_PARM1(ctx) + PT_REGS_PARM2(ctx) and PT_REGS_IP(ctx) into the map
+        let prog = [
+            0xfffc_0a62, // *(u32 *)(r10 - 4) = 0
+            0x16bf, // r6 = r1
+            0x0000_0000_0000_1118 | (fd as u64) << 32, // r1 = map[fd]
+            0,
+            0xa2bf, // r2 = r10
+            0xffff_fffc_0000_0207, // r2 += -4
+            0x0000_0001_0000_0085, // call bpf_map_lookup_elem
+            0x0006_0015, // if r0 == 0 goto +6
+            0x6179 | (PT_REGS_PARM1 as u64) << 16, // r1 = *(u64 *)(r6 + PARM1)
+            0x6279 | (PT_REGS_PARM2 as u64) << 16, // r2 = *(u64 *)(r6 + PARM2)
+            0x210f, // r1 += r2
+            0x107b, // *(u64 *)(r0 + 0) = r1
+            0x6179 | (PT_REGS_IP as u64) << 16, // r1 = *(u64 *)(r6 + IP)
+            0x0008_107b, // *(u64 *)(r0 + 8) = r1
+            0xb7, // r0 = 0
+            0x95,
+        ];
+        let mut ctx = JitContext::new(&prog);
+        ctx.set_map_table(&maps);
+        ctx.set_program_type(ProgramType::Kprobe);
+        compile(&mut ctx, &helpers, 8).unwrap();
+
+        // the context is read-only: *(u64 *)(r1 + PARM1) = r2
+        let bad = [0x217b | (PT_REGS_PARM1 as u64) << 16, 0xb7, 0x95];
+        let mut bad_ctx = JitContext::new(&bad);
+        bad_ctx.set_program_type(ProgramType::Kprobe);
+        assert!(compile(&mut bad_ctx, &helpers, 0).is_err());
+
+        // the probed function returns a0 * a1, its entry is patched to jump to the trampoline
+        let mut func = std::vec![
+            auipc(RV_REG_T0, 0),
+            ld(RV_REG_T0, RV_REG_T0, 24),
+            jalr(RV_REG_T0, RV_REG_T0, 0),
+            mul(RV_REG_A0, RV_REG_A0, RV_REG_A1),
+            jalr(RV_REG_ZERO, RV_REG_RA, 0),
+            addi(RV_REG_ZERO, RV_REG_ZERO, 0),
+            0,
+            0,
+        ];
+        let mut emu = Emu::new();
+        let probe_addr = emu.add_code(&func);
+        let jitted = emu.add_code(ctx.get_rv_code());
+        let trampoline = build_kprobe_trampoline(probe_addr, jitted);
+        let trampoline_addr = emu.add_code(&trampoline);
+        func[6] = trampoline_addr as u32;
+        func[7] = (trampoline_addr >> 32) as u32;
+
+        assert_eq!(emu.call(probe_addr, &[6, 7]), 42);
+        let value = maps.lookup_elem(fd, &0u32.to_ne_bytes()).unwrap();
+        assert_eq!(value[..8], 13u64.to_ne_bytes());
+        assert_eq!(value[8..], probe_addr.to_ne_bytes());
+    }
+
+    #[test]
+    fn xdp_test() {
+        use crate::consts::*;
+        use crate::emu::Emu;
+        use crate::program::ProgramType;
+        use crate::verifier::*;
+        use crate::xdp::*;
+
+        let mut helpers = HelperRegistry::new();
+        helpers.register_xdp_helpers();
+
+        // strip as many bytes as the first one says, then return the next first byte & 3
+        let mut prog = [
+            0x16bf, // r6 = r1
+            0x0000_6261, // r2 = *(u32 *)(r6 + 0)
+            0x0004_6361, // r3 = *(u32 *)(r6 + 4)
+            0x24bf, // r4 = r2
+            0x0000_0001_0000_0407, // r4 += 1
+            0x000d_342d, // if r4 > r3 goto drop
+            0x27bf, // r7 = r2
+            0x0000_2271, // r2 = *(u8 *)(r2 + 0)
+            0x61bf, // r1 = r6
+            0x0000_002c_0000_0085, // call bpf_xdp_adjust_head
+            0x000a_0055, // if r0 != 0 goto aborted
+            0x0000_6261, // r2 = *(u32 *)(r6 + 0)
+            0x0004_6361, // r3 = *(u32 *)(r6 + 4)
+            0x24bf, // r4 = r2
+            0x0000_0001_0000_0407, // r4 += 1
+            0x0003_342d, // if r4 > r3 goto drop
+            0x0000_2071, // r0 = *(u8 *)(r2 + 0)
+            0x0000_0003_0000_0057, // r0 &= 3
+            0x95,
+            0x0000_0001_0000_00b7, // drop: r0 = XDP_DROP
+            0x95,
+            0xb7, // aborted: r0 = XDP_ABORTED
+            0x95,
+        ];
+        let mut ctx = JitContext::new(&prog);
+        ctx.set_program_type(ProgramType::Xdp);
+        compile(&mut ctx, &helpers, 0).unwrap();
+
+        let mut emu = Emu::new();
+        let entry = emu.add_code(ctx.get_rv_code());
+        let rxq = XdpRxQueue {
+            ifindex: 1,
+            queue_index: 0,
+        };
+        let mut frame = [0u8; 36];
+        frame[16] = 4;
+        frame[20] = 2;
+        let verdict = run_xdp_with(&mut frame, 16, rxq, |xdp| emu.call(entry, &[xdp as u64]));
+        assert_eq!(verdict.action, XdpAction::Pass);
+        assert_eq!(verdict.data, 20..36);
+        assert_eq!(verdict.meta, 20..20);
+
+        // less than an ethernet header would be left
+        frame[16] = 8;
+        let verdict = run_xdp_with(&mut frame, 16, rxq, |xdp| emu.call(entry, &[xdp as u64]));
+        assert_eq!(verdict.action, XdpAction::Aborted);
+        assert_eq!(verdict.data, 16..36);
+
+        // the packet pointer saved in r7 is invalid once the packet moved
+        prog[16] = 0x0000_7071; // r0 = *(u8 *)(r7 + 0)
+        let mut ctx = JitContext::new(&prog);
+        ctx.set_program_type(ProgramType::Xdp);
+        let res = compile(&mut ctx, &helpers, 0);
+        assert!(matches!(
+            res,
+            Err(CompileError::Verifier(VerifierError {
+                bpf_pc: 16,
+                kind: VerifierErrorKind::InvalidMemAccess { reg: 7, .. }
+            }))
+        ));
+
+        let verdict = run_xdp_with(&mut frame, 16, rxq, |xdp| unsafe {
+            assert_eq!(bpf_xdp_adjust_meta(xdp as u64, -8i64 as u64), 0);
+            // before the metadata, beyond the ethernet header, and far out of the frame either way
+            for &offset in [-9i64, 7, i32::MIN as i64, i32::MAX as i64].iter() {
+                assert_eq!(bpf_xdp_adjust_head(xdp as u64, offset as u64), -EINVAL as u64);
+            }
+            assert_eq!(bpf_xdp_adjust_tail(xdp as u64, -6i64 as u64), 0);
+            assert_eq!(bpf_xdp_adjust_tail(xdp as u64, 8), -EINVAL as u64);
+            XdpAction::Tx as u64
+        });
+        assert_eq!(verdict.action, XdpAction::Tx);
+        assert_eq!(verdict.data, 16..30);
+        assert_eq!(verdict.meta, 8..16);
+
+        // a frame at the top of the address space, where the end of an ethernet header would wrap around
+        let mut top = XdpBuff {
+            data: u64::MAX - 31,
+            data_end: u64::MAX,
+            data_meta: u64::MAX - 31,
+            data_hard_start: u64::MAX - 63,
+            frame_sz: 63,
+            ..Default::default()
+        };
+        let res = unsafe { bpf_xdp_adjust_head(&mut top as *mut XdpBuff as u64, 20) };
+        assert_eq!(res, -EINVAL as u64);
+    }
+
+    #[test]
+    fn cbpf_test() {
+        use crate::cbpf::*;
+        use crate::consts::{ALU_K_NEG, ALU_K_RSH, ALU_X_LSH, ALU_X_RSH};
+        use crate::emu::Emu;
+        use crate::program::{ProgramType, SkBuff};
+
+        // tcpdump -dd 'ip and tcp', returning the length of the packet and of its IP header
+        let filter = [
+            SockFilter::new(0x28, 0, 0, 12), // ldh [12]
+            SockFilter::new(0x15, 0, 8, 0x800), // jeq #0x800, 0, drop
+            SockFilter::new(0x30, 0, 0, 23), // ldb [23]
+            SockFilter::new(0x02, 0, 0, 0), // st M[0]
+            SockFilter::new(0xb1, 0, 0, 14), // ldxb 4 * ([14] & 0xf)
+            SockFilter::new(0x60, 0, 0, 0), // ld M[0]
+            SockFilter::new(0x15, 0, 3, 6), // jeq #6, 0, drop
+            SockFilter::new(0x80, 0, 0, 0), // ld #len
+            SockFilter::new(0x0c, 0, 0, 0), // add x
+            SockFilter::new(0x16, 0, 0, 0), // ret a
+            SockFilter::new(0x06, 0, 0, 0), // drop: ret #0
+        ];
+        let insns = convert_classic(&filter).unwrap();
+        let mut ctx = JitContext::new(&insns);
+        ctx.set_program_type(ProgramType::SocketFilter);
+        compile(&mut ctx, &HelperRegistry::new(), CBPF_STACK_SIZE).unwrap();
+
+        let mut emu = Emu::new();
+        let entry = emu.add_code(ctx.get_rv_code());
+        let mut run = |pkt: &[u8]| {
+            let skb = SkBuff {
+                data: pkt.as_ptr() as u64,
+                data_end: pkt.as_ptr() as u64 + pkt.len() as u64,
+                len: pkt.len() as u32,
+                ..Default::default()
+            };
+            emu.call(entry, &[&skb as *const SkBuff as u64])
+        };
+        let mut pkt = [0u8; 34];
+        pkt[12] = 0x08;
+        pkt[14] = 0x45;
+        pkt[23] = 6;
+        assert_eq!(run(&pkt), 34 + 20);
+        pkt[23] = 17;
+        assert_eq!(run(&pkt), 0);
+        // out of the packet
+        assert_eq!(run(&pkt[..20]), 0);
+
+        // shifts and NEG are translated to their 32-bit eBPF forms, as linux does
+        let filter = [
+            SockFilter::new(0x01, 0, 0, 33), // ldx #33
+            SockFilter::new(0x80, 0, 0, 0), // ld #len
+            SockFilter::new(0x6c, 0, 0, 0), // lsh x
+            SockFilter::new(0x84, 0, 0, 0), // neg
+            SockFilter::new(0x7c, 0, 0, 0), // rsh x
+            SockFilter::new(0x74, 0, 0, 24), // rsh #24
+            SockFilter::new(0x16, 0, 0, 0), // ret a
+        ];
+        let insns = convert_classic(&filter).unwrap();
+        let ops: std::vec::Vec<u8> = insns.iter().map(|&insn| insn as u8).collect();
+        for &op in [ALU_X_LSH, ALU_K_NEG, ALU_X_RSH, ALU_K_RSH].iter() {
+            assert!(ops.contains(&op), "{:#x}", op);
+        }
+        let mut ctx = JitContext::new(&insns);
+        ctx.set_program_type(ProgramType::SocketFilter);
+        compile(&mut ctx, &HelperRegistry::new(), CBPF_STACK_SIZE).unwrap();
+        let entry = emu.add_code(ctx.get_rv_code());
+        let skb = SkBuff {
+            len: 34,
+            ..Default::default()
+        };
+        // -(34 << 1) >> 1 >> 24
+        assert_eq!(emu.call(entry, &[&skb as *const SkBuff as u64]), 0x7f);
+
+        // ld M[1]; ret a
+        let filter = [SockFilter::new(0x60, 0, 0, 1), SockFilter::new(0x16, 0, 0, 0)];
+        assert_eq!(check_classic(&filter), Err(CbpfError::UninitMemWord { pc: 0, k: 1 }));
+        // div #0; ret a
+        let filter = [SockFilter::new(0x34, 0, 0, 0), SockFilter::new(0x16, 0, 0, 0)];
+        assert_eq!(check_classic(&filter), Err(CbpfError::DivisionByZero { pc: 0 }));
+        // jeq #0, 1, 0; ret a
+        let filter = [SockFilter::new(0x15, 1, 0, 0), SockFilter::new(0x16, 0, 0, 0)];
+        assert_eq!(check_classic(&filter), Err(CbpfError::JumpOutOfRange { pc: 0 }));
+        // ld #1
+        assert_eq!(check_classic(&[SockFilter::new(0x00, 0, 0, 1)]), Err(CbpfError::MissingReturn));
+    }
+
+    #[test]
+    fn seccomp_test() {
+        use crate::cbpf::*;
+        use crate::emu::Emu;
+        use crate::seccomp::*;
+
+        let allowed = [
+            SockFilter::new(0x20, 0, 0, 4), // ld [4]
+            SockFilter::new(0x15, 1, 0, AUDIT_ARCH_RISCV64), // jeq #AUDIT_ARCH_RISCV64, 1, 0
+            SockFilter::new(0x06, 0, 0, SECCOMP_RET_KILL_PROCESS),
+            SockFilter::new(0x20, 0, 0, 0), // ld [0]
+            SockFilter::new(0x15, 0, 1, 64), // jeq #64, 0, 1
+            SockFilter::new(0x06, 0, 0, SECCOMP_RET_ALLOW),
+            SockFilter::new(0x15, 0, 1, 57), // jeq #57, 0, 1
+            SockFilter::new(0x06, 0, 0, SECCOMP_RET_ERRNO | 1),
+            SockFilter::new(0x20, 0, 0, 16), // ld [16]
+            SockFilter::new(0x25, 0, 1, 2), // jgt #2, 0, 1
+            SockFilter::new(0x06, 0, 0, SECCOMP_RET_TRAP | 7),
+            SockFilter::new(0x06, 0, 0, SECCOMP_RET_ALLOW),
+        ];
+        let logged = [
+            SockFilter::new(0x20, 0, 0, 0), // ld [0]
+            SockFilter::new(0x15, 0, 1, 64), // jeq #64, 0, 1
+            SockFilter::new(0x06, 0, 0, SECCOMP_RET_ERRNO | 9),
+            SockFilter::new(0x06, 0, 0, SECCOMP_RET_LOG),
+        ];
+        let desc = seccomp_data_descriptor();
+        let jit = |filter: &[SockFilter]| {
+            let insns = convert_seccomp(filter).unwrap();
+            let mut ctx = JitContext::new(&insns);
+            ctx.set_ctx_descriptor(&desc);
+            compile(&mut ctx, &HelperRegistry::new(), CBPF_STACK_SIZE).unwrap();
+            ctx.get_rv_code().clone()
+        };
+        let (allowed, logged) = (jit(&allowed), jit(&logged));
+
+        let mut emu = Emu::new();
+        let mut filters = SeccompFilters::new();
+        filters.push(emu.add_code(&allowed));
+        filters.push(emu.add_code(&logged));
+        let mut run = |nr, arch, arg0| {
+            let data = SeccompData {
+                nr,
+                arch,
+                instruction_pointer: 0x1000,
+                args: [arg0, 0, 0, 0, 0, 0],
+            };
+            let ret = filters.run_with(&data, |filter, data| emu.call(filter, &[data as u64]));
+            SeccompAction::from_ret(ret)
+        };
+        assert_eq!(run(64, AUDIT_ARCH_RISCV64, 0), SeccompAction::Errno(9));
+        assert_eq!(run(57, AUDIT_ARCH_RISCV64, 0), SeccompAction::Errno(1));
+        assert_eq!(run(100, AUDIT_ARCH_RISCV64, 5), SeccompAction::Trap(7));
+        assert_eq!(run(100, AUDIT_ARCH_RISCV64, 1), SeccompAction::Log);
+        assert_eq!(run(100, 0x4000_003e, 1), SeccompAction::KillProcess);
+
+        // ld [2]; ret a
+        let filter = [SockFilter::new(0x20, 0, 0, 2), SockFilter::new(0x16, 0, 0, 0)];
+        assert_eq!(check_seccomp(&filter), Err(CbpfError::InvalidDataOffset { pc: 0 }));
+        // ldb [0]; ret a
+        let filter = [SockFilter::new(0x30, 0, 0, 0), SockFilter::new(0x16, 0, 0, 0)];
+        assert_eq!(check_seccomp(&filter), Err(CbpfError::InvalidOpcode { pc: 0, code: 0x30 }));
+    }
 }
@@ -0,0 +1,441 @@
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::slice;
+
+use crate::consts::*;
+pub use crate::consts::BPF_F_RDONLY_PROG;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MapType {
+    Hash,
+    Array,
+    ArrayOfMaps,
+    HashOfMaps,
+}
+
+impl MapType {
+    pub fn from_raw(map_type: u32) -> Option<Self> {
+        match map_type {
+            BPF_MAP_TYPE_HASH => Some(MapType::Hash),
+            BPF_MAP_TYPE_ARRAY => Some(MapType::Array),
+            BPF_MAP_TYPE_ARRAY_OF_MAPS => Some(MapType::ArrayOfMaps),
+            BPF_MAP_TYPE_HASH_OF_MAPS => Some(MapType::HashOfMaps),
+            _ => None,
+        }
+    }
+
+    pub fn is_map_in_map(self) -> bool {
+        matches!(self, MapType::ArrayOfMaps | MapType::HashOfMaps)
+    }
+
+    fn is_array(self) -> bool {
+        matches!(self, MapType::Array | MapType::ArrayOfMaps)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MapAttr {
+    pub map_type: MapType,
+    pub key_size: u32,
+    // for map-in-map, this is the size of the map fd passed by the host (4 bytes)
+    pub value_size: u32,
+    pub max_entries: u32,
+    // `BPF_F_RDONLY_PROG` makes values read-only for programs
+    pub map_flags: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MapError {
+    InvalidAttr,
+    InvalidKey,
+    InvalidValue,
+    BadFd,
+    NoEntry,
+    Exists,
+    TooBig,
+    InnerMapMismatch,
+    // a write into a frozen map by the host, or into a `BPF_F_RDONLY_PROG` map by a program
+    ReadOnly,
+}
+
+impl MapError {
+    // negative errno, as returned by helpers to eBPF programs
+    pub fn errno(self) -> i64 {
+        match self {
+            MapError::NoEntry => -ENOENT,
+            MapError::Exists => -EEXIST,
+            MapError::TooBig => -E2BIG,
+            MapError::ReadOnly => -EPERM,
+            _ => -EINVAL,
+        }
+    }
+}
+
+// Values of all map types are preallocated in one buffer, as linux does by default for hash maps,
+// so pointers handed out to programs stay valid and all values of a map form a single region.
+pub struct BpfMap {
+    attr: MapAttr,
+    // template every inner map must match, only set for map-in-map
+    inner_attr: Option<MapAttr>,
+    values: Vec<u8>,
+    // hash maps only, key -> slot
+    index: BTreeMap<Vec<u8>, usize>,
+    free_slots: Vec<usize>,
+    // set by `MapTable::freeze`, after which the host may not change values anymore
+    frozen: bool,
+}
+
+impl BpfMap {
+    fn new(attr: MapAttr, inner_attr: Option<MapAttr>) -> Result<Self, MapError> {
+        if attr.key_size == 0 || attr.value_size == 0 || attr.max_entries == 0 {
+            return Err(MapError::InvalidAttr);
+        }
+        if attr.map_flags & !BPF_F_RDONLY_PROG != 0 {
+            return Err(MapError::InvalidAttr);
+        }
+        if attr.map_type.is_array() && attr.key_size != 4 {
+            return Err(MapError::InvalidAttr);
+        }
+        // like linux, the host refers inner maps by their 4-byte fd
+        if attr.map_type.is_map_in_map() && attr.value_size != 4 {
+            return Err(MapError::InvalidAttr);
+        }
+
+        let mut map = Self {
+            attr,
+            inner_attr,
+            values: Vec::new(),
+            index: BTreeMap::new(),
+            free_slots: Vec::new(),
+            frozen: false,
+        };
+        map.values = vec![0; map.slot_size() * attr.max_entries as usize];
+        if !attr.map_type.is_array() {
+            map.free_slots = (0..attr.max_entries as usize).rev().collect();
+        }
+        Ok(map)
+    }
+
+    pub fn attr(&self) -> &MapAttr {
+        &self.attr
+    }
+
+    pub fn inner_attr(&self) -> Option<&MapAttr> {
+        self.inner_attr.as_ref()
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.frozen
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.attr.map_flags & BPF_F_RDONLY_PROG != 0
+    }
+
+    // address and length of the buffer holding all values
+    pub fn value_region(&self) -> (u64, usize) {
+        (self.values.as_ptr() as u64, self.values.len())
+    }
+
+    // Addresses from which a string ends within the values, up to their last NUL, if they are constant,
+    // i.e. the map is frozen and read-only for programs
+    pub fn const_str_region(&self) -> Option<(u64, u64)> {
+        if !self.frozen || !self.is_read_only() || self.attr.map_type.is_map_in_map() {
+            return None;
+        }
+        let last = self.values.iter().rposition(|&b| b == 0)?;
+        let start = self.values.as_ptr() as u64;
+        Some((start, start + last as u64 + 1))
+    }
+
+    // Whether every value holds a NUL at or after `off`, so a string starting there ends within it.
+    // Only meaningful once the values are constant, see `MapTable::freeze`.
+    pub fn has_nul_from(&self, off: usize) -> bool {
+        let size = self.slot_size();
+        off < size && self.values.chunks(size).all(|value| value[off..].contains(&0))
+    }
+
+    // size of a stored value, map-in-map stores pointers to inner maps
+    fn slot_size(&self) -> usize {
+        if self.attr.map_type.is_map_in_map() {
+            8
+        } else {
+            self.attr.value_size as usize
+        }
+    }
+
+    fn slot(&self, key: &[u8]) -> Result<usize, MapError> {
+        if !self.attr.map_type.is_array() {
+            return self.index.get(key).copied().ok_or(MapError::NoEntry);
+        }
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(key);
+        let index = u32::from_ne_bytes(buf);
+        if index >= self.attr.max_entries {
+            return Err(MapError::TooBig);
+        }
+        Ok(index as usize)
+    }
+
+    fn slot_mut(&mut self, slot: usize) -> &mut [u8] {
+        let slot_size = self.slot_size();
+        &mut self.values[slot * slot_size..(slot + 1) * slot_size]
+    }
+
+    pub fn lookup(&mut self, key: &[u8]) -> Option<&mut [u8]> {
+        if key.len() != self.attr.key_size as usize {
+            return None;
+        }
+        let slot = self.slot(key).ok()?;
+        Some(self.slot_mut(slot))
+    }
+
+    pub fn update(&mut self, key: &[u8], value: &[u8], flags: u64) -> Result<(), MapError> {
+        if key.len() != self.attr.key_size as usize {
+            return Err(MapError::InvalidKey);
+        }
+        if value.len() != self.slot_size() {
+            return Err(MapError::InvalidValue);
+        }
+        if flags > BPF_EXIST {
+            return Err(MapError::InvalidAttr);
+        }
+
+        let slot = match self.slot(key) {
+            // array elements always exist
+            Ok(_) if flags == BPF_NOEXIST => return Err(MapError::Exists),
+            Ok(slot) => slot,
+            Err(MapError::NoEntry) if flags == BPF_EXIST => return Err(MapError::NoEntry),
+            Err(MapError::NoEntry) => {
+                let slot = self.free_slots.pop().ok_or(MapError::TooBig)?;
+                self.index.insert(key.to_vec(), slot);
+                slot
+            }
+            Err(e) => return Err(e),
+        };
+        self.slot_mut(slot).copy_from_slice(value);
+        Ok(())
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<(), MapError> {
+        if key.len() != self.attr.key_size as usize {
+            return Err(MapError::InvalidKey);
+        }
+        let slot = self.slot(key)?;
+        if self.attr.map_type.is_array() {
+            // only map-in-map arrays have empty slots
+            if !self.attr.map_type.is_map_in_map() {
+                return Err(MapError::InvalidAttr);
+            }
+        } else {
+            self.index.remove(key);
+            self.free_slots.push(slot);
+        }
+        self.slot_mut(slot).fill(0);
+        Ok(())
+    }
+
+    // inner map stored in a map-in-map slot, null if the slot is empty
+    fn inner_map(slot: &[u8]) -> u64 {
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(slot);
+        u64::from_ne_bytes(buf)
+    }
+}
+
+// Host side registry of maps, indexed by fd.
+// Maps are never freed before the table, so pointers embedded into jitted code
+// (see `BPF_PSEUDO_MAP_FD`) and into map-in-map slots stay valid.
+pub struct MapTable {
+    maps: Vec<*mut BpfMap>,
+}
+
+impl MapTable {
+    pub fn new() -> Self {
+        Self { maps: Vec::new() }
+    }
+
+    fn insert(&mut self, map: BpfMap) -> u32 {
+        self.maps.push(Box::into_raw(Box::new(map)));
+        (self.maps.len() - 1) as u32
+    }
+
+    pub fn create(&mut self, attr: MapAttr) -> Result<u32, MapError> {
+        if attr.map_type.is_map_in_map() {
+            return Err(MapError::InvalidAttr);
+        }
+        Ok(self.insert(BpfMap::new(attr, None)?))
+    }
+
+    // create an array-of-maps or hash-of-maps, `inner_fd` is the template of inner maps
+    pub fn create_map_in_map(&mut self, attr: MapAttr, inner_fd: u32) -> Result<u32, MapError> {
+        if !attr.map_type.is_map_in_map() {
+            return Err(MapError::InvalidAttr);
+        }
+        let inner_attr = *self.get(inner_fd).ok_or(MapError::BadFd)?.attr();
+        // nested map-in-map is not supported, as linux does
+        if inner_attr.map_type.is_map_in_map() {
+            return Err(MapError::InvalidAttr);
+        }
+        Ok(self.insert(BpfMap::new(attr, Some(inner_attr))?))
+    }
+
+    pub fn get(&self, fd: u32) -> Option<&BpfMap> {
+        self.maps.get(fd as usize).map(|&map| unsafe { &*map })
+    }
+
+    pub fn get_mut(&mut self, fd: u32) -> Option<&mut BpfMap> {
+        self.maps.get(fd as usize).map(|&map| unsafe { &mut *map })
+    }
+
+    // address of the map, as loaded by `LD_IMM_DW` with `BPF_PSEUDO_MAP_FD`
+    pub fn map_ptr(&self, fd: u32) -> Option<u64> {
+        self.maps.get(fd as usize).map(|&map| map as u64)
+    }
+
+    // every map along with its address
+    pub fn maps(&self) -> impl Iterator<Item = (u64, &BpfMap)> + '_ {
+        self.maps.iter().map(|&map| (map as u64, unsafe { &*map }))
+    }
+
+    // Stop the host from changing the values of a map, as `BPF_MAP_FREEZE` does.
+    // Along with `BPF_F_RDONLY_PROG`, values are constant, which the verifier requires of format strings.
+    // Values returned by `lookup_elem` must not be written once the map is frozen.
+    pub fn freeze(&mut self, fd: u32) -> Result<(), MapError> {
+        self.get_mut(fd).ok_or(MapError::BadFd)?.frozen = true;
+        Ok(())
+    }
+
+    pub fn lookup_elem(&mut self, fd: u32, key: &[u8]) -> Result<&mut [u8], MapError> {
+        let map = self.get_mut(fd).ok_or(MapError::BadFd)?;
+        map.lookup(key).ok_or(MapError::NoEntry)
+    }
+
+    // for map-in-map, `value` is the fd of the inner map and it is checked against the template
+    pub fn update_elem(&mut self, fd: u32, key: &[u8], value: &[u8], flags: u64) -> Result<(), MapError> {
+        let map = self.get(fd).ok_or(MapError::BadFd)?;
+        if map.frozen {
+            return Err(MapError::ReadOnly);
+        }
+        let attr = *map.attr();
+        if !attr.map_type.is_map_in_map() {
+            return self.get_mut(fd).unwrap().update(key, value, flags);
+        }
+
+        if value.len() != 4 {
+            return Err(MapError::InvalidValue);
+        }
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(value);
+        let inner_fd = u32::from_ne_bytes(buf);
+        let inner = self.get(inner_fd).ok_or(MapError::BadFd)?;
+        if Some(inner.attr()) != self.get(fd).unwrap().inner_attr() {
+            return Err(MapError::InnerMapMismatch);
+        }
+        let inner_ptr = self.map_ptr(inner_fd).unwrap();
+        self.get_mut(fd).unwrap().update(key, &inner_ptr.to_ne_bytes(), flags)
+    }
+
+    pub fn delete_elem(&mut self, fd: u32, key: &[u8]) -> Result<(), MapError> {
+        let map = self.get_mut(fd).ok_or(MapError::BadFd)?;
+        if map.frozen {
+            return Err(MapError::ReadOnly);
+        }
+        map.delete(key)
+    }
+
+    // Regions holding values which programs may access directly, and whether they may write them.
+    // Map-in-map is excluded.
+    pub fn value_regions(&self) -> impl Iterator<Item = (u64, usize, bool)> + '_ {
+        self.maps
+            .iter()
+            .map(|&map| unsafe { &*map })
+            .filter(|map| !map.attr.map_type.is_map_in_map())
+            .map(|map| {
+                let (start, len) = map.value_region();
+                (start, len, !map.is_read_only())
+            })
+    }
+}
+
+impl Default for MapTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for MapTable {
+    fn drop(&mut self) {
+        for &map in &self.maps {
+            drop(unsafe { Box::from_raw(map) });
+        }
+    }
+}
+
+// Map helpers called by jitted code, `map` is a pointer obtained from `MapTable::map_ptr`.
+
+/// bpf_map_lookup_elem, helper #1.
+/// Lookups in map-in-map return the inner map, which can be passed to other map helpers.
+///
+/// # Safety
+///
+/// `map` must be a live map obtained from `MapTable::map_ptr`, or an inner map returned by a lookup,
+/// and `key` must point to `key_size` readable bytes.
+pub unsafe extern "C" fn bpf_map_lookup_elem(map: u64, key: u64) -> u64 {
+    let map = &mut *(map as *mut BpfMap);
+    let key = slice::from_raw_parts(key as *const u8, map.attr.key_size as usize);
+    let is_map_in_map = map.attr.map_type.is_map_in_map();
+    match map.lookup(key) {
+        Some(slot) if is_map_in_map => BpfMap::inner_map(slot),
+        Some(value) => value.as_mut_ptr() as u64,
+        None => 0,
+    }
+}
+
+/// bpf_map_update_elem, helper #2.
+///
+/// # Safety
+///
+/// `map` must be a live map obtained from `MapTable::map_ptr`, or an inner map returned by a lookup,
+/// `key` must point to `key_size` readable bytes and `value` to `value_size` readable bytes.
+pub unsafe extern "C" fn bpf_map_update_elem(map: u64, key: u64, value: u64, flags: u64) -> u64 {
+    let map = &mut *(map as *mut BpfMap);
+    // inner maps can only be replaced by the host
+    if map.attr.map_type.is_map_in_map() {
+        return MapError::InvalidAttr.errno() as u64;
+    }
+    if map.is_read_only() {
+        return MapError::ReadOnly.errno() as u64;
+    }
+    let key = slice::from_raw_parts(key as *const u8, map.attr.key_size as usize);
+    let value = slice::from_raw_parts(value as *const u8, map.attr.value_size as usize);
+    match map.update(key, value, flags) {
+        Ok(()) => 0,
+        Err(e) => e.errno() as u64,
+    }
+}
+
+/// bpf_map_delete_elem, helper #3.
+///
+/// # Safety
+///
+/// `map` must be a live map obtained from `MapTable::map_ptr`, or an inner map returned by a lookup,
+/// and `key` must point to `key_size` readable bytes.
+pub unsafe extern "C" fn bpf_map_delete_elem(map: u64, key: u64) -> u64 {
+    let map = &mut *(map as *mut BpfMap);
+    if map.attr.map_type.is_map_in_map() {
+        return MapError::InvalidAttr.errno() as u64;
+    }
+    if map.is_read_only() {
+        return MapError::ReadOnly.errno() as u64;
+    }
+    let key = slice::from_raw_parts(key as *const u8, map.attr.key_size as usize);
+    match map.delete(key) {
+        Ok(()) => 0,
+        Err(e) => e.errno() as u64,
+    }
+}
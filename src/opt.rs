@@ -0,0 +1,758 @@
+extern crate alloc;
+
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::consts::*;
+
+// How much the program is optimized before jitting.
+// `O0` jits instructions one by one, which is the fastest to compile.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OptLevel {
+    #[default]
+    O0,
+    // constant folding and propagation, branch folding and removal of unreachable code
+    O1,
+    // copy propagation and dead code elimination as well
+    O2,
+}
+
+const NREGS: usize = BPF_MAX_REGS;
+
+fn insn(op: u32, dst: u8, src: u8, off: i16, imm: i32) -> u64 {
+    op as u8 as u64 | (dst as u64) << 8 | (src as u64) << 12 | (off as u16 as u64) << 16 | (imm as u32 as u64) << 32
+}
+
+fn reg_bit(reg: u8) -> u16 {
+    1 << reg
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Operand {
+    Reg(u8),
+    Imm(i32),
+}
+
+impl Operand {
+    fn reg_bit(self) -> u16 {
+        match self {
+            Operand::Reg(reg) => reg_bit(reg),
+            Operand::Imm(_) => 0,
+        }
+    }
+}
+
+// an instruction of a block, close to eBPF
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Inst {
+    // dst = dst <code> src, or dst = src for BPF_MOV
+    Alu {
+        code: u32,
+        is64: bool,
+        dst: u8,
+        src: Operand,
+    },
+    // LD_IMM_DW of a constant
+    LoadImm64 {
+        dst: u8,
+        imm: u64,
+    },
+    // anything else, as it is, with the second half of LD_IMM_DW of a map
+    Other {
+        insn: u64,
+        next: Option<u64>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Term {
+    Goto(usize),
+    // to `taken` if dst <code> src, to `not_taken` otherwise
+    Branch {
+        code: u32,
+        is64: bool,
+        dst: u8,
+        src: Operand,
+        taken: usize,
+        not_taken: usize,
+    },
+    Exit,
+}
+
+impl Term {
+    fn successors(&self) -> [Option<usize>; 2] {
+        match *self {
+            Term::Goto(block) => [Some(block), None],
+            Term::Branch { taken, not_taken, .. } => [Some(taken), Some(not_taken)],
+            Term::Exit => [None, None],
+        }
+    }
+}
+
+// A basic block, each instruction along with the pc of the eBPF instruction it comes from.
+#[derive(Clone, Debug)]
+struct Block {
+    insts: Vec<(Inst, usize)>,
+    term: Term,
+    term_pc: usize,
+}
+
+// ALU ops lifted into `Inst::Alu`, others being kept as they are
+fn is_lifted_alu(code: u32) -> bool {
+    matches!(
+        code,
+        BPF_ADD
+            | BPF_SUB
+            | BPF_MUL
+            | BPF_DIV
+            | BPF_OR
+            | BPF_AND
+            | BPF_LSH
+            | BPF_RSH
+            | BPF_MOD
+            | BPF_XOR
+            | BPF_MOV
+            | BPF_ARSH
+    )
+}
+
+fn is_cond_jump(code: u32) -> bool {
+    !matches!(code, BPF_JA | BPF_CALL | BPF_EXIT)
+}
+
+// registers read and written by an instruction kept as it is
+fn other_uses_defs(insn: u64) -> (u16, u16) {
+    const CALLER_SAVED: u16 = 0x3f;
+    let op = insn as u8;
+    let dst = ((insn >> 8) & 0xf) as u8;
+    let src = ((insn >> 12) & 0xf) as u8;
+    let src_bit = if (op & 8) != 0 { reg_bit(src) } else { 0 };
+    match (op & 0b111) as u32 {
+        BPF_ALU | BPF_ALU64 => (reg_bit(dst) | src_bit, reg_bit(dst)),
+        BPF_JMP if op == JMP_K_CALL => (0x3e, CALLER_SAVED),
+        BPF_LDX => (reg_bit(src), reg_bit(dst)),
+        BPF_ST => (reg_bit(dst), 0),
+        BPF_STX if (op & 0xe0) as u32 == BPF_MEM => (reg_bit(dst) | reg_bit(src), 0),
+        // atomics may write back into src
+        BPF_STX => (reg_bit(dst) | reg_bit(src), reg_bit(src) | reg_bit(BPF_REG_R0)),
+        _ if op == LD_IMM_DW => (0, reg_bit(dst)),
+        // LD_ABS / LD_IND
+        BPF_LD => {
+            let index = if (op & 0xe0) as u32 == BPF_IND { reg_bit(src) } else { 0 };
+            (reg_bit(BPF_REG_R6) | index, CALLER_SAVED)
+        }
+        _ => (0xffff, 0xffff),
+    }
+}
+
+impl Inst {
+    fn uses_defs(&self) -> (u16, u16) {
+        match *self {
+            Inst::Alu { code, dst, src, .. } => {
+                let dst_bit = if code == BPF_MOV { 0 } else { reg_bit(dst) };
+                (dst_bit | src.reg_bit(), reg_bit(dst))
+            }
+            Inst::LoadImm64 { dst, .. } => (0, reg_bit(dst)),
+            Inst::Other { insn, .. } => other_uses_defs(insn),
+        }
+    }
+
+    // instructions without side effects, which may be removed if their result is not used
+    fn is_pure(&self) -> bool {
+        !matches!(self, Inst::Other { .. })
+    }
+
+    fn dst(&self) -> Option<u8> {
+        match *self {
+            Inst::Alu { dst, .. } | Inst::LoadImm64 { dst, .. } => Some(dst),
+            Inst::Other { .. } => None,
+        }
+    }
+}
+
+impl Term {
+    fn uses(&self) -> u16 {
+        match *self {
+            Term::Branch { dst, src, .. } => reg_bit(dst) | src.reg_bit(),
+            Term::Exit => reg_bit(BPF_REG_R0),
+            Term::Goto(_) => 0,
+        }
+    }
+}
+
+// Split the program into basic blocks. Returns None if control may leave the program, or reach
+// the second half of LD_IMM_DW.
+fn build_blocks(insns: &[u64]) -> Option<Vec<Block>> {
+    let len = insns.len();
+    let mut leaders = vec![false; len + 1];
+    let mut inside = vec![false; len];
+    leaders[0] = true;
+    let mut pc = 0;
+    while pc < len {
+        let insn = insns[pc];
+        let op = insn as u8;
+        if op == LD_IMM_DW {
+            *inside.get_mut(pc + 1)? = true;
+            pc += 2;
+            continue;
+        }
+        let class = (op & 0b111) as u32;
+        let code = (op & 0xf0) as u32;
+        if (class == BPF_JMP || class == BPF_JMP32) && code != BPF_CALL {
+            if code != BPF_EXIT {
+                let target = pc as isize + 1 + (insn >> 16) as i16 as isize;
+                if target < 0 || target as usize >= len {
+                    return None;
+                }
+                leaders[target as usize] = true;
+            }
+            leaders[pc + 1] = true;
+        }
+        pc += 1;
+    }
+    if (0..len).any(|pc| leaders[pc] && inside[pc]) {
+        return None;
+    }
+
+    let starts: Vec<usize> = (0..len).filter(|&pc| leaders[pc]).collect();
+    let block_of: BTreeMap<usize, usize> = starts.iter().enumerate().map(|(i, &pc)| (pc, i)).collect();
+    let mut blocks = Vec::with_capacity(starts.len());
+    for (i, &start) in starts.iter().enumerate() {
+        let end = starts.get(i + 1).copied().unwrap_or(len);
+        let mut block = Block {
+            insts: Vec::new(),
+            term: Term::Exit,
+            term_pc: end - 1,
+        };
+        let mut pc = start;
+        let mut term = None;
+        while pc < end {
+            let insn = insns[pc];
+            let op = insn as u8;
+            let dst = ((insn >> 8) & 0xf) as u8;
+            let src = ((insn >> 12) & 0xf) as u8;
+            let imm = (insn >> 32) as i32;
+            let class = (op & 0b111) as u32;
+            let code = (op & 0xf0) as u32;
+            let operand = if (op & 8) != 0 {
+                Operand::Reg(src)
+            } else {
+                Operand::Imm(imm)
+            };
+            if op == LD_IMM_DW {
+                let next = insns[pc + 1];
+                let inst = if src == 0 {
+                    let imm = (imm as u32 as u64) | ((next >> 32) << 32);
+                    Inst::LoadImm64 { dst, imm }
+                } else {
+                    Inst::Other { insn, next: Some(next) }
+                };
+                block.insts.push((inst, pc));
+                pc += 2;
+                continue;
+            }
+            if (class == BPF_ALU || class == BPF_ALU64) && is_lifted_alu(code) {
+                let is64 = class == BPF_ALU64;
+                block.insts.push((
+                    Inst::Alu {
+                        code,
+                        is64,
+                        dst,
+                        src: operand,
+                    },
+                    pc,
+                ));
+            } else if (class == BPF_JMP || class == BPF_JMP32) && code != BPF_CALL {
+                let target = (pc as isize + 1 + (insn >> 16) as i16 as isize) as usize;
+                term = Some(match code {
+                    BPF_EXIT => Term::Exit,
+                    BPF_JA => Term::Goto(block_of[&target]),
+                    _ if is_cond_jump(code) => Term::Branch {
+                        code,
+                        is64: class == BPF_JMP,
+                        dst,
+                        src: operand,
+                        taken: block_of[&target],
+                        not_taken: *block_of.get(&(pc + 1))?,
+                    },
+                    _ => return None,
+                });
+            } else {
+                block.insts.push((Inst::Other { insn, next: None }, pc));
+            }
+            pc += 1;
+        }
+        block.term = match term {
+            Some(term) => term,
+            // falls into the next block, there must be one
+            None => Term::Goto(*block_of.get(&end)?),
+        };
+        blocks.push(block);
+    }
+    Some(blocks)
+}
+
+// value of an operand in an op of `is64`, 32-bit ops reading the lower halves
+fn operand_value(src: Operand, regs: &[Option<u64>; NREGS], is64: bool) -> Option<u64> {
+    let v = match src {
+        Operand::Reg(reg) => regs[reg as usize]?,
+        Operand::Imm(imm) => imm as i64 as u64,
+    };
+    Some(if is64 { v } else { v as u32 as u64 })
+}
+
+// Evaluate an ALU op as eBPF does, 32-bit results being zero-extended.
+// Division and modulo by zero are left to the JIT.
+fn eval_alu(code: u32, is64: bool, a: u64, b: u64) -> Option<u64> {
+    let (sh32, sh64) = ((b & 31) as u32, (b & 63) as u32);
+    let r = match code {
+        BPF_ADD => a.wrapping_add(b),
+        BPF_SUB => a.wrapping_sub(b),
+        BPF_MUL => a.wrapping_mul(b),
+        BPF_DIV | BPF_MOD if b == 0 => return None,
+        BPF_DIV => a / b,
+        BPF_MOD => a % b,
+        BPF_OR => a | b,
+        BPF_AND => a & b,
+        BPF_XOR => a ^ b,
+        BPF_MOV => b,
+        BPF_LSH if is64 => a << sh64,
+        BPF_LSH => ((a as u32) << sh32) as u64,
+        BPF_RSH if is64 => a >> sh64,
+        BPF_RSH => ((a as u32) >> sh32) as u64,
+        BPF_ARSH if is64 => ((a as i64) >> sh64) as u64,
+        BPF_ARSH => ((a as i32) >> sh32) as u32 as u64,
+        _ => return None,
+    };
+    Some(if is64 { r } else { r as u32 as u64 })
+}
+
+fn eval_cond(code: u32, is64: bool, a: u64, b: u64) -> Option<bool> {
+    let (sa, sb) = if is64 {
+        (a as i64, b as i64)
+    } else {
+        (a as i32 as i64, b as i32 as i64)
+    };
+    Some(match code {
+        BPF_JEQ => a == b,
+        BPF_JNE => a != b,
+        BPF_JGT => a > b,
+        BPF_JGE => a >= b,
+        BPF_JLT => a < b,
+        BPF_JLE => a <= b,
+        BPF_JSGT => sa > sb,
+        BPF_JSGE => sa >= sb,
+        BPF_JSLT => sa < sb,
+        BPF_JSLE => sa <= sb,
+        BPF_JSET => a & b != 0,
+        _ => return None,
+    })
+}
+
+// the immediate of a K form giving `v` in an op of `is64`, if any
+fn as_imm(v: u64, is64: bool) -> Option<i32> {
+    if !is64 {
+        Some(v as u32 as i32)
+    } else if v as i64 == v as i32 as i64 {
+        Some(v as i32)
+    } else {
+        None
+    }
+}
+
+// the cheapest instruction setting `dst` to `v`
+fn materialize(dst: u8, v: u64) -> Inst {
+    if let Some(imm) = as_imm(v, true) {
+        Inst::Alu {
+            code: BPF_MOV,
+            is64: true,
+            dst,
+            src: Operand::Imm(imm),
+        }
+    } else if v >> 32 == 0 {
+        Inst::Alu {
+            code: BPF_MOV,
+            is64: false,
+            dst,
+            src: Operand::Imm(v as u32 as i32),
+        }
+    } else {
+        Inst::LoadImm64 { dst, imm: v }
+    }
+}
+
+type Consts = [Option<u64>; NREGS];
+
+fn transfer_consts(inst: &Inst, regs: &mut Consts) {
+    match *inst {
+        Inst::Alu { code, is64, dst, src } => {
+            let a = regs[dst as usize].map(|a| if is64 { a } else { a as u32 as u64 });
+            let b = operand_value(src, regs, is64);
+            regs[dst as usize] = match (a, b) {
+                (_, Some(b)) if code == BPF_MOV => eval_alu(code, is64, 0, b),
+                (Some(a), Some(b)) => eval_alu(code, is64, a, b),
+                _ => None,
+            };
+        }
+        Inst::LoadImm64 { dst, imm } => regs[dst as usize] = Some(imm),
+        Inst::Other { .. } => {
+            let (_, defs) = inst.uses_defs();
+            for (reg, value) in regs.iter_mut().enumerate() {
+                if defs & reg_bit(reg as u8) != 0 {
+                    *value = None;
+                }
+            }
+        }
+    }
+}
+
+// Propagate and fold constants, turning register operands known to be constant into immediates
+// and branches on known conditions into gotos.
+fn fold_constants(blocks: &mut [Block]) {
+    // registers known at the start of each block, None if not reached yet
+    let mut entry: Vec<Option<Consts>> = vec![None; blocks.len()];
+    entry[0] = Some([None; NREGS]);
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for i in 0..blocks.len() {
+            let mut regs = match entry[i] {
+                Some(regs) => regs,
+                None => continue,
+            };
+            for (inst, _) in &blocks[i].insts {
+                transfer_consts(inst, &mut regs);
+            }
+            for succ in blocks[i].term.successors().iter().flatten() {
+                let merged = match entry[*succ] {
+                    None => regs,
+                    Some(old) => {
+                        let mut merged = old;
+                        for (m, r) in merged.iter_mut().zip(regs.iter()) {
+                            if *m != *r {
+                                *m = None;
+                            }
+                        }
+                        merged
+                    }
+                };
+                if entry[*succ] != Some(merged) {
+                    entry[*succ] = Some(merged);
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    for (block, entry) in blocks.iter_mut().zip(entry) {
+        let mut regs = match entry {
+            Some(regs) => regs,
+            None => continue,
+        };
+        for (inst, _) in block.insts.iter_mut() {
+            if let Inst::Alu { code, is64, dst, src } = *inst {
+                let a = regs[dst as usize].map(|a| if is64 { a } else { a as u32 as u64 });
+                let b = operand_value(src, &regs, is64);
+                let result = match (a, b) {
+                    (_, Some(b)) if code == BPF_MOV => eval_alu(code, is64, 0, b),
+                    (Some(a), Some(b)) => eval_alu(code, is64, a, b),
+                    _ => None,
+                };
+                if let Some(v) = result {
+                    *inst = materialize(dst, v);
+                } else if let (Operand::Reg(_), Some(b)) = (src, b) {
+                    // shifts by a register only take the low bits of the amount, those by an immediate all of it
+                    let b = match code {
+                        BPF_LSH | BPF_RSH | BPF_ARSH if is64 => b & 63,
+                        BPF_LSH | BPF_RSH | BPF_ARSH => b & 31,
+                        _ => b,
+                    };
+                    if let Some(imm) = as_imm(b, is64) {
+                        *inst = Inst::Alu {
+                            code,
+                            is64,
+                            dst,
+                            src: Operand::Imm(imm),
+                        };
+                    }
+                }
+            }
+            transfer_consts(inst, &mut regs);
+        }
+        if let Term::Branch {
+            code,
+            is64,
+            dst,
+            src,
+            taken,
+            not_taken,
+        } = block.term
+        {
+            let a = regs[dst as usize].map(|a| if is64 { a } else { a as u32 as u64 });
+            let b = operand_value(src, &regs, is64);
+            block.term = match (a, b) {
+                (Some(a), Some(b)) => match eval_cond(code, is64, a, b) {
+                    Some(true) => Term::Goto(taken),
+                    Some(false) => Term::Goto(not_taken),
+                    None => block.term,
+                },
+                (_, Some(b)) if matches!(src, Operand::Reg(_)) => match as_imm(b, is64) {
+                    Some(imm) => Term::Branch {
+                        code,
+                        is64,
+                        dst,
+                        src: Operand::Imm(imm),
+                        taken,
+                        not_taken,
+                    },
+                    None => block.term,
+                },
+                _ => block.term,
+            };
+        }
+    }
+}
+
+// rewrite the register fields of `insn` read by it through `map`
+fn rename_other(insn: u64, map: impl Fn(u8) -> u8) -> u64 {
+    let op = insn as u8;
+    let dst = ((insn >> 8) & 0xf) as u8;
+    let src = ((insn >> 12) & 0xf) as u8;
+    let (dst, src) = match (op & 0b111) as u32 {
+        BPF_LDX => (dst, map(src)),
+        BPF_ST => (map(dst), src),
+        BPF_STX if (op & 0xe0) as u32 == BPF_MEM => (map(dst), map(src)),
+        _ => (dst, src),
+    };
+    (insn & !0xff00) | (dst as u64) << 8 | (src as u64) << 12
+}
+
+// Within each block, read the source of `mov dst, src` instead of its copy while both are unchanged.
+fn propagate_copies(blocks: &mut [Block]) {
+    for block in blocks.iter_mut() {
+        let mut copy_of: [Option<u8>; NREGS] = [None; NREGS];
+        for (inst, _) in block.insts.iter_mut() {
+            let resolve = |reg: u8| copy_of[reg as usize].unwrap_or(reg);
+            match inst {
+                Inst::Alu {
+                    src: Operand::Reg(reg), ..
+                } => *reg = resolve(*reg),
+                Inst::Other { insn, next: None } => *insn = rename_other(*insn, resolve),
+                _ => {}
+            }
+            let (_, defs) = inst.uses_defs();
+            for (reg, copy) in copy_of.iter_mut().enumerate() {
+                if defs & reg_bit(reg as u8) != 0 || copy.is_some_and(|src| defs & reg_bit(src) != 0) {
+                    *copy = None;
+                }
+            }
+            if let Inst::Alu {
+                code: BPF_MOV,
+                is64: true,
+                dst,
+                src: Operand::Reg(src),
+            } = *inst
+            {
+                if dst != src {
+                    copy_of[dst as usize] = Some(src);
+                }
+            }
+        }
+        if let Term::Branch { dst, src, .. } = &mut block.term {
+            *dst = copy_of[*dst as usize].unwrap_or(*dst);
+            if let Operand::Reg(reg) = src {
+                *reg = copy_of[*reg as usize].unwrap_or(*reg);
+            }
+        }
+    }
+}
+
+// Remove pure instructions whose result is never read, until there is none.
+fn eliminate_dead_code(blocks: &mut [Block]) {
+    loop {
+        let mut live_in = vec![0u16; blocks.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for i in (0..blocks.len()).rev() {
+                let block = &blocks[i];
+                let mut live = block
+                    .term
+                    .successors()
+                    .iter()
+                    .flatten()
+                    .fold(0, |live, &succ| live | live_in[succ]);
+                live |= block.term.uses();
+                for (inst, _) in block.insts.iter().rev() {
+                    let (uses, defs) = inst.uses_defs();
+                    live = (live & !defs) | uses;
+                }
+                if live != live_in[i] {
+                    live_in[i] = live;
+                    changed = true;
+                }
+            }
+        }
+
+        let mut removed = false;
+        for block in blocks.iter_mut() {
+            let live_out = block
+                .term
+                .successors()
+                .iter()
+                .flatten()
+                .fold(0, |live, &succ| live | live_in[succ]);
+            let mut live = live_out | block.term.uses();
+            let mut keep = vec![true; block.insts.len()];
+            for (j, (inst, _)) in block.insts.iter().enumerate().rev() {
+                if inst.is_pure() && inst.dst().is_some_and(|dst| live & reg_bit(dst) == 0) {
+                    keep[j] = false;
+                    removed = true;
+                    continue;
+                }
+                let (uses, defs) = inst.uses_defs();
+                live = (live & !defs) | uses;
+            }
+            let mut keep = keep.into_iter();
+            block.insts.retain(|_| keep.next().unwrap_or(true));
+        }
+        if !removed {
+            return;
+        }
+    }
+}
+
+// blocks reachable from the entry
+fn reachable(blocks: &[Block]) -> Vec<bool> {
+    let mut seen = vec![false; blocks.len()];
+    let mut stack = vec![0];
+    while let Some(i) = stack.pop() {
+        if seen[i] {
+            continue;
+        }
+        seen[i] = true;
+        stack.extend(blocks[i].term.successors().iter().flatten());
+    }
+    seen
+}
+
+fn lower_inst(inst: &Inst, out: &mut Vec<u64>) {
+    match *inst {
+        Inst::Alu { code, is64, dst, src } => {
+            let class = if is64 { BPF_ALU64 } else { BPF_ALU };
+            out.push(match src {
+                Operand::Reg(src) => insn(class | BPF_X | code, dst, src, 0, 0),
+                Operand::Imm(imm) => insn(class | BPF_K | code, dst, 0, 0, imm),
+            });
+        }
+        Inst::LoadImm64 { dst, imm } => {
+            out.push(insn(LD_IMM_DW as u32, dst, 0, 0, imm as i32));
+            out.push(insn(0, 0, 0, 0, (imm >> 32) as i32));
+        }
+        Inst::Other { insn, next } => {
+            out.push(insn);
+            out.extend(next);
+        }
+    }
+}
+
+// Lay out reachable blocks in their original order, and lower them back to eBPF.
+// Returns None if a jump does not fit 16 bits.
+fn lower(blocks: &[Block]) -> Option<(Vec<u64>, Vec<usize>)> {
+    let order: Vec<usize> = reachable(blocks)
+        .iter()
+        .enumerate()
+        .filter_map(|(i, &seen)| if seen { Some(i) } else { None })
+        .collect();
+    let next_of = |k: usize| order.get(k + 1).copied();
+
+    // size of each block once lowered, gotos to the next block being dropped
+    let mut starts = BTreeMap::new();
+    let mut size = 0;
+    for (k, &i) in order.iter().enumerate() {
+        starts.insert(i, size);
+        let block = &blocks[i];
+        let insts: usize = block
+            .insts
+            .iter()
+            .map(|(inst, _)| match inst {
+                Inst::LoadImm64 { .. } | Inst::Other { next: Some(_), .. } => 2,
+                _ => 1,
+            })
+            .sum();
+        let term = match block.term {
+            Term::Goto(t) if next_of(k) == Some(t) => 0,
+            Term::Branch { not_taken, .. } if next_of(k) != Some(not_taken) => 2,
+            _ => 1,
+        };
+        size += insts + term;
+    }
+
+    let mut out = Vec::with_capacity(size);
+    let mut origin = Vec::with_capacity(size);
+    for (k, &i) in order.iter().enumerate() {
+        let block = &blocks[i];
+        for (inst, pc) in &block.insts {
+            let len = out.len();
+            lower_inst(inst, &mut out);
+            origin.extend((0..out.len() - len).map(|j| pc + j));
+        }
+        let off = |from: usize, to: usize| -> Option<i16> {
+            let off = starts[&to] as isize - from as isize - 1;
+            if off as i16 as isize == off {
+                Some(off as i16)
+            } else {
+                None
+            }
+        };
+        match block.term {
+            Term::Goto(t) if next_of(k) == Some(t) => {}
+            Term::Goto(t) => {
+                out.push(insn(BPF_JMP | BPF_JA, 0, 0, off(out.len(), t)?, 0));
+                origin.push(block.term_pc);
+            }
+            Term::Exit => {
+                out.push(insn(BPF_JMP | BPF_EXIT, 0, 0, 0, 0));
+                origin.push(block.term_pc);
+            }
+            Term::Branch {
+                code,
+                is64,
+                dst,
+                src,
+                taken,
+                not_taken,
+            } => {
+                let class = if is64 { BPF_JMP } else { BPF_JMP32 };
+                let off_taken = off(out.len(), taken)?;
+                out.push(match src {
+                    Operand::Reg(src) => insn(class | BPF_X | code, dst, src, off_taken, 0),
+                    Operand::Imm(imm) => insn(class | BPF_K | code, dst, 0, off_taken, imm),
+                });
+                origin.push(block.term_pc);
+                if next_of(k) != Some(not_taken) {
+                    out.push(insn(BPF_JMP | BPF_JA, 0, 0, off(out.len(), not_taken)?, 0));
+                    origin.push(block.term_pc);
+                }
+            }
+        }
+    }
+    Some((out, origin))
+}
+
+// Optimize a program at `level`, returning the optimized program along with the pc of the
+// instruction each of its instructions comes from. Returns None if the program is left as it is,
+// at `O0` or if its control flow cannot be followed, e.g. a jump out of it.
+pub fn optimize(insns: &[u64], level: OptLevel) -> Option<(Vec<u64>, Vec<usize>)> {
+    if level == OptLevel::O0 || insns.is_empty() {
+        return None;
+    }
+    let mut blocks = build_blocks(insns)?;
+    fold_constants(&mut blocks);
+    if level >= OptLevel::O2 {
+        propagate_copies(&mut blocks);
+        eliminate_dead_code(&mut blocks);
+    }
+    lower(&blocks)
+}
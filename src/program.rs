@@ -0,0 +1,149 @@
+use crate::context::{ContextDescriptor, CtxField, FieldAccess};
+use crate::helper::*;
+use crate::kprobe::PT_REGS_COUNT;
+
+// Program types, consistent with `enum bpf_prog_type` in linux.
+// A type gives the context of the program, the helpers it may call and the values it may return.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgramType {
+    SocketFilter = 1,
+    Kprobe = 2,
+    Tracepoint = 5,
+    Xdp = 6,
+}
+
+// helpers available to every program type
+const BASE_HELPERS: &[u32] = &[
+    BPF_FUNC_MAP_LOOKUP_ELEM,
+    BPF_FUNC_MAP_UPDATE_ELEM,
+    BPF_FUNC_MAP_DELETE_ELEM,
+    BPF_FUNC_KTIME_GET_NS,
+    BPF_FUNC_TRACE_PRINTK,
+    BPF_FUNC_GET_PRANDOM_U32,
+    BPF_FUNC_GET_SMP_PROCESSOR_ID,
+    BPF_FUNC_KTIME_GET_BOOT_NS,
+    BPF_FUNC_SNPRINTF,
+];
+
+const XDP_HELPERS: &[u32] = &[BPF_FUNC_XDP_ADJUST_HEAD, BPF_FUNC_XDP_ADJUST_META, BPF_FUNC_XDP_ADJUST_TAIL];
+
+const TRACING_HELPERS: &[u32] = &[
+    BPF_FUNC_PROBE_READ,
+    BPF_FUNC_PROBE_READ_KERNEL,
+    BPF_FUNC_GET_CURRENT_PID_TGID,
+    BPF_FUNC_GET_CURRENT_COMM,
+];
+
+// The context of socket filters, as seen by the JIT. Programs see it as `struct __sk_buff`.
+// `LD_ABS` and `LD_IND` read the packet through `data` and `data_end`, which must stay first.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SkBuff {
+    pub data: u64,
+    pub data_end: u64,
+    pub len: u32,
+    pub pkt_type: u32,
+    pub mark: u32,
+    pub queue_mapping: u32,
+    pub protocol: u32,
+    pub priority: u32,
+    pub ingress_ifindex: u32,
+    pub ifindex: u32,
+    pub hash: u32,
+    pub cb: [u32; 5],
+}
+
+fn field(off: u32, size: u32, real_off: u32, real_size: u32, access: FieldAccess) -> CtxField {
+    CtxField {
+        off,
+        size,
+        real_off,
+        real_size,
+        access,
+    }
+}
+
+impl ProgramType {
+    // select the type of a program from the name of its ELF section, as libbpf does with `SEC(..)`
+    pub fn from_section_name(name: &str) -> Option<Self> {
+        let prefixed = |prefix: &str| name == prefix || (name.starts_with(prefix) && name[prefix.len()..].starts_with('/'));
+        if prefixed("socket") {
+            Some(ProgramType::SocketFilter)
+        } else if prefixed("kprobe") || prefixed("kretprobe") {
+            Some(ProgramType::Kprobe)
+        } else if prefixed("tracepoint") || prefixed("tp") {
+            Some(ProgramType::Tracepoint)
+        } else if prefixed("xdp") {
+            Some(ProgramType::Xdp)
+        } else {
+            None
+        }
+    }
+
+    pub fn is_helper_allowed(self, id: u32) -> bool {
+        BASE_HELPERS.contains(&id)
+            || match self {
+                ProgramType::Kprobe | ProgramType::Tracepoint => TRACING_HELPERS.contains(&id),
+                ProgramType::Xdp => XDP_HELPERS.contains(&id),
+                ProgramType::SocketFilter => false,
+            }
+    }
+
+    // bounds of the value returned, if restricted
+    pub fn return_range(self) -> Option<(u64, u64)> {
+        match self {
+            // whether the event is passed on
+            ProgramType::Kprobe | ProgramType::Tracepoint => Some((0, 1)),
+            // XDP_ABORTED .. XDP_REDIRECT
+            ProgramType::Xdp => Some((0, 4)),
+            // number of bytes of the packet to keep
+            ProgramType::SocketFilter => None,
+        }
+    }
+
+    // whether `LD_ABS` and `LD_IND` may read the packet
+    pub fn allows_ld_abs(self) -> bool {
+        self == ProgramType::SocketFilter
+    }
+
+    // programs may not write to the context
+    pub fn is_ctx_read_only(self) -> bool {
+        matches!(self, ProgramType::Kprobe | ProgramType::Tracepoint)
+    }
+
+    // Fields of the context as seen by programs, mapped to `SkBuff`, `XdpBuff` or `PtRegs`.
+    // Tracepoints have none, their context is the record of the event given by `set_ctx_layout`.
+    pub fn ctx_descriptor(self) -> Option<ContextDescriptor> {
+        let mut desc;
+        match self {
+            ProgramType::SocketFilter => {
+                // struct __sk_buff, up to hash
+                desc = ContextDescriptor::new(72);
+                let ro = FieldAccess::ReadOnly;
+                for &(off, real_off) in &[(0, 16), (4, 20), (8, 24), (12, 28), (16, 32), (32, 36), (36, 40), (40, 44), (68, 48)] {
+                    desc.add_field(field(off, 4, real_off, 4, ro)).unwrap();
+                }
+                for i in 0..5 {
+                    desc.add_field(field(48 + 4 * i, 4, 52 + 4 * i, 4, FieldAccess::ReadWrite)).unwrap();
+                }
+            }
+            ProgramType::Xdp => {
+                // struct xdp_md, but egress_ifindex
+                desc = ContextDescriptor::new(24);
+                desc.add_field(field(0, 4, 0, 8, FieldAccess::PktData)).unwrap();
+                desc.add_field(field(4, 4, 8, 8, FieldAccess::PktDataEnd)).unwrap();
+                desc.add_field(field(8, 4, 16, 8, FieldAccess::PktMeta)).unwrap();
+                desc.add_field(field(12, 4, 36, 4, FieldAccess::ReadOnly)).unwrap();
+                desc.add_field(field(16, 4, 40, 4, FieldAccess::ReadOnly)).unwrap();
+            }
+            ProgramType::Kprobe => {
+                desc = ContextDescriptor::new(8 * PT_REGS_COUNT);
+                for i in 0..PT_REGS_COUNT as u32 {
+                    desc.add_field(field(8 * i, 8, 8 * i, 8, FieldAccess::ReadOnly)).unwrap();
+                }
+            }
+            ProgramType::Tracepoint => return None,
+        }
+        Some(desc)
+    }
+}
@@ -0,0 +1,236 @@
+// Compression of RV64 instructions into their 16-bit encodings of the C extension.
+
+fn rd(insn: u32) -> u8 {
+    ((insn >> 7) & 31) as u8
+}
+
+fn rs1(insn: u32) -> u8 {
+    ((insn >> 15) & 31) as u8
+}
+
+fn rs2(insn: u32) -> u8 {
+    ((insn >> 20) & 31) as u8
+}
+
+fn imm_i(insn: u32) -> i32 {
+    insn as i32 >> 20
+}
+
+fn imm_s(insn: u32) -> i32 {
+    (insn as i32 >> 25) << 5 | ((insn >> 7) & 31) as i32
+}
+
+fn imm_b(insn: u32) -> i32 {
+    let imm = (insn >> 31) << 12 | ((insn >> 7) & 1) << 11 | ((insn >> 25) & 0x3f) << 5 | ((insn >> 8) & 0xf) << 1;
+    (imm << 19) as i32 >> 19
+}
+
+fn imm_j(insn: u32) -> i32 {
+    let imm = (insn >> 31) << 20 | ((insn >> 12) & 0xff) << 12 | ((insn >> 20) & 1) << 11 | ((insn >> 21) & 0x3ff) << 1;
+    (imm << 11) as i32 >> 11
+}
+
+// bit `from` of `v` moved to bit `to`
+fn bit(v: i32, from: u32, to: u32) -> u16 {
+    (((v >> from) & 1) as u16) << to
+}
+
+// bits `hi..=lo` of `v` moved to bit `to`
+fn bits(v: i32, hi: u32, lo: u32, to: u32) -> u16 {
+    (((v >> lo) & ((1 << (hi - lo + 1)) - 1)) as u16) << to
+}
+
+fn fits(v: i32, bits: u32) -> bool {
+    v == (v << (32 - bits)) >> (32 - bits)
+}
+
+// x8-x15, addressable by the 3-bit register fields
+fn is_creg(reg: u8) -> bool {
+    (8..16).contains(&reg)
+}
+
+fn creg(reg: u8) -> u16 {
+    (reg - 8) as u16
+}
+
+// `funct3 | imm[5] | rd | imm[4:0] | op` of c.addi, c.li, c.slli, ...
+fn ci(funct3: u16, rd: u8, imm: i32, op: u16) -> u16 {
+    funct3 << 13 | bit(imm, 5, 12) | (rd as u16) << 7 | bits(imm, 4, 0, 2) | op
+}
+
+// `100 | imm[5] | funct2 | rd' | imm[4:0] | 01` of c.srli, c.srai and c.andi
+fn cb(funct2: u16, rd: u8, imm: i32) -> u16 {
+    0b100 << 13 | bit(imm, 5, 12) | funct2 << 10 | creg(rd) << 7 | bits(imm, 4, 0, 2) | 0b01
+}
+
+// c.sub, c.xor, c.or, c.and, c.subw and c.addw
+fn ca(word: u16, funct2: u16, rd: u8, rs2: u8) -> u16 {
+    0b100011 << 10 | word << 12 | creg(rd) << 7 | funct2 << 5 | creg(rs2) << 2 | 0b01
+}
+
+// c.lw, c.ld, c.sw and c.sd, which access `base + off` in x8-x15
+fn cl(funct3: u16, reg: u8, base: u8, off: i32, size: i32) -> Option<u16> {
+    if !is_creg(reg) || !is_creg(base) || off < 0 || off >= 32 * size || off % size != 0 {
+        return None;
+    }
+    let low = if size == 8 {
+        bits(off, 7, 6, 5)
+    } else {
+        bit(off, 2, 6) | bit(off, 6, 5)
+    };
+    Some(funct3 << 13 | bits(off, 5, 3, 10) | creg(base) << 7 | low | creg(reg) << 2)
+}
+
+// c.lwsp and c.ldsp
+fn ci_sp(funct3: u16, rd: u8, off: i32, size: i32) -> Option<u16> {
+    if rd == 0 || off < 0 || off >= 64 * size || off % size != 0 {
+        return None;
+    }
+    let low = if size == 8 {
+        bits(off, 4, 3, 5) | bits(off, 8, 6, 2)
+    } else {
+        bits(off, 4, 2, 4) | bits(off, 7, 6, 2)
+    };
+    Some(funct3 << 13 | bit(off, 5, 12) | (rd as u16) << 7 | low | 0b10)
+}
+
+// c.swsp and c.sdsp
+fn css(funct3: u16, rs2: u8, off: i32, size: i32) -> Option<u16> {
+    if off < 0 || off >= 64 * size || off % size != 0 {
+        return None;
+    }
+    let off = if size == 8 {
+        bits(off, 5, 3, 10) | bits(off, 8, 6, 7)
+    } else {
+        bits(off, 5, 2, 9) | bits(off, 7, 6, 7)
+    };
+    Some(funct3 << 13 | off | (rs2 as u16) << 2 | 0b10)
+}
+
+// `rd = rd op rs` for a commutative op written as either `rd = rs op rd` or `rd = rd op rs`
+fn commute(insn: u32) -> Option<u8> {
+    if rs1(insn) == rd(insn) {
+        Some(rs2(insn))
+    } else if rs2(insn) == rd(insn) {
+        Some(rs1(insn))
+    } else {
+        None
+    }
+}
+
+// The 16-bit encoding of `insn`, if the C extension has one with the same effect.
+pub fn compress(insn: u32) -> Option<u16> {
+    let (rd, rs1, rs2) = (rd(insn), rs1(insn), rs2(insn));
+    let funct3 = (insn >> 12) & 7;
+    let funct7 = insn >> 25;
+    match (insn & 0x7f, funct3) {
+        // addi
+        (0x13, 0) => {
+            let imm = imm_i(insn);
+            if rd == 0 {
+                None
+            } else if rs1 == 0 && fits(imm, 6) {
+                Some(ci(0b010, rd, imm, 0b01)) // c.li
+            } else if imm == 0 && rs1 != 0 {
+                Some(0b1000 << 12 | (rd as u16) << 7 | (rs1 as u16) << 2 | 0b10)
+            // c.mv
+            } else if rd == rs1 && fits(imm, 6) {
+                Some(ci(0b000, rd, imm, 0b01)) // c.addi
+            } else if rd == rs1 && rd == 2 && imm % 16 == 0 && fits(imm, 10) {
+                let imm = bit(imm, 9, 12) | bit(imm, 4, 6) | bit(imm, 6, 5) | bits(imm, 8, 7, 3) | bit(imm, 5, 2);
+                Some(0b011 << 13 | 2 << 7 | imm | 0b01) // c.addi16sp
+            } else if rs1 == 2 && is_creg(rd) && imm > 0 && imm < 1024 && imm % 4 == 0 {
+                let imm = bits(imm, 5, 4, 11) | bits(imm, 9, 6, 7) | bit(imm, 2, 6) | bit(imm, 3, 5);
+                Some(imm | creg(rd) << 2) // c.addi4spn
+            } else {
+                None
+            }
+        }
+        // slli, srli, srai
+        (0x13, 1) if rd == rs1 && rd != 0 && funct7 >> 1 == 0 && imm_i(insn) != 0 => {
+            Some(ci(0b000, rd, imm_i(insn), 0b10))
+        }
+        (0x13, 5) if rd == rs1 && is_creg(rd) && funct7 >> 1 & !0b010000 == 0 && imm_i(insn) & 63 != 0 => {
+            Some(cb((funct7 >> 5) as u16, rd, imm_i(insn) & 63))
+        }
+        // andi
+        (0x13, 7) if rd == rs1 && is_creg(rd) && fits(imm_i(insn), 6) => Some(cb(0b10, rd, imm_i(insn))),
+        // addiw
+        (0x1b, 0) if rd == rs1 && rd != 0 && fits(imm_i(insn), 6) => Some(ci(0b001, rd, imm_i(insn), 0b01)),
+        // lui
+        (0x37, _) => {
+            let imm = insn as i32 >> 12;
+            if rd != 0 && rd != 2 && imm != 0 && fits(imm, 6) {
+                Some(ci(0b011, rd, imm, 0b01))
+            } else {
+                None
+            }
+        }
+        // add
+        (0x33, 0) if funct7 == 0 => {
+            if rd == 0 {
+                None
+            } else if rs1 == 0 && rs2 != 0 {
+                Some(0b1000 << 12 | (rd as u16) << 7 | (rs2 as u16) << 2 | 0b10)
+            // c.mv
+            } else if rs2 == 0 && rs1 != 0 {
+                Some(0b1000 << 12 | (rd as u16) << 7 | (rs1 as u16) << 2 | 0b10)
+            // c.mv
+            } else {
+                match commute(insn) {
+                    Some(rs) if rs != 0 => Some(0b1001 << 12 | (rd as u16) << 7 | (rs as u16) << 2 | 0b10), // c.add
+                    _ => None,
+                }
+            }
+        }
+        // sub, xor, or, and
+        (0x33, 0) if funct7 == 0x20 && rd == rs1 && is_creg(rd) && is_creg(rs2) => Some(ca(0, 0b00, rd, rs2)),
+        (0x33, 4) | (0x33, 6) | (0x33, 7) if funct7 == 0 && is_creg(rd) => {
+            let funct2 = match funct3 {
+                4 => 0b01,
+                6 => 0b10,
+                _ => 0b11,
+            };
+            commute(insn).filter(|&rs| is_creg(rs)).map(|rs| ca(0, funct2, rd, rs))
+        }
+        // subw, addw
+        (0x3b, 0) if funct7 == 0x20 && rd == rs1 && is_creg(rd) && is_creg(rs2) => Some(ca(1, 0b00, rd, rs2)),
+        (0x3b, 0) if funct7 == 0 && is_creg(rd) => {
+            commute(insn).filter(|&rs| is_creg(rs)).map(|rs| ca(1, 0b01, rd, rs))
+        }
+        // lw, ld
+        (0x03, 2) if rs1 == 2 => ci_sp(0b010, rd, imm_i(insn), 4),
+        (0x03, 3) if rs1 == 2 => ci_sp(0b011, rd, imm_i(insn), 8),
+        (0x03, 2) => cl(0b010, rd, rs1, imm_i(insn), 4),
+        (0x03, 3) => cl(0b011, rd, rs1, imm_i(insn), 8),
+        // sw, sd
+        (0x23, 2) if rs1 == 2 => css(0b110, rs2, imm_s(insn), 4),
+        (0x23, 3) if rs1 == 2 => css(0b111, rs2, imm_s(insn), 8),
+        (0x23, 2) => cl(0b110, rs2, rs1, imm_s(insn), 4),
+        (0x23, 3) => cl(0b111, rs2, rs1, imm_s(insn), 8),
+        // jal zero
+        (0x6f, _) if rd == 0 && fits(imm_j(insn), 12) => {
+            let imm = imm_j(insn);
+            let imm = bit(imm, 11, 12)
+                | bit(imm, 4, 11)
+                | bits(imm, 9, 8, 9)
+                | bit(imm, 10, 8)
+                | bit(imm, 6, 7)
+                | bit(imm, 7, 6)
+                | bits(imm, 3, 1, 3)
+                | bit(imm, 5, 2);
+            Some(0b101 << 13 | imm | 0b01) // c.j
+        }
+        // jalr zero / ra
+        (0x67, 0) if rs1 != 0 && imm_i(insn) == 0 && (rd == 0 || rd == 1) => {
+            Some(0b100 << 13 | (rd as u16) << 12 | (rs1 as u16) << 7 | 0b10) // c.jr, c.jalr
+        }
+        // beq, bne against zero
+        (0x63, 0) | (0x63, 1) if rs2 == 0 && is_creg(rs1) && fits(imm_b(insn), 9) => {
+            let imm = imm_b(insn);
+            let imm = bit(imm, 8, 12) | bits(imm, 4, 3, 10) | bits(imm, 7, 6, 5) | bits(imm, 2, 1, 3) | bit(imm, 5, 2);
+            Some((0b110 | funct3 as u16) << 13 | imm | creg(rs1) << 7 | 0b01) // c.beqz, c.bnez
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,140 @@
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+use crate::context::{ContextDescriptor, CtxField, FieldAccess};
+
+// `AUDIT_ARCH_RISCV64`, the `arch` of system calls on RV64
+pub const AUDIT_ARCH_RISCV64: u32 = 0xc000_00f3;
+
+// values returned by seccomp filters, an action in the upper 16 bits and its data in the lower ones
+pub const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+pub const SECCOMP_RET_KILL_THREAD: u32 = 0x0000_0000;
+pub const SECCOMP_RET_TRAP: u32 = 0x0003_0000;
+pub const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+pub const SECCOMP_RET_USER_NOTIF: u32 = 0x7fc0_0000;
+pub const SECCOMP_RET_TRACE: u32 = 0x7ff0_0000;
+pub const SECCOMP_RET_LOG: u32 = 0x7ffc_0000;
+pub const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+pub const SECCOMP_RET_ACTION_FULL: u32 = 0xffff_0000;
+pub const SECCOMP_RET_DATA: u32 = 0x0000_ffff;
+
+// The context of seccomp filters, `struct seccomp_data` in linux.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SeccompData {
+    pub nr: i32,
+    pub arch: u32,
+    pub instruction_pointer: u64,
+    pub args: [u64; 6],
+}
+
+// `SeccompData` as a read-only context, for filters translated by `cbpf::convert_seccomp`
+pub fn seccomp_data_descriptor() -> ContextDescriptor {
+    let mut desc = ContextDescriptor::new(core::mem::size_of::<SeccompData>());
+    let field = |off, size| CtxField {
+        off,
+        size,
+        real_off: off,
+        real_size: size,
+        access: FieldAccess::ReadOnly,
+    };
+    desc.add_field(field(0, 4)).unwrap();
+    desc.add_field(field(4, 4)).unwrap();
+    for i in 0..7 {
+        desc.add_field(field(8 + 8 * i, 8)).unwrap();
+    }
+    desc
+}
+
+// the action taken on a system call, see `SECCOMP_RET_*`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SeccompAction {
+    KillProcess,
+    KillThread,
+    // raise SIGSYS, with the data of the filter
+    Trap(u16),
+    // fail with the data as errno
+    Errno(u16),
+    UserNotif,
+    Trace(u16),
+    Log,
+    Allow,
+}
+
+impl SeccompAction {
+    // an unknown action kills the process, as linux does
+    pub fn from_ret(ret: u32) -> Self {
+        let data = (ret & SECCOMP_RET_DATA) as u16;
+        match ret & SECCOMP_RET_ACTION_FULL {
+            SECCOMP_RET_KILL_THREAD => SeccompAction::KillThread,
+            SECCOMP_RET_TRAP => SeccompAction::Trap(data),
+            SECCOMP_RET_ERRNO => SeccompAction::Errno(data),
+            SECCOMP_RET_USER_NOTIF => SeccompAction::UserNotif,
+            SECCOMP_RET_TRACE => SeccompAction::Trace(data),
+            SECCOMP_RET_LOG => SeccompAction::Log,
+            SECCOMP_RET_ALLOW => SeccompAction::Allow,
+            _ => SeccompAction::KillProcess,
+        }
+    }
+}
+
+// the jitted code of a seccomp filter
+pub type SeccompFn = unsafe extern "C" fn(*const SeccompData) -> u64;
+
+// The filters of a task, each the address of a jitted filter. A child inherits the filters of its
+// parent, and may only add more.
+#[derive(Clone, Debug, Default)]
+pub struct SeccompFilters {
+    // in the order they are installed
+    filters: Vec<u64>,
+}
+
+impl SeccompFilters {
+    pub fn new() -> Self {
+        Self { filters: Vec::new() }
+    }
+
+    pub fn push(&mut self, filter: u64) {
+        self.filters.push(filter);
+    }
+
+    pub fn len(&self) -> usize {
+        self.filters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    /// Run every filter on a system call, the value returned is taken as `SeccompAction::from_ret`.
+    ///
+    /// # Safety
+    ///
+    /// Every filter pushed must be the address of the jitted code of a seccomp filter, translated by
+    /// `cbpf::convert_seccomp` and compiled with `seccomp_data_descriptor`, and still be mapped.
+    pub unsafe fn run(&self, data: &SeccompData) -> u32 {
+        self.run_with(data, |filter, data| {
+            let filter: SeccompFn = core::mem::transmute(filter as usize);
+            filter(data)
+        })
+    }
+
+    // Same as `run`, each filter being run by `run`, e.g. in an emulator.
+    // As `seccomp_run_filters` in linux, the most recent filter runs first, and the value with the
+    // lowest action wins, KILL_PROCESS being the lowest. Among equal actions, the first one wins.
+    pub fn run_with<F>(&self, data: &SeccompData, mut run: F) -> u32
+    where
+        F: FnMut(u64, *const SeccompData) -> u64,
+    {
+        let action = |ret: u32| (ret & SECCOMP_RET_ACTION_FULL) as i32;
+        let mut ret = SECCOMP_RET_ALLOW;
+        for &filter in self.filters.iter().rev() {
+            let cur = run(filter, data) as u32;
+            if action(cur) < action(ret) {
+                ret = cur;
+            }
+        }
+        ret
+    }
+}
@@ -0,0 +1,2068 @@
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use crate::consts::*;
+use crate::context::{ContextDescriptor, CtxAccess, FieldAccess};
+use crate::helper::{
+    changes_pkt_data, ArgKind, HelperRegistry, RetKind, BPF_FUNC_MAP_DELETE_ELEM, BPF_FUNC_MAP_UPDATE_ELEM,
+    BPF_FUNC_PROBE_READ, BPF_FUNC_PROBE_READ_KERNEL, PROBE_READ_ARGS,
+};
+use crate::map::{MapAttr, MapTable};
+use crate::program::ProgramType;
+
+// bound of variable offsets added to pointers, as in linux
+const BPF_MAX_VAR_OFF: i64 = 1 << 29;
+
+// default number of instructions simulated on all paths before giving up, as in linux
+pub const BPF_COMPLEXITY_LIMIT_INSNS: usize = 1_000_000;
+
+// instructions are no longer logged past this size, the reason of rejection always is
+const LOG_SIZE_MAX: usize = 1 << 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VerifierErrorKind {
+    EmptyProgram,
+    // more than `BPF_MAXINSNS` instructions
+    ProgramTooLarge { len: usize },
+    UnknownOpcode(u8),
+    InvalidRegister(u8),
+    // LD_IMM_DW without its second half
+    IncompleteLdImm64,
+    JumpOutOfRange { target: isize },
+    JumpIntoLdImm64 { target: usize },
+    // execution falls off the end of the program
+    FallThroughEnd,
+    BackEdge { target: usize },
+    Unreachable,
+    // a loop reaches the same state twice
+    InfiniteLoop,
+    // `limit` instructions were simulated, `header` is the innermost loop on the path
+    ComplexityLimit { header: Option<usize>, limit: usize },
+    UninitRegister(u8),
+    WriteToFrameReg,
+    UnknownHelper(u32),
+    UnknownMapFd(u32),
+    UnsupportedPseudoSrc(u8),
+    InvalidShift(i32),
+    // byte swap of a size other than 16, 32 or 64 bits
+    InvalidEndSize(i32),
+    // LD_ABS or LD_IND in a program whose type does not allow them
+    LdAbsNotAllowed,
+    // helper not allowed for the type of the program
+    HelperNotAllowed(u32),
+    // R0 at exit out of the return range of the program type
+    InvalidReturnValue { umin: u64, umax: u64, range: (u64, u64) },
+    // arithmetic other than adding or subtracting a scalar, or on a pointer which must not be moved
+    PointerArithmetic { reg: u8 },
+    // a pointer stored out of the stack, or returned
+    PointerLeak { reg: u8 },
+    // dereference of a register which is not a valid pointer, `kind` is its type
+    InvalidMemAccess { reg: u8, kind: &'static str },
+    // dereference of a pointer which may be NULL, before it is checked
+    NullPointerAccess { reg: u8 },
+    OutOfBounds { reg: u8, region: Region, off: i64, size: u64 },
+    // variable offset on a pointer to the stack or the context
+    VariableOffsetAccess { reg: u8 },
+    // a scalar added to a pointer, or a pointer, may exceed `BPF_MAX_VAR_OFF`
+    UnboundedOffset { reg: u8 },
+    UninitStackRead { off: i64, size: u64 },
+    InvalidHelperArg { reg: u8, kind: &'static str, expected: ArgKind },
+    // size argument of a helper which is negative, unbounded or zero
+    InvalidSizeArg { reg: u8 },
+    // the context is accessed by an instruction which also accesses other memory
+    MixedPointerTypes,
+    // a write into the values of a map created with `BPF_F_RDONLY_PROG`
+    WriteToReadOnlyMap { reg: u8 },
+    // a string argument which is not in a frozen read-only map, or not terminated within its value
+    UnterminatedString { reg: u8 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VerifierError {
+    pub bpf_pc: usize,
+    pub kind: VerifierErrorKind,
+}
+
+impl VerifierError {
+    fn new(bpf_pc: usize, kind: VerifierErrorKind) -> Self {
+        Self { bpf_pc, kind }
+    }
+}
+
+// memory region accessible through a pointer, with its bound
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Region {
+    Ctx { size: usize },
+    Stack { size: usize },
+    MapValue { value_size: u32 },
+    Packet { range: i64 },
+}
+
+// layout of the context passed in R1
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CtxLayout {
+    pub size: usize,
+    // offsets of the u64 fields pointing to the start and the end of packet data, if any
+    pub pkt_data: Option<usize>,
+    pub pkt_data_end: Option<usize>,
+}
+
+// the kind of memory accessed by an LDX, ST or STX on every path
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemKind {
+    Ctx,
+    Stack,
+    MapValue,
+    Packet,
+    // a BPF_PROBE_MEM load, whose address is unknown
+    Probe,
+}
+
+// the map passed to a helper call, see `map_ptr_state` in linux
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MapPtrState {
+    // the map of this fd on every path
+    Fd(u32),
+    // different maps, or an inner map of a map-in-map
+    Poison,
+}
+
+// facts about an instruction proven by the verifier, which the JIT could rely on
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct InsnAux {
+    pub mem: Option<MemKind>,
+    // conversion of an access to the context, see `ContextDescriptor`
+    pub ctx: Option<CtxAccess>,
+    pub map: Option<MapPtrState>,
+}
+
+fn reg_name(reg: u8, is64: bool) -> String {
+    format!("{}{}", if is64 { 'r' } else { 'w' }, reg)
+}
+
+fn size_name(op: u8) -> &'static str {
+    match (op & 0b11000) as u32 {
+        BPF_B => "u8",
+        BPF_H => "u16",
+        BPF_W => "u32",
+        _ => "u64",
+    }
+}
+
+// Print an instruction as the kernel verifier log does, e.g. `if r1 > 0x64 goto pc-3`.
+pub fn disasm(insns: &[u64], pc: usize) -> String {
+    let insn = insns[pc];
+    let op = (insn & 0xff) as u8;
+    let dst = ((insn & 0x0f00) >> 8) as u8;
+    let src = ((insn & 0xf000) >> 12) as u8;
+    let off = (insn >> 16) as i16;
+    let imm = (insn >> 32) as i32;
+    let class = (op & 0b111) as u32;
+    let use_imm = (op & 8) == 0;
+
+    let operand = |is64: bool| {
+        if use_imm {
+            format!("{}", imm)
+        } else {
+            reg_name(src, is64)
+        }
+    };
+
+    match class {
+        BPF_ALU | BPF_ALU64 => {
+            let is64 = class == BPF_ALU64;
+            let d = reg_name(dst, is64);
+            let sym = match (op & 0xf0) as u32 {
+                BPF_ADD => "+=",
+                BPF_SUB => "-=",
+                BPF_MUL => "*=",
+                BPF_DIV => "/=",
+                BPF_OR => "|=",
+                BPF_AND => "&=",
+                BPF_LSH => "<<=",
+                BPF_RSH => ">>=",
+                BPF_MOD => "%=",
+                BPF_XOR => "^=",
+                BPF_MOV => "=",
+                BPF_ARSH => "s>>=",
+                BPF_NEG => return format!("{} = -{}", d, d),
+                BPF_END => {
+                    let order = if use_imm { "le" } else { "be" };
+                    return format!("{} = {}{} {}", d, order, imm, d);
+                }
+                _ => return format!("unknown opcode {:02x}", op),
+            };
+            format!("{} {} {}", d, sym, operand(is64))
+        }
+        BPF_LD if op == LD_IMM_DW => {
+            let hi = insns.get(pc + 1).map_or(0, |&next| next >> 32);
+            let imm64 = (imm as u32 as u64) | (hi << 32);
+            match src as u32 {
+                BPF_PSEUDO_MAP_FD => format!("r{} = map[id:{}]", dst, imm),
+                _ => format!("r{} = {:#x} ll", dst, imm64),
+            }
+        }
+        BPF_LD if (op & 0xe0) as u32 == BPF_ABS => format!("r0 = *({} *)skb[{}]", size_name(op), imm),
+        BPF_LD if (op & 0xe0) as u32 == BPF_IND => format!("r0 = *({} *)skb[r{} + {}]", size_name(op), src, imm),
+        BPF_LDX => format!("r{} = *({} *)(r{} {:+})", dst, size_name(op), src, off),
+        BPF_ST => format!("*({} *)(r{} {:+}) = {}", size_name(op), dst, off, imm),
+        BPF_STX => format!("*({} *)(r{} {:+}) = r{}", size_name(op), dst, off, src),
+        BPF_JMP | BPF_JMP32 => {
+            let is64 = class == BPF_JMP;
+            let sym = match (op & 0xf0) as u32 {
+                BPF_JA => return format!("goto pc{:+}", off),
+                BPF_CALL => return format!("call {}", imm),
+                BPF_EXIT => return String::from("exit"),
+                BPF_JEQ => "==",
+                BPF_JGT => ">",
+                BPF_JGE => ">=",
+                BPF_JSET => "&",
+                BPF_JNE => "!=",
+                BPF_JSGT => "s>",
+                BPF_JSGE => "s>=",
+                BPF_JLT => "<",
+                BPF_JLE => "<=",
+                BPF_JSLT => "s<",
+                BPF_JSLE => "s<=",
+                _ => return format!("unknown opcode {:02x}", op),
+            };
+            let operand = if use_imm {
+                format!("{:#x}", imm)
+            } else {
+                reg_name(src, is64)
+            };
+            format!("if {} {} {} goto pc{:+}", reg_name(dst, is64), sym, operand, off)
+        }
+        _ => format!("unknown opcode {:02x}", op),
+    }
+}
+
+// opcodes which the JIT is able to compile
+// packet loads of socket filters, LD_ABS_DW and LD_IND_DW are not part of the instruction set
+fn is_ld_abs(op: u8) -> bool {
+    matches!(op, LD_ABS_B | LD_ABS_H | LD_ABS_W | LD_IND_B | LD_IND_H | LD_IND_W)
+}
+
+fn is_supported_opcode(op: u8) -> bool {
+    match op {
+        LD_IMM_DW | JMP_K_JA | JMP_K_CALL | JMP_K_EXIT => true,
+        ALU64_K_LSH | ALU64_X_LSH | ALU64_K_RSH | ALU64_X_RSH | ALU64_K_ARSH | ALU64_X_ARSH => true,
+        ALU_K_LSH | ALU_X_LSH | ALU_K_RSH | ALU_X_RSH | ALU_K_ARSH | ALU_X_ARSH => true,
+        ALU_K_NEG | ALU64_K_NEG => true,
+        ALU_K_END | ALU_X_END | ALU64_K_END => true,
+        LDX_MEM_B | LDX_MEM_H | LDX_MEM_W | LDX_MEM_DW => true,
+        LDX_PROBE_MEM_B | LDX_PROBE_MEM_H | LDX_PROBE_MEM_W | LDX_PROBE_MEM_DW => true,
+        ST_MEM_B | ST_MEM_H | ST_MEM_W | ST_MEM_DW => true,
+        STX_MEM_B | STX_MEM_H | STX_MEM_W | STX_MEM_DW => true,
+        _ => match (op & 0b111) as u32 {
+            BPF_ALU | BPF_ALU64 => matches!(
+                (op & 0xf0) as u32,
+                BPF_ADD | BPF_SUB | BPF_MUL | BPF_DIV | BPF_OR | BPF_AND | BPF_MOD | BPF_XOR | BPF_MOV
+            ),
+            BPF_JMP | BPF_JMP32 => matches!(
+                (op & 0xf0) as u32,
+                BPF_JEQ
+                    | BPF_JGT
+                    | BPF_JGE
+                    | BPF_JSET
+                    | BPF_JNE
+                    | BPF_JSGT
+                    | BPF_JSGE
+                    | BPF_JLT
+                    | BPF_JLE
+                    | BPF_JSLT
+                    | BPF_JSLE
+            ),
+            _ => false,
+        },
+    }
+}
+
+
+// Tracked number, as `struct tnum` in linux: bits set in `mask` are unknown,
+// the others are those of `value`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Tnum {
+    pub value: u64,
+    pub mask: u64,
+}
+
+impl Tnum {
+    const UNKNOWN: Tnum = Tnum { value: 0, mask: u64::MAX };
+
+    fn konst(value: u64) -> Self {
+        Self { value, mask: 0 }
+    }
+
+    // the smallest tnum containing every number from `min` to `max`
+    fn range(min: u64, max: u64) -> Self {
+        let bits = 64 - (min ^ max).leading_zeros();
+        if bits > 63 {
+            return Self::UNKNOWN;
+        }
+        let delta = (1u64 << bits) - 1;
+        Self {
+            value: min & !delta,
+            mask: delta,
+        }
+    }
+
+    fn is_const(self) -> bool {
+        self.mask == 0
+    }
+
+    fn add(self, other: Self) -> Self {
+        let sm = self.mask.wrapping_add(other.mask);
+        let sv = self.value.wrapping_add(other.value);
+        let chi = sm.wrapping_add(sv) ^ sv;
+        let mu = chi | self.mask | other.mask;
+        Self { value: sv & !mu, mask: mu }
+    }
+
+    fn sub(self, other: Self) -> Self {
+        let dv = self.value.wrapping_sub(other.value);
+        let alpha = dv.wrapping_add(self.mask);
+        let beta = dv.wrapping_sub(other.mask);
+        let mu = (alpha ^ beta) | self.mask | other.mask;
+        Self { value: dv & !mu, mask: mu }
+    }
+
+    fn mul(self, other: Self) -> Self {
+        let acc_v = self.value.wrapping_mul(other.value);
+        let mut acc_m = Self::konst(0);
+        let (mut a, mut b) = (self, other);
+        while a.value != 0 || a.mask != 0 {
+            if a.value & 1 != 0 {
+                acc_m = acc_m.add(Self { value: 0, mask: b.mask });
+            } else if a.mask & 1 != 0 {
+                acc_m = acc_m.add(Self {
+                    value: 0,
+                    mask: b.value | b.mask,
+                });
+            }
+            a = a.rshift(1);
+            b = b.lshift(1);
+        }
+        Self::konst(acc_v).add(acc_m)
+    }
+
+    fn and(self, other: Self) -> Self {
+        let alpha = self.value | self.mask;
+        let beta = other.value | other.mask;
+        let v = self.value & other.value;
+        Self {
+            value: v,
+            mask: alpha & beta & !v,
+        }
+    }
+
+    fn or(self, other: Self) -> Self {
+        let v = self.value | other.value;
+        let mu = self.mask | other.mask;
+        Self { value: v, mask: mu & !v }
+    }
+
+    fn xor(self, other: Self) -> Self {
+        let v = self.value ^ other.value;
+        let mu = self.mask | other.mask;
+        Self { value: v & !mu, mask: mu }
+    }
+
+    fn lshift(self, shift: u32) -> Self {
+        Self {
+            value: self.value << shift,
+            mask: self.mask << shift,
+        }
+    }
+
+    fn rshift(self, shift: u32) -> Self {
+        Self {
+            value: self.value >> shift,
+            mask: self.mask >> shift,
+        }
+    }
+
+    fn arshift(self, shift: u32) -> Self {
+        Self {
+            value: ((self.value as i64) >> shift) as u64,
+            mask: ((self.mask as i64) >> shift) as u64,
+        }
+    }
+
+    fn intersect(self, other: Self) -> Self {
+        let v = self.value | other.value;
+        let mu = self.mask & other.mask;
+        Self { value: v & !mu, mask: mu }
+    }
+
+    // whether every number of `other` is in `self`
+    fn contains(self, other: Self) -> bool {
+        if other.mask & !self.mask != 0 {
+            return false;
+        }
+        self.value == other.value & !self.mask
+    }
+
+    // truncate to `size` bytes
+    fn cast(self, size: u64) -> Self {
+        let m = u64::MAX >> (64 - 8 * size);
+        Self {
+            value: self.value & m,
+            mask: self.mask & m,
+        }
+    }
+}
+
+// Value of a scalar, or variable offset of a pointer, as bounds along with a tnum.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Scalar {
+    pub var: Tnum,
+    pub umin: u64,
+    pub umax: u64,
+    pub smin: i64,
+    pub smax: i64,
+}
+
+impl Scalar {
+    pub fn unknown() -> Self {
+        Self {
+            var: Tnum::UNKNOWN,
+            umin: 0,
+            umax: u64::MAX,
+            smin: i64::MIN,
+            smax: i64::MAX,
+        }
+    }
+
+    pub fn konst(v: u64) -> Self {
+        Self {
+            var: Tnum::konst(v),
+            umin: v,
+            umax: v,
+            smin: v as i64,
+            smax: v as i64,
+        }
+    }
+
+    pub fn const_value(&self) -> Option<u64> {
+        if self.var.is_const() {
+            Some(self.var.value)
+        } else {
+            None
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        self.umin <= self.umax && self.smin <= self.smax && self.var.value & self.var.mask == 0
+    }
+
+    // tighten the bounds and the tnum against each other
+    fn sync(&mut self) {
+        self.umin = self.umin.max(self.var.value);
+        self.umax = self.umax.min(self.var.value | self.var.mask);
+        let sign = 1u64 << 63;
+        self.smin = self.smin.max((self.var.value | (self.var.mask & sign)) as i64);
+        self.smax = self.smax.min((self.var.value | (self.var.mask & !sign)) as i64);
+        // signed and unsigned orders agree within the same half
+        if self.smin >= 0 || self.smax < 0 {
+            self.umin = self.umin.max(self.smin as u64);
+            self.umax = self.umax.min(self.smax as u64);
+        }
+        if (self.umin ^ self.umax) >> 63 == 0 {
+            self.smin = self.smin.max(self.umin as i64);
+            self.smax = self.smax.min(self.umax as i64);
+        }
+        if self.umin <= self.umax {
+            self.var = self.var.intersect(Tnum::range(self.umin, self.umax));
+        }
+    }
+
+    // zero extension of the lowest `size` bytes
+    fn cast(self, size: u64) -> Self {
+        if size >= 8 || self.umax <= u64::MAX >> (64 - 8 * size) {
+            return self;
+        }
+        let mut r = Self::unknown();
+        r.var = self.var.cast(size);
+        r.umax = u64::MAX >> (64 - 8 * size);
+        r.sync();
+        r
+    }
+
+    fn contains(&self, other: &Self) -> bool {
+        self.umin <= other.umin
+            && self.umax >= other.umax
+            && self.smin <= other.smin
+            && self.smax >= other.smax
+            && self.var.contains(other.var)
+    }
+
+    // 64-bit ALU operation, `other` is ignored by BPF_NEG
+    fn alu(self, code: u32, other: Self) -> Self {
+        let (a, b) = (self, other);
+        let mut r = Self::unknown();
+        match code {
+            BPF_ADD => {
+                r.var = a.var.add(b.var);
+                if let (Some(lo), Some(hi)) = (a.umin.checked_add(b.umin), a.umax.checked_add(b.umax)) {
+                    r.umin = lo;
+                    r.umax = hi;
+                }
+                if let (Some(lo), Some(hi)) = (a.smin.checked_add(b.smin), a.smax.checked_add(b.smax)) {
+                    r.smin = lo;
+                    r.smax = hi;
+                }
+            }
+            BPF_SUB => {
+                r.var = a.var.sub(b.var);
+                if a.umin >= b.umax {
+                    r.umin = a.umin - b.umax;
+                    r.umax = a.umax - b.umin;
+                }
+                if let (Some(lo), Some(hi)) = (a.smin.checked_sub(b.smax), a.smax.checked_sub(b.smin)) {
+                    r.smin = lo;
+                    r.smax = hi;
+                }
+            }
+            BPF_MUL => {
+                r.var = a.var.mul(b.var);
+                if a.umax <= u32::MAX as u64 && b.umax <= u32::MAX as u64 {
+                    r.umin = a.umin * b.umin;
+                    r.umax = a.umax * b.umax;
+                }
+            }
+            // division by zero gives zero
+            BPF_DIV => match b.const_value() {
+                Some(0) => r = Self::konst(0),
+                Some(k) => {
+                    r.umin = a.umin / k;
+                    r.umax = a.umax / k;
+                }
+                None => r.umax = a.umax,
+            },
+            // modulo by zero keeps the dividend
+            BPF_MOD => match b.const_value() {
+                Some(0) => r = a,
+                Some(k) => r.umax = a.umax.min(k - 1),
+                None if b.umin > 0 => r.umax = a.umax.min(b.umax - 1),
+                None => r.umax = a.umax,
+            },
+            BPF_AND => {
+                r.var = a.var.and(b.var);
+                r.umax = a.umax.min(b.umax);
+            }
+            BPF_OR => {
+                r.var = a.var.or(b.var);
+                r.umin = a.umin.max(b.umin);
+            }
+            BPF_XOR => r.var = a.var.xor(b.var),
+            BPF_LSH | BPF_RSH | BPF_ARSH => {
+                if let Some(k) = b.const_value().filter(|&k| k < 64) {
+                    let k = k as u32;
+                    match code {
+                        BPF_LSH => {
+                            r.var = a.var.lshift(k);
+                            if a.umax.leading_zeros() >= k {
+                                r.umin = a.umin << k;
+                                r.umax = a.umax << k;
+                            }
+                        }
+                        BPF_RSH => {
+                            r.var = a.var.rshift(k);
+                            r.umin = a.umin >> k;
+                            r.umax = a.umax >> k;
+                        }
+                        _ => {
+                            r.var = a.var.arshift(k);
+                            r.smin = a.smin >> k;
+                            r.smax = a.smax >> k;
+                        }
+                    }
+                }
+            }
+            BPF_NEG => {
+                if let Some(v) = a.const_value() {
+                    r = Self::konst(v.wrapping_neg());
+                }
+            }
+            BPF_MOV => r = b,
+            _ => {}
+        }
+        r.sync();
+        r
+    }
+}
+
+// remove `c` from the bounds of `x`, if it is at their edge
+fn exclude(x: &mut Scalar, c: u64) -> Option<()> {
+    if x.umin == c {
+        x.umin = c.checked_add(1)?;
+    }
+    if x.umax == c {
+        x.umax = c.checked_sub(1)?;
+    }
+    if x.smin == c as i64 {
+        x.smin = (c as i64).checked_add(1)?;
+    }
+    if x.smax == c as i64 {
+        x.smax = (c as i64).checked_sub(1)?;
+    }
+    Some(())
+}
+
+// Refine `a` and `b` on the branch where `a <code> b` is `taken`.
+// Returns `None` if the branch can never be taken.
+fn refine_cond(code: u32, taken: bool, a: &mut Scalar, b: &mut Scalar) -> Option<()> {
+    // reduce to >, >=, s>, s>=, ==, != and jset
+    let (code, swap) = match (code, taken) {
+        (BPF_JGT, true) | (BPF_JLE, false) => (BPF_JGT, false),
+        (BPF_JGT, false) | (BPF_JLE, true) => (BPF_JGE, true),
+        (BPF_JGE, true) | (BPF_JLT, false) => (BPF_JGE, false),
+        (BPF_JGE, false) | (BPF_JLT, true) => (BPF_JGT, true),
+        (BPF_JSGT, true) | (BPF_JSLE, false) => (BPF_JSGT, false),
+        (BPF_JSGT, false) | (BPF_JSLE, true) => (BPF_JSGE, true),
+        (BPF_JSGE, true) | (BPF_JSLT, false) => (BPF_JSGE, false),
+        (BPF_JSGE, false) | (BPF_JSLT, true) => (BPF_JSGT, true),
+        (BPF_JEQ, true) | (BPF_JNE, false) => (BPF_JEQ, false),
+        (BPF_JEQ, false) | (BPF_JNE, true) => (BPF_JNE, false),
+        (code, _) => (code, false),
+    };
+    let (a, b) = if swap { (b, a) } else { (a, b) };
+
+    match code {
+        BPF_JGT => {
+            a.umin = a.umin.max(b.umin.checked_add(1)?);
+            b.umax = b.umax.min(a.umax.checked_sub(1)?);
+        }
+        BPF_JGE => {
+            a.umin = a.umin.max(b.umin);
+            b.umax = b.umax.min(a.umax);
+        }
+        BPF_JSGT => {
+            a.smin = a.smin.max(b.smin.checked_add(1)?);
+            b.smax = b.smax.min(a.smax.checked_sub(1)?);
+        }
+        BPF_JSGE => {
+            a.smin = a.smin.max(b.smin);
+            b.smax = b.smax.min(a.smax);
+        }
+        BPF_JEQ => {
+            let mut r = Scalar {
+                var: a.var.intersect(b.var),
+                umin: a.umin.max(b.umin),
+                umax: a.umax.min(b.umax),
+                smin: a.smin.max(b.smin),
+                smax: a.smax.min(b.smax),
+            };
+            if (a.var.value ^ b.var.value) & !(a.var.mask | b.var.mask) != 0 {
+                return None;
+            }
+            r.sync();
+            *a = r;
+            *b = r;
+        }
+        BPF_JNE => {
+            if let Some(c) = b.const_value() {
+                exclude(a, c)?;
+            }
+            if let Some(c) = a.const_value() {
+                exclude(b, c)?;
+            }
+        }
+        BPF_JSET if taken && a.var.and(b.var) == Tnum::konst(0) => return None,
+        BPF_JSET if !taken => {
+            if let Some(c) = b.const_value() {
+                if a.var.value & c != 0 {
+                    return None;
+                }
+                a.var = Tnum {
+                    value: a.var.value,
+                    mask: a.var.mask & !c,
+                };
+            }
+        }
+        _ => {}
+    }
+    a.sync();
+    b.sync();
+    if a.is_valid() && b.is_valid() {
+        Some(())
+    } else {
+        None
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegType {
+    NotInit,
+    Scalar,
+    PtrToCtx,
+    PtrToStack,
+    // `fd` is unknown for values of inner maps
+    PtrToMapValue { attr: MapAttr, fd: Option<u32> },
+    // `inner` is the template of a map-in-map, `fd` is unknown for inner maps
+    ConstPtrToMap { attr: MapAttr, inner: Option<MapAttr>, fd: Option<u32> },
+    PtrToPacket,
+    PtrToPacketEnd,
+    // metadata before the packet, whose end is the start of packet
+    PtrToPacketMeta,
+}
+
+// Abstract value of a register. A scalar is described by `var`, a pointer by its kind,
+// a fixed offset `off` and a variable offset `var`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RegState {
+    pub kind: RegType,
+    pub off: i32,
+    pub var: Scalar,
+    // pointers returned by the same call share their id, until checked against NULL
+    pub id: u32,
+    pub maybe_null: bool,
+}
+
+impl RegState {
+    fn not_init() -> Self {
+        Self::ptr(RegType::NotInit)
+    }
+
+    fn scalar(var: Scalar) -> Self {
+        Self {
+            kind: RegType::Scalar,
+            off: 0,
+            var,
+            id: 0,
+            maybe_null: false,
+        }
+    }
+
+    fn ptr(kind: RegType) -> Self {
+        Self {
+            kind,
+            off: 0,
+            var: Scalar::konst(0),
+            id: 0,
+            maybe_null: false,
+        }
+    }
+
+    fn is_pointer(&self) -> bool {
+        !matches!(self.kind, RegType::NotInit | RegType::Scalar)
+    }
+
+    fn type_name(&self) -> &'static str {
+        match (self.kind, self.maybe_null) {
+            (RegType::NotInit, _) => "?",
+            (RegType::Scalar, _) => "scalar",
+            (RegType::PtrToCtx, _) => "ctx",
+            (RegType::PtrToStack, _) => "fp",
+            (RegType::PtrToMapValue { .. }, false) => "map_value",
+            (RegType::PtrToMapValue { .. }, true) => "map_value_or_null",
+            (RegType::ConstPtrToMap { .. }, false) => "map_ptr",
+            (RegType::ConstPtrToMap { .. }, true) => "map_ptr_or_null",
+            (RegType::PtrToPacket, _) => "pkt",
+            (RegType::PtrToPacketEnd, _) => "pkt_end",
+            (RegType::PtrToPacketMeta, _) => "pkt_meta",
+        }
+    }
+
+    // whether every value of `other` is a value of `self`, for state pruning
+    fn covers(&self, other: &Self) -> bool {
+        match self.kind {
+            RegType::NotInit => true,
+            RegType::Scalar => other.kind == RegType::Scalar && self.var.contains(&other.var),
+            _ => {
+                self.kind == other.kind
+                    && self.off == other.off
+                    && self.id == other.id
+                    && self.maybe_null == other.maybe_null
+                    && self.var.contains(&other.var)
+            }
+        }
+    }
+}
+
+fn fmt_scalar(s: &Scalar) -> String {
+    if let Some(v) = s.const_value() {
+        return format!("{}", v as i64);
+    }
+    let mut parts = Vec::new();
+    if s.umin != 0 {
+        parts.push(format!("umin={}", s.umin));
+    }
+    if s.umax != u64::MAX {
+        parts.push(format!("umax={}", s.umax));
+    }
+    if s.smin != i64::MIN {
+        parts.push(format!("smin={}", s.smin));
+    }
+    if s.smax != i64::MAX {
+        parts.push(format!("smax={}", s.smax));
+    }
+    if s.var != Tnum::UNKNOWN {
+        parts.push(format!("var_off=({:#x}; {:#x})", s.var.value, s.var.mask));
+    }
+    parts.join(",")
+}
+
+fn fmt_reg(r: &RegState) -> String {
+    let mut args = Vec::new();
+    if r.maybe_null {
+        args.push(format!("id={}", r.id));
+    }
+    match r.kind {
+        RegType::Scalar => {
+            let s = fmt_scalar(&r.var);
+            return match r.var.const_value() {
+                Some(_) => s,
+                None => format!("scalar({})", s),
+            };
+        }
+        RegType::PtrToStack if r.var.const_value() == Some(0) => return format!("fp{}", r.off),
+        RegType::PtrToPacketEnd | RegType::NotInit => return String::from(r.type_name()),
+        RegType::PtrToMapValue { attr, .. } | RegType::ConstPtrToMap { attr, .. } => {
+            args.push(format!("off={}", r.off));
+            args.push(format!("ks={}", attr.key_size));
+            args.push(format!("vs={}", attr.value_size));
+        }
+        _ => args.push(format!("off={}", r.off)),
+    }
+    if r.var.const_value() != Some(0) {
+        args.push(fmt_scalar(&r.var));
+    }
+    format!("{}({})", r.type_name(), args.join(","))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct StackSlot {
+    // an 8-byte register spilled to the slot
+    spill: Option<RegState>,
+    // bit mask of written bytes
+    init: u8,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct State {
+    regs: [RegState; BPF_MAX_REGS],
+    // slot i holds bytes at fp - 8 * (slots - i) and above
+    stack: Vec<StackSlot>,
+    // bytes of packet data and metadata proven to be readable
+    pkt_range: i64,
+    meta_range: i64,
+}
+
+impl State {
+    fn proven_range(&mut self, meta: bool, range: i64) {
+        let proven = if meta { &mut self.meta_range } else { &mut self.pkt_range };
+        *proven = (*proven).max(range);
+    }
+
+    // forget packet pointers, in registers and spilled to the stack
+    fn clear_pkt_pointers(&mut self) {
+        let is_pkt = |r: &RegState| {
+            matches!(
+                r.kind,
+                RegType::PtrToPacket | RegType::PtrToPacketEnd | RegType::PtrToPacketMeta
+            )
+        };
+        let spills = self.stack.iter_mut().filter_map(|slot| slot.spill.as_mut());
+        for r in self.regs.iter_mut().chain(spills).filter(|r| is_pkt(r)) {
+            *r = RegState::scalar(Scalar::unknown());
+        }
+        self.pkt_range = 0;
+        self.meta_range = 0;
+    }
+
+    fn stack_slot(&self, off: i64) -> (usize, usize) {
+        let idx = (off + 8 * self.stack.len() as i64) as usize;
+        (idx / 8, idx % 8)
+    }
+
+    fn covers(&self, other: &Self) -> bool {
+        let regs = self.regs.iter().zip(other.regs.iter()).all(|(a, b)| a.covers(b));
+        let stack = self.stack.iter().zip(other.stack.iter()).all(|(a, b)| {
+            if a.init & !b.init != 0 {
+                return false;
+            }
+            match (a.spill, b.spill) {
+                (Some(a), Some(b)) => a.covers(&b),
+                (Some(_), None) => false,
+                (None, Some(b)) => !b.is_pointer(),
+                (None, None) => true,
+            }
+        });
+        regs && stack && self.pkt_range <= other.pkt_range && self.meta_range <= other.meta_range
+    }
+
+    fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        for (i, r) in self.regs.iter().enumerate() {
+            if r.kind != RegType::NotInit {
+                parts.push(format!("R{}={}", i, fmt_reg(r)));
+            }
+        }
+        let slots = self.stack.len() as i64;
+        for (i, slot) in self.stack.iter().enumerate() {
+            let off = -8 * (slots - i as i64);
+            match slot.spill {
+                Some(r) => parts.push(format!("fp{}={}", off, fmt_reg(&r))),
+                None if slot.init != 0 => {
+                    let bytes: String = (0..8)
+                        .map(|b| if slot.init & (1 << b) != 0 { 'm' } else { '?' })
+                        .collect();
+                    parts.push(format!("fp{}={}", off, bytes));
+                }
+                None => {}
+            }
+        }
+        parts.join(" ")
+    }
+
+    // mark pointers of `id` as checked against NULL
+    fn mark_ptr_or_null(&mut self, id: u32, is_null: bool) {
+        let mark = |r: &mut RegState| {
+            if r.maybe_null && r.id == id {
+                if is_null {
+                    *r = RegState::scalar(Scalar::konst(0));
+                } else {
+                    r.maybe_null = false;
+                }
+            }
+        };
+        self.regs.iter_mut().for_each(mark);
+        self.stack.iter_mut().filter_map(|slot| slot.spill.as_mut()).for_each(mark);
+    }
+}
+
+enum Access {
+    Read,
+    // value of the register or the immediate stored
+    Write(RegState),
+    HelperRead,
+    HelperWrite,
+}
+
+enum Step {
+    Next(usize),
+    // the fall-through state is updated in place, unless it is unreachable
+    Branch { fall: bool, target: usize, taken: Option<Box<State>> },
+    Exit,
+}
+
+fn access_size(op: u8) -> u64 {
+    match (op & 0b11000) as u32 {
+        BPF_B => 1,
+        BPF_H => 2,
+        BPF_W => 4,
+        _ => 8,
+    }
+}
+
+// Static checks run before the program is jitted.
+// The control flow graph must cover the whole program, and must be a DAG unless bounded loops are
+// enabled. Then every path is simulated on abstract register and stack states as the linux verifier
+// does: registers must be initialized before they are read, memory is only accessed through pointers
+// within their bounds, and pointers which may be NULL are checked before use. The result is explained
+// in a kernel-style log.
+pub struct Verifier<'a> {
+    insns: &'a [u64],
+    helpers: &'a HelperRegistry,
+    maps: Option<&'a MapTable>,
+    ctx: CtxLayout,
+    ctx_desc: Option<&'a ContextDescriptor>,
+    prog_type: Option<ProgramType>,
+    stack_size: usize,
+    log: String,
+    // set on the second half of LD_IMM_DW
+    is_ld_imm64_tail: Vec<bool>,
+    // jump targets, where explored states are kept for pruning
+    prune_point: Vec<bool>,
+    // targets of back-edges
+    loop_header: Vec<bool>,
+    explored: Vec<Vec<Explored>>,
+    aux: Vec<InsnAux>,
+    next_id: u32,
+    bounded_loops: bool,
+    complexity_limit: usize,
+}
+
+// A state kept at a prune point. Paths going on from it and not yet finished are counted in `branches`,
+// the state may prune other paths only when they are all finished, or a loop could prune itself.
+struct Explored {
+    state: State,
+    // the previous prune point on the path, as (pc, index in `explored`)
+    parent: Option<(usize, usize)>,
+    branches: u32,
+}
+
+impl<'a> Verifier<'a> {
+    pub fn new(insns: &'a [u64], helpers: &'a HelperRegistry) -> Self {
+        Self {
+            insns,
+            helpers,
+            maps: None,
+            ctx: CtxLayout::default(),
+            ctx_desc: None,
+            prog_type: None,
+            stack_size: 512,
+            log: String::new(),
+            is_ld_imm64_tail: vec![false; insns.len()],
+            prune_point: vec![false; insns.len()],
+            loop_header: vec![false; insns.len()],
+            explored: (0..insns.len()).map(|_| Vec::new()).collect(),
+            aux: vec![InsnAux::default(); insns.len()],
+            next_id: 0,
+            bounded_loops: false,
+            complexity_limit: BPF_COMPLEXITY_LIMIT_INSNS,
+        }
+    }
+
+    // maps referred by `BPF_PSEUDO_MAP_FD`, a program using maps is rejected without it
+    pub fn set_map_table(&mut self, maps: &'a MapTable) {
+        self.maps = Some(maps);
+    }
+
+    // the context is not accessible unless its layout is given
+    pub fn set_ctx_layout(&mut self, ctx: CtxLayout) {
+        self.ctx = ctx;
+    }
+
+    // Check accesses to the context against the fields of `desc` instead of the layout.
+    // The conversion of each access is given by `insn_aux`.
+    pub fn set_ctx_descriptor(&mut self, desc: &'a ContextDescriptor) {
+        self.ctx_desc = Some(desc);
+    }
+
+    // restrict the helpers, the return value, and the access to the context and the packet
+    pub fn set_program_type(&mut self, prog_type: ProgramType) {
+        self.prog_type = Some(prog_type);
+    }
+
+    pub fn set_stack_size(&mut self, stack_size: usize) {
+        self.stack_size = stack_size;
+    }
+
+    // Accept back-edges. Loops are simulated iteration by iteration until they exit, so a loop is
+    // accepted only if every path through it terminates within the complexity limit.
+    pub fn set_bounded_loops(&mut self, enable: bool) {
+        self.bounded_loops = enable;
+    }
+
+    // number of instructions simulated on all paths, `BPF_COMPLEXITY_LIMIT_INSNS` by default
+    pub fn set_complexity_limit(&mut self, limit: usize) {
+        self.complexity_limit = limit;
+    }
+
+    pub fn log(&self) -> &str {
+        &self.log
+    }
+
+    // valid after a successful `verify`
+    pub fn insn_aux(&self) -> &[InsnAux] {
+        &self.aux
+    }
+
+    pub fn verify(&mut self) -> Result<(), VerifierError> {
+        let res = self.check_cfg().and_then(|_| self.do_check());
+        if let Err(e) = res {
+            let msg = self.describe(&e);
+            let _ = writeln!(self.log, "{}", msg);
+        }
+        res
+    }
+
+    fn describe(&self, e: &VerifierError) -> String {
+        let pc = e.bpf_pc;
+        match e.kind {
+            VerifierErrorKind::EmptyProgram => String::from("empty program"),
+            VerifierErrorKind::ProgramTooLarge { len } => {
+                format!("program of {} insns exceeds BPF_MAXINSNS {}", len, BPF_MAXINSNS)
+            }
+            VerifierErrorKind::UnknownOpcode(op) => format!("unknown opcode {:02x} at insn {}", op, pc),
+            VerifierErrorKind::InvalidRegister(reg) => format!("R{} is invalid at insn {}", reg, pc),
+            VerifierErrorKind::IncompleteLdImm64 => format!("invalid BPF_LD_IMM insn {}", pc),
+            VerifierErrorKind::JumpOutOfRange { target } => {
+                format!("jump out of range from insn {} to {}", pc, target)
+            }
+            VerifierErrorKind::JumpIntoLdImm64 { target } => {
+                format!("jump into the middle of ldimm64 insn {} from insn {}", target, pc)
+            }
+            VerifierErrorKind::FallThroughEnd => String::from("last insn is not an exit or jmp"),
+            VerifierErrorKind::BackEdge { target } => format!("back-edge from insn {} to {}", pc, target),
+            VerifierErrorKind::Unreachable => format!("unreachable insn {}", pc),
+            VerifierErrorKind::InfiniteLoop => format!("infinite loop detected at insn {}", pc),
+            VerifierErrorKind::ComplexityLimit { header: Some(header), limit } => format!(
+                "loop at insn {} is not bounded within {} insns, stopped at insn {}",
+                header, limit, pc
+            ),
+            VerifierErrorKind::ComplexityLimit { header: None, limit } => {
+                format!("BPF program is too large. Processed {} insn", limit)
+            }
+            VerifierErrorKind::UninitRegister(reg) => format!("R{} !read_ok", reg),
+            VerifierErrorKind::WriteToFrameReg => String::from("frame pointer is read only"),
+            VerifierErrorKind::UnknownHelper(id) => format!("invalid func unknown#{}", id),
+            VerifierErrorKind::UnknownMapFd(fd) => format!("fd {} is not pointing to valid bpf_map", fd),
+            VerifierErrorKind::UnsupportedPseudoSrc(src) => format!("unsupported pseudo src {} at insn {}", src, pc),
+            VerifierErrorKind::InvalidShift(shift) => format!("invalid shift {}", shift),
+            VerifierErrorKind::InvalidEndSize(size) => format!("BPF_END uses reserved fields, size {}", size),
+            VerifierErrorKind::LdAbsNotAllowed => {
+                String::from("BPF_LD_[ABS|IND] instructions not allowed for this program type")
+            }
+            VerifierErrorKind::HelperNotAllowed(id) => format!("unknown func #{} for this program type", id),
+            VerifierErrorKind::InvalidReturnValue { umin, umax, range } => format!(
+                "At program exit the register R0 has value ({:#x}; {:#x}) should have been in ({:#x}; {:#x})",
+                umin, umax, range.0, range.1
+            ),
+            VerifierErrorKind::PointerArithmetic { reg } => {
+                format!("R{} pointer arithmetic prohibited at insn {}", reg, pc)
+            }
+            VerifierErrorKind::PointerLeak { reg } => format!("R{} leaks addr at insn {}", reg, pc),
+            VerifierErrorKind::InvalidMemAccess { reg, kind } => format!("R{} invalid mem access '{}'", reg, kind),
+            VerifierErrorKind::NullPointerAccess { reg } => {
+                format!("R{} invalid mem access 'map_value_or_null'", reg)
+            }
+            VerifierErrorKind::OutOfBounds { reg, region, off, size } => match region {
+                Region::Ctx { size: ctx_size } => {
+                    format!("invalid bpf_context access off={} size={}, ctx_size={}", off, size, ctx_size)
+                }
+                Region::Stack { size: stack_size } => {
+                    format!("invalid stack off={} size={}, stack_size={}", off, size, stack_size)
+                }
+                Region::MapValue { value_size } => format!(
+                    "invalid access to map value, value_size={} off={} size={}",
+                    value_size, off, size
+                ),
+                Region::Packet { range } => {
+                    format!("invalid access to packet, off={} size={}, R{}(r={})", off, size, reg, range)
+                }
+            },
+            VerifierErrorKind::VariableOffsetAccess { reg } => {
+                format!("R{} variable offset access prohibited at insn {}", reg, pc)
+            }
+            VerifierErrorKind::UnboundedOffset { reg } => {
+                format!("R{} unbounded memory access, make sure to bounds check", reg)
+            }
+            VerifierErrorKind::UninitStackRead { off, size } => {
+                format!("invalid read from stack off {} size {}", off, size)
+            }
+            VerifierErrorKind::InvalidHelperArg { reg, kind, expected } => {
+                format!("R{} type={} expected={:?}", reg, kind, expected)
+            }
+            VerifierErrorKind::InvalidSizeArg { reg } => {
+                format!("R{} invalid size argument, must be bounded and non-zero", reg)
+            }
+            VerifierErrorKind::MixedPointerTypes => String::from("same insn cannot be used with different pointers"),
+            VerifierErrorKind::WriteToReadOnlyMap { reg } => format!("R{} write into map forbidden", reg),
+            VerifierErrorKind::UnterminatedString { reg } => {
+                format!("R{} does not point to a frozen readonly map with a zero-terminated string", reg)
+            }
+        }
+    }
+
+    // successors of the instruction at `pc`, which is not the tail of LD_IMM_DW
+    fn successors(&self, pc: usize) -> Result<Vec<usize>, VerifierError> {
+        let insn = self.insns[pc];
+        let op = (insn & 0xff) as u8;
+        let off = (insn >> 16) as i16;
+
+        let next = if op == LD_IMM_DW { pc + 2 } else { pc + 1 };
+        let class = (op & 0b111) as u32;
+        let code = (op & 0xf0) as u32;
+        let mut succs = Vec::new();
+        let is_jump = class == BPF_JMP || class == BPF_JMP32;
+        if is_jump && code == BPF_EXIT {
+            return Ok(succs);
+        }
+        if !(is_jump && code == BPF_JA) {
+            succs.push(next);
+        }
+        if is_jump && code != BPF_CALL {
+            let target = pc as isize + 1 + off as isize;
+            if target < 0 || target >= self.insns.len() as isize {
+                return Err(VerifierError::new(pc, VerifierErrorKind::JumpOutOfRange { target }));
+            }
+            succs.push(target as usize);
+        }
+
+        for &succ in &succs {
+            if succ >= self.insns.len() {
+                return Err(VerifierError::new(pc, VerifierErrorKind::FallThroughEnd));
+            }
+            if self.is_ld_imm64_tail[succ] {
+                return Err(VerifierError::new(pc, VerifierErrorKind::JumpIntoLdImm64 { target: succ - 1 }));
+            }
+            if succ <= pc && !self.bounded_loops {
+                return Err(VerifierError::new(pc, VerifierErrorKind::BackEdge { target: succ }));
+            }
+        }
+        Ok(succs)
+    }
+
+    fn check_cfg(&mut self) -> Result<(), VerifierError> {
+        let len = self.insns.len();
+        if len == 0 {
+            return Err(VerifierError::new(0, VerifierErrorKind::EmptyProgram));
+        }
+        if len > BPF_MAXINSNS as usize {
+            return Err(VerifierError::new(0, VerifierErrorKind::ProgramTooLarge { len }));
+        }
+
+        let mut pc = 0;
+        while pc < len {
+            let insn = self.insns[pc];
+            let op = (insn & 0xff) as u8;
+            let dst = ((insn & 0x0f00) >> 8) as u8;
+            let src = ((insn & 0xf000) >> 12) as u8;
+            if is_ld_abs(op) {
+                if !self.prog_type.is_some_and(|t| t.allows_ld_abs()) {
+                    return Err(VerifierError::new(pc, VerifierErrorKind::LdAbsNotAllowed));
+                }
+            } else if !is_supported_opcode(op) {
+                return Err(VerifierError::new(pc, VerifierErrorKind::UnknownOpcode(op)));
+            }
+            if dst as usize >= BPF_MAX_REGS {
+                return Err(VerifierError::new(pc, VerifierErrorKind::InvalidRegister(dst)));
+            }
+            // src of LD_IMM_DW and CALL is a pseudo source
+            if op != LD_IMM_DW && op != JMP_K_CALL && src as usize >= BPF_MAX_REGS {
+                return Err(VerifierError::new(pc, VerifierErrorKind::InvalidRegister(src)));
+            }
+            if op == LD_IMM_DW {
+                // the second half must have all fields but imm zeroed
+                if pc + 1 >= len || self.insns[pc + 1] as u32 != 0 {
+                    return Err(VerifierError::new(pc, VerifierErrorKind::IncompleteLdImm64));
+                }
+                self.is_ld_imm64_tail[pc + 1] = true;
+                pc += 1;
+            }
+            pc += 1;
+        }
+
+        // every instruction must be reachable from the first one
+        let mut reached = vec![false; len];
+        let mut stack = vec![0];
+        reached[0] = true;
+        while let Some(pc) = stack.pop() {
+            let class = (self.insns[pc] & 0b111) as u32;
+            let is_branch = (class == BPF_JMP || class == BPF_JMP32) && self.successors(pc)?.len() > 1;
+            for succ in self.successors(pc)? {
+                if is_branch || succ != pc + 1 {
+                    self.prune_point[succ] = true;
+                }
+                if succ <= pc {
+                    self.loop_header[succ] = true;
+                }
+                if !reached[succ] {
+                    reached[succ] = true;
+                    stack.push(succ);
+                }
+            }
+        }
+        match (0..len).find(|&pc| !reached[pc] && !self.is_ld_imm64_tail[pc]) {
+            Some(pc) => Err(VerifierError::new(pc, VerifierErrorKind::Unreachable)),
+            None => Ok(()),
+        }
+    }
+
+    fn init_state(&self) -> State {
+        let mut regs = [RegState::not_init(); BPF_MAX_REGS];
+        regs[BPF_REG_R1 as usize] = RegState::ptr(RegType::PtrToCtx);
+        regs[BPF_REG_FP as usize] = RegState::ptr(RegType::PtrToStack);
+        State {
+            regs,
+            stack: vec![StackSlot { spill: None, init: 0 }; self.stack_size.div_ceil(8)],
+            pkt_range: 0,
+            meta_range: 0,
+        }
+    }
+
+    // Walk every path from the first instruction in depth, the fall-through branch first.
+    // A path is pruned when it reaches a jump target in a state covered by one already explored there.
+    fn do_check(&mut self) -> Result<(), VerifierError> {
+        let mut pending = vec![(None, 0, self.init_state(), None)];
+        let mut processed = 0;
+        let mut states = 0;
+        while let Some((from, mut pc, mut state, mut parent)) = pending.pop() {
+            if let Some(from) = from {
+                self.trace(format_args!("from {} to {}: {}", from, pc, state.describe()));
+            }
+            loop {
+                if self.prune_point[pc] {
+                    let mut pruned = false;
+                    for old in &self.explored[pc] {
+                        if old.branches == 0 && old.state.covers(&state) {
+                            pruned = true;
+                            break;
+                        }
+                        if old.branches > 0 && old.state == state {
+                            return Err(VerifierError::new(pc, VerifierErrorKind::InfiniteLoop));
+                        }
+                    }
+                    if pruned {
+                        self.trace(format_args!("{}: safe", pc));
+                        self.finish_path(parent);
+                        break;
+                    }
+                    self.explored[pc].push(Explored {
+                        state: state.clone(),
+                        parent,
+                        branches: 1,
+                    });
+                    parent = Some((pc, self.explored[pc].len() - 1));
+                    states += 1;
+                }
+
+                processed += 1;
+                if processed > self.complexity_limit {
+                    let header = self.innermost_loop(parent);
+                    let limit = self.complexity_limit;
+                    return Err(VerifierError::new(pc, VerifierErrorKind::ComplexityLimit { header, limit }));
+                }
+                if self.log.len() < LOG_SIZE_MAX {
+                    let op = (self.insns[pc] & 0xff) as u8;
+                    self.trace(format_args!("{}: ({:02x}) {}", pc, op, disasm(self.insns, pc)));
+                }
+
+                match self.step(pc, &mut state)? {
+                    Step::Next(next) => pc = next,
+                    Step::Branch { fall, target, taken } => {
+                        if let Some(taken) = taken {
+                            if let Some((p, i)) = parent {
+                                self.explored[p][i].branches += 1;
+                            }
+                            pending.push((Some(pc), target, *taken, parent));
+                        }
+                        if !fall {
+                            self.finish_path(parent);
+                            break;
+                        }
+                        pc += 1;
+                    }
+                    Step::Exit => {
+                        self.finish_path(parent);
+                        break;
+                    }
+                }
+            }
+        }
+        self.trace(format_args!("processed {} insns, {} states", processed, states));
+        Ok(())
+    }
+
+    // a path ends, the states it went through are finished unless other paths go on from them
+    fn finish_path(&mut self, mut parent: Option<(usize, usize)>) {
+        while let Some((pc, i)) = parent {
+            let explored = &mut self.explored[pc][i];
+            explored.branches -= 1;
+            if explored.branches > 0 {
+                break;
+            }
+            parent = explored.parent;
+        }
+    }
+
+    fn innermost_loop(&self, mut parent: Option<(usize, usize)>) -> Option<usize> {
+        while let Some((pc, i)) = parent {
+            if self.loop_header[pc] {
+                return Some(pc);
+            }
+            parent = self.explored[pc][i].parent;
+        }
+        None
+    }
+
+    fn trace(&mut self, args: core::fmt::Arguments) {
+        if self.log.len() < LOG_SIZE_MAX {
+            let _ = self.log.write_fmt(args);
+            self.log.push('\n');
+        }
+    }
+
+    fn check_reg_read(&self, pc: usize, state: &State, reg: u8) -> Result<(), VerifierError> {
+        if state.regs[reg as usize].kind == RegType::NotInit {
+            return Err(VerifierError::new(pc, VerifierErrorKind::UninitRegister(reg)));
+        }
+        Ok(())
+    }
+
+    fn step(&mut self, pc: usize, state: &mut State) -> Result<Step, VerifierError> {
+        let insn = self.insns[pc];
+        let op = (insn & 0xff) as u8;
+        let dst = ((insn & 0x0f00) >> 8) as u8;
+        let src = ((insn & 0xf000) >> 12) as u8;
+        let off = (insn >> 16) as i16;
+        let imm = (insn >> 32) as i32;
+        let class = (op & 0b111) as u32;
+
+        match class {
+            BPF_ALU | BPF_ALU64 => {
+                self.check_alu(pc, state, op, dst, src, imm)?;
+                Ok(Step::Next(pc + 1))
+            }
+            BPF_LD if is_ld_abs(op) => {
+                // the packet is read through the context in R6, R1-R5 are clobbered
+                self.check_reg_read(pc, state, BPF_REG_R6)?;
+                let ctx = state.regs[BPF_REG_R6 as usize];
+                if ctx.kind != RegType::PtrToCtx || ctx.off != 0 || ctx.var.const_value() != Some(0) {
+                    return Err(VerifierError::new(
+                        pc,
+                        VerifierErrorKind::InvalidMemAccess {
+                            reg: BPF_REG_R6,
+                            kind: ctx.type_name(),
+                        },
+                    ));
+                }
+                if (op & 0xe0) as u32 == BPF_IND {
+                    self.check_reg_read(pc, state, src)?;
+                    if state.regs[src as usize].is_pointer() {
+                        return Err(VerifierError::new(pc, VerifierErrorKind::PointerArithmetic { reg: src }));
+                    }
+                }
+                for reg in BPF_REG_R1..=BPF_REG_R5 {
+                    state.regs[reg as usize] = RegState::not_init();
+                }
+                state.regs[BPF_REG_R0 as usize] = RegState::scalar(Scalar::unknown().cast(access_size(op)));
+                Ok(Step::Next(pc + 1))
+            }
+            BPF_LD => {
+                if dst == BPF_REG_FP {
+                    return Err(VerifierError::new(pc, VerifierErrorKind::WriteToFrameReg));
+                }
+                let imm64 = (imm as u32 as u64) | (self.insns[pc + 1] >> 32 << 32);
+                state.regs[dst as usize] = match src as u32 {
+                    0 => RegState::scalar(Scalar::konst(imm64)),
+                    BPF_PSEUDO_MAP_FD => {
+                        let fd = imm as u32;
+                        let map = self
+                            .maps
+                            .and_then(|maps| maps.get(fd))
+                            .ok_or(VerifierError::new(pc, VerifierErrorKind::UnknownMapFd(fd)))?;
+                        RegState::ptr(RegType::ConstPtrToMap {
+                            attr: *map.attr(),
+                            inner: map.inner_attr().copied(),
+                            fd: Some(fd),
+                        })
+                    }
+                    _ => return Err(VerifierError::new(pc, VerifierErrorKind::UnsupportedPseudoSrc(src))),
+                };
+                Ok(Step::Next(pc + 2))
+            }
+            BPF_LDX => {
+                self.check_reg_read(pc, state, src)?;
+                if dst == BPF_REG_FP {
+                    return Err(VerifierError::new(pc, VerifierErrorKind::WriteToFrameReg));
+                }
+                let size = access_size(op);
+                let value = if (op & 0xe0) as u32 == BPF_PROBE_MEM {
+                    // a fault reads zero, whatever the address is
+                    self.record_mem(pc, MemKind::Probe, None)?;
+                    RegState::scalar(Scalar::unknown().cast(size))
+                } else {
+                    let (value, kind, ctx) = self.check_mem_access(pc, state, src, off as i64, size, Access::Read)?;
+                    self.record_mem(pc, kind, ctx)?;
+                    value
+                };
+                state.regs[dst as usize] = value;
+                Ok(Step::Next(pc + 1))
+            }
+            BPF_ST | BPF_STX => {
+                self.check_reg_read(pc, state, dst)?;
+                let value = if class == BPF_ST {
+                    RegState::scalar(Scalar::konst(imm as i64 as u64))
+                } else {
+                    self.check_reg_read(pc, state, src)?;
+                    state.regs[src as usize]
+                };
+                // pointers may only be spilled to the stack
+                if value.is_pointer() && state.regs[dst as usize].kind != RegType::PtrToStack {
+                    return Err(VerifierError::new(pc, VerifierErrorKind::PointerLeak { reg: src }));
+                }
+                let size = access_size(op);
+                let (_, kind, ctx) = self.check_mem_access(pc, state, dst, off as i64, size, Access::Write(value))?;
+                self.record_mem(pc, kind, ctx)?;
+                Ok(Step::Next(pc + 1))
+            }
+            _ => match (op & 0xf0) as u32 {
+                BPF_JA => Ok(Step::Next((pc as isize + 1 + off as isize) as usize)),
+                BPF_CALL => {
+                    if src != 0 {
+                        return Err(VerifierError::new(pc, VerifierErrorKind::UnsupportedPseudoSrc(src)));
+                    }
+                    self.check_call(pc, state, imm as u32)?;
+                    Ok(Step::Next(pc + 1))
+                }
+                BPF_EXIT => {
+                    self.check_reg_read(pc, state, BPF_REG_R0)?;
+                    let r0 = state.regs[BPF_REG_R0 as usize];
+                    if r0.is_pointer() {
+                        return Err(VerifierError::new(pc, VerifierErrorKind::PointerLeak { reg: BPF_REG_R0 }));
+                    }
+                    if let Some(range) = self.prog_type.and_then(|t| t.return_range()) {
+                        if r0.var.umin < range.0 || r0.var.umax > range.1 {
+                            let (umin, umax) = (r0.var.umin, r0.var.umax);
+                            return Err(VerifierError::new(pc, VerifierErrorKind::InvalidReturnValue { umin, umax, range }));
+                        }
+                    }
+                    Ok(Step::Exit)
+                }
+                _ => self.check_cond_jmp(pc, state, op, dst, src, off, imm),
+            },
+        }
+    }
+
+    // a memory access of an instruction must be of the same kind on every path
+    fn record_mem(&mut self, pc: usize, kind: MemKind, ctx: Option<CtxAccess>) -> Result<(), VerifierError> {
+        let aux = &mut self.aux[pc];
+        match aux.mem {
+            Some(old) if old != kind && (old == MemKind::Ctx || kind == MemKind::Ctx) => {
+                Err(VerifierError::new(pc, VerifierErrorKind::MixedPointerTypes))
+            }
+            // the context is converted in the same way on every path
+            Some(_) if aux.ctx != ctx => Err(VerifierError::new(pc, VerifierErrorKind::MixedPointerTypes)),
+            _ => {
+                aux.mem = Some(kind);
+                aux.ctx = ctx;
+                Ok(())
+            }
+        }
+    }
+
+    // the map passed to a helper call, poisoned unless it is the same on every path
+    fn record_map(&mut self, pc: usize, fd: Option<u32>) {
+        let aux = &mut self.aux[pc];
+        aux.map = match (aux.map, fd) {
+            (None, Some(fd)) => Some(MapPtrState::Fd(fd)),
+            (Some(MapPtrState::Fd(old)), Some(fd)) if old == fd => aux.map,
+            _ => Some(MapPtrState::Poison),
+        };
+    }
+
+    fn check_alu(&mut self, pc: usize, state: &mut State, op: u8, dst: u8, src: u8, imm: i32) -> Result<(), VerifierError> {
+        let is64 = (op & 0b111) as u32 == BPF_ALU64;
+        let code = (op & 0xf0) as u32;
+        let use_imm = (op & 8) == 0;
+        if !use_imm {
+            self.check_reg_read(pc, state, src)?;
+        }
+        if code != BPF_MOV {
+            self.check_reg_read(pc, state, dst)?;
+        }
+        if dst == BPF_REG_FP {
+            return Err(VerifierError::new(pc, VerifierErrorKind::WriteToFrameReg));
+        }
+        if matches!(code, BPF_LSH | BPF_RSH | BPF_ARSH) && use_imm && imm as u32 >= if is64 { 64 } else { 32 } {
+            return Err(VerifierError::new(pc, VerifierErrorKind::InvalidShift(imm)));
+        }
+        if code == BPF_END && !matches!(imm, 16 | 32 | 64) {
+            return Err(VerifierError::new(pc, VerifierErrorKind::InvalidEndSize(imm)));
+        }
+
+        let a = state.regs[dst as usize];
+        let b = if use_imm || code == BPF_NEG || code == BPF_END {
+            RegState::scalar(Scalar::konst(imm as i64 as u64))
+        } else {
+            state.regs[src as usize]
+        };
+
+        let result = if (a.is_pointer() && code != BPF_MOV) || b.is_pointer() {
+            if code == BPF_MOV && is64 {
+                b
+            } else if !is64 {
+                let reg = if b.is_pointer() { src } else { dst };
+                return Err(VerifierError::new(pc, VerifierErrorKind::PointerArithmetic { reg }));
+            } else {
+                self.check_ptr_alu(pc, code, dst, src, a, b)?
+            }
+        } else if code == BPF_END {
+            // to little endian truncates, the ALU64 form always swaps bytes
+            let size = imm as u64 / 8;
+            let swap = |v: u64| v.swap_bytes() >> (64 - imm);
+            match (is64 || op as u32 & BPF_X == BPF_TO_BE, a.var.const_value()) {
+                (false, _) => RegState::scalar(a.var.cast(size)),
+                (true, Some(v)) => RegState::scalar(Scalar::konst(swap(v))),
+                (true, None) => RegState::scalar(Scalar::unknown().cast(size)),
+            }
+        } else if is64 {
+            RegState::scalar(a.var.alu(code, b.var))
+        } else if matches!(code, BPF_LSH | BPF_RSH | BPF_ARSH) {
+            // 32 bit shifts use the low 5 bits of the amount, an arithmetic one the sign bit of the lower half
+            let k = b.var.cast(4).alu(BPF_AND, Scalar::konst(31));
+            let a = a.var.cast(4);
+            let r = match code {
+                BPF_ARSH => a.alu(BPF_LSH, Scalar::konst(32)).alu(BPF_ARSH, Scalar::konst(32)).alu(BPF_ARSH, k),
+                _ => a.alu(code, k),
+            };
+            RegState::scalar(r.cast(4))
+        } else {
+            RegState::scalar(a.var.cast(4).alu(code, b.var.cast(4)).cast(4))
+        };
+        state.regs[dst as usize] = result;
+        Ok(())
+    }
+
+    // only a scalar may be added to or subtracted from a pointer, and pointers of the same kind may be subtracted
+    fn check_ptr_alu(&self, pc: usize, code: u32, dst: u8, src: u8, a: RegState, b: RegState) -> Result<RegState, VerifierError> {
+        let (ptr, ptr_reg, offset, offset_reg) = match (code, a.is_pointer(), b.is_pointer()) {
+            (BPF_ADD, true, false) | (BPF_SUB, true, false) => (a, dst, b.var, src),
+            (BPF_ADD, false, true) => (b, src, a.var, dst),
+            (BPF_SUB, true, true) if a.kind == b.kind && !a.maybe_null && !b.maybe_null => {
+                return Ok(RegState::scalar(Scalar::unknown()));
+            }
+            _ => {
+                let reg = if a.is_pointer() { dst } else { src };
+                return Err(VerifierError::new(pc, VerifierErrorKind::PointerArithmetic { reg }));
+            }
+        };
+        let movable = matches!(
+            ptr.kind,
+            RegType::PtrToCtx
+                | RegType::PtrToStack
+                | RegType::PtrToMapValue { .. }
+                | RegType::PtrToPacket
+                | RegType::PtrToPacketMeta
+        );
+        if !movable || ptr.maybe_null {
+            return Err(VerifierError::new(pc, VerifierErrorKind::PointerArithmetic { reg: ptr_reg }));
+        }
+
+        let mut ptr = ptr;
+        match offset.const_value() {
+            Some(c) => {
+                let off = if code == BPF_SUB {
+                    ptr.off as i64 - c as i64
+                } else {
+                    ptr.off as i64 + c as i64
+                };
+                if off <= -BPF_MAX_VAR_OFF || off >= BPF_MAX_VAR_OFF {
+                    return Err(VerifierError::new(pc, VerifierErrorKind::UnboundedOffset { reg: offset_reg }));
+                }
+                ptr.off = off as i32;
+            }
+            None => {
+                if offset.smin <= -BPF_MAX_VAR_OFF || offset.smax >= BPF_MAX_VAR_OFF {
+                    return Err(VerifierError::new(pc, VerifierErrorKind::UnboundedOffset { reg: offset_reg }));
+                }
+                ptr.var = ptr.var.alu(code, offset);
+                if ptr.var.smin <= -BPF_MAX_VAR_OFF || ptr.var.smax >= BPF_MAX_VAR_OFF {
+                    return Err(VerifierError::new(pc, VerifierErrorKind::UnboundedOffset { reg: ptr_reg }));
+                }
+            }
+        }
+        Ok(ptr)
+    }
+
+    // Check an access of `size` bytes at `off` from the pointer in `reg`.
+    // Returns the value read, the kind of memory accessed and the conversion of an access to the context.
+    fn check_mem_access(
+        &self,
+        pc: usize,
+        state: &mut State,
+        reg: u8,
+        off: i64,
+        size: u64,
+        access: Access,
+    ) -> Result<(RegState, MemKind, Option<CtxAccess>), VerifierError> {
+        let ptr = state.regs[reg as usize];
+        let err = |kind| Err(VerifierError::new(pc, kind));
+        if ptr.maybe_null {
+            return err(VerifierErrorKind::NullPointerAccess { reg });
+        }
+        let is_read = matches!(access, Access::Read | Access::HelperRead);
+        let unknown = RegState::scalar(Scalar::unknown().cast(size.min(8)));
+
+        // bounds of the offset from the start of the region
+        let lo = (ptr.off as i64 + off).saturating_add(ptr.var.smin);
+        let hi = (ptr.off as i64 + off + size as i64).saturating_add(ptr.var.smax);
+        let out_of_bounds = |region| {
+            err(VerifierErrorKind::OutOfBounds {
+                reg,
+                region,
+                off: if lo < 0 { lo } else { hi - size as i64 },
+                size,
+            })
+        };
+
+        match ptr.kind {
+            RegType::PtrToStack => {
+                if ptr.var.const_value().is_none() {
+                    return err(VerifierErrorKind::VariableOffsetAccess { reg });
+                }
+                if lo < -(self.stack_size as i64) || hi > 0 {
+                    return out_of_bounds(Region::Stack { size: self.stack_size });
+                }
+                let (slot, byte) = state.stack_slot(lo);
+                let value = match access {
+                    Access::Write(value) if size == 8 && byte == 0 => {
+                        state.stack[slot] = StackSlot {
+                            spill: Some(value),
+                            init: 0xff,
+                        };
+                        unknown
+                    }
+                    Access::Read if size == 8 && byte == 0 && state.stack[slot].spill.is_some() => {
+                        state.stack[slot].spill.unwrap()
+                    }
+                    _ => {
+                        for addr in lo..hi {
+                            let (slot, byte) = state.stack_slot(addr);
+                            let slot = &mut state.stack[slot];
+                            if is_read {
+                                if slot.init & (1 << byte) == 0 {
+                                    return err(VerifierErrorKind::UninitStackRead { off: lo, size });
+                                }
+                                // bytes of a pointer must not be read as a scalar
+                                if slot.spill.is_some_and(|r| r.is_pointer()) {
+                                    return err(VerifierErrorKind::PointerLeak { reg });
+                                }
+                            } else {
+                                slot.spill = None;
+                                slot.init |= 1 << byte;
+                            }
+                        }
+                        unknown
+                    }
+                };
+                Ok((value, MemKind::Stack, None))
+            }
+            RegType::PtrToCtx => {
+                if ptr.var.const_value().is_none() {
+                    return err(VerifierErrorKind::VariableOffsetAccess { reg });
+                }
+                if let Some(desc) = self.ctx_desc {
+                    let region = Region::Ctx { size: desc.size() };
+                    // helpers would access the real struct at offsets of the UAPI one
+                    let is_write = match access {
+                        Access::Read => false,
+                        Access::Write(_) => true,
+                        Access::HelperRead | Access::HelperWrite => return out_of_bounds(region),
+                    };
+                    if lo < 0 || hi > desc.size() as i64 {
+                        return out_of_bounds(region);
+                    }
+                    let Some((field, conv)) = desc.convert(lo as u32, size as u32, is_write) else {
+                        return out_of_bounds(region);
+                    };
+                    // the offset of the converted instruction must fit
+                    if let CtxAccess::Mem { delta, .. } = conv {
+                        if off + delta as i64 > i16::MAX as i64 || off + (delta as i64) < i16::MIN as i64 {
+                            return out_of_bounds(region);
+                        }
+                    }
+                    let value = match field.access {
+                        FieldAccess::PktData => RegState::ptr(RegType::PtrToPacket),
+                        FieldAccess::PktDataEnd => RegState::ptr(RegType::PtrToPacketEnd),
+                        FieldAccess::PktMeta => RegState::ptr(RegType::PtrToPacketMeta),
+                        _ => unknown,
+                    };
+                    return Ok((value, MemKind::Ctx, Some(conv)));
+                }
+                let region = Region::Ctx { size: self.ctx.size };
+                let is_write = !matches!(access, Access::Read | Access::HelperRead);
+                let read_only = self.prog_type.is_some_and(|t| t.is_ctx_read_only());
+                if lo < 0 || hi > self.ctx.size as i64 || is_write && read_only {
+                    return out_of_bounds(region);
+                }
+                // packet pointers are read-only and must be read as a whole
+                let mut value = unknown;
+                for (field, kind) in [
+                    (self.ctx.pkt_data, RegType::PtrToPacket),
+                    (self.ctx.pkt_data_end, RegType::PtrToPacketEnd),
+                ] {
+                    if let Some(field) = field.map(|field| field as i64) {
+                        if lo == field && size == 8 && matches!(access, Access::Read) {
+                            value = RegState::ptr(kind);
+                        } else if lo < field + 8 && hi > field {
+                            return out_of_bounds(region);
+                        }
+                    }
+                }
+                Ok((value, MemKind::Ctx, None))
+            }
+            RegType::PtrToMapValue { attr, .. } => {
+                if lo < 0 || hi > attr.value_size as i64 {
+                    return out_of_bounds(Region::MapValue {
+                        value_size: attr.value_size,
+                    });
+                }
+                if !is_read && attr.map_flags & BPF_F_RDONLY_PROG != 0 {
+                    return err(VerifierErrorKind::WriteToReadOnlyMap { reg });
+                }
+                Ok((unknown, MemKind::MapValue, None))
+            }
+            RegType::PtrToPacket => {
+                if lo < 0 || hi > state.pkt_range {
+                    return out_of_bounds(Region::Packet { range: state.pkt_range });
+                }
+                Ok((unknown, MemKind::Packet, None))
+            }
+            RegType::PtrToPacketMeta => {
+                if lo < 0 || hi > state.meta_range {
+                    return out_of_bounds(Region::Packet { range: state.meta_range });
+                }
+                Ok((unknown, MemKind::Packet, None))
+            }
+            _ => err(VerifierErrorKind::InvalidMemAccess {
+                reg,
+                kind: ptr.type_name(),
+            }),
+        }
+    }
+
+    fn check_call(&mut self, pc: usize, state: &mut State, id: u32) -> Result<(), VerifierError> {
+        if self.prog_type.is_some_and(|t| !t.is_helper_allowed(id)) {
+            return Err(VerifierError::new(pc, VerifierErrorKind::HelperNotAllowed(id)));
+        }
+        let (args, ret) = match self.helpers.get(id) {
+            Some(helper) => (helper.args, helper.ret),
+            None if id == BPF_FUNC_PROBE_READ || id == BPF_FUNC_PROBE_READ_KERNEL => {
+                (&PROBE_READ_ARGS[..], RetKind::Integer)
+            }
+            None => return Err(VerifierError::new(pc, VerifierErrorKind::UnknownHelper(id))),
+        };
+
+        // the map of a `ConstMapPtr` argument, and the memory of a pending `PtrToMem` argument
+        let mut map: Option<(MapAttr, Option<MapAttr>, Option<u32>)> = None;
+        let mut mem: Option<(u8, bool)> = None;
+        for (i, &arg) in args.iter().enumerate() {
+            let reg = i as u8 + 1;
+            self.check_reg_read(pc, state, reg)?;
+            let r = state.regs[reg as usize];
+            let bad_arg = VerifierError::new(
+                pc,
+                VerifierErrorKind::InvalidHelperArg {
+                    reg,
+                    kind: r.type_name(),
+                    expected: arg,
+                },
+            );
+            match arg {
+                ArgKind::Anything => {}
+                ArgKind::ConstMapPtr => match r.kind {
+                    RegType::ConstPtrToMap { attr, inner, fd } if !r.maybe_null => {
+                        let is_write = id == BPF_FUNC_MAP_UPDATE_ELEM || id == BPF_FUNC_MAP_DELETE_ELEM;
+                        if is_write && attr.map_flags & BPF_F_RDONLY_PROG != 0 {
+                            return Err(VerifierError::new(pc, VerifierErrorKind::WriteToReadOnlyMap { reg }));
+                        }
+                        map = Some((attr, inner, fd));
+                        self.record_map(pc, fd);
+                    }
+                    _ => return Err(bad_arg),
+                },
+                ArgKind::PtrToMapKey | ArgKind::PtrToMapValue => {
+                    let (attr, _, _) = map.ok_or(bad_arg)?;
+                    let size = if arg == ArgKind::PtrToMapKey {
+                        attr.key_size
+                    } else {
+                        attr.value_size
+                    };
+                    self.check_mem_access(pc, state, reg, 0, size as u64, Access::HelperRead)?;
+                }
+                ArgKind::PtrToCtx => {
+                    if r.kind != RegType::PtrToCtx || r.off != 0 || r.var.const_value() != Some(0) {
+                        return Err(bad_arg);
+                    }
+                }
+                ArgKind::PtrToMem => mem = Some((reg, false)),
+                ArgKind::PtrToUninitMem => mem = Some((reg, true)),
+                ArgKind::ConstSize | ArgKind::ConstSizeOrZero => {
+                    if r.kind != RegType::Scalar {
+                        return Err(bad_arg);
+                    }
+                    let size = r.var;
+                    if size.umax >= BPF_MAX_VAR_OFF as u64 || (arg == ArgKind::ConstSize && size.umin == 0) {
+                        return Err(VerifierError::new(pc, VerifierErrorKind::InvalidSizeArg { reg }));
+                    }
+                    if let Some((mem_reg, write)) = mem.take() {
+                        if size.umax > 0 {
+                            let access = if write {
+                                Access::HelperWrite
+                            } else {
+                                Access::HelperRead
+                            };
+                            self.check_mem_access(pc, state, mem_reg, 0, size.umax, access)?;
+                        }
+                    }
+                }
+                ArgKind::PtrToConstStr => {
+                    // as linux, the string must be in a read-only and frozen map, whose values are constant
+                    let fd = match r.kind {
+                        RegType::PtrToMapValue { attr, fd: Some(fd) } if attr.map_flags & BPF_F_RDONLY_PROG != 0 => fd,
+                        _ => return Err(bad_arg),
+                    };
+                    self.check_mem_access(pc, state, reg, 0, 1, Access::HelperRead)?;
+                    let off = match r.var.const_value() {
+                        Some(var) => r.off as i64 + var as i64,
+                        None => return Err(VerifierError::new(pc, VerifierErrorKind::VariableOffsetAccess { reg })),
+                    };
+                    let map = self.maps.and_then(|maps| maps.get(fd)).filter(|map| map.is_frozen());
+                    if !map.is_some_and(|map| map.has_nul_from(off as usize)) {
+                        return Err(VerifierError::new(pc, VerifierErrorKind::UnterminatedString { reg }));
+                    }
+                }
+                ArgKind::PtrToLong => {
+                    self.check_mem_access(pc, state, reg, 0, 8, Access::HelperWrite)?;
+                }
+            }
+        }
+        // memory without a size argument must have at least one byte
+        if let Some((mem_reg, write)) = mem {
+            let access = if write {
+                Access::HelperWrite
+            } else {
+                Access::HelperRead
+            };
+            self.check_mem_access(pc, state, mem_reg, 0, 1, access)?;
+        }
+
+        for reg in BPF_REG_R1..=BPF_REG_R5 {
+            state.regs[reg as usize] = RegState::not_init();
+        }
+        // packet pointers may be out of the moved packet, they must be loaded again
+        if changes_pkt_data(id) {
+            state.clear_pkt_pointers();
+        }
+        state.regs[BPF_REG_R0 as usize] = match (ret, map) {
+            (RetKind::Integer, _) => RegState::scalar(Scalar::unknown()),
+            (RetKind::Void, _) => RegState::not_init(),
+            (RetKind::PtrToMapValueOrNull, Some((attr, inner, fd))) => {
+                // a lookup in a map-in-map gives the inner map
+                let kind = match attr.map_type.is_map_in_map() {
+                    true => inner.map(|inner| RegType::ConstPtrToMap {
+                        attr: inner,
+                        inner: None,
+                        fd: None,
+                    }),
+                    false => Some(RegType::PtrToMapValue { attr, fd }),
+                };
+                match kind {
+                    Some(kind) => {
+                        self.next_id += 1;
+                        RegState {
+                            id: self.next_id,
+                            maybe_null: true,
+                            ..RegState::ptr(kind)
+                        }
+                    }
+                    None => RegState::scalar(Scalar::unknown()),
+                }
+            }
+            (RetKind::PtrToMapValueOrNull, None) => RegState::scalar(Scalar::unknown()),
+        };
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn check_cond_jmp(
+        &mut self,
+        pc: usize,
+        state: &mut State,
+        op: u8,
+        dst: u8,
+        src: u8,
+        off: i16,
+        imm: i32,
+    ) -> Result<Step, VerifierError> {
+        let is64 = (op & 0b111) as u32 == BPF_JMP;
+        let code = (op & 0xf0) as u32;
+        let use_imm = (op & 8) == 0;
+        let target = (pc as isize + 1 + off as isize) as usize;
+        self.check_reg_read(pc, state, dst)?;
+        if !use_imm {
+            self.check_reg_read(pc, state, src)?;
+        }
+
+        let a = state.regs[dst as usize];
+        let b = if use_imm {
+            let imm = if is64 { imm as i64 as u64 } else { imm as u32 as u64 };
+            RegState::scalar(Scalar::konst(imm))
+        } else {
+            state.regs[src as usize]
+        };
+        let mut taken = Box::new(state.clone());
+
+        if a.maybe_null && is64 && matches!(code, BPF_JEQ | BPF_JNE) && b.var.const_value() == Some(0) && !b.is_pointer() {
+            let (null, non_null) = if code == BPF_JEQ {
+                (&mut *taken, &mut *state)
+            } else {
+                (&mut *state, &mut *taken)
+            };
+            null.mark_ptr_or_null(a.id, true);
+            non_null.mark_ptr_or_null(a.id, false);
+            return Ok(Step::Branch {
+                fall: true,
+                target,
+                taken: Some(taken),
+            });
+        }
+
+        // Comparing a packet pointer with the end of packet proves the bytes before it readable,
+        // as does comparing a metadata pointer with the start of packet for metadata.
+        let swapped = match code {
+            BPF_JGT => BPF_JLT,
+            BPF_JGE => BPF_JLE,
+            BPF_JLT => BPF_JGT,
+            BPF_JLE => BPF_JGE,
+            code => code,
+        };
+        let pkt_start = |r: &RegState| r.kind == RegType::PtrToPacket && r.off == 0 && r.var.const_value() == Some(0);
+        let pkt = match (a.kind, b.kind) {
+            (RegType::PtrToPacket, RegType::PtrToPacketEnd) => Some((a, code, false)),
+            (RegType::PtrToPacketEnd, RegType::PtrToPacket) => Some((b, swapped, false)),
+            (RegType::PtrToPacketMeta, RegType::PtrToPacket) if pkt_start(&b) => Some((a, code, true)),
+            (RegType::PtrToPacket, RegType::PtrToPacketMeta) if pkt_start(&a) => Some((b, swapped, true)),
+            _ => None,
+        };
+        if let Some((ptr, code, meta)) = pkt.filter(|&(ptr, _, _)| is64 && ptr.var.const_value() == Some(0)) {
+            let range = ptr.off as i64;
+            match code {
+                BPF_JGT | BPF_JGE => state.proven_range(meta, range),
+                BPF_JLT | BPF_JLE => taken.proven_range(meta, range),
+                _ => {}
+            }
+            return Ok(Step::Branch {
+                fall: true,
+                target,
+                taken: Some(taken),
+            });
+        }
+
+        if a.is_pointer() || b.is_pointer() {
+            return Ok(Step::Branch {
+                fall: true,
+                target,
+                taken: Some(taken),
+            });
+        }
+
+        // 32-bit comparisons are only refined when they agree with 64-bit ones
+        let limit = match code {
+            BPF_JSGT | BPF_JSGE | BPF_JSLT | BPF_JSLE => i32::MAX as u64,
+            _ => u32::MAX as u64,
+        };
+        if !is64 && (a.var.umax > limit || b.var.umax > limit) {
+            return Ok(Step::Branch {
+                fall: true,
+                target,
+                taken: Some(taken),
+            });
+        }
+
+        let refine = |state: &mut State, branch: bool| {
+            let (mut x, mut y) = (a.var, b.var);
+            refine_cond(code, branch, &mut x, &mut y)?;
+            state.regs[dst as usize].var = x;
+            if !use_imm {
+                state.regs[src as usize].var = y;
+            }
+            Some(())
+        };
+        let fall = refine(state, false).is_some();
+        let taken = refine(&mut taken, true).map(|_| taken);
+        Ok(Step::Branch { fall, target, taken })
+    }
+}
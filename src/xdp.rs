@@ -0,0 +1,174 @@
+use core::ops::Range;
+use core::ptr;
+
+use crate::consts::*;
+
+// length of an ethernet header, the least a packet keeps
+const ETH_HLEN: u64 = 14;
+// metadata is made of u32, up to 32 bytes
+const XDP_META_MAX: u64 = 32;
+
+// The context of XDP programs, as seen by the JIT. Programs see it as `struct xdp_md`.
+// The packet is `data` to `data_end` in the frame starting at `data_hard_start`, and `data_meta` to
+// `data` holds metadata passed along with it.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct XdpBuff {
+    pub data: u64,
+    pub data_end: u64,
+    pub data_meta: u64,
+    pub data_hard_start: u64,
+    pub frame_sz: u32,
+    pub ingress_ifindex: u32,
+    pub rx_queue_index: u32,
+}
+
+// values returned by XDP programs, consistent with `enum xdp_action` in linux
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum XdpAction {
+    Aborted = 0,
+    Drop = 1,
+    Pass = 2,
+    Tx = 3,
+    Redirect = 4,
+}
+
+impl XdpAction {
+    // an invalid value aborts, as linux does
+    pub fn from_ret(ret: u64) -> Self {
+        match ret {
+            1 => XdpAction::Drop,
+            2 => XdpAction::Pass,
+            3 => XdpAction::Tx,
+            4 => XdpAction::Redirect,
+            _ => XdpAction::Aborted,
+        }
+    }
+}
+
+// the device and the queue a frame is received from
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct XdpRxQueue {
+    pub ifindex: u32,
+    pub queue_index: u32,
+}
+
+// the verdict of an XDP program on a frame, and where the packet and its metadata are once adjusted
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct XdpVerdict {
+    pub action: XdpAction,
+    pub data: Range<usize>,
+    pub meta: Range<usize>,
+}
+
+// the jitted code of an XDP program
+pub type XdpProgFn = unsafe extern "C" fn(*mut XdpBuff) -> u64;
+
+/// Run an XDP program on `frame`, whose packet starts after `headroom` bytes.
+/// The program is given the whole frame, so it may grow the packet into the headroom, and back
+/// to the end of the frame once shrunk.
+///
+/// # Safety
+///
+/// `prog` must be the jitted code of a verified XDP program, calling only helpers which keep the
+/// pointers of the `XdpBuff` inside the frame, such as the `bpf_xdp_adjust_*` ones here.
+pub unsafe fn run_xdp(prog: XdpProgFn, frame: &mut [u8], headroom: usize, rxq: XdpRxQueue) -> XdpVerdict {
+    run_xdp_with(frame, headroom, rxq, |xdp| prog(xdp))
+}
+
+// Same as `run_xdp`, the program being run by `run`, e.g. in an emulator.
+pub fn run_xdp_with<F>(frame: &mut [u8], headroom: usize, rxq: XdpRxQueue, run: F) -> XdpVerdict
+where
+    F: FnOnce(*mut XdpBuff) -> u64,
+{
+    assert!(headroom <= frame.len(), "headroom beyond the frame");
+    let start = frame.as_mut_ptr() as u64;
+    let data = start + headroom as u64;
+    let mut xdp = XdpBuff {
+        data,
+        data_end: start + frame.len() as u64,
+        data_meta: data,
+        data_hard_start: start,
+        frame_sz: frame.len() as u32,
+        ingress_ifindex: rxq.ifindex,
+        rx_queue_index: rxq.queue_index,
+    };
+    let action = XdpAction::from_ret(run(&mut xdp));
+    let offset = |addr: u64| (addr - start) as usize;
+    XdpVerdict {
+        action,
+        data: offset(xdp.data)..offset(xdp.data_end),
+        meta: offset(xdp.data_meta)..offset(xdp.data),
+    }
+}
+
+/// bpf_xdp_adjust_head, helper #44.
+/// Move the start of the packet by `offset`, along with metadata, keeping at least an ethernet header.
+///
+/// # Safety
+///
+/// `xdp` must point to the `XdpBuff` of a frame being run by `run_xdp`, with its pointers inside the frame.
+pub unsafe extern "C" fn bpf_xdp_adjust_head(xdp: u64, offset: u64) -> u64 {
+    let xdp = &mut *(xdp as *mut XdpBuff);
+    let offset = offset as i32 as i64;
+    let meta_len = xdp.data - xdp.data_meta;
+    // checked on offsets from the start of the frame, where addresses could wrap around
+    let head = xdp.data - xdp.data_hard_start;
+    let end = xdp.data_end - xdp.data_hard_start;
+    let head = if offset < 0 {
+        head.checked_sub(-offset as u64)
+    } else {
+        head.checked_add(offset as u64)
+    };
+    let head = match head {
+        Some(head) if head >= meta_len && end >= ETH_HLEN && head <= end - ETH_HLEN => head,
+        _ => return -EINVAL as u64,
+    };
+    let data = xdp.data_hard_start + head;
+    if meta_len > 0 {
+        ptr::copy(xdp.data_meta as *const u8, (data - meta_len) as *mut u8, meta_len as usize);
+    }
+    xdp.data_meta = data - meta_len;
+    xdp.data = data;
+    0
+}
+
+/// bpf_xdp_adjust_meta, helper #54.
+/// Move the start of metadata by `offset`, metadata being a multiple of 4 bytes, up to 32 bytes.
+///
+/// # Safety
+///
+/// `xdp` must point to the `XdpBuff` of a frame being run by `run_xdp`, with its pointers inside the frame.
+pub unsafe extern "C" fn bpf_xdp_adjust_meta(xdp: u64, offset: u64) -> u64 {
+    let xdp = &mut *(xdp as *mut XdpBuff);
+    let meta = xdp.data_meta.wrapping_add(offset as i32 as i64 as u64);
+    if meta < xdp.data_hard_start || meta > xdp.data {
+        return -EINVAL as u64;
+    }
+    let meta_len = xdp.data - meta;
+    if meta_len & 3 != 0 || meta_len > XDP_META_MAX {
+        return -EACCES as u64;
+    }
+    xdp.data_meta = meta;
+    0
+}
+
+/// bpf_xdp_adjust_tail, helper #65.
+/// Move the end of the packet by `offset`, up to the end of the frame. Grown bytes are zeroed.
+///
+/// # Safety
+///
+/// `xdp` must point to the `XdpBuff` of a frame being run by `run_xdp`, with its pointers inside the frame.
+pub unsafe extern "C" fn bpf_xdp_adjust_tail(xdp: u64, offset: u64) -> u64 {
+    let xdp = &mut *(xdp as *mut XdpBuff);
+    let offset = offset as i32 as i64;
+    let data_end = xdp.data_end.wrapping_add(offset as u64);
+    if data_end > xdp.data_hard_start + xdp.frame_sz as u64 || data_end < xdp.data + ETH_HLEN {
+        return -EINVAL as u64;
+    }
+    if offset > 0 {
+        ptr::write_bytes(xdp.data_end as *mut u8, 0, offset as usize);
+    }
+    xdp.data_end = data_end;
+    0
+}
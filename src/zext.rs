@@ -0,0 +1,126 @@
+extern crate alloc;
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::consts::*;
+
+fn reg_bit(reg: u8) -> u16 {
+    1 << reg
+}
+
+// R0 - R5, clobbered by helper calls and LD_ABS / LD_IND
+pub const CALLER_SAVED: u16 = 0x3f;
+// R1 - R5, helper arguments
+pub const ARG_REGS: u16 = 0x3e;
+
+// Registers whose upper 32 bits `insn` reads, and registers it writes.
+// 32-bit ALU ops and JMP32 only read the lower halves, so do stores of less than 8 bytes.
+fn uses_defs(insn: u64) -> (u16, u16) {
+    let op = insn as u8;
+    let dst = ((insn >> 8) & 0xf) as u8;
+    let src = ((insn >> 12) & 0xf) as u8;
+    let code = (op & 0xf0) as u32;
+    let src_bit = if (op & 8) != 0 { reg_bit(src) } else { 0 };
+    match (op & 0b111) as u32 {
+        BPF_ALU64 if code == BPF_MOV => (src_bit, reg_bit(dst)),
+        BPF_ALU64 => (reg_bit(dst) | src_bit, reg_bit(dst)),
+        BPF_ALU if code == BPF_END => (reg_bit(dst), reg_bit(dst)),
+        BPF_ALU => (0, reg_bit(dst)),
+        BPF_JMP32 => (0, 0),
+        BPF_JMP => match code {
+            BPF_JA => (0, 0),
+            BPF_CALL => (ARG_REGS, CALLER_SAVED),
+            BPF_EXIT => (reg_bit(BPF_REG_R0), 0),
+            _ => (reg_bit(dst) | src_bit, 0),
+        },
+        BPF_LDX => (reg_bit(src), reg_bit(dst)),
+        BPF_ST => (reg_bit(dst), 0),
+        BPF_STX if (op & 0xe0) as u32 == BPF_MEM && (op & 0b11000) as u32 != BPF_DW => (reg_bit(dst), 0),
+        BPF_STX => (reg_bit(dst) | reg_bit(src), 0),
+        _ if op == LD_IMM_DW => (0, reg_bit(dst)),
+        // LD_ABS / LD_IND
+        _ => {
+            let index = if (op & 0xe0) as u32 == BPF_IND { reg_bit(src) } else { 0 };
+            (reg_bit(BPF_REG_R6) | index, CALLER_SAVED)
+        }
+    }
+}
+
+// instructions control may flow to after `pc`
+fn successors(insns: &[u64], pc: usize) -> [Option<usize>; 2] {
+    let insn = insns[pc];
+    let op = insn as u8;
+    if op == LD_IMM_DW {
+        return [Some(pc + 2), None];
+    }
+    let class = (op & 0b111) as u32;
+    if class != BPF_JMP && class != BPF_JMP32 {
+        return [Some(pc + 1), None];
+    }
+    let target = (pc as isize + 1 + (insn >> 16) as i16 as isize) as usize;
+    match (op & 0xf0) as u32 {
+        BPF_CALL => [Some(pc + 1), None],
+        BPF_EXIT => [None, None],
+        BPF_JA => [Some(target), None],
+        _ => [Some(pc + 1), Some(target)],
+    }
+}
+
+// Whether each pc starts an instruction, the second halves of LD_IMM_DW being never reached.
+pub fn insn_starts(insns: &[u64]) -> Vec<bool> {
+    let len = insns.len();
+    let mut starts = vec![true; len];
+    let mut pc = 0;
+    while pc < len {
+        if insns[pc] as u8 == LD_IMM_DW && pc + 1 < len {
+            starts[pc + 1] = false;
+            pc += 1;
+        }
+        pc += 1;
+    }
+    starts
+}
+
+// Registers live after each pc, found by a backward liveness analysis of what `uses_defs` gives
+// as read and written by each instruction.
+pub fn live_out(insns: &[u64], uses_defs: fn(u64) -> (u16, u16)) -> Vec<u16> {
+    let len = insns.len();
+    let starts = insn_starts(insns);
+    let live_out = |live_in: &[u16], pc: usize| {
+        successors(insns, pc)
+            .iter()
+            .flatten()
+            .filter_map(|&succ| live_in.get(succ))
+            .fold(0, |live, &succ_live| live | succ_live)
+    };
+    let mut live_in = vec![0u16; len];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for pc in (0..len).rev().filter(|&pc| starts[pc]) {
+            let (uses, defs) = uses_defs(insns[pc]);
+            let live = (live_out(&live_in, pc) & !defs) | uses;
+            if live != live_in[pc] {
+                live_in[pc] = live;
+                changed = true;
+            }
+        }
+    }
+    (0..len).map(|pc| if starts[pc] { live_out(&live_in, pc) } else { 0 }).collect()
+}
+
+// Whether the result of the 32-bit ALU op at each pc has to be zero-extended, as its upper half
+// may be read later. This is the `zext_dst` of linux, found by a backward liveness analysis of
+// the upper halves of registers.
+pub fn zext_needed(insns: &[u64]) -> Vec<bool> {
+    let starts = insn_starts(insns);
+    let live = live_out(insns, uses_defs);
+    (0..insns.len())
+        .map(|pc| {
+            let insn = insns[pc];
+            let dst = ((insn >> 8) & 0xf) as u8;
+            starts[pc] && (insn as u8 & 0b111) as u32 == BPF_ALU && live[pc] & reg_bit(dst) != 0
+        })
+        .collect()
+}